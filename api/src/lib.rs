@@ -8,16 +8,32 @@ pub enum UserInfoResult {
     NoSuchToken,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum VerificationMethod {
     None = 0,
     ProofOfWork = 1,
+
+    /// A code was sent to an email address, and entered back.
+    EmailCode = 2,
+
+    /// An admin or instructor approved the account by hand.
+    AdminApproval = 3,
+
+    /// A promocode that verifies accounts was redeemed.
+    Promocode = 4,
+
+    /// The account logged in through single sign-on, which is trusted to only know real people.
+    SingleSignOn = 5,
 }
 
 impl From<i64> for VerificationMethod {
     fn from(val: i64) -> Self {
         match val {
             1 => VerificationMethod::ProofOfWork,
+            2 => VerificationMethod::EmailCode,
+            3 => VerificationMethod::AdminApproval,
+            4 => VerificationMethod::Promocode,
+            5 => VerificationMethod::SingleSignOn,
             _ => VerificationMethod::None,
         }
     }
@@ -32,11 +48,64 @@ impl From<Option<i64>> for VerificationMethod {
     }
 }
 
+/// What an account is allowed to do.
+/// Each role can do everything that the roles before it can.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    #[default]
+    Student = 0,
+    /// Can manage the members of groups and see promocodes and allowances.
+    Instructor = 1,
+    /// Can do anything, including making accounts and money.
+    Admin = 2,
+}
+
+impl From<i64> for Role {
+    fn from(val: i64) -> Self {
+        match val {
+            1 => Role::Instructor,
+            2 => Role::Admin,
+            _ => Role::Student,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UserInfo {
     pub name: String,
     pub balance: f64,
+
+    /// How the account was verified, or None if it wasn't yet; unverified accounts can't make orders.
     pub verification: VerificationMethod,
+
+    /// The ways that the account can be verified on this server.
+    #[serde(default)]
+    pub available_verification_methods: Vec<VerificationMethod>,
+
+    /// Whether the account has asked to be approved by hand, and is waiting for a decision.
+    #[serde(default)]
+    pub verification_request_pending: bool,
+
+    /// If the account is in a group, orders are paid from the group's balance first.
+    #[serde(default)]
+    pub group: Option<GroupInfo>,
+
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GroupInfo {
+    pub name: String,
+
+    /// How much this member can still spend from the group's balance right now.
+    pub available: f64,
+
+    /// How much this member may spend from the group's balance in total; if None, there is no limit.
+    pub spending_limit: Option<f64>,
+
+    /// How much this member has spent from the group's balance so far.
+    pub spent: f64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
@@ -48,9 +117,19 @@ pub struct PricingInfo {
     pub process_fork_cost: f64,
     pub overdraft_seconds_allowed: f64,
     pub error_order_cost: f64,
+
+    /// The load-based multiplier that has already been applied to the resource factors above.
+    /// Orders that were recorded before surge pricing existed were charged at 1.0.
+    #[serde(default = "default_surge_multiplier")]
+    pub surge_multiplier: f64,
+}
+
+fn default_surge_multiplier() -> f64 {
+    1.0
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum OrderInfoResult {
     /// Either the order does not exist or you can't access it.
     NotAccessible,
@@ -66,8 +145,15 @@ pub enum OrderInfoResult {
 /// This record is stored in the database.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct OrderInfo {
+    /// The funds available to the order when it started:
+    /// the personal balance, plus what could be spent from the group's balance.
     pub balance_before: f64,
     pub order_cost: f64,
+
+    /// The part of the order cost that was paid from the group's balance rather than the personal one.
+    #[serde(default)]
+    pub group_charge: f64,
+
     pub pricing_applied: PricingInfo,
     pub termination: JobTerminationStatus,
 }
@@ -78,6 +164,19 @@ pub struct OrderInfoFull {
     pub record: OrderInfo,
     pub is_on_disk: bool,
     pub created_at_unix_time: u64,
+
+    /// If some of the order's cost was given back to the user, this is the record of that.
+    pub refund: Option<OrderRefund>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OrderRefund {
+    pub amount: f64,
+    pub reason: String,
+
+    /// True if the refund was issued by the automatic refund policy, false if by an admin.
+    pub is_automatic: bool,
+    pub refunded_at_unix_time: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
@@ -88,6 +187,9 @@ pub struct OrderExecutionMetrics {
     pub uploaded_mb: f64,
     pub uploaded_files: usize,
     pub time_until_overdraft_stop: Option<f64>,
+
+    /// If the user set a spending cap for this order, how much of it is left.
+    pub remaining_budget: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -145,6 +247,9 @@ pub enum TerminationCause {
 
     /// Killed because of running out of money
     BalanceKill,
+
+    /// Killed because the order cost reached the spending cap that the user set for it
+    BudgetCapReached,
 }
 
 /// This represents the status of a running job.
@@ -163,7 +268,12 @@ pub enum JobStatus {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LiveStatus {
     pub status: JobStatus,
+
+    /// The pricing that was locked in when this order started.
     pub pricing: PricingInfo,
+
+    /// The surge multiplier that a new order would get right now.
+    pub current_surge_multiplier: f64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -176,10 +286,144 @@ pub struct OrderFile {
     pub is_new: bool,
 }
 
+/// A file that the user is planning to upload as part of an order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlannedFile {
+    pub path: String,
+    pub size_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CostEstimateRequest {
+    pub files: Vec<PlannedFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CostEstimate {
+    /// The pricing that the estimate was made with.
+    /// The actual order may get a different surge multiplier if it is started later.
+    pub pricing: PricingInfo,
+
+    /// The cost of uploading the planned files. This part is exact.
+    pub upload_cost: f64,
+
+    /// The lowest and highest cost of running the makefile among the similar past orders,
+    /// recalculated with the current pricing.
+    /// None if the account has no similar past orders.
+    pub execution_cost_range: Option<(f64, f64)>,
+
+    /// How many past orders the execution cost range was computed from.
+    pub similar_orders: usize,
+}
+
+impl CostEstimate {
+    /// The lowest and highest total cost of the order.
+    /// If there is no history to go on, this only includes the upload cost.
+    pub fn total_range(&self) -> (f64, f64) {
+        match self.execution_cost_range {
+            Some((min, max)) => (self.upload_cost + min, self.upload_cost + max),
+            None => (self.upload_cost, self.upload_cost),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LoginRequest {
     pub handle: String,
     pub password: String,
+
+    /// A solution to the challenge from [`LoginResponse::ProofOfWorkRequired`], if one was given.
+    #[serde(default)]
+    pub proof_of_work: Option<verification::ProofOfWorkAttempt>,
+
+    /// A code from the authenticator app, or an unused recovery code,
+    /// if the account has two-factor authentication.
+    #[serde(default)]
+    pub two_factor_code: Option<String>,
+}
+
+/// How to log in with single sign-on, if the server offers it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SingleSignOnInfo {
+    /// What to call the identity provider on the login button.
+    pub provider_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LoginResponse {
+    /// Logged in; here is the session token.
+    Ok {
+        token: String,
+    },
+    InvalidCredentials,
+    /// There were too many failed logins: solve this challenge and send it with the next attempt.
+    ProofOfWorkRequired {
+        challenge: String,
+    },
+    /// There were far too many failed logins, so no more attempts are accepted until then.
+    LockedOut {
+        until_unix_time: u64,
+    },
+    /// The password is right, but the account also needs a two-factor code: send it with the password.
+    TwoFactorRequired,
+    /// The two-factor code was wrong or has already been used.
+    InvalidTwoFactorCode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoginLinkRequest {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LoginLinkResponse {
+    /// Logged in; here is the session token.
+    Ok { token: String },
+    /// The link doesn't exist, has expired or was already used.
+    InvalidLink,
+}
+
+/// Why a new password isn't accepted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PasswordProblem {
+    TooShort {
+        min_length: usize,
+    },
+    TooLong {
+        max_length: usize,
+    },
+    /// The password is the same as the login handle.
+    SameAsHandle,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RegisterRequest {
+    pub invite_code: String,
+    pub name: String,
+    pub handle: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RegisterResponse {
+    /// The account was made and is logged in; here is the session token.
+    /// It still needs to be verified before it can make orders.
+    Ok {
+        token: String,
+    },
+    /// The invite code doesn't exist, has expired or has been used up.
+    InvalidInviteCode,
+    /// The name is empty.
+    InvalidName,
+    /// The handle must be from 3 to 32 Latin letters, digits, dots, dashes and underscores.
+    InvalidHandle,
+    /// Someone already has this handle.
+    HandleTaken,
+    BadPassword(PasswordProblem),
+    /// Too many wrong invite codes came from this address; registering is refused until then.
+    LockedOut {
+        until_unix_time: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -193,6 +437,8 @@ pub enum ChangePasswordResponse {
     Ok { new_token: String },
     /// The old token was not correct, so the change was not applied
     InvalidToken,
+    /// The new password doesn't follow the rules, so the change was not applied
+    BadPassword(PasswordProblem),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -201,10 +447,309 @@ pub enum RedeemPromocodeResponse {
     Ok {
         promocode_value: f64,
         user_balance_after: f64,
+
+        /// Whether redeeming the promocode also verified the account.
+        #[serde(default)]
+        verified_account: bool,
     },
-    /// The promocode was redeemed already
+    /// The promocode was redeemed already.
+    /// If `by_me` is true, you have used up all the redemptions allowed per account,
+    /// and `when_unix_time` is your latest one.
+    /// Otherwise, this was a single-use promocode that someone else redeemed.
     AlreadyRedeemed { when_unix_time: u64, by_me: bool },
 
+    /// The promocode was valid until the given time, but isn't anymore.
+    Expired { when_unix_time: u64 },
+
+    /// The promocode can only be redeemed this many times in total, and all of them have been used.
+    ExhaustedUses { max_uses: u64 },
+
     /// The promocode doesn't seem to exist at all.
     NotFound,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransferRequest {
+    /// The login handle of the account that receives the money.
+    pub recipient_handle: String,
+    pub amount: f64,
+    pub comment: Option<String>,
+
+    /// If false, nothing is transferred yet:
+    /// the server only checks the request and replies with [`TransferResponse::NeedsConfirmation`].
+    pub confirm: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferResponse {
+    /// The transfer is possible; send the same request with `confirm` set to perform it.
+    NeedsConfirmation {
+        recipient_name: String,
+        amount: f64,
+        balance_after: f64,
+    },
+
+    /// The money was transferred.
+    Ok { balance_after: f64 },
+
+    /// There is no account with this handle.
+    NoSuchRecipient,
+
+    /// The recipient is the same account as the sender.
+    CannotTransferToSelf,
+
+    /// The amount must be a positive number.
+    InvalidAmount,
+
+    /// The sender doesn't have enough money for this transfer.
+    InsufficientBalance { balance: f64 },
+
+    /// A single transfer can't be larger than this.
+    OverTransferLimit { max_amount: f64 },
+
+    /// The sender can't transfer more than `limit` in a day, and has already transferred `used` of it.
+    OverDailyLimit { limit: f64, used: f64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BalanceChangeKind {
+    Other = 0,
+    TransferIn = 1,
+    TransferOut = 2,
+    AllowanceGrant = 3,
+    Payment = 4,
+    StartingBalance = 5,
+    Refund = 6,
+}
+
+impl From<i64> for BalanceChangeKind {
+    fn from(val: i64) -> Self {
+        match val {
+            1 => BalanceChangeKind::TransferIn,
+            2 => BalanceChangeKind::TransferOut,
+            3 => BalanceChangeKind::AllowanceGrant,
+            4 => BalanceChangeKind::Payment,
+            5 => BalanceChangeKind::StartingBalance,
+            6 => BalanceChangeKind::Refund,
+            _ => BalanceChangeKind::Other,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceHistoryEntry {
+    pub kind: BalanceChangeKind,
+
+    /// Negative if money was taken from the account.
+    pub amount: f64,
+
+    /// The name of the other account involved, if any.
+    pub counterparty_name: Option<String>,
+    pub comment: Option<String>,
+    pub when_unix_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NewPaymentRequest {
+    pub amount: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum NewPaymentResponse {
+    /// The payment was created; send the user to the URL to pay.
+    /// After that, the provider sends them back to the payment page on the frontend.
+    Redirect {
+        payment_id: String,
+        redirect_url: String,
+    },
+
+    /// The amount must be between these values.
+    InvalidAmount { min: f64, max: f64 },
+
+    /// Payments are not set up on this server.
+    Unavailable,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PaymentStatus {
+    /// The provider hasn't told us about the outcome yet.
+    Pending = 0,
+    /// The money has been added to the balance.
+    Succeeded = 1,
+    Failed = 2,
+}
+
+impl From<i64> for PaymentStatus {
+    fn from(val: i64) -> Self {
+        match val {
+            1 => PaymentStatus::Succeeded,
+            2 => PaymentStatus::Failed,
+            _ => PaymentStatus::Pending,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PaymentInfo {
+    pub id: String,
+    pub amount: f64,
+    pub status: PaymentStatus,
+    pub created_at_unix_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub created_at_unix_time: u64,
+    pub last_used_at_unix_time: u64,
+    pub expires_at_unix_time: u64,
+
+    /// Whether this is the session that the request was made with.
+    pub is_current: bool,
+}
+
+/// What an API key can be used for.
+/// A key can't do anything else: managing the account needs a login session.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiKeyScope {
+    UploadOrders = 1,
+    ReadOrders = 2,
+    ReadProfile = 4,
+}
+
+impl ApiKeyScope {
+    pub const ALL: [ApiKeyScope; 3] = [
+        ApiKeyScope::UploadOrders,
+        ApiKeyScope::ReadOrders,
+        ApiKeyScope::ReadProfile,
+    ];
+
+    /// The scopes whose bits are set.
+    pub fn from_bits(bits: i64) -> Vec<ApiKeyScope> {
+        Self::ALL
+            .into_iter()
+            .filter(|scope| bits & *scope as i64 != 0)
+            .collect()
+    }
+
+    pub fn to_bits(scopes: &[ApiKeyScope]) -> i64 {
+        scopes.iter().fold(0, |bits, scope| bits | *scope as i64)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NewApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub expires_at_unix_time: Option<u64>,
+
+    /// The most that orders made with this key can cost in total.
+    pub spending_limit: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum NewApiKeyResponse {
+    /// The key is only ever shown here: after this, only its prefix is known.
+    Ok {
+        id: i64,
+        key: String,
+    },
+    EmptyName,
+    NoScopes,
+    InvalidSpendingLimit,
+    AlreadyExpired,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiKeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub spending_limit: Option<f64>,
+    pub spent: f64,
+    pub created_at_unix_time: u64,
+    pub last_used_at_unix_time: Option<u64>,
+    pub expires_at_unix_time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SecurityEventKind {
+    /// Logging in was locked out because of too many wrong passwords.
+    LoginLockout = 0,
+    TwoFactorEnabled = 1,
+    TwoFactorDisabled = 2,
+    /// Someone logged in with a recovery code instead of the authenticator app.
+    RecoveryCodeUsed = 3,
+    /// Staff turned off two-factor authentication, for someone who lost their authenticator.
+    TwoFactorReset = 4,
+}
+
+impl From<i64> for SecurityEventKind {
+    fn from(val: i64) -> Self {
+        match val {
+            1 => SecurityEventKind::TwoFactorEnabled,
+            2 => SecurityEventKind::TwoFactorDisabled,
+            3 => SecurityEventKind::RecoveryCodeUsed,
+            4 => SecurityEventKind::TwoFactorReset,
+            _ => SecurityEventKind::LoginLockout,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SecurityEvent {
+    pub kind: SecurityEventKind,
+    pub ip: Option<String>,
+    pub when_unix_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum BeginTwoFactorResponse {
+    /// Add this to the authenticator app, then confirm with a code from it.
+    Ok {
+        /// The secret in base32, for typing in by hand.
+        secret: String,
+        /// The `otpauth://` URI, which is what the QR code holds.
+        provisioning_uri: String,
+        /// The QR code as an SVG image.
+        qr_svg: String,
+    },
+    AlreadyEnabled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConfirmTwoFactorResponse {
+    /// Two-factor authentication is on.
+    /// The recovery codes are only ever shown here, and each can be used once instead of a code.
+    Ok {
+        recovery_codes: Vec<String>,
+    },
+    NotStarted,
+    AlreadyEnabled,
+    InvalidCode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DisableTwoFactorResponse {
+    Ok,
+    NotEnabled,
+    InvalidCode,
+
+    /// There were too many wrong codes; codes aren't checked until then.
+    LockedOut {
+        until_unix_time: u64,
+    },
+}
@@ -1,8 +1,48 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum ProofOfWorkAlgorithm {
     Sha256,
+
+    /// Memory-hard, so that GPUs and ASICs don't get much of an edge over the browser.
+    Argon2id {
+        /// How much memory each hash takes, in KiB.
+        memory_kib: u32,
+        iterations: u32,
+    },
+}
+
+impl ProofOfWorkAlgorithm {
+    /// Hash the challenge's nonce together with an attempted postfix.
+    pub fn hash(&self, nonce: &[u8], postfix: &[u8]) -> Vec<u8> {
+        match *self {
+            ProofOfWorkAlgorithm::Sha256 => {
+                use sha2::{Digest, Sha256};
+                let mut hasher = Sha256::new();
+                hasher.update(nonce);
+                hasher.update(postfix);
+                hasher.finalize().to_vec()
+            }
+            ProofOfWorkAlgorithm::Argon2id {
+                memory_kib,
+                iterations,
+            } => {
+                // The attempt is the password, and the nonce is the salt.
+                let params = argon2::Params::new(memory_kib, iterations, 1, Some(32))
+                    .expect("invalid Argon2 parameters in challenge");
+                let argon2 = argon2::Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    params,
+                );
+                let mut output = vec![0u8; 32];
+                argon2
+                    .hash_password_into(postfix, nonce, &mut output)
+                    .expect("failed to compute Argon2 hash");
+                output
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -10,20 +50,100 @@ pub struct ProofOfWorkChallenge {
     pub nonce: Vec<u8>,
     pub difficulty: u64,
     pub algorithm: ProofOfWorkAlgorithm,
+
+    /// Who the challenge was given to, so that its solution can't be used by anyone else.
+    pub subject: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProofOfWorkChallengeResponse {
+    /// The signed challenge, to be sent back along with the solution.
+    pub challenge: String,
+
+    /// How many hashes it takes to solve the challenge, on average.
+    pub expected_hashes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ProofOfWorkAttempt {
     pub challenge_string: String,
     pub nonce_postfix: Vec<u8>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SendEmailCodeRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SendEmailCodeResponse {
+    /// The code was sent, and can now be confirmed.
+    Sent,
+
+    /// The account is already verified, so there is no need.
+    AlreadyVerified,
+
+    /// That doesn't look like an email address.
+    InvalidEmail,
+
+    /// Only addresses on these domains are accepted.
+    DomainNotAllowed { allowed_domains: Vec<String> },
+
+    /// The address has already been used to verify another account.
+    EmailTaken,
+
+    /// A code was sent only recently; another one can be sent after this many seconds.
+    TooSoon { retry_after_seconds: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConfirmEmailCodeRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConfirmEmailCodeResponse {
+    /// The account is now verified.
+    Ok,
+
+    AlreadyVerified,
+
+    /// No code was sent, or the last one was used up or expired; a new one needs to be sent.
+    NoCodeSent,
+
+    WrongCode {
+        attempts_left: u64,
+    },
+
+    /// Someone else verified their account with this address after the code was sent.
+    EmailTaken,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApprovalRequest {
+    /// Who the user is, so that the staff can decide; like a student ID or a group name.
+    pub note: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApprovalRequestResponse {
+    /// The staff will see the request.
+    Requested,
+    AlreadyRequested,
+    AlreadyVerified,
+    EmptyNote,
+}
+
+/// How many hashes it takes, on average, to find one with the given difficulty.
+pub fn expected_hashes(difficulty: u64) -> u64 {
+    1u64.checked_shl(difficulty as u32).unwrap_or(u64::MAX)
+}
+
 /// Compute the difficulty of the PoW hash
 pub fn check_hash_difficulty(hash: &[u8]) -> u64 {
     // Count the number of bits in front, which are equal to zero
     let mut count: u64 = 0;
-    let mut iter = hash.iter();
-    while let Some(b) = iter.next() {
+    for b in hash {
         // If the current byte is zero, increase the count by 8
         if *b == 0 {
             count += 8;
@@ -0,0 +1,282 @@
+use api::{ApiKeyInfo, ApiKeyScope, NewApiKeyRequest, NewApiKeyResponse};
+use chrono::Local;
+use shadow_clone::shadow_clone;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew::suspense::use_future;
+use yew_autoprops::autoprops;
+use yew_bootstrap::{
+    component::{
+        form::{FormControl, FormControlType, FormControlValidation},
+        Button, Spinner,
+    },
+    util::Color,
+};
+use yew_hooks::use_async;
+
+use crate::{url_macro::url, MONEY};
+
+fn scope_name(scope: ApiKeyScope) -> &'static str {
+    match scope {
+        ApiKeyScope::UploadOrders => "Загрузка заказов",
+        ApiKeyScope::ReadOrders => "Просмотр заказов",
+        ApiKeyScope::ReadProfile => "Просмотр профиля",
+    }
+}
+
+fn format_time(unix_time: u64) -> String {
+    chrono::DateTime::from_timestamp(unix_time as i64, 0)
+        .expect("failed to parse incoming unix time as date")
+        .with_timezone(&Local)
+        .to_string()
+}
+
+#[function_component(ApiKeys)]
+pub fn api_keys() -> Html {
+    html! {
+        <>
+            <h3>{"Ключи API"}</h3>
+            <p>{"Ключи нужны для скриптов и CI: с ними можно загружать и смотреть заказы, не входя в аккаунт. Передавайте ключ в заголовке "}<code>{"Authorization: Bearer"}</code>{"."}</p>
+            <Suspense fallback={html!(<Spinner />)}>
+                <ApiKeyList />
+            </Suspense>
+            <NewApiKeyForm />
+        </>
+    }
+}
+
+#[function_component(ApiKeyList)]
+fn api_key_list() -> HtmlResult {
+    let resp = use_future(|| async move {
+        reqwest::get(url!("/api/me/api-keys"))
+            .await?
+            .error_for_status()?
+            .json::<Vec<ApiKeyInfo>>()
+            .await
+    })?;
+
+    let keys = match *resp {
+        Ok(ref keys) => keys,
+        Err(ref failure) => {
+            return Ok(
+                html!(<div class="alert alert-danger">{"Ошибка при загрузке ключей: "}{failure.to_string()}</div>),
+            )
+        }
+    };
+
+    if keys.is_empty() {
+        return Ok(html!(<p>{"У вас пока нет ключей."}</p>));
+    }
+
+    Ok(html! {
+        <table class="table">
+            <thead>
+                <tr>
+                    <th>{"Название"}</th>
+                    <th>{"Ключ"}</th>
+                    <th>{"Права"}</th>
+                    <th>{"Потрачено"}</th>
+                    <th>{"Последнее использование"}</th>
+                    <th>{"Истекает"}</th>
+                    <th></th>
+                </tr>
+            </thead>
+            <tbody>
+                {for keys.iter().map(|key| html!(<ApiKeyRow key_info={key.clone()} />))}
+            </tbody>
+        </table>
+    })
+}
+
+#[autoprops]
+#[function_component(ApiKeyRow)]
+fn api_key_row(key_info: &ApiKeyInfo) -> Html {
+    let revoke: yew_hooks::prelude::UseAsyncHandle<(), String> = use_async({
+        let id = key_info.id;
+        async move {
+            reqwest::Client::default()
+                .post(url!("/api/me/api-keys/{id}/revoke"))
+                .send()
+                .await
+                .map_err(|v| v.to_string())?
+                .error_for_status()
+                .map_err(|v| v.to_string())?;
+            gloo::utils::document()
+                .location()
+                .unwrap()
+                .reload()
+                .unwrap();
+            Ok(())
+        }
+    });
+    let onclick = {
+        shadow_clone!(revoke);
+        move |_ev| revoke.run()
+    };
+
+    let scopes = key_info
+        .scopes
+        .iter()
+        .map(|scope| scope_name(*scope))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let spent = match key_info.spending_limit {
+        Some(limit) => format!("{:.3} из {limit:.3}{MONEY}", key_info.spent),
+        None => format!("{:.3}{MONEY}", key_info.spent),
+    };
+
+    html! {
+        <tr>
+            <td>{&key_info.name}</td>
+            <td><code>{format!("{}…", key_info.key_prefix)}</code></td>
+            <td>{scopes}</td>
+            <td>{spent}</td>
+            <td>{key_info.last_used_at_unix_time.map(format_time).unwrap_or_else(|| "никогда".to_string())}</td>
+            <td>{key_info.expires_at_unix_time.map(format_time).unwrap_or_else(|| "никогда".to_string())}</td>
+            <td>
+                <Button style={Color::Danger} outline={true} disabled={revoke.loading} {onclick}>
+                    {"Отозвать"}
+                </Button>
+                if let Some(why) = &revoke.error {
+                    <div class="text-danger">{why}</div>
+                }
+            </td>
+        </tr>
+    }
+}
+
+#[function_component(NewApiKeyForm)]
+fn new_api_key_form() -> Html {
+    let name_state = use_state(String::new);
+    let scopes_state = use_state(Vec::<ApiKeyScope>::new);
+    let days_state = use_state(String::new);
+    let limit_state = use_state(String::new);
+
+    let make_oninput = |state: &UseStateHandle<String>| {
+        shadow_clone!(state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            state.set(target.value());
+        }
+    };
+    let oninput_name = make_oninput(&name_state);
+    let oninput_days = make_oninput(&days_state);
+    let oninput_limit = make_oninput(&limit_state);
+
+    let create_result: yew_hooks::prelude::UseAsyncHandle<NewApiKeyResponse, String> = use_async({
+        shadow_clone!(name_state, scopes_state, days_state, limit_state);
+        async move {
+            let expires_at_unix_time = match days_state.trim() {
+                "" => None,
+                days => {
+                    let days: u64 = days
+                        .parse()
+                        .map_err(|_| "Срок действия должен быть целым числом дней".to_string())?;
+                    let now = (js_sys::Date::now() / 1000.0) as u64;
+                    Some(now + days * 24 * 60 * 60)
+                }
+            };
+            let spending_limit = match limit_state.trim() {
+                "" => None,
+                limit => Some(
+                    limit
+                        .parse::<f64>()
+                        .map_err(|_| "Лимит расходов должен быть числом".to_string())?,
+                ),
+            };
+            reqwest::Client::default()
+                .post(url!("/api/me/api-keys/new"))
+                .json(&NewApiKeyRequest {
+                    name: (*name_state).clone(),
+                    scopes: (*scopes_state).clone(),
+                    expires_at_unix_time,
+                    spending_limit,
+                })
+                .send()
+                .await
+                .map_err(|v| v.to_string())?
+                .json::<NewApiKeyResponse>()
+                .await
+                .map_err(|v| v.to_string())
+        }
+    });
+    let create = {
+        shadow_clone!(create_result);
+        move |_ev| {
+            create_result.run();
+        }
+    };
+
+    let scope_checkboxes = ApiKeyScope::ALL.into_iter().map(|scope| {
+        let checked = scopes_state.contains(&scope);
+        let onchange = {
+            shadow_clone!(scopes_state);
+            move |_ev| {
+                let mut scopes = (*scopes_state).clone();
+                if checked {
+                    scopes.retain(|s| *s != scope);
+                } else {
+                    scopes.push(scope);
+                }
+                scopes_state.set(scopes);
+            }
+        };
+        let id = format!("api-key-scope-{}", scope as i64);
+        html! {
+            <div class="form-check">
+                <input class="form-check-input" type="checkbox" id={id.clone()} {checked} {onchange} />
+                <label class="form-check-label" for={id}>{scope_name(scope)}</label>
+            </div>
+        }
+    });
+
+    let validation = match (&create_result.data, &create_result.error) {
+        (Some(NewApiKeyResponse::Ok { .. }), _) => FormControlValidation::None,
+        (Some(NewApiKeyResponse::EmptyName), _) => {
+            FormControlValidation::Invalid("Дайте ключу название.".into())
+        }
+        (Some(NewApiKeyResponse::NoScopes), _) => {
+            FormControlValidation::Invalid("Выберите хотя бы одно право.".into())
+        }
+        (Some(NewApiKeyResponse::InvalidSpendingLimit), _) => {
+            FormControlValidation::Invalid("Лимит расходов должен быть положительным.".into())
+        }
+        (Some(NewApiKeyResponse::AlreadyExpired), _) => {
+            FormControlValidation::Invalid("Срок действия должен быть в будущем.".into())
+        }
+        (None, Some(why)) => {
+            FormControlValidation::Invalid(format!("Ошибка при создании ключа: {why}").into())
+        }
+        (None, None) => FormControlValidation::None,
+    };
+
+    let new_key = match &create_result.data {
+        Some(NewApiKeyResponse::Ok { key, .. }) => html! {
+            <div class="alert alert-success">
+                <p>{"Ключ создан. Скопируйте его сейчас: больше он показан не будет."}</p>
+                <code>{key}</code>
+            </div>
+        },
+        _ => html!(),
+    };
+
+    html! {
+        <>
+            <h4>{"Новый ключ"}</h4>
+            <FormControl id="api-key-name" ctype={FormControlType::Text} class="mb-3" label="Название" oninput={oninput_name} value={(*name_state).clone()} disabled={create_result.loading} />
+            <div class="mb-3">
+                {for scope_checkboxes}
+            </div>
+            <FormControl id="api-key-days" ctype={FormControlType::Number { min: Some(1), max: None }} class="mb-3" label="Срок действия, дней (необязательно)" oninput={oninput_days} value={(*days_state).clone()} disabled={create_result.loading} />
+            <FormControl id="api-key-limit" ctype={FormControlType::Number { min: None, max: None }} class="mb-3" label={format!("Лимит расходов, {MONEY} (необязательно)")} oninput={oninput_limit} value={(*limit_state).clone()} disabled={create_result.loading} {validation} />
+            {new_key}
+            <Button style={Color::Primary} disabled={create_result.loading} onclick={create}>
+                if create_result.loading {
+                    <Spinner small={true} />
+                }
+                {"Создать ключ"}
+            </Button>
+        </>
+    }
+}
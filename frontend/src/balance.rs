@@ -0,0 +1,368 @@
+use api::{
+    BalanceChangeKind, BalanceHistoryEntry, NewPaymentRequest, NewPaymentResponse, PaymentInfo,
+    PaymentStatus, TransferRequest, TransferResponse,
+};
+use chrono::Local;
+use shadow_clone::shadow_clone;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew::suspense::use_future;
+use yew_autoprops::autoprops;
+use yew_bootstrap::{
+    component::{
+        form::{FormControl, FormControlType, FormControlValidation},
+        Button, Spinner,
+    },
+    util::Color,
+};
+use yew_hooks::use_async;
+
+use crate::{profile::is_logged_in, url_macro::url, MONEY};
+
+#[function_component(TransferWidget)]
+pub fn transfer_widget() -> Html {
+    let handle_state = use_state(String::new);
+    let amount_state = use_state(String::new);
+    let comment_state = use_state(String::new);
+
+    let make_oninput = |state: &UseStateHandle<String>| {
+        shadow_clone!(state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            state.set(target.value());
+        }
+    };
+    let oninput_handle = make_oninput(&handle_state);
+    let oninput_amount = make_oninput(&amount_state);
+    let oninput_comment = make_oninput(&comment_state);
+
+    // The same request is sent twice: first to check it, then to confirm it.
+    let make_request = |confirm: bool| {
+        shadow_clone!(handle_state, amount_state, comment_state);
+        async move {
+            let amount: f64 = amount_state
+                .trim()
+                .parse()
+                .map_err(|_| "Сумма должна быть числом".to_string())?;
+            let comment = Some((*comment_state).clone()).filter(|c| !c.is_empty());
+            let client = reqwest::Client::default();
+            client
+                .post(url!("/api/me/transfer"))
+                .json(&TransferRequest {
+                    recipient_handle: (*handle_state).clone(),
+                    amount,
+                    comment,
+                    confirm,
+                })
+                .send()
+                .await
+                .map_err(|v| v.to_string())?
+                .json::<TransferResponse>()
+                .await
+                .map_err(|v| v.to_string())
+        }
+    };
+    let check_result: yew_hooks::prelude::UseAsyncHandle<TransferResponse, String> =
+        use_async(make_request(false));
+    let send_result: yew_hooks::prelude::UseAsyncHandle<TransferResponse, String> =
+        use_async(make_request(true));
+
+    let check = {
+        shadow_clone!(check_result);
+        move |_ev| {
+            check_result.run();
+        }
+    };
+    let send = {
+        shadow_clone!(send_result);
+        move |_ev| {
+            send_result.run();
+        }
+    };
+
+    let loading = check_result.loading || send_result.loading;
+
+    let describe = |resp: &TransferResponse| -> FormControlValidation {
+        match resp {
+            TransferResponse::NeedsConfirmation { .. } => FormControlValidation::None,
+            TransferResponse::Ok { balance_after } => FormControlValidation::Valid(Some(format!("Перевод выполнен! Теперь у вас {balance_after:.3}{MONEY}, обновите страницу чтобы увидеть результат.").into())),
+            TransferResponse::NoSuchRecipient => FormControlValidation::Invalid("Пользователь с таким логином не найден.".into()),
+            TransferResponse::CannotTransferToSelf => FormControlValidation::Invalid("Нельзя перевести деньги самому себе.".into()),
+            TransferResponse::InvalidAmount => FormControlValidation::Invalid("Сумма перевода должна быть положительной.".into()),
+            TransferResponse::InsufficientBalance { balance } => FormControlValidation::Invalid(format!("Недостаточно средств: у вас только {balance:.3}{MONEY}.").into()),
+            TransferResponse::OverTransferLimit { max_amount } => FormControlValidation::Invalid(format!("За один раз можно перевести не больше {max_amount:.3}{MONEY}.").into()),
+            TransferResponse::OverDailyLimit { limit, used } => FormControlValidation::Invalid(format!("За сутки можно перевести не больше {limit:.3}{MONEY}, а вы уже перевели {used:.3}{MONEY}.").into()),
+        }
+    };
+
+    let validation = match (&send_result.data, &send_result.error) {
+        (Some(resp), _) => describe(resp),
+        (None, Some(why)) => {
+            FormControlValidation::Invalid(format!("Ошибка при переводе: {why}").into())
+        }
+        (None, None) => match (&check_result.data, &check_result.error) {
+            (Some(resp), _) => describe(resp),
+            (None, Some(why)) => FormControlValidation::Invalid(
+                format!("Ошибка при проверке перевода: {why}").into(),
+            ),
+            (None, None) => FormControlValidation::None,
+        },
+    };
+
+    let confirmation = match (&check_result.data, &send_result.data) {
+        (
+            Some(TransferResponse::NeedsConfirmation {
+                recipient_name,
+                amount,
+                balance_after,
+            }),
+            None,
+        ) => html! {
+            <div class="alert alert-warning">
+                <p>{format!("Перевести {amount:.3}{MONEY} пользователю ")}<b>{recipient_name}</b>{"? "}{format!("После перевода у вас останется {balance_after:.3}{MONEY}.")}</p>
+                <Button style={Color::Warning} disabled={loading} onclick={send}>
+                    if send_result.loading {
+                        <Spinner small={true} />
+                        {"Переводим..."}
+                    } else {
+                        {"Подтвердить перевод"}
+                    }
+                </Button>
+            </div>
+        },
+        _ => html!(),
+    };
+
+    html! {
+        <>
+            <h3>{"Перевести деньги"}</h3>
+            <FormControl id="transfer-handle" ctype={FormControlType::Text} class="mb-3" label="Логин получателя" oninput={oninput_handle} value={(*handle_state).clone()} disabled={loading} />
+            <FormControl id="transfer-amount" ctype={FormControlType::Number { min: None, max: None }} class="mb-3" label={format!("Сумма, {MONEY}")} oninput={oninput_amount} value={(*amount_state).clone()} disabled={loading} />
+            <FormControl id="transfer-comment" ctype={FormControlType::Text} class="mb-3" label="Комментарий (необязательно)" oninput={oninput_comment} value={(*comment_state).clone()} disabled={loading} {validation} />
+
+            {confirmation}
+
+            <Button style={Color::Primary} disabled={loading} onclick={check}>
+                if check_result.loading {
+                    <Spinner small={true} />
+                    {"Проверяем..."}
+                } else {
+                    {"Продолжить"}
+                }
+            </Button>
+        </>
+    }
+}
+
+#[function_component(BalanceHistory)]
+pub fn balance_history() -> HtmlResult {
+    let resp = use_future(|| async move {
+        reqwest::get(url!("/api/me/balance-history"))
+            .await?
+            .json::<Vec<BalanceHistoryEntry>>()
+            .await
+    })?;
+
+    let entries = match *resp {
+        Ok(ref entries) => entries,
+        Err(ref failure) => {
+            return Ok(
+                html!(<div class="alert alert-danger">{"Ошибка при загрузке истории баланса: "}{failure.to_string()}</div>),
+            )
+        }
+    };
+
+    if entries.is_empty() {
+        return Ok(html!(<p>{"Здесь пока ничего нет."}</p>));
+    }
+
+    let rows = entries.iter().map(|entry| {
+        let when = chrono::DateTime::from_timestamp(entry.when_unix_time as i64, 0)
+            .expect("failed to parse incoming unix time as date")
+            .with_timezone(&Local)
+            .to_string();
+        let counterparty = entry.counterparty_name.clone().unwrap_or_default();
+        let description = match entry.kind {
+            BalanceChangeKind::TransferIn => format!("Перевод от {counterparty}"),
+            BalanceChangeKind::TransferOut => format!("Перевод для {counterparty}"),
+            BalanceChangeKind::AllowanceGrant => "Ежемесячное пополнение".to_string(),
+            BalanceChangeKind::Payment => "Пополнение через платежную систему".to_string(),
+            BalanceChangeKind::StartingBalance => "Начальный баланс".to_string(),
+            BalanceChangeKind::Refund => "Возврат за заказ".to_string(),
+            BalanceChangeKind::Other => "Изменение баланса".to_string(),
+        };
+        let class = if entry.amount < 0.0 {
+            "text-danger"
+        } else {
+            "text-success"
+        };
+        html! {
+            <tr>
+                <td>{when}</td>
+                <td>{description}</td>
+                <td>{entry.comment.clone().unwrap_or_default()}</td>
+                <td class={class}>{format!("{:+.3}{MONEY}", entry.amount)}</td>
+            </tr>
+        }
+    });
+
+    Ok(html! {
+        <table class="table">
+            <thead>
+                <tr>
+                    <th>{"Когда"}</th>
+                    <th>{"Что"}</th>
+                    <th>{"Комментарий"}</th>
+                    <th>{"Сумма"}</th>
+                </tr>
+            </thead>
+            <tbody>
+                {for rows}
+            </tbody>
+        </table>
+    })
+}
+
+#[function_component(TopUpWidget)]
+pub fn top_up_widget() -> Html {
+    let amount_state = use_state(String::new);
+
+    let oninput = {
+        shadow_clone!(amount_state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            amount_state.set(target.value());
+        }
+    };
+
+    let payment_result: yew_hooks::prelude::UseAsyncHandle<NewPaymentResponse, String> =
+        use_async({
+            shadow_clone!(amount_state);
+            async move {
+                let amount: f64 = amount_state
+                    .trim()
+                    .parse()
+                    .map_err(|_| "Сумма должна быть числом".to_string())?;
+                let client = reqwest::Client::default();
+                client
+                    .post(url!("/api/me/payments/new"))
+                    .json(&NewPaymentRequest { amount })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .json::<NewPaymentResponse>()
+                    .await
+                    .map_err(|v| v.to_string())
+            }
+        });
+
+    let start = {
+        shadow_clone!(payment_result);
+        move |_ev| {
+            payment_result.run();
+        }
+    };
+
+    let validation = match &payment_result.data {
+        Some(NewPaymentResponse::Redirect { redirect_url, .. }) => {
+            gloo::utils::window()
+                .location()
+                .set_href(redirect_url)
+                .unwrap();
+            FormControlValidation::Valid(Some("Переходим на страницу оплаты...".into()))
+        }
+        Some(NewPaymentResponse::InvalidAmount { min, max }) => FormControlValidation::Invalid(
+            format!("Сумма пополнения должна быть от {min:.3}{MONEY} до {max:.3}{MONEY}.").into(),
+        ),
+        Some(NewPaymentResponse::Unavailable) => FormControlValidation::Invalid(
+            "Пополнение баланса сейчас недоступно. Свяжитесь с администратором для информации."
+                .into(),
+        ),
+        None => match &payment_result.error {
+            Some(why) => {
+                FormControlValidation::Invalid(format!("Ошибка при создании платежа: {why}").into())
+            }
+            None => FormControlValidation::None,
+        },
+    };
+
+    html! {
+        <>
+            <h3>{"Пополнить баланс"}</h3>
+            <FormControl id="top-up-amount" ctype={FormControlType::Number { min: None, max: None }} class="mb-3" label={format!("Сумма, {MONEY}")} {oninput} value={(*amount_state).clone()} disabled={payment_result.loading} {validation} />
+
+            <Button style={Color::Primary} disabled={payment_result.loading} onclick={start}>
+                if payment_result.loading {
+                    <Spinner small={true} />
+                    {"Создаем платеж..."}
+                } else {
+                    {"Перейти к оплате"}
+                }
+            </Button>
+        </>
+    }
+}
+
+/// The provider sends the user here after they pay.
+#[autoprops]
+#[function_component(Payment)]
+pub fn payment(id: &AttrValue) -> Html {
+    let fallback = html! {
+        <h1>{"Загружаем состояние платежа..."}<Spinner/></h1>
+    };
+    if is_logged_in() {
+        html!(
+            <Suspense {fallback}>
+                <PaymentInner {id} />
+            </Suspense>
+        )
+    } else {
+        html!(<div class="alert alert-warning">{"Войдите в аккаунт, чтобы увидеть состояние платежа."}</div>)
+    }
+}
+
+#[autoprops]
+#[function_component(PaymentInner)]
+fn payment_inner(id: &AttrValue) -> HtmlResult {
+    let resp = use_future({
+        shadow_clone!(id);
+        || async move {
+            reqwest::get(url!("/api/me/payments/{id}"))
+                .await?
+                .json::<Option<PaymentInfo>>()
+                .await
+        }
+    })?;
+
+    let reload = |_ev| {
+        gloo::utils::document()
+            .location()
+            .unwrap()
+            .reload()
+            .unwrap();
+    };
+
+    Ok(match *resp {
+        Ok(Some(ref payment)) => {
+            let (class, text) = match payment.status {
+                PaymentStatus::Pending => ("alert alert-info", "Мы еще не получили подтверждение оплаты от платежной системы. Обычно это занимает не больше минуты."),
+                PaymentStatus::Succeeded => ("alert alert-success", "Оплата прошла успешно, деньги зачислены на ваш баланс."),
+                PaymentStatus::Failed => ("alert alert-danger", "Оплата не прошла. Деньги не были списаны."),
+            };
+            html! {
+                <>
+                    <h1>{format!("Пополнение на {:.3}{MONEY}", payment.amount)}</h1>
+                    <div class={class}>{text}</div>
+                    if payment.status == PaymentStatus::Pending {
+                        <Button style={Color::Primary} onclick={reload}>{"Обновить"}</Button>
+                    }
+                </>
+            }
+        }
+        Ok(None) => html!(<div class="alert alert-danger">{"Такой платеж не найден."}</div>),
+        Err(ref failure) => {
+            html!(<div class="alert alert-danger">{"Ошибка при загрузке платежа: "}{failure.to_string()}</div>)
+        }
+    })
+}
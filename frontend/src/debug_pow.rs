@@ -1,55 +1,125 @@
-use api::verification::ProofOfWorkChallenge;
+use api::verification::{expected_hashes as expected_hashes_for, ProofOfWorkChallengeResponse};
 use shadow_clone::shadow_clone;
 use web_sys::HtmlInputElement;
 use yew::prelude::*;
-use yew_agent::reactor::ReactorProvider;
-use yew_hooks::use_list;
+use yew_hooks::{use_async, use_list};
 
-use crate::proof_of_work_agent::{PowReactorCommand, PowReactorInput, Sha256PowReactor};
+use crate::{
+    pow_workers::use_pow_workers,
+    proof_of_work_agent::{parse_challenge, PowReactorInput, PowReactorOutput},
+    url_macro::url,
+};
 
-#[function_component(DebugPow)]
-pub fn debug_pow_outer() -> Html {
-    html!(
-        <ReactorProvider<Sha256PowReactor> path="/worker.js">
-            <DebugPowInner />
-        </ReactorProvider<Sha256PowReactor>>
+/// Roughly how long it will take to do the rest of the expected hashes, at the current speed.
+fn format_eta(expected_hashes: u64, attempts_done: usize, hashes_per_second: f64) -> String {
+    let remaining = expected_hashes.saturating_sub(attempts_done as u64);
+    if remaining == 0 {
+        return "any moment now".to_string();
+    }
+    if hashes_per_second <= 0.0 || !hashes_per_second.is_finite() {
+        return "unknown".to_string();
+    }
+    let seconds = (remaining as f64 / hashes_per_second).ceil() as i64;
+    chrono_humanize::HumanTime::from(chrono::Duration::seconds(seconds)).to_text_en(
+        chrono_humanize::Accuracy::Rough,
+        chrono_humanize::Tense::Present,
     )
 }
 
-#[function_component(DebugPowInner)]
-fn debug_proof_of_work() -> Html {
+#[function_component(DebugPow)]
+pub fn debug_proof_of_work() -> Html {
     let challenge_state = use_state(String::new);
     let logs = use_list(vec![]);
+    let eta_state = use_state(|| None::<String>);
+    // Read from the workers' callback, which doesn't see later states.
+    let expected_hashes = use_mut_ref(|| None::<u64>);
 
-    let sub = yew_agent::reactor::use_reactor_bridge::<Sha256PowReactor, _>({
-        shadow_clone!(logs);
+    let sub = use_pow_workers({
+        shadow_clone!(logs, eta_state, expected_hashes);
         move |msg| {
+            match &msg {
+                PowReactorOutput::Progress {
+                    attempts_done,
+                    hashes_per_second,
+                    ..
+                } => {
+                    if let Some(expected) = *expected_hashes.borrow() {
+                        eta_state.set(Some(format_eta(
+                            expected,
+                            *attempts_done,
+                            *hashes_per_second,
+                        )));
+                    }
+                }
+                PowReactorOutput::FoundSolution { .. } => eta_state.set(None),
+            }
             logs.push(format!("> {msg:?}"));
         }
     });
 
+    let fetch_result: yew_hooks::prelude::UseAsyncHandle<ProofOfWorkChallengeResponse, String> =
+        use_async(async move {
+            reqwest::get(url!("/api/me/verification/proof-of-work/get-challenge"))
+                .await
+                .map_err(|v| v.to_string())?
+                .error_for_status()
+                .map_err(|v| v.to_string())?
+                .json::<ProofOfWorkChallengeResponse>()
+                .await
+                .map_err(|v| v.to_string())
+        });
+    use_effect_with(fetch_result.data.clone(), {
+        shadow_clone!(challenge_state, logs, expected_hashes);
+        move |data| {
+            if let Some(response) = data {
+                logs.push(format!(
+                    "Got a challenge, expecting about {} hashes",
+                    response.expected_hashes
+                ));
+                *expected_hashes.borrow_mut() = Some(response.expected_hashes);
+                challenge_state.set(response.challenge.clone());
+            }
+        }
+    });
+    let fetch_challenge = Callback::from({
+        shadow_clone!(fetch_result);
+        move |ev: MouseEvent| {
+            ev.prevent_default();
+            fetch_result.run();
+        }
+    });
+
     let oninput = {
         let challenge_state = challenge_state.clone();
+        let expected_hashes = expected_hashes.clone();
         Callback::from(move |event: InputEvent| {
             shadow_clone!(challenge_state);
             let input: HtmlInputElement = event.target_unchecked_into();
             challenge_state.set(input.value());
+            *expected_hashes.borrow_mut() = None;
         })
     };
 
     let start_calc = Callback::from({
-        shadow_clone!(challenge_state, logs, sub);
+        shadow_clone!(challenge_state, logs, sub, expected_hashes);
         move |ev: MouseEvent| {
             ev.prevent_default();
             let state = (*challenge_state).clone();
-            logs.push(format!("Starting calculation using data: {state}"));
-            let state = state.split(".").next().unwrap();
-            let challenge: ProofOfWorkChallenge = serde_json::from_str(&state).unwrap();
+            logs.push(format!(
+                "Starting calculation on {} workers using data: {state}",
+                sub.workers()
+            ));
+            let challenge = parse_challenge(&state).unwrap();
+            // A pasted challenge doesn't come with an estimate, but its difficulty gives one.
+            expected_hashes
+                .borrow_mut()
+                .get_or_insert(expected_hashes_for(challenge.difficulty));
 
-            sub.send(PowReactorCommand::Input(PowReactorInput {
+            sub.start(PowReactorInput {
                 difficulty: challenge.difficulty,
                 nonce: challenge.nonce,
-            }));
+                algorithm: challenge.algorithm,
+            });
         }
     });
 
@@ -58,7 +128,7 @@ fn debug_proof_of_work() -> Html {
         move |ev: MouseEvent| {
             ev.prevent_default();
             logs.push("Stopping calculation".to_string());
-            sub.send(PowReactorCommand::Stop);
+            sub.stop();
         }
     });
 
@@ -67,9 +137,17 @@ fn debug_proof_of_work() -> Html {
             <div class="form-group">
                 <input cls="form-control" type="text" value={(*challenge_state).clone()} {oninput} />
 
+                <button class="btn btn-secondary" onclick={fetch_challenge} disabled={fetch_result.loading}>{"Get challenge"}</button>
                 <button class="btn btn-primary" onclick={start_calc}>{"Start"}</button>
                 <button class="btn btn-danger" onclick={stop_calc}>{"Stop"}</button>
 
+                if let Some(why) = &fetch_result.error {
+                    <div class="alert alert-danger">{"Failed to get a challenge: "}{why}</div>
+                }
+                if let Some(eta) = &*eta_state {
+                    <div class="alert alert-info">{"ETA: "}{eta}</div>
+                }
+
                 <ul class="list-group">
                     { for logs.current().iter().map(|msg| html! { <li class="list-group-item">{msg}</li> }) }
                 </ul>
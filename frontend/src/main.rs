@@ -1,11 +1,16 @@
+mod api_keys;
+mod balance;
 mod debug_pow;
 mod order;
+mod pow_workers;
 mod profile;
 mod promocodes;
 mod proof_of_work_agent;
+mod registration;
+mod two_factor;
 mod upload;
+mod verification;
 
-use gloo::storage::Storage;
 use yew::prelude::*;
 use yew_bootstrap::component::*;
 use yew_bootstrap::icons::*;
@@ -32,9 +37,24 @@ enum Route {
     #[at("/order/:order_id")]
     Order { order_id: i64 },
 
+    #[at("/payment/:id")]
+    Payment { id: String },
+
+    #[at("/redeem/:code")]
+    Redeem { code: String },
+
     #[at("/debug/pow")]
     DebugPow,
 
+    #[at("/sso-done")]
+    SsoDone,
+
+    #[at("/login-link/:token")]
+    LoginLink { token: String },
+
+    #[at("/invite/:code")]
+    Invite { code: String },
+
     #[not_found]
     #[at("/404")]
     NotFound,
@@ -44,12 +64,7 @@ enum Route {
 fn home() -> Html {
     let navigator: Navigator = use_navigator().unwrap();
 
-    let profile_key = gloo::storage::LocalStorage::get("token");
-    let profile_key: Option<String> = match profile_key {
-        Ok(key) => key,
-        Err(_) => None,
-    };
-    if profile_key.is_none() {
+    if !profile::is_logged_in() {
         navigator.push(&Route::Profile);
     }
 
@@ -68,7 +83,12 @@ fn app() -> Html {
             Route::Profile => html!(<Profile />),
             Route::Upload => html!(<Upload />),
             Route::Order { order_id: id } => html!(<Order {id} />),
+            Route::Payment { id } => html!(<balance::Payment {id} />),
+            Route::Redeem { code } => html!(<promocodes::RedeemPromocodeWidget initial_code={code} />),
             Route::DebugPow => html!(<debug_pow::DebugPow />),
+            Route::SsoDone => html!(<profile::SingleSignOnDone />),
+            Route::LoginLink { token } => html!(<profile::LoginLink {token} />),
+            Route::Invite { code } => html!(<registration::RegistrationForm initial_code={code} />),
             Route::NotFound => html!("404"),
         }
     }
@@ -1,15 +1,15 @@
 use std::collections::HashMap;
 
 use api::{LiveStatus, OrderExecutionMetrics, OrderFileList, OrderInfoFull, OrderInfoResult};
-use gloo::{storage::Storage, utils::document};
+use gloo::utils::document;
 use shadow_clone::shadow_clone;
 use yew::{prelude::*, suspense::use_future};
 use yew_autoprops::autoprops;
-use yew_bootstrap::component::{Column, Row, Spinner};
+use yew_bootstrap::component::Spinner;
 use yew_hooks::{use_list, use_renders_count, use_websocket};
 use yew_router::hooks::use_navigator;
 
-use crate::{url_macro::url, Route, MONEY};
+use crate::{profile::is_logged_in, url_macro::url, Route, MONEY};
 
 #[autoprops]
 #[function_component(Order)]
@@ -32,20 +32,12 @@ pub fn order_inner(id: i64) -> HtmlResult {
     let resp = {
         shadow_clone!(navigator);
         use_future(|| async move {
-            let profile_key = gloo::storage::LocalStorage::get("token");
-            let profile_key: Option<String> = match profile_key {
-                Ok(key) => key,
-                Err(_) => None,
-            };
-            let token = if let Some(key) = profile_key {
-                key
-            } else {
+            if !is_logged_in() {
                 navigator.push(&Route::Profile);
-                String::new()
-            };
+            }
 
             let order_info =
-                reqwest::get(url!("/api/orders/{token}/{id}"))
+                reqwest::get(url!("/api/me/orders/{id}"))
                     .await?
                     .error_for_status()?
                     .json::<OrderInfoResult>()
@@ -145,6 +137,7 @@ fn display_completed_order(id: i64, info: &OrderInfoFull) -> Html {
                 api::TerminationCause::NaturalTermination => "процесс завершился самостоятельно",
                 api::TerminationCause::UserKill => "остановка пользователем",
                 api::TerminationCause::BalanceKill => "остановка по недостатку баланса",
+                api::TerminationCause::BudgetCapReached => "достигнут лимит расходов на заказ",
             };
             html!(
                 <>
@@ -176,23 +169,46 @@ fn display_completed_order(id: i64, info: &OrderInfoFull) -> Html {
                     {"Процесс был остановлен заранее, потому что ваш баланс закончился во время исполнения заказа. Возможно, какие-то файлы были не полностью обработаны."}
                 </div>
             ),
+            api::TerminationCause::BudgetCapReached => html!(
+                <div class="alert alert-danger">
+                    {"Процесс был остановлен заранее, потому что стоимость заказа достигла установленного вами лимита. Возможно, какие-то файлы были не полностью обработаны."}
+                </div>
+            ),
         }
     } else {
         html!()
     };
 
+    let refund_alert = match info.refund {
+        Some(ref refund) => html!(
+            <div class="alert alert-info">
+                {"Вам вернули "}<code>{format!("{:.3}{MONEY}", refund.amount)}</code>
+                {if refund.is_automatic { " за этот заказ автоматически, " } else { " за этот заказ по решению администратора, " }}
+                {format_unix_time(refund.refunded_at_unix_time as f64)}
+                {". Причина: "}{&refund.reason}
+            </div>
+        ),
+        None => html!(),
+    };
+
     html!(
         <>
             <h1>{"Заказ "}{id}</h1>
             <p>{"Создан: "}{format_unix_time(info.created_at_unix_time as f64)}</p>
             <details>
-                <summary>{"Стоимость: "}<code>{format!("{:.3}{MONEY}", info.record.order_cost)}</code></summary>
+                <summary>
+                    {"Стоимость: "}<code>{format!("{:.3}{MONEY}", info.record.order_cost)}</code>
+                    if info.record.group_charge > 0.0 {
+                        {", из них за счет группы: "}<code>{format!("{:.3}{MONEY}", info.record.group_charge)}</code>
+                    }
+                </summary>
 
                 {cost_breakdown}
 
             </details>
 
             {termination_alert}
+            {refund_alert}
             <hr />
             {files}
             <hr />
@@ -207,20 +223,12 @@ fn order_files(id: i64) -> HtmlResult {
     let resp = {
         shadow_clone!(navigator);
         use_future(|| async move {
-            let profile_key = gloo::storage::LocalStorage::get("token");
-            let profile_key: Option<String> = match profile_key {
-                Ok(key) => key,
-                Err(_) => None,
-            };
-            let token = if let Some(key) = profile_key {
-                key
-            } else {
+            if !is_logged_in() {
                 navigator.push(&Route::Profile);
-                String::new()
-            };
+            }
 
             let order_info = reqwest::get(format!(
-                "/api/orders/{token}/{id}/files"
+                "/api/me/orders/{id}/files"
             ))
             .await?
             .error_for_status()?
@@ -233,18 +241,6 @@ fn order_files(id: i64) -> HtmlResult {
 
     Ok(match &*resp {
         Ok(files) => {
-            let profile_key = gloo::storage::LocalStorage::get("token");
-            let profile_key: Option<String> = match profile_key {
-                Ok(key) => key,
-                Err(_) => None,
-            };
-            let token = if let Some(key) = profile_key {
-                key
-            } else {
-                navigator.push(&Route::Profile);
-                String::new()
-            };
-
             let file_cards = {
                 let extension_mapping = vec![
                     ("docx", "file-earmark-richtext"),
@@ -265,7 +261,7 @@ fn order_files(id: i64) -> HtmlResult {
                         icon_class.map(|cls| {
                             let filename = v.path.split("/").last().unwrap_or(&v.path);
                             let urlpath = urlencoding::encode(&v.path);
-                            let download_url = format!("/api/orders/{token}/{id}/files/download/{filename}?download=true&path={urlpath}");
+                            let download_url = format!("/api/me/orders/{id}/files/download/{filename}?download=true&path={urlpath}");
                             let border_color = v.is_new.then_some("border-success");
                             let text_color = v.is_new.then_some("text-success");
                             let btn_color = if v.is_new {
@@ -299,8 +295,8 @@ fn order_files(id: i64) -> HtmlResult {
                 .map(|v| {
                     let filename = v.path.split("/").last().unwrap_or(&v.path);
                     let urlpath = urlencoding::encode(&v.path);
-                    let url = url!("/api/orders/{token}/{id}/files/download/{filename}?path={urlpath}");
-                    let download_url = url!("/api/orders/{token}/{id}/files/download/{filename}?download=true&path={urlpath}");
+                    let url = url!("/api/me/orders/{id}/files/download/{filename}?path={urlpath}");
+                    let download_url = url!("/api/me/orders/{id}/files/download/{filename}?download=true&path={urlpath}");
                     let text_class = if v.is_new {
                         "text-success"
                     } else {""};
@@ -309,9 +305,7 @@ fn order_files(id: i64) -> HtmlResult {
                     } else {
                         "btn btn-outline-primary"
                     };
-                    let bg_style = if v.is_new {
-                        ""
-                    } else {""};
+                    let bg_style = "";
                     html!(
                         <tr>
                             <td style={bg_style}><a href={url} class={text_class}>{&v.path}</a></td>
@@ -355,20 +349,11 @@ fn order_inner_live(id: i64, balance_at_start: f64) -> Html {
     let last_data = use_state_eq(|| None);
     let did_open = use_state_eq(|| false);
 
-    let token = gloo::storage::LocalStorage::get("token");
-    let token: Option<String> = match token {
-        Ok(token) => token,
-        Err(_) => None,
-    };
-
-    let token = if let Some(token) = token {
-        token
-    } else {
+    if !is_logged_in() {
         navigator.push(&Route::Profile);
-        String::new()
-    };
+    }
 
-    let ws = use_websocket(url!("/api/orders/{token}/{id}/ws"));
+    let ws = use_websocket(url!("/api/me/orders/{id}/ws"));
 
     match *ws.ready_state {
         yew_hooks::UseWebSocketReadyState::Connecting => {
@@ -435,6 +420,31 @@ fn order_live_status(status: &LiveStatus, balance_at_start: f64) -> Html {
             } else {
                 html!()
             };
+            let budget_info = match metrics.remaining_budget {
+                Some(remaining) if remaining > 0.0 => html!(
+                    <p>{"До лимита расходов на этот заказ осталось: "}<code>{format!("{remaining:.3}")}{MONEY}</code></p>
+                ),
+                Some(_) => html!(
+                    <div class="alert alert-warning fs-3">
+                        <i class="bi bi-exclamation-diamond-fill" />
+                        {"Заказ достиг установленного вами лимита расходов и сейчас будет остановлен!"}
+                    </div>
+                ),
+                None => html!(),
+            };
+            let surge_info = if status.pricing.surge_multiplier > 1.0
+                || status.current_surge_multiplier > 1.0
+            {
+                html!(
+                    <p class="text-secondary">
+                        {"Этот заказ выполняется по тарифу "}<code>{format!("×{:.2}", status.pricing.surge_multiplier)}</code>
+                        {", а новые заказы сейчас стоят "}<code>{format!("×{:.2}", status.current_surge_multiplier)}</code>
+                        {" из-за нагрузки на сервер."}
+                    </p>
+                )
+            } else {
+                html!()
+            };
             html!(<>
                 <p>{"Секунд процессора: "}<code>{format!("{:.5}", metrics.cpu_seconds)}</code>{"="}<code>{format!("{:.5}", priced.cpu_time)}{MONEY}</code></p>
                 <p>{"Секунд реального времени: "}<code>{format!("{:.5}", metrics.wall_seconds)}</code>{"="}<code>{format!("{:.5}", priced.wall_time)}{MONEY}</code></p>
@@ -448,7 +458,7 @@ fn order_live_status(status: &LiveStatus, balance_at_start: f64) -> Html {
                         <span class="fs-1"><code>{format!("{:.3}", balance_at_start - priced.grand_total())}{MONEY}</code></span>
                     </div>
                     <div class="col row" style="text-align: center;">
-                        <ArrowStream metrics={metrics.clone()}/>
+                        <ArrowStream metrics={metrics}/>
                     </div>
                     <div class="col" style="text-align: center;">
                         <h3>{"Стоимость этого заказа"}</h3>
@@ -456,7 +466,9 @@ fn order_live_status(status: &LiveStatus, balance_at_start: f64) -> Html {
                     </div>
                 </div>
 
+                {budget_info}
                 {termination_alert}
+                {surge_info}
                 </>)
         }
         api::JobStatus::Terminated(_) => html!(<h1>{"Заказ скоро завершится..."}<Spinner/></h1>),
@@ -492,20 +504,11 @@ fn order_stream_logs(id: i64, stream_name: AttrValue) -> Html {
     let last_data_id = use_state_eq(Vec::new);
     let is_done = use_state(|| false);
 
-    let token = gloo::storage::LocalStorage::get("token");
-    let token: Option<String> = match token {
-        Ok(token) => token,
-        Err(_) => None,
-    };
-
-    let token = if let Some(token) = token {
-        token
-    } else {
+    if !is_logged_in() {
         navigator.push(&Route::Profile);
-        String::new()
-    };
+    }
 
-    let ws = use_websocket(url!("/api/orders/{token}/{id}/stream/{stream_name}"));
+    let ws = use_websocket(url!("/api/me/orders/{id}/stream/{stream_name}"));
 
     if !*is_done {
         match *ws.ready_state {
@@ -520,7 +523,7 @@ fn order_stream_logs(id: i64, stream_name: AttrValue) -> Html {
                     if id != *last_data_id {
                         log::debug!("It is a new message, its length is {}", data.len());
                         text_data.append(&mut data.to_vec());
-                        if data.len() == 0 {
+                        if data.is_empty() {
                             log::debug!("It is a final message");
                             is_done.set(true);
                             text_data
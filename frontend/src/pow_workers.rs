@@ -0,0 +1,197 @@
+//! Solving a proof of work on every core at once.
+//!
+//! Each worker is a separate [`PowReactor`] searching its own part of the nonce space;
+//! their progress is added up, and the first solution stops all of them.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures::sink::SinkExt;
+use futures::stream::{SplitSink, StreamExt};
+use yew::platform::pinned::RwLock;
+use yew::platform::spawn_local;
+use yew::prelude::*;
+use yew_agent::reactor::{ReactorBridge, ReactorSpawner};
+
+use crate::proof_of_work_agent::{
+    PowReactor, PowReactorCommand, PowReactorInput, PowReactorOutput,
+};
+
+const WORKER_PATH: &str = "/worker.js";
+
+/// Browsers may report many cores, but there's little point in more workers than this.
+const MAX_WORKERS: usize = 16;
+
+type WorkerTx = Rc<RwLock<SplitSink<ReactorBridge<PowReactor>, PowReactorCommand>>>;
+
+#[derive(Clone, Copy, Default)]
+struct WorkerProgress {
+    attempts_done: usize,
+    max_difficulty_found: u64,
+    hashes_per_second: f64,
+}
+
+#[derive(Default)]
+struct Aggregate {
+    running: bool,
+    workers: Vec<WorkerProgress>,
+}
+
+/// How many workers to use: one per core that the browser reports.
+fn worker_count() -> usize {
+    let cores = gloo::utils::window().navigator().hardware_concurrency() as usize;
+    cores.clamp(1, MAX_WORKERS)
+}
+
+/// Add one worker's output to the total.
+/// Returns what to tell the component, if anything: outputs that come after a solution are dropped.
+fn combine(
+    aggregate: &mut Aggregate,
+    index: usize,
+    output: PowReactorOutput,
+) -> Option<PowReactorOutput> {
+    if !aggregate.running {
+        return None;
+    }
+    match output {
+        PowReactorOutput::Progress {
+            attempts_done,
+            max_difficulty_found,
+            hashes_per_second,
+        } => {
+            aggregate.workers[index] = WorkerProgress {
+                attempts_done,
+                max_difficulty_found,
+                hashes_per_second,
+            };
+            let workers = &aggregate.workers;
+            Some(PowReactorOutput::Progress {
+                attempts_done: workers.iter().map(|w| w.attempts_done).sum(),
+                max_difficulty_found: workers
+                    .iter()
+                    .map(|w| w.max_difficulty_found)
+                    .max()
+                    .unwrap_or(0),
+                hashes_per_second: workers.iter().map(|w| w.hashes_per_second).sum(),
+            })
+        }
+        PowReactorOutput::FoundSolution { nonce } => {
+            aggregate.running = false;
+            Some(PowReactorOutput::FoundSolution { nonce })
+        }
+    }
+}
+
+/// Hook handle for the [`use_pow_workers`] hook.
+#[derive(Clone)]
+pub struct UsePowWorkersHandle {
+    txs: Rc<Vec<WorkerTx>>,
+    aggregate: Rc<RefCell<Aggregate>>,
+}
+
+fn send(tx: &WorkerTx, command: PowReactorCommand) {
+    let tx = tx.clone();
+    spawn_local(async move {
+        let _ = tx.write().await.send(command).await;
+    });
+}
+
+impl UsePowWorkersHandle {
+    /// Start solving a challenge on all the workers, each in its own part of the nonce space.
+    pub fn start(&self, input: PowReactorInput) {
+        {
+            let mut aggregate = self.aggregate.borrow_mut();
+            aggregate.running = true;
+            aggregate.workers = vec![WorkerProgress::default(); self.txs.len()];
+        }
+        for (partition, tx) in self.txs.iter().enumerate() {
+            send(
+                tx,
+                PowReactorCommand::Input {
+                    input: input.clone(),
+                    partition: partition as u32,
+                },
+            );
+        }
+    }
+
+    pub fn stop(&self) {
+        self.aggregate.borrow_mut().running = false;
+        for tx in self.txs.iter() {
+            send(tx, PowReactorCommand::Stop);
+        }
+    }
+
+    /// How many workers are solving the challenge.
+    pub fn workers(&self) -> usize {
+        self.txs.len()
+    }
+}
+
+/// A hook to solve proofs of work in parallel.
+///
+/// The callback gets the combined progress of all the workers,
+/// and then the solution of whichever finds it first.
+#[hook]
+pub fn use_pow_workers<F>(on_output: F) -> UsePowWorkersHandle
+where
+    F: Fn(PowReactorOutput) + 'static,
+{
+    let on_output: Rc<dyn Fn(PowReactorOutput)> = Rc::new(on_output);
+    let on_output_ref = use_mut_ref(|| on_output.clone());
+    // Refresh the callback on every render, so that it sees the current states.
+    *on_output_ref.borrow_mut() = on_output;
+
+    let aggregate = use_mut_ref(Aggregate::default);
+
+    let txs = use_memo((), {
+        let aggregate = aggregate.clone();
+        move |_| {
+            let (txs, rxs): (Vec<WorkerTx>, Vec<_>) = (0..worker_count())
+                .map(|_| {
+                    let (tx, rx) = ReactorSpawner::<PowReactor>::new()
+                        .spawn(WORKER_PATH)
+                        .split();
+                    (Rc::new(RwLock::new(tx)), rx)
+                })
+                .unzip();
+            let txs = Rc::new(txs);
+
+            for (index, mut rx) in rxs.into_iter().enumerate() {
+                let aggregate = aggregate.clone();
+                let on_output_ref = on_output_ref.clone();
+                let txs = txs.clone();
+                spawn_local(async move {
+                    while let Some(output) = rx.next().await {
+                        let Some(combined) = combine(&mut aggregate.borrow_mut(), index, output)
+                        else {
+                            continue;
+                        };
+                        // Once one worker has found the solution, the others can stop.
+                        if let PowReactorOutput::FoundSolution { .. } = combined {
+                            for tx in txs.iter() {
+                                send(tx, PowReactorCommand::Stop);
+                            }
+                        }
+                        let on_output = on_output_ref.borrow().clone();
+                        on_output(combined);
+                    }
+                });
+            }
+            txs
+        }
+    });
+
+    let handle = UsePowWorkersHandle {
+        txs: (*txs).clone(),
+        aggregate,
+    };
+
+    // Don't leave the workers busy after the component is gone.
+    use_effect_with((), {
+        let handle = handle.clone();
+        move |_| move || handle.stop()
+    });
+
+    handle
+}
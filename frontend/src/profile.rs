@@ -1,38 +1,66 @@
+use api::verification::ProofOfWorkAttempt;
+use api::GroupInfo;
 use api::LoginRequest;
+use api::LoginResponse;
+use api::SingleSignOnInfo;
 use api::UserInfo;
 use api::UserInfoResult;
+use api::{LoginLinkRequest, LoginLinkResponse};
+use api::{SecurityEvent, SecurityEventKind};
 use gloo::storage::Storage;
+use serde::Deserialize;
 use shadow_clone::shadow_clone;
 use wasm_bindgen::JsCast;
 use web_sys::HtmlInputElement;
 use yew::prelude::*;
 use yew::suspense::use_future;
+use yew_autoprops::autoprops;
 use yew_bootstrap::component::form::*;
 use yew_bootstrap::component::*;
 use yew_bootstrap::util::*;
 use yew_hooks::use_async;
-use yew_router::hooks::use_navigator;
+use yew_router::hooks::{use_location, use_navigator};
 use yew_router::prelude::Link;
 
+use crate::api_keys::ApiKeys;
+use crate::balance::{BalanceHistory, TopUpWidget, TransferWidget};
+use crate::pow_workers::use_pow_workers;
 use crate::promocodes::RedeemPromocodeWidget;
+use crate::proof_of_work_agent::{parse_challenge, PowReactorInput, PowReactorOutput};
+use crate::registration::{RegistrationForm, StartVerificationQuery};
+use crate::two_factor::TwoFactor;
 use crate::url_macro::url;
+use crate::verification::Verification;
 use crate::Route;
+use crate::MONEY;
+
+/// The session token is kept in an HttpOnly cookie, which scripts can't read.
+/// This only remembers whether we have logged in, to know whether to show the login form.
+pub const LOGGED_IN_KEY: &str = "logged_in";
+
+pub fn is_logged_in() -> bool {
+    gloo::storage::LocalStorage::get(LOGGED_IN_KEY).unwrap_or_default()
+}
+
+/// Forget that we are logged in, and start over from the login form.
+fn forget_login() {
+    gloo::storage::LocalStorage::delete(LOGGED_IN_KEY);
+    gloo::utils::document()
+        .location()
+        .unwrap()
+        .reload()
+        .unwrap();
+}
 
 #[function_component(Profile)]
 pub fn profile() -> Html {
-    let profile_token = gloo::storage::LocalStorage::get("token");
-    let profile_token: Option<String> = match profile_token {
-        Ok(key) => key,
-        Err(_) => None,
-    };
-
-    if let Some(token) = profile_token {
+    if is_logged_in() {
         let fallback = html! {
             <h1>{"Загружаем информацию профиля..."}<Spinner /></h1>
         };
         html!(
             <Suspense {fallback}>
-                <ProfileInner token={token} />
+                <ProfileInner />
             </Suspense>
 
         )
@@ -42,13 +70,14 @@ pub fn profile() -> Html {
 }
 
 #[function_component(ProfileInner)]
-fn profile_inner(props: &ProfileNavInnerProps) -> HtmlResult {
+fn profile_inner() -> HtmlResult {
     let navigator = use_navigator().unwrap();
-    let ProfileNavInnerProps { token } = props;
-    let token = token.clone();
+    let start_verification = use_location()
+        .and_then(|location| location.query::<StartVerificationQuery>().ok())
+        .is_some_and(|query| query.start_verification);
 
     let resp = use_future(|| async move {
-        reqwest::get(url!("/api/user-info/{token}"))
+        reqwest::get(url!("/api/me"))
             .await?
             .json::<UserInfoResult>()
             .await
@@ -60,28 +89,42 @@ fn profile_inner(props: &ProfileNavInnerProps) -> HtmlResult {
                 name,
                 balance,
                 verification,
+                available_verification_methods,
+                verification_request_pending,
+                group,
+                role: _,
             }) => html! {
                 <>
-                    <h1>{name}</h1>
+                    <h1>{name}<LogoutButton /></h1>
                     <h2>{"Ваш текущий баланс: "}<code>{format!("{balance:.3}")}{"𐆘"}</code></h2>
+                    <Suspense fallback={html!()}>
+                        <SecurityEvents />
+                    </Suspense>
+                    <Verification verification={*verification} available={available_verification_methods.clone()} request_pending={*verification_request_pending} start_proof_of_work={start_verification} />
+                    if let Some(group) = group {
+                        <GroupFundsInfo group={group.clone()} />
+                    }
                     <Row>
                         <Column>
                             <RedeemPromocodeWidget />
+                            <hr />
+                            <TopUpWidget />
                         </Column>
                         <Column>
-                            //<AccountOptions />
+                            <TransferWidget />
                         </Column>
                     </Row>
+                    <h3>{"История баланса"}</h3>
+                    <Suspense fallback={html!(<Spinner />)}>
+                        <BalanceHistory />
+                    </Suspense>
+                    <TwoFactor />
+                    <ApiKeys />
                 </>
             },
             UserInfoResult::NoSuchToken => {
                 navigator.push(&Route::Profile);
-                gloo::storage::LocalStorage::delete("token");
-                gloo::utils::document()
-                    .location()
-                    .unwrap()
-                    .reload()
-                    .unwrap();
+                forget_login();
                 html!({ "Пользователь не существует" })
             }
         },
@@ -93,23 +136,115 @@ fn profile_inner(props: &ProfileNavInnerProps) -> HtmlResult {
     Ok(result_html)
 }
 
-#[function_component(ProfileNav)]
-pub fn profile_nav() -> Html {
-    let profile_key: Result<Option<String>, gloo::storage::errors::StorageError> =
-        gloo::storage::LocalStorage::get("token");
-    let profile_key: Option<String> = match profile_key {
-        Ok(key) => key,
-        Err(_) => None,
+#[derive(Properties, PartialEq, Clone)]
+struct GroupFundsInfoProps {
+    pub group: GroupInfo,
+}
+
+#[function_component(GroupFundsInfo)]
+fn group_funds_info(props: &GroupFundsInfoProps) -> Html {
+    let GroupInfo {
+        name,
+        available,
+        spending_limit,
+        spent,
+    } = &props.group;
+
+    html! {
+        <div class="alert alert-info">
+            <p>
+                {"Вы состоите в группе "}<b>{name}</b>{". Заказы сначала оплачиваются из баланса группы, а затем из вашего личного баланса."}
+            </p>
+            <p>
+                {"Сейчас вам доступно из баланса группы: "}<code>{format!("{available:.3}{MONEY}")}</code>
+                {"; вы уже потратили "}<code>{format!("{spent:.3}{MONEY}")}</code>
+                if let Some(limit) = spending_limit {
+                    {" из "}<code>{format!("{limit:.3}{MONEY}")}</code>
+                }
+                {"."}
+            </p>
+        </div>
+    }
+}
+
+/// Warnings about things like lockouts after someone guessed at the password.
+#[function_component(SecurityEvents)]
+fn security_events() -> HtmlResult {
+    let resp = use_future(|| async move {
+        reqwest::get(url!("/api/me/security-events"))
+            .await?
+            .error_for_status()?
+            .json::<Vec<SecurityEvent>>()
+            .await
+    })?;
+
+    // This is only a warning, so failing to load it shouldn't get in the way.
+    let events = match *resp {
+        Ok(ref events) => events,
+        Err(_) => return Ok(html!()),
+    };
+
+    Ok(html! {
+        {for events.iter().map(|event| {
+            let when = chrono::DateTime::from_timestamp(event.when_unix_time as i64, 0)
+                .expect("failed to parse incoming unix time as date")
+                .with_timezone(&chrono::Local)
+                .to_string();
+            let what = match event.kind {
+                SecurityEventKind::LoginLockout => "Вход в аккаунт был временно заблокирован из-за множества неверных паролей",
+                SecurityEventKind::TwoFactorEnabled => "Включена двухфакторная аутентификация",
+                SecurityEventKind::TwoFactorDisabled => "Двухфакторная аутентификация отключена",
+                SecurityEventKind::RecoveryCodeUsed => "Для входа был использован код восстановления",
+                SecurityEventKind::TwoFactorReset => "Администратор сбросил двухфакторную аутентификацию",
+            };
+            html! {
+                <div class="alert alert-warning">
+                    {what}{" ("}{when}
+                    if let Some(ip) = &event.ip {
+                        {", IP "}{ip}
+                    }
+                    {"). Если это были не вы, смените пароль."}
+                </div>
+            }
+        })}
+    })
+}
+
+#[function_component(LogoutButton)]
+fn logout_button() -> Html {
+    let logout: yew_hooks::prelude::UseAsyncHandle<(), String> = use_async(async move {
+        reqwest::Client::default()
+            .post(url!("/api/me/logout"))
+            .send()
+            .await
+            .map_err(|v| v.to_string())?;
+        // Even if the server didn't accept it, the session is no use to us anymore.
+        forget_login();
+        Ok(())
+    });
+
+    let onclick = {
+        shadow_clone!(logout);
+        move |_ev| logout.run()
     };
 
-    if let Some(key) = profile_key {
+    html! {
+        <Button class="ms-3" style={Color::Secondary} outline={true} disabled={logout.loading} {onclick}>
+            {"Выйти"}
+        </Button>
+    }
+}
+
+#[function_component(ProfileNav)]
+pub fn profile_nav() -> Html {
+    if is_logged_in() {
         let fallback = html! {
             <Link<Route> classes="nav-link" to={Route::Profile}>{"Загружаем пользователя..."}</Link<Route>>
         };
         html!(
             <div class="nav-item">
                 <Suspense {fallback}>
-                    <ProfileNavInner token={key} />
+                    <ProfileNavInner />
                 </Suspense>
             </div>
         )
@@ -122,19 +257,12 @@ pub fn profile_nav() -> Html {
     }
 }
 
-#[derive(Properties, PartialEq, Clone)]
-struct ProfileNavInnerProps {
-    pub token: AttrValue,
-}
-
 #[function_component(ProfileNavInner)]
-fn profile_nav_inner(props: &ProfileNavInnerProps) -> HtmlResult {
+fn profile_nav_inner() -> HtmlResult {
     let navigator = use_navigator().unwrap();
-    let ProfileNavInnerProps { token } = props;
-    let token = token.clone();
 
     let resp = use_future(|| async move {
-        reqwest::get(url!("/api/user-info/{token}"))
+        reqwest::get(url!("/api/me"))
             .await?
             .json::<UserInfoResult>()
             .await
@@ -147,13 +275,7 @@ fn profile_nav_inner(props: &ProfileNavInnerProps) -> HtmlResult {
             }
             UserInfoResult::NoSuchToken => {
                 navigator.push(&Route::Profile);
-                gloo::storage::LocalStorage::delete("token");
-
-                gloo::utils::document()
-                    .location()
-                    .unwrap()
-                    .reload()
-                    .unwrap();
+                forget_login();
 
                 "Пользователь не существует".to_string()
             }
@@ -175,6 +297,9 @@ fn register() -> Html {
                 <Column>
                     <ExistingRegister />
                 </Column>
+                <Column>
+                    <RegistrationForm />
+                </Column>
             </Row>
         </div>
     )
@@ -182,9 +307,18 @@ fn register() -> Html {
 
 #[function_component(ExistingRegister)]
 fn existing_register() -> Html {
+    html!(<LoginForm />)
+}
+
+#[function_component(LoginForm)]
+fn login_form() -> Html {
     let navigator = use_navigator().unwrap();
-    let handle_state = use_state(|| String::new());
-    let password_state = use_state(|| String::new());
+    let handle_state = use_state(String::new);
+    let password_state = use_state(String::new);
+    let two_factor_state = use_state(String::new);
+    let pow_attempt_state = use_state(|| None::<ProofOfWorkAttempt>);
+    let pow_progress_state = use_state(|| None::<(u64, f64)>);
+    let pending_challenge = use_mut_ref(|| None::<String>);
 
     let oninput_handle = {
         shadow_clone!(handle_state);
@@ -201,30 +335,122 @@ fn existing_register() -> Html {
             password_state.set(target.value());
         }
     };
-    let token_result: yew_hooks::prelude::UseAsyncHandle<Option<String>, String> = use_async({
-        shadow_clone!(handle_state, password_state);
+
+    let oninput_two_factor = {
+        shadow_clone!(two_factor_state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            two_factor_state.set(target.value());
+        }
+    };
+    // The server also sends back the token, for clients without cookies;
+    // we don't keep it, since the cookie is what the browser uses.
+    let token_result: yew_hooks::prelude::UseAsyncHandle<LoginResponse, String> = use_async({
+        shadow_clone!(
+            handle_state,
+            password_state,
+            two_factor_state,
+            pow_attempt_state
+        );
         async move {
             Ok({
                 let handle = (*handle_state).clone();
                 let password = (*password_state).clone();
+                let proof_of_work = (*pow_attempt_state).clone();
+                let two_factor_code = Some((*two_factor_state).clone()).filter(|c| !c.is_empty());
                 let client = reqwest::Client::default();
                 client
                     .post(url!("/api/user-info/login"))
-                    .json(&LoginRequest { handle, password })
+                    .json(&LoginRequest {
+                        handle,
+                        password,
+                        proof_of_work,
+                        two_factor_code,
+                    })
                     .send()
                     .await
                     .map_err(|v| v.to_string())?
-                    .json::<Option<String>>()
+                    .json::<LoginResponse>()
                     .await
                     .map_err(|v| v.to_string())?
             })
         }
     });
 
+    // After too many failed logins, the server asks for a proof of work,
+    // which is solved in web workers so that the page doesn't freeze.
+    let pow_workers = use_pow_workers({
+        shadow_clone!(pow_attempt_state, pow_progress_state, pending_challenge);
+        move |output| match output {
+            PowReactorOutput::Progress {
+                max_difficulty_found,
+                hashes_per_second,
+                ..
+            } => pow_progress_state.set(Some((max_difficulty_found, hashes_per_second))),
+            PowReactorOutput::FoundSolution { nonce } => {
+                if let Some(challenge_string) = pending_challenge.borrow_mut().take() {
+                    pow_progress_state.set(None);
+                    pow_attempt_state.set(Some(ProofOfWorkAttempt {
+                        challenge_string,
+                        nonce_postfix: nonce,
+                    }));
+                }
+            }
+        }
+    });
+
+    // When the server asks for a proof of work, start solving it...
+    use_effect_with(token_result.data.clone(), {
+        shadow_clone!(pow_workers, pending_challenge, pow_progress_state);
+        move |data| {
+            if let Some(LoginResponse::ProofOfWorkRequired { challenge }) = data {
+                if let Some(parsed) = parse_challenge(challenge) {
+                    *pending_challenge.borrow_mut() = Some(challenge.clone());
+                    pow_progress_state.set(Some((0, 0.0)));
+                    pow_workers.start(PowReactorInput {
+                        difficulty: parsed.difficulty,
+                        nonce: parsed.nonce,
+                        algorithm: parsed.algorithm,
+                    });
+                }
+            }
+        }
+    });
+
+    // ...and when it is solved, try logging in again with the solution.
+    use_effect_with((*pow_attempt_state).clone(), {
+        shadow_clone!(token_result);
+        move |attempt| {
+            if attempt.is_some() {
+                token_result.run();
+            }
+        }
+    });
+
+    let solving = pow_progress_state.is_some();
+    let loading = token_result.loading || solving;
+
     let validation = match &token_result.data {
         Some(data) => match data {
-            Some(_) => FormControlValidation::Valid(None),
-            None => FormControlValidation::Invalid("Неверный логин или пароль".into()),
+            LoginResponse::Ok { .. } => FormControlValidation::Valid(None),
+            LoginResponse::InvalidCredentials => {
+                FormControlValidation::Invalid("Неверный логин или пароль".into())
+            }
+            LoginResponse::ProofOfWorkRequired { .. }
+            | LoginResponse::TwoFactorRequired
+            | LoginResponse::InvalidTwoFactorCode => FormControlValidation::None,
+            LoginResponse::LockedOut { until_unix_time } => {
+                let until = chrono::DateTime::from_timestamp(*until_unix_time as i64, 0)
+                    .expect("failed to parse incoming unix time as date")
+                    .with_timezone(&chrono::Local)
+                    .to_string();
+                FormControlValidation::Invalid(
+                    format!(
+                        "Слишком много неудачных попыток входа. Попробуйте снова после {until}"
+                    )
+                    .into(),
+                )
+            }
         },
         None => match &token_result.error {
             Some(why) => FormControlValidation::Invalid(format!("Ошибка при входе: {why}").into()),
@@ -232,6 +458,31 @@ fn existing_register() -> Html {
         },
     };
 
+    // The code is asked for once the password turns out to be right,
+    // and stays shown while a proof of work is solved before trying again.
+    let two_factor_validation = match &token_result.data {
+        Some(LoginResponse::InvalidTwoFactorCode) => {
+            FormControlValidation::Invalid("Неверный или уже использованный код".into())
+        }
+        _ => FormControlValidation::None,
+    };
+    let show_two_factor = !two_factor_state.is_empty()
+        || matches!(
+            token_result.data,
+            Some(LoginResponse::TwoFactorRequired | LoginResponse::InvalidTwoFactorCode)
+        );
+
+    let pow_progress = match *pow_progress_state {
+        Some((max_difficulty_found, hashes_per_second)) => html! {
+            <div class="alert alert-info">
+                <Spinner small={true} />
+                {" Было много неудачных попыток входа, поэтому браузер сначала решает задачу. "}
+                {format!("Лучший результат: {max_difficulty_found}, скорость: {hashes_per_second:.0} хешей/с на {} потоках", pow_workers.workers())}
+            </div>
+        },
+        None => html!(),
+    };
+
     let start = {
         shadow_clone!(token_result);
         move |_ev| {
@@ -239,16 +490,14 @@ fn existing_register() -> Html {
         }
     };
 
-    if let Some(data) = &token_result.data {
-        if let Some(ref token) = data {
-            gloo::storage::LocalStorage::set("token", token.to_string()).unwrap();
-            navigator.push(&Route::Home);
-            gloo::utils::document()
-                .location()
-                .unwrap()
-                .reload()
-                .unwrap();
-        }
+    if let Some(LoginResponse::Ok { .. }) = &token_result.data {
+        gloo::storage::LocalStorage::set(LOGGED_IN_KEY, true).unwrap();
+        navigator.push(&Route::Home);
+        gloo::utils::document()
+            .location()
+            .unwrap()
+            .reload()
+            .unwrap();
     }
 
     html!(
@@ -256,19 +505,142 @@ fn existing_register() -> Html {
             <h1>{"Войти в аккаунт"}</h1>
 
             <form>
-                <FormControl id="handle" ctype={FormControlType::Text} class="mb-3" label="Логин" oninput={oninput_handle} value={(*handle_state).clone()} disabled={&token_result.loading} validation={validation.clone()}/>
-                <FormControl id="password" ctype={FormControlType::Password} class="mb-3" label="Пароль" oninput={oninput_password} value={(*password_state).clone()} disabled={&token_result.loading} {validation}/>
+                <FormControl id="handle" ctype={FormControlType::Text} class="mb-3" label="Логин" oninput={oninput_handle} value={(*handle_state).clone()} disabled={loading} validation={validation.clone()}/>
+                <FormControl id="password" ctype={FormControlType::Password} class="mb-3" label="Пароль" oninput={oninput_password} value={(*password_state).clone()} disabled={loading} {validation}/>
+                if show_two_factor {
+                    <FormControl id="two-factor-code" ctype={FormControlType::Text} class="mb-3" label="Код из приложения-аутентификатора или код восстановления" oninput={oninput_two_factor} value={(*two_factor_state).clone()} disabled={loading} validation={two_factor_validation} />
+                }
 
+                {pow_progress}
 
-                <Button style={Color::Primary} disabled={&token_result.loading} onclick={start}>
-                    if token_result.loading {
+                <Button style={Color::Primary} disabled={loading} onclick={start}>
+                    if loading {
                         <Spinner small={true}  />
                     }
                     {"Войти"}
                 </Button>
+                <Suspense fallback={html!()}>
+                    <SingleSignOnButton />
+                </Suspense>
             </form>
             <hr />
-            <p>{"Если у вас нет аккаунта, "}<a href="https://t.me/danya02">{"обратитесь к администратору для регистрации"}</a>{"."}</p>
+            <p>{"Если у вас нет ни аккаунта, ни кода приглашения, "}<a href="https://t.me/danya02">{"обратитесь к администратору для регистрации"}</a>{"."}</p>
         </>
     )
 }
+
+/// A button to log in with the university's accounts, if the server offers that.
+#[function_component(SingleSignOnButton)]
+fn single_sign_on_button() -> HtmlResult {
+    let resp = use_future(|| async move {
+        reqwest::get(url!("/api/sso"))
+            .await?
+            .error_for_status()?
+            .json::<Option<SingleSignOnInfo>>()
+            .await
+    })?;
+
+    Ok(match *resp {
+        Ok(Some(ref info)) => html! {
+            <a class="btn btn-outline-primary ms-2" href={url!("/api/sso/login")}>
+                {format!("Войти через {}", info.provider_name)}
+            </a>
+        },
+        _ => html!(),
+    })
+}
+
+#[derive(Deserialize)]
+struct SingleSignOnDoneQuery {
+    error: Option<String>,
+    /// The handle is taken by an account that the identity can be linked to, after logging in with its password.
+    link_handle: Option<String>,
+}
+
+/// Where the server sends the browser back to after logging in with single sign-on.
+/// The session cookie is already set by then, so all that's left is to remember that we logged in.
+#[function_component(SingleSignOnDone)]
+pub fn single_sign_on_done() -> Html {
+    let navigator = use_navigator().unwrap();
+    let (error, link_handle) = use_location()
+        .and_then(|location| location.query::<SingleSignOnDoneQuery>().ok())
+        .map_or((None, None), |query| (query.error, query.link_handle));
+
+    use_effect_with(
+        (error.clone(), link_handle.clone()),
+        move |(error, link_handle)| {
+            if error.is_none() && link_handle.is_none() {
+                gloo::storage::LocalStorage::set(LOGGED_IN_KEY, true).unwrap();
+                navigator.replace(&Route::Profile);
+            }
+        },
+    );
+
+    match (error, link_handle) {
+        (Some(why), _) => html! {
+            <div class="alert alert-danger">
+                {"Не удалось войти: "}{why}{". "}
+                <Link<Route> to={Route::Profile}>{"Попробовать снова"}</Link<Route>>
+            </div>
+        },
+        (None, Some(handle)) => html! {
+            <div class="alert alert-info">
+                {"Аккаунт с логином "}<b>{handle}</b>{" уже есть. "}
+                <Link<Route> to={Route::Profile}>{"Войдите в него с паролем"}</Link<Route>>
+                {", и после этого в него можно будет входить и через единый вход."}
+            </div>
+        },
+        (None, None) => html!(<Spinner />),
+    }
+}
+
+#[autoprops]
+#[function_component(LoginLink)]
+pub fn login_link(token: AttrValue) -> Html {
+    html! {
+        <Suspense fallback={html!(<Spinner />)}>
+            <LoginLinkInner {token} />
+        </Suspense>
+    }
+}
+
+/// Uses up a one-time login link; this is done with a request rather than by opening the link itself,
+/// so that mail scanners that follow links don't use it up first.
+#[autoprops]
+#[function_component(LoginLinkInner)]
+fn login_link_inner(token: AttrValue) -> HtmlResult {
+    let navigator = use_navigator().unwrap();
+    let resp = use_future(|| async move {
+        reqwest::Client::default()
+            .post(url!("/api/login-link"))
+            .json(&LoginLinkRequest {
+                token: token.to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<LoginLinkResponse>()
+            .await
+    })?;
+
+    let logged_in = matches!(*resp, Ok(LoginLinkResponse::Ok { .. }));
+    use_effect_with(logged_in, move |logged_in| {
+        if *logged_in {
+            gloo::storage::LocalStorage::set(LOGGED_IN_KEY, true).unwrap();
+            navigator.replace(&Route::Profile);
+        }
+    });
+
+    Ok(match *resp {
+        Ok(LoginLinkResponse::Ok { .. }) => html!(<Spinner />),
+        Ok(LoginLinkResponse::InvalidLink) => html! {
+            <div class="alert alert-danger">
+                {"Эта ссылка для входа уже использована или устарела. Попросите преподавателя выдать новую, или "}
+                <Link<Route> to={Route::Profile}>{"войдите с паролем"}</Link<Route>>{"."}
+            </div>
+        },
+        Err(ref failure) => html! {
+            <div class="alert alert-danger">{"Не удалось войти: "}{failure.to_string()}</div>
+        },
+    })
+}
@@ -1,10 +1,10 @@
 use api::RedeemPromocodeResponse;
 use chrono::Local;
-use gloo::storage::Storage;
 use shadow_clone::shadow_clone;
 use wasm_bindgen::JsCast;
 use web_sys::HtmlInputElement;
 use yew::prelude::*;
+use yew_autoprops::autoprops;
 use yew_bootstrap::{
     component::{
         form::{FormControl, FormControlType, FormControlValidation},
@@ -15,25 +15,18 @@ use yew_bootstrap::{
 use yew_hooks::use_async;
 use yew_router::hooks::use_navigator;
 
-use crate::{url_macro::url, Route};
+use crate::{profile::is_logged_in, url_macro::url, Route};
 
+/// The code can be pre-filled, for when the user comes from a link on a printed promocode.
+#[autoprops]
 #[function_component(RedeemPromocodeWidget)]
-pub fn redeem_promocode_widget() -> Html {
+pub fn redeem_promocode_widget(#[prop_or_default] initial_code: &AttrValue) -> Html {
     let navigator = use_navigator().unwrap();
-    let profile_key: Result<Option<String>, gloo::storage::errors::StorageError> =
-        gloo::storage::LocalStorage::get("token");
-    let profile_key: Option<String> = match profile_key {
-        Ok(key) => key,
-        Err(_) => None,
-    };
-    let token = if let Some(t) = profile_key {
-        t
-    } else {
+    if !is_logged_in() {
         navigator.push(&Route::Profile);
-        String::new()
-    };
+    }
 
-    let code_state = use_state(|| String::new());
+    let code_state = use_state(|| initial_code.to_string());
 
     let oninput = {
         shadow_clone!(code_state);
@@ -51,7 +44,7 @@ pub fn redeem_promocode_widget() -> Html {
                     let code = (*code_state).clone();
                     let client = reqwest::Client::default();
                     client
-                        .post(url!("/api/user-info/{token}/redeem/{code}"))
+                        .post(url!("/api/me/redeem/{code}"))
                         .send()
                         .await
                         .map_err(|v| v.to_string())?
@@ -74,7 +67,8 @@ pub fn redeem_promocode_widget() -> Html {
             RedeemPromocodeResponse::Ok {
                 promocode_value,
                 user_balance_after,
-            } => FormControlValidation::Valid(Some(format!("Вы успешно пополнили баланс на {promocode_value:.3}𐆘! Теперь у вас {user_balance_after:.3}𐆘, обновите страницу чтобы увидеть результат.").into())),
+                verified_account,
+            } => FormControlValidation::Valid(Some(format!("Вы успешно пополнили баланс на {promocode_value:.3}𐆘! Теперь у вас {user_balance_after:.3}𐆘{}, обновите страницу чтобы увидеть результат.", if *verified_account { ", и ваш аккаунт подтверждён" } else { "" }).into())),
             RedeemPromocodeResponse::AlreadyRedeemed {
                 when_unix_time,
                 by_me,
@@ -88,6 +82,16 @@ pub fn redeem_promocode_widget() -> Html {
                 false => format!("Этот промокод уже был активирован кем-то еще в {when}. Свяжитесь с администратором для информации.")
             }.into()
             }),
+            RedeemPromocodeResponse::Expired { when_unix_time } => FormControlValidation::Invalid({
+                let when = chrono::DateTime::from_timestamp(*when_unix_time as i64, 0)
+                    .expect("failed to parse incoming unix time as date")
+                    .with_timezone(&Local)
+                    .to_string();
+                format!("Срок действия этого промокода истек в {when}.").into()
+            }),
+            RedeemPromocodeResponse::ExhaustedUses { max_uses } => FormControlValidation::Invalid(
+                format!("Этот промокод уже активировали максимальное число раз ({max_uses}). Свяжитесь с администратором для информации.").into(),
+            ),
             RedeemPromocodeResponse::NotFound => FormControlValidation::Invalid(
                 "Мы не смогли найти такой промокод. Свяжитесь с администратором для информации."
                     .into(),
@@ -1,20 +1,19 @@
-use api::verification::check_hash_difficulty;
+use api::verification::{check_hash_difficulty, ProofOfWorkAlgorithm, ProofOfWorkChallenge};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
-use wasm_bindgen::prelude::*;
 use yew_agent::reactor::{reactor, ReactorScope};
 
-#[derive(Clone, Debug, Serialize, Deserialize, Hash)]
-pub enum HashMethod {
-    Sha256,
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PowReactorCommand {
-    Input(PowReactorInput),
+    /// Search the given part of the nonce space, so that workers solving the same challenge
+    /// don't try the same attempts.
+    Input {
+        input: PowReactorInput,
+        partition: u32,
+    },
     Stop,
 }
 
@@ -22,6 +21,7 @@ pub enum PowReactorCommand {
 pub struct PowReactorInput {
     pub difficulty: u64,
     pub nonce: Vec<u8>,
+    pub algorithm: ProofOfWorkAlgorithm,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -36,6 +36,14 @@ pub enum PowReactorOutput {
     },
 }
 
+/// Read the challenge out of the signed string that the server sent.
+/// The signature isn't checked: only the server can do that.
+pub fn parse_challenge(challenge_string: &str) -> Option<ProofOfWorkChallenge> {
+    // The string is `<json>.<timestamp>.<signature>`, and the JSON itself may contain dots.
+    let json = challenge_string.rsplitn(3, '.').nth(2)?;
+    serde_json::from_str(json).ok()
+}
+
 // #[wasm_bindgen(module = "node_modules/hash-wasm/dist/index.umd.js")]
 // //#[wasm_bindgen]
 // extern "C" {
@@ -53,55 +61,62 @@ fn increment_byte_slice(data: &mut [u8]) {
     }
 }
 
-#[reactor(Sha256PowReactor)]
-pub async fn sha256_proof_of_work_agent(
-    mut scope: ReactorScope<PowReactorCommand, PowReactorOutput>,
-) {
+#[reactor(PowReactor)]
+pub async fn proof_of_work_agent(mut scope: ReactorScope<PowReactorCommand, PowReactorOutput>) {
     loop {
         // Wait for reactor input
         let mut input = None;
         while let Some(cmd) = scope.next().await {
-            match cmd {
-                PowReactorCommand::Input(data) => {
-                    input = Some(data);
-                    break;
-                }
-                _ => {}
+            if let PowReactorCommand::Input {
+                input: data,
+                partition,
+            } = cmd
+            {
+                input = Some((data, partition));
+                break;
             }
         }
-        let input = if let Some(i) = input {
+        let (input, partition) = if let Some(i) = input {
             i
         } else {
             return;
         };
 
         let mut hasher_original = sha2::Sha256::new();
-        hasher_original.update(input.nonce);
+        hasher_original.update(&input.nonce);
 
         let mut attempts_done: usize = 0;
 
-        let mut my_attempt = vec![0u8; std::mem::size_of_val(&0usize)];
+        // The attempt is the partition followed by a counter, which is the only part that changes.
+        let partition_len = std::mem::size_of_val(&partition);
+        let mut my_attempt = partition.to_be_bytes().to_vec();
+        my_attempt.resize(partition_len + std::mem::size_of_val(&0usize), 0);
 
         let mut max_difficulty_found = 0;
         let mut last_time = web_time::Instant::now();
         'solution_loop: loop {
             futures::select! {
-                m = scope.next() => match m {
-                    Some(PowReactorCommand::Stop) => break,
-                    _ => {}
-                },
+                m = scope.next() => if let Some(PowReactorCommand::Stop) = m { break },
                 _ = yew::platform::time::sleep(std::time::Duration::from_millis(0)).fuse() => {
                 }
             }
-            let loop_attempts = 1000;
+            // Memory-hard hashes are slow, so check for a stop command after each one.
+            let loop_attempts = match input.algorithm {
+                ProofOfWorkAlgorithm::Sha256 => 1000,
+                ProofOfWorkAlgorithm::Argon2id { .. } => 1,
+            };
             for _ in 0..loop_attempts {
                 attempts_done += 1;
-                increment_byte_slice(&mut my_attempt);
+                increment_byte_slice(&mut my_attempt[partition_len..]);
 
-                let mut hasher = hasher_original.clone();
-                hasher.update(&my_attempt);
-                let hash = hasher.finalize();
-                let hash = &hash[..];
+                let hash = match input.algorithm {
+                    ProofOfWorkAlgorithm::Sha256 => {
+                        let mut hasher = hasher_original.clone();
+                        hasher.update(&my_attempt);
+                        hasher.finalize().to_vec()
+                    }
+                    algorithm => algorithm.hash(&input.nonce, &my_attempt),
+                };
 
                 let difficulty = check_hash_difficulty(&hash);
                 if difficulty >= input.difficulty {
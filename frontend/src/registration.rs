@@ -0,0 +1,171 @@
+use api::{PasswordProblem, RegisterRequest, RegisterResponse};
+use gloo::storage::Storage;
+use serde::{Deserialize, Serialize};
+use shadow_clone::shadow_clone;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_autoprops::autoprops;
+use yew_bootstrap::{
+    component::{
+        form::{FormControl, FormControlType, FormControlValidation},
+        Button, Spinner,
+    },
+    util::Color,
+};
+use yew_hooks::use_async;
+use yew_router::hooks::use_navigator;
+
+use crate::{
+    profile::{is_logged_in, LOGGED_IN_KEY},
+    url_macro::url,
+    Route,
+};
+
+/// Added to the profile address after registering, so that verification starts right away.
+#[derive(Serialize, Deserialize, Default)]
+pub struct StartVerificationQuery {
+    #[serde(default)]
+    pub start_verification: bool,
+}
+
+pub fn password_problem_text(problem: &PasswordProblem) -> String {
+    match problem {
+        PasswordProblem::TooShort { min_length } => {
+            format!("Пароль должен быть не короче {min_length} символов")
+        }
+        PasswordProblem::TooLong { max_length } => {
+            format!("Пароль должен быть не длиннее {max_length} символов")
+        }
+        PasswordProblem::SameAsHandle => "Пароль не должен совпадать с логином".to_string(),
+    }
+}
+
+/// The code can be pre-filled, for when the user comes from an invite link.
+#[autoprops]
+#[function_component(RegistrationForm)]
+pub fn registration_form(#[prop_or_default] initial_code: &AttrValue) -> Html {
+    let navigator = use_navigator().unwrap();
+    if is_logged_in() {
+        navigator.push(&Route::Profile);
+    }
+
+    let code_state = use_state(|| initial_code.to_string());
+    let name_state = use_state(String::new);
+    let handle_state = use_state(String::new);
+    let password_state = use_state(String::new);
+
+    let make_oninput = |state: &UseStateHandle<String>| {
+        shadow_clone!(state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            state.set(target.value());
+        }
+    };
+    let oninput_code = make_oninput(&code_state);
+    let oninput_name = make_oninput(&name_state);
+    let oninput_handle = make_oninput(&handle_state);
+    let oninput_password = make_oninput(&password_state);
+
+    let register_result: yew_hooks::prelude::UseAsyncHandle<RegisterResponse, String> =
+        use_async({
+            shadow_clone!(code_state, name_state, handle_state, password_state);
+            async move {
+                reqwest::Client::default()
+                    .post(url!("/api/register"))
+                    .json(&RegisterRequest {
+                        invite_code: (*code_state).clone(),
+                        name: (*name_state).clone(),
+                        handle: (*handle_state).clone(),
+                        password: (*password_state).clone(),
+                    })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .error_for_status()
+                    .map_err(|v| v.to_string())?
+                    .json::<RegisterResponse>()
+                    .await
+                    .map_err(|v| v.to_string())
+            }
+        });
+
+    let start = {
+        shadow_clone!(register_result);
+        move |_ev| register_result.run()
+    };
+
+    // The new account isn't verified yet, so the profile starts that as soon as it opens.
+    if let Some(RegisterResponse::Ok { .. }) = &register_result.data {
+        gloo::storage::LocalStorage::set(LOGGED_IN_KEY, true).unwrap();
+        navigator
+            .push_with_query(
+                &Route::Profile,
+                &StartVerificationQuery {
+                    start_verification: true,
+                },
+            )
+            .unwrap();
+        gloo::utils::document()
+            .location()
+            .unwrap()
+            .reload()
+            .unwrap();
+    }
+
+    let invalid = |text: &str| FormControlValidation::Invalid(text.to_string().into());
+    let (mut code_validation, mut name_validation, mut handle_validation, mut password_validation) = (
+        FormControlValidation::None,
+        FormControlValidation::None,
+        FormControlValidation::None,
+        FormControlValidation::None,
+    );
+    match &register_result.data {
+        Some(RegisterResponse::InvalidInviteCode) => {
+            code_validation = invalid("Код приглашения не найден, истёк или уже использован")
+        }
+        Some(RegisterResponse::InvalidName) => name_validation = invalid("Укажите имя"),
+        Some(RegisterResponse::InvalidHandle) => {
+            handle_validation = invalid(
+                "Логин должен быть от 3 до 32 символов: латинские буквы, цифры, точки, дефисы и подчёркивания",
+            )
+        }
+        Some(RegisterResponse::HandleTaken) => handle_validation = invalid("Этот логин уже занят"),
+        Some(RegisterResponse::BadPassword(problem)) => {
+            password_validation = invalid(&password_problem_text(problem))
+        }
+        Some(RegisterResponse::LockedOut { until_unix_time }) => {
+            let until = chrono::DateTime::from_timestamp(*until_unix_time as i64, 0)
+                .expect("failed to parse incoming unix time as date")
+                .with_timezone(&chrono::Local)
+                .to_string();
+            code_validation = invalid(&format!(
+                "Слишком много неверных кодов приглашения. Попробуйте снова после {until}"
+            ))
+        }
+        Some(RegisterResponse::Ok { .. }) | None => {}
+    }
+    let loading = register_result.loading;
+
+    html! {
+        <>
+            <h1>{"Зарегистрироваться"}</h1>
+            <p>{"Если преподаватель выдал вам код приглашения, создайте аккаунт с ним."}</p>
+            <form>
+                <FormControl id="register-code" ctype={FormControlType::Text} class="mb-3" label="Код приглашения" oninput={oninput_code} value={(*code_state).clone()} disabled={loading} validation={code_validation} />
+                <FormControl id="register-name" ctype={FormControlType::Text} class="mb-3" label="Имя и фамилия" oninput={oninput_name} value={(*name_state).clone()} disabled={loading} validation={name_validation} />
+                <FormControl id="register-handle" ctype={FormControlType::Text} class="mb-3" label="Логин" oninput={oninput_handle} value={(*handle_state).clone()} disabled={loading} validation={handle_validation} />
+                <FormControl id="register-password" ctype={FormControlType::Password} class="mb-3" label="Пароль" oninput={oninput_password} value={(*password_state).clone()} disabled={loading} validation={password_validation} />
+                if let Some(why) = &register_result.error {
+                    <div class="text-danger mb-3">{"Ошибка при регистрации: "}{why}</div>
+                }
+                <Button style={Color::Primary} disabled={loading} onclick={start}>
+                    if loading {
+                        <Spinner small={true} />
+                    }
+                    {"Зарегистрироваться"}
+                </Button>
+            </form>
+        </>
+    }
+}
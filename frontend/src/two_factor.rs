@@ -0,0 +1,269 @@
+use api::{
+    BeginTwoFactorResponse, ConfirmTwoFactorResponse, DisableTwoFactorResponse,
+    TwoFactorCodeRequest, TwoFactorStatus,
+};
+use shadow_clone::shadow_clone;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew::suspense::use_future;
+use yew_autoprops::autoprops;
+use yew_bootstrap::{
+    component::{
+        form::{FormControl, FormControlType, FormControlValidation},
+        Button, Spinner,
+    },
+    util::Color,
+};
+use yew_hooks::use_async;
+
+use crate::url_macro::url;
+
+fn reload() {
+    gloo::utils::document()
+        .location()
+        .unwrap()
+        .reload()
+        .unwrap();
+}
+
+#[function_component(TwoFactor)]
+pub fn two_factor() -> Html {
+    html! {
+        <>
+            <h3>{"Двухфакторная аутентификация"}</h3>
+            <p>{"С ней для входа нужен не только пароль, но и код из приложения-аутентификатора на телефоне."}</p>
+            <Suspense fallback={html!(<Spinner />)}>
+                <TwoFactorSettings />
+            </Suspense>
+        </>
+    }
+}
+
+#[function_component(TwoFactorSettings)]
+fn two_factor_settings() -> HtmlResult {
+    let resp = use_future(|| async move {
+        reqwest::get(url!("/api/me/two-factor"))
+            .await?
+            .error_for_status()?
+            .json::<TwoFactorStatus>()
+            .await
+    })?;
+
+    Ok(match *resp {
+        Ok(ref status) if status.enabled => html! {
+            <>
+                <p>
+                    {"Двухфакторная аутентификация включена. Неиспользованных кодов восстановления: "}
+                    {status.recovery_codes_left}{"."}
+                </p>
+                <DisableTwoFactorForm />
+            </>
+        },
+        Ok(_) => html!(<EnableTwoFactorForm />),
+        Err(ref failure) => html! {
+            <div class="alert alert-danger">{"Ошибка при загрузке настроек: "}{failure.to_string()}</div>
+        },
+    })
+}
+
+/// A field for a code from the authenticator app.
+#[autoprops]
+#[function_component(CodeField)]
+fn code_field(
+    id: AttrValue,
+    label: AttrValue,
+    state: &UseStateHandle<String>,
+    disabled: bool,
+    validation: &FormControlValidation,
+) -> Html {
+    let oninput = {
+        shadow_clone!(state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            state.set(target.value());
+        }
+    };
+    html! {
+        <FormControl {id} ctype={FormControlType::Text} class="mb-3" {label} {oninput} value={(**state).clone()} {disabled} validation={validation.clone()} />
+    }
+}
+
+#[function_component(EnableTwoFactorForm)]
+fn enable_two_factor_form() -> Html {
+    let code_state = use_state(String::new);
+
+    let begin_result: yew_hooks::prelude::UseAsyncHandle<BeginTwoFactorResponse, String> =
+        use_async(async move {
+            reqwest::Client::default()
+                .post(url!("/api/me/two-factor/begin"))
+                .send()
+                .await
+                .map_err(|v| v.to_string())?
+                .error_for_status()
+                .map_err(|v| v.to_string())?
+                .json::<BeginTwoFactorResponse>()
+                .await
+                .map_err(|v| v.to_string())
+        });
+    let begin = {
+        shadow_clone!(begin_result);
+        move |_ev| begin_result.run()
+    };
+
+    let confirm_result: yew_hooks::prelude::UseAsyncHandle<ConfirmTwoFactorResponse, String> =
+        use_async({
+            shadow_clone!(code_state);
+            async move {
+                reqwest::Client::default()
+                    .post(url!("/api/me/two-factor/confirm"))
+                    .json(&TwoFactorCodeRequest {
+                        code: (*code_state).clone(),
+                    })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .error_for_status()
+                    .map_err(|v| v.to_string())?
+                    .json::<ConfirmTwoFactorResponse>()
+                    .await
+                    .map_err(|v| v.to_string())
+            }
+        });
+    let confirm = {
+        shadow_clone!(confirm_result);
+        move |_ev| confirm_result.run()
+    };
+
+    // The recovery codes are only shown once, so the page isn't reloaded until they are saved.
+    if let Some(ConfirmTwoFactorResponse::Ok { recovery_codes }) = &confirm_result.data {
+        return html! {
+            <div class="alert alert-success">
+                <p>{"Двухфакторная аутентификация включена. Сохраните коды восстановления: каждый из них можно один раз ввести вместо кода из приложения, если телефон потеряется. Больше они показаны не будут."}</p>
+                <ul>
+                    {for recovery_codes.iter().map(|code| html!(<li><code>{code}</code></li>))}
+                </ul>
+                <Button style={Color::Primary} onclick={|_ev| reload()}>{"Я сохранил коды"}</Button>
+            </div>
+        };
+    }
+
+    let validation = match (&confirm_result.data, &confirm_result.error) {
+        (Some(ConfirmTwoFactorResponse::InvalidCode), _) => {
+            FormControlValidation::Invalid("Неверный код. Проверьте время на телефоне.".into())
+        }
+        (Some(ConfirmTwoFactorResponse::NotStarted), _) => {
+            FormControlValidation::Invalid("Начните настройку заново.".into())
+        }
+        (Some(ConfirmTwoFactorResponse::AlreadyEnabled), _) => {
+            FormControlValidation::Invalid("Двухфакторная аутентификация уже включена.".into())
+        }
+        (None, Some(why)) => FormControlValidation::Invalid(format!("Ошибка: {why}").into()),
+        _ => FormControlValidation::None,
+    };
+
+    match &begin_result.data {
+        Some(BeginTwoFactorResponse::Ok {
+            secret,
+            provisioning_uri,
+            qr_svg,
+        }) => html! {
+            <>
+                <p>{"Отсканируйте QR-код в приложении-аутентификаторе (например, Google Authenticator или Aegis), или введите ключ вручную: "}<code>{secret}</code></p>
+                <div class="mb-3">
+                    <a href={provisioning_uri.clone()}>
+                        {Html::from_html_unchecked(qr_svg.clone().into())}
+                    </a>
+                </div>
+                <CodeField id="two-factor-confirm-code" label="Код из приложения" state={code_state.clone()} disabled={confirm_result.loading} {validation} />
+                <Button style={Color::Primary} disabled={confirm_result.loading} onclick={confirm}>
+                    if confirm_result.loading {
+                        <Spinner small={true} />
+                    }
+                    {"Включить"}
+                </Button>
+            </>
+        },
+        Some(BeginTwoFactorResponse::AlreadyEnabled) => {
+            html!(<p>{"Двухфакторная аутентификация уже включена."}</p>)
+        }
+        None => html! {
+            <>
+                <Button style={Color::Primary} disabled={begin_result.loading} onclick={begin}>
+                    if begin_result.loading {
+                        <Spinner small={true} />
+                    }
+                    {"Настроить"}
+                </Button>
+                if let Some(why) = &begin_result.error {
+                    <div class="text-danger">{"Ошибка: "}{why}</div>
+                }
+            </>
+        },
+    }
+}
+
+#[function_component(DisableTwoFactorForm)]
+fn disable_two_factor_form() -> Html {
+    let code_state = use_state(String::new);
+
+    let disable_result: yew_hooks::prelude::UseAsyncHandle<DisableTwoFactorResponse, String> =
+        use_async({
+            shadow_clone!(code_state);
+            async move {
+                let resp = reqwest::Client::default()
+                    .post(url!("/api/me/two-factor/disable"))
+                    .json(&TwoFactorCodeRequest {
+                        code: (*code_state).clone(),
+                    })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .error_for_status()
+                    .map_err(|v| v.to_string())?
+                    .json::<DisableTwoFactorResponse>()
+                    .await
+                    .map_err(|v| v.to_string())?;
+                if matches!(
+                    resp,
+                    DisableTwoFactorResponse::Ok | DisableTwoFactorResponse::NotEnabled
+                ) {
+                    reload();
+                }
+                Ok(resp)
+            }
+        });
+    let disable = {
+        shadow_clone!(disable_result);
+        move |_ev| disable_result.run()
+    };
+
+    let validation = match (&disable_result.data, &disable_result.error) {
+        (Some(DisableTwoFactorResponse::InvalidCode), _) => {
+            FormControlValidation::Invalid("Неверный или уже использованный код".into())
+        }
+        (Some(DisableTwoFactorResponse::LockedOut { until_unix_time }), _) => {
+            let until = chrono::DateTime::from_timestamp(*until_unix_time as i64, 0)
+                .expect("failed to parse incoming unix time as date")
+                .with_timezone(&chrono::Local)
+                .to_string();
+            FormControlValidation::Invalid(
+                format!("Слишком много неверных кодов. Попробуйте снова после {until}").into(),
+            )
+        }
+        (None, Some(why)) => FormControlValidation::Invalid(format!("Ошибка: {why}").into()),
+        _ => FormControlValidation::None,
+    };
+
+    html! {
+        <>
+            <CodeField id="two-factor-disable-code" label="Чтобы отключить, введите код из приложения или код восстановления" state={code_state.clone()} disabled={disable_result.loading} {validation} />
+            <Button style={Color::Danger} outline={true} disabled={disable_result.loading} onclick={disable}>
+                if disable_result.loading {
+                    <Spinner small={true} />
+                }
+                {"Отключить"}
+            </Button>
+        </>
+    }
+}
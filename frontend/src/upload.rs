@@ -1,5 +1,4 @@
-use api::{PricingInfo, UserInfoResult};
-use gloo::storage::Storage;
+use api::{CostEstimate, CostEstimateRequest, PlannedFile, PricingInfo, UserInfoResult};
 use gloo::utils::window;
 use js_sys::ArrayBuffer;
 use js_sys::Promise;
@@ -22,6 +21,7 @@ use yew_hooks::use_async;
 use yew_hooks::{use_drop_with_options, use_list, UseDropOptions};
 use yew_router::hooks::use_navigator;
 
+use crate::profile::is_logged_in;
 use crate::url_macro::url;
 use crate::Route;
 use crate::MONEY;
@@ -45,17 +45,9 @@ fn upload_inner() -> HtmlResult {
     let resp = {
         shadow_clone!(navigator);
         use_future(|| async move {
-            let profile_key = gloo::storage::LocalStorage::get("token");
-            let profile_key: Option<String> = match profile_key {
-                Ok(key) => key,
-                Err(_) => None,
-            };
-            let token = if let Some(key) = profile_key {
-                key
-            } else {
+            if !is_logged_in() {
                 navigator.push(&Route::Profile);
-                String::new()
-            };
+            }
 
             let pricing = reqwest::get(url!("/api/pricing"))
                 .await?
@@ -63,7 +55,7 @@ fn upload_inner() -> HtmlResult {
                 .json::<PricingInfo>()
                 .await?;
 
-            let my_info = reqwest::get(url!("/api/user-info/{token}"))
+            let my_info = reqwest::get(url!("/api/me"))
                 .await?
                 .error_for_status()?
                 .json::<UserInfoResult>()
@@ -73,26 +65,33 @@ fn upload_inner() -> HtmlResult {
         })?
     };
 
+    // The spending cap for the order, as typed by the user; empty means no cap.
+    let budget_state = use_state(String::new);
+
     let do_upload: yew_hooks::prelude::UseAsyncHandle<String, String> = {
-        shadow_clone!(dropped_files, navigator);
+        shadow_clone!(dropped_files, navigator, budget_state);
         use_async(async move {
-            let profile_key = gloo::storage::LocalStorage::get("token");
-            let profile_key: Option<String> = match profile_key {
-                Ok(key) => key,
-                Err(_) => None,
-            };
-            let token = if let Some(key) = profile_key {
-                key
-            } else {
+            if !is_logged_in() {
                 navigator.push(&Route::Profile);
-                return Err("Нет токена".to_string());
+                return Err("Вы не вошли в аккаунт".to_string());
+            }
+
+            let budget = budget_state.trim();
+            let budget = if budget.is_empty() {
+                None
+            } else {
+                match budget.parse::<f64>() {
+                    Ok(v) if v > 0.0 => Some(v),
+                    _ => return Err("Лимит расходов должен быть положительным числом".to_string()),
+                }
             };
 
             let client = reqwest::Client::new();
 
             let mut form = reqwest::multipart::Form::new();
 
-            for (file, name) in dropped_files.current().iter() {
+            let files = dropped_files.current().to_vec();
+            for (file, name) in files.iter() {
                 let promise: Promise = file.array_buffer();
                 let array_buf = wasm_bindgen_futures::JsFuture::from(promise).await;
                 let array_buf = if let Ok(a) = array_buf {
@@ -110,7 +109,10 @@ fn upload_inner() -> HtmlResult {
             }
 
             let resp = client
-                .post(url!("/api/orders/{token}/new"))
+                .post(match budget {
+                    Some(budget) => url!("/api/me/orders/new?budget={budget}"),
+                    None => url!("/api/me/orders/new"),
+                })
                 .multipart(form)
                 .send()
                 .await
@@ -118,14 +120,58 @@ fn upload_inner() -> HtmlResult {
                 .error_for_status()
                 .map_err(|v| format!("Ошибка при отправке файлов: {v}"))?;
 
-            Ok::<String, String>(
-                resp.text()
-                    .await
-                    .map_err(|v| format!("Что-то не так с ответом: {v}"))?,
-            )
+            resp.text()
+                .await
+                .map_err(|v| format!("Что-то не так с ответом: {v}"))
         })
     };
 
+    let estimate: yew_hooks::prelude::UseAsyncHandle<CostEstimate, String> = {
+        shadow_clone!(dropped_files, navigator);
+        use_async(async move {
+            if !is_logged_in() {
+                navigator.push(&Route::Profile);
+                return Err("Вы не вошли в аккаунт".to_string());
+            }
+
+            let files = dropped_files
+                .current()
+                .iter()
+                .map(|(file, name)| PlannedFile {
+                    path: name.clone(),
+                    size_bytes: file.size() as u64,
+                })
+                .collect();
+
+            reqwest::Client::new()
+                .post(url!("/api/me/orders/estimate"))
+                .json(&CostEstimateRequest { files })
+                .send()
+                .await
+                .map_err(|v| format!("Ошибка при оценке стоимости: {v}"))?
+                .error_for_status()
+                .map_err(|v| format!("Ошибка при оценке стоимости: {v}"))?
+                .json::<CostEstimate>()
+                .await
+                .map_err(|v| format!("Что-то не так с ответом: {v}"))
+        })
+    };
+
+    // Re-estimate every time the set of files changes.
+    let planned_files: Vec<(String, u64)> = dropped_files
+        .current()
+        .iter()
+        .map(|(file, name)| (name.clone(), file.size() as u64))
+        .collect();
+    use_effect_with(planned_files, {
+        shadow_clone!(estimate);
+        move |planned_files| {
+            if !planned_files.is_empty() {
+                estimate.run();
+            }
+        }
+    });
+
     // let push = {
     //     shadow_clone!(dropped_files);
     //     Callback::from(move |what: File| {
@@ -142,7 +188,7 @@ fn upload_inner() -> HtmlResult {
         shadow_clone!(dropped_files);
         Callback::from(move |items: Vec<(File, String)>| {
             let mut common_prefix;
-            if let Some(first) = items.iter().next() {
+            if let Some(first) = items.first() {
                 common_prefix = first.1.as_str();
             } else {
                 return;
@@ -180,7 +226,7 @@ fn upload_inner() -> HtmlResult {
                 what
             } else {
                 window().location().reload().unwrap();
-                loop {}
+                return Ok(html!());
             };
             let make_delete_cb = {
                 |name: String| {
@@ -188,7 +234,7 @@ fn upload_inner() -> HtmlResult {
                     Callback::from(move |ev: MouseEvent| {
                         ev.prevent_default();
                         shadow_clone!(name);
-                        f.retain(|v| &v.1 != &name);
+                        f.retain(|v| v.1 != name);
                     })
                 }
             };
@@ -237,10 +283,50 @@ fn upload_inner() -> HtmlResult {
 
             let cost_str = format!("{total_cost:.3}");
 
+            let on_budget_input = {
+                shadow_clone!(budget_state);
+                Callback::from(move |ev: InputEvent| {
+                    let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+                    budget_state.set(target.value());
+                })
+            };
+
+            let estimate_block = if dropped_files.current().is_empty() {
+                html!()
+            } else if estimate.loading {
+                html!(<p>{"Оцениваем стоимость заказа..."}<Spinner small={true} /></p>)
+            } else if let Some(ref error) = estimate.error {
+                html!(<p class="text-danger">{error}</p>)
+            } else if let Some(ref estimate) = estimate.data {
+                let (min, max) = estimate.total_range();
+                match estimate.execution_cost_range {
+                    Some(_) => html!(
+                        <p>
+                            {"Ожидаемая стоимость заказа: от "}<code>{format!("{min:.3}{MONEY}")}</code>
+                            {" до "}<code>{format!("{max:.3}{MONEY}")}</code>
+                            <br />
+                            <small class="text-secondary">{"Оценка сделана по "}{estimate.similar_orders}{" вашим похожим заказам."}</small>
+                        </p>
+                    ),
+                    None => html!(
+                        <p class="text-secondary">
+                            {"У вас еще не было похожих заказов, поэтому мы не можем оценить стоимость выполнения. Загрузка файлов будет стоить "}
+                            <code>{format!("{min:.3}{MONEY}")}</code>
+                            {", плюс стоимость выполнения."}
+                        </p>
+                    ),
+                }
+            } else {
+                html!()
+            };
+
+            // The server also lets orders spend the group's money, as far as the member's limit allows.
+            let available_funds = me.balance + me.group.as_ref().map_or(0.0, |g| g.available);
+
             let upload_block = {
                 let mut failure_reasons = vec![];
 
-                if total_cost > me.balance {
+                if total_cost > available_funds {
                     failure_reasons.push(html!(<>{"У вас недостаточно баланса, чтобы загрузить все эти файлы. "}<a href="https://t.me/danya02">{"Свяжитесь с администратором"}</a>{" для покупки промо-кодов, удалите или замените большие файлы или подождите восполнения баланса."}</>));
                 }
                 let mut makefile_exists = false;
@@ -305,7 +391,16 @@ fn upload_inner() -> HtmlResult {
             html!(
                 <>
                 <p>{"Текущий баланс: "}<code>{format!("{:.3}{MONEY}", me.balance)}</code></p>
+                if let Some(group) = &me.group {
+                    <p>{"Доступно из баланса группы «"}{&group.name}{"»: "}<code>{format!("{:.3}{MONEY}", group.available)}</code></p>
+                }
 
+                if pricing.surge_multiplier > 1.0 {
+                    <div class="alert alert-warning">
+                        {"Сейчас сервер сильно загружен, поэтому все расценки умножены на "}<code>{format!("×{:.2}", pricing.surge_multiplier)}</code>
+                        {". Если вы не торопитесь, можно подождать, пока нагрузка спадет."}
+                    </div>
+                }
                 <p>{"Текушие расценки:"}</p>
                 <ul>
                     <li><code>{pricing.wall_time_factor}{MONEY}</code>{" за секунду реального времени выполнения"}</li>
@@ -326,7 +421,7 @@ fn upload_inner() -> HtmlResult {
                         <div class="input-group mb-1" >
                             <button onclick={delete_all.clone()} class="btn btn-outline-danger">{"Удалить все файлы"}</button>
                         </div>
-                        if dropped_files.current().len() == 0 {
+                        if dropped_files.current().is_empty() {
                             <div class="input-group mb-1" >
                                 <span class="input-group-text">{"Пока не загружено файлов..."}</span>
                             </div>
@@ -340,6 +435,11 @@ fn upload_inner() -> HtmlResult {
                         </Column>
 
                         <Column>
+                            <div class="input-group mb-3">
+                                <span class="input-group-text">{"Лимит расходов на заказ"}</span>
+                                <input type="number" min="0" class="form-control" placeholder="без лимита" value={(*budget_state).clone()} oninput={on_budget_input} />
+                                <span class="input-group-text">{MONEY}</span>
+                            </div>
                             {upload_block}
                             <p class="fs-3">
                                 {"Общая стоимость файлов: "}
@@ -348,6 +448,7 @@ fn upload_inner() -> HtmlResult {
                                 {MONEY}
                                 </code>
                             </p>
+                            {estimate_block}
 
                         </Column>
                     </Row>
@@ -441,7 +542,7 @@ fn uploadbox(on_upload: Callback<Vec<(web_sys::File, String)>>) -> Html {
                                 let path = js_sys::Reflect::get(&item, &propname)
                                     .unwrap_or_else(|_| JsValue::from_str(""))
                                     .as_string()
-                                    .unwrap_or_else(String::new);
+                                    .unwrap_or_default();
                                 (item, path)
                             })
                             .collect();
@@ -0,0 +1,428 @@
+use api::verification::{
+    ApprovalRequest, ApprovalRequestResponse, ConfirmEmailCodeRequest, ConfirmEmailCodeResponse,
+    ProofOfWorkAttempt, ProofOfWorkChallengeResponse, SendEmailCodeRequest, SendEmailCodeResponse,
+};
+use api::VerificationMethod;
+use shadow_clone::shadow_clone;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_autoprops::autoprops;
+use yew_bootstrap::{
+    component::{
+        form::{FormControl, FormControlType, FormControlValidation},
+        Button, Spinner,
+    },
+    util::Color,
+};
+use yew_hooks::use_async;
+
+use crate::{
+    pow_workers::use_pow_workers,
+    proof_of_work_agent::{parse_challenge, PowReactorInput, PowReactorOutput},
+    url_macro::url,
+};
+
+fn method_name(method: VerificationMethod) -> &'static str {
+    match method {
+        VerificationMethod::None => "не подтверждён",
+        VerificationMethod::ProofOfWork => "вычислением в браузере",
+        VerificationMethod::EmailCode => "по электронной почте",
+        VerificationMethod::AdminApproval => "преподавателем",
+        VerificationMethod::Promocode => "промокодом",
+        VerificationMethod::SingleSignOn => "входом через университет",
+    }
+}
+
+fn reload() {
+    gloo::utils::document()
+        .location()
+        .unwrap()
+        .reload()
+        .unwrap();
+}
+
+/// Shows how the account was verified, or the ways it can be, if it wasn't yet.
+/// Right after registering, the proof of work is started without waiting for a click.
+#[autoprops]
+#[function_component(Verification)]
+pub fn verification(
+    verification: &VerificationMethod,
+    available: &Vec<VerificationMethod>,
+    request_pending: bool,
+    #[prop_or_default] start_proof_of_work: bool,
+) -> Html {
+    if *verification != VerificationMethod::None {
+        return html! {
+            <p class="text-success">{"Аккаунт подтверждён "}{method_name(*verification)}{"."}</p>
+        };
+    }
+
+    let methods = available.iter().map(|method| match method {
+        VerificationMethod::ProofOfWork => html!(<PowVerification autostart={start_proof_of_work} />),
+        VerificationMethod::EmailCode => html!(<EmailVerification />),
+        VerificationMethod::AdminApproval => html!(<ApprovalVerification {request_pending} />),
+        VerificationMethod::Promocode => html! {
+            <>
+                <h5>{"Промокодом"}</h5>
+                <p>{"Если преподаватель выдал вам промокод для подтверждения, активируйте его ниже."}</p>
+            </>
+        },
+        // This one isn't chosen, it comes from logging in.
+        VerificationMethod::None | VerificationMethod::SingleSignOn => html!(),
+    });
+
+    html! {
+        <div class="alert alert-warning">
+            <h4>{"Подтвердите аккаунт"}</h4>
+            <p>{"Пока аккаунт не подтверждён, заказы делать нельзя. Выберите любой из способов:"}</p>
+            {for methods}
+        </div>
+    }
+}
+
+#[autoprops]
+#[function_component(PowVerification)]
+fn pow_verification(autostart: bool) -> Html {
+    let attempt_state = use_state(|| None::<ProofOfWorkAttempt>);
+    let progress_state = use_state(|| None::<(usize, f64)>);
+    // Read from the workers' callback, which doesn't see later states.
+    let pending_challenge = use_mut_ref(|| None::<String>);
+
+    let challenge_result: yew_hooks::prelude::UseAsyncHandle<ProofOfWorkChallengeResponse, String> =
+        use_async(async move {
+            reqwest::get(url!("/api/me/verification/proof-of-work/get-challenge"))
+                .await
+                .map_err(|v| v.to_string())?
+                .error_for_status()
+                .map_err(|v| v.to_string())?
+                .json::<ProofOfWorkChallengeResponse>()
+                .await
+                .map_err(|v| v.to_string())
+        });
+
+    let verify_result: yew_hooks::prelude::UseAsyncHandle<(), String> = use_async({
+        shadow_clone!(attempt_state);
+        async move {
+            reqwest::Client::default()
+                .post(url!("/api/me/verification/proof-of-work/verify-challenge"))
+                .json(&*attempt_state)
+                .send()
+                .await
+                .map_err(|v| v.to_string())?
+                .error_for_status()
+                .map_err(|v| v.to_string())?;
+            reload();
+            Ok(())
+        }
+    });
+
+    let pow_workers = use_pow_workers({
+        shadow_clone!(attempt_state, progress_state, pending_challenge);
+        move |output| match output {
+            PowReactorOutput::Progress {
+                attempts_done,
+                hashes_per_second,
+                ..
+            } => progress_state.set(Some((attempts_done, hashes_per_second))),
+            PowReactorOutput::FoundSolution { nonce } => {
+                if let Some(challenge_string) = pending_challenge.borrow_mut().take() {
+                    progress_state.set(None);
+                    attempt_state.set(Some(ProofOfWorkAttempt {
+                        challenge_string,
+                        nonce_postfix: nonce,
+                    }));
+                }
+            }
+        }
+    });
+
+    use_effect_with(challenge_result.data.clone(), {
+        shadow_clone!(pow_workers, pending_challenge, progress_state);
+        move |data| {
+            if let Some(response) = data {
+                if let Some(parsed) = parse_challenge(&response.challenge) {
+                    *pending_challenge.borrow_mut() = Some(response.challenge.clone());
+                    progress_state.set(Some((0, 0.0)));
+                    pow_workers.start(PowReactorInput {
+                        difficulty: parsed.difficulty,
+                        nonce: parsed.nonce,
+                        algorithm: parsed.algorithm,
+                    });
+                }
+            }
+        }
+    });
+
+    use_effect_with((*attempt_state).clone(), {
+        shadow_clone!(verify_result);
+        move |attempt| {
+            if attempt.is_some() {
+                verify_result.run();
+            }
+        }
+    });
+
+    use_effect_with(autostart, {
+        shadow_clone!(challenge_result);
+        move |autostart| {
+            if *autostart {
+                challenge_result.run();
+            }
+        }
+    });
+
+    let start = {
+        shadow_clone!(challenge_result);
+        move |_ev| challenge_result.run()
+    };
+    let busy = challenge_result.loading || progress_state.is_some() || verify_result.loading;
+
+    let progress = match (*progress_state, &challenge_result.data) {
+        (Some((attempts_done, hashes_per_second)), Some(response)) => {
+            let percent =
+                (attempts_done as f64 / response.expected_hashes as f64 * 100.0).min(99.0);
+            html! {
+                <p>
+                    <Spinner small={true} />
+                    {format!(" Идёт вычисление: примерно {percent:.0}%, {hashes_per_second:.0} хешей/с на {} потоках", pow_workers.workers())}
+                </p>
+            }
+        }
+        _ => html!(),
+    };
+    let error = challenge_result
+        .error
+        .as_ref()
+        .or(verify_result.error.as_ref())
+        .map(|why| html!(<div class="text-danger">{"Ошибка: "}{why}</div>));
+
+    html! {
+        <>
+            <h5>{"Вычислением в браузере"}</h5>
+            <p>{"Браузер решит задачу, которая займёт немного времени. Не закрывайте страницу, пока она решается."}</p>
+            {progress}
+            {error}
+            <Button class="mb-3" style={Color::Primary} disabled={busy} onclick={start}>
+                {"Подтвердить вычислением"}
+            </Button>
+        </>
+    }
+}
+
+#[function_component(EmailVerification)]
+fn email_verification() -> Html {
+    let email_state = use_state(String::new);
+    let code_state = use_state(String::new);
+
+    let make_oninput = |state: &UseStateHandle<String>| {
+        shadow_clone!(state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            state.set(target.value());
+        }
+    };
+    let oninput_email = make_oninput(&email_state);
+    let oninput_code = make_oninput(&code_state);
+
+    let send_result: yew_hooks::prelude::UseAsyncHandle<SendEmailCodeResponse, String> =
+        use_async({
+            shadow_clone!(email_state);
+            async move {
+                reqwest::Client::default()
+                    .post(url!("/api/me/verification/email/send-code"))
+                    .json(&SendEmailCodeRequest {
+                        email: (*email_state).clone(),
+                    })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .error_for_status()
+                    .map_err(|v| v.to_string())?
+                    .json::<SendEmailCodeResponse>()
+                    .await
+                    .map_err(|v| v.to_string())
+            }
+        });
+
+    let confirm_result: yew_hooks::prelude::UseAsyncHandle<ConfirmEmailCodeResponse, String> =
+        use_async({
+            shadow_clone!(code_state);
+            async move {
+                let response = reqwest::Client::default()
+                    .post(url!("/api/me/verification/email/confirm-code"))
+                    .json(&ConfirmEmailCodeRequest {
+                        code: (*code_state).clone(),
+                    })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .error_for_status()
+                    .map_err(|v| v.to_string())?
+                    .json::<ConfirmEmailCodeResponse>()
+                    .await
+                    .map_err(|v| v.to_string())?;
+                if response == ConfirmEmailCodeResponse::Ok {
+                    reload();
+                }
+                Ok(response)
+            }
+        });
+
+    let send = {
+        shadow_clone!(send_result);
+        move |_ev| send_result.run()
+    };
+    let confirm = {
+        shadow_clone!(confirm_result);
+        move |_ev| confirm_result.run()
+    };
+
+    let send_validation = match (&send_result.data, &send_result.error) {
+        (Some(SendEmailCodeResponse::Sent), _) => {
+            FormControlValidation::Valid(Some("Код отправлен, проверьте почту.".into()))
+        }
+        (Some(SendEmailCodeResponse::AlreadyVerified), _) => {
+            FormControlValidation::Valid(Some("Аккаунт уже подтверждён.".into()))
+        }
+        (Some(SendEmailCodeResponse::InvalidEmail), _) => {
+            FormControlValidation::Invalid("Это не похоже на адрес почты.".into())
+        }
+        (Some(SendEmailCodeResponse::DomainNotAllowed { allowed_domains }), _) => {
+            FormControlValidation::Invalid(
+                format!("Подходят только адреса на {}.", allowed_domains.join(", ")).into(),
+            )
+        }
+        (Some(SendEmailCodeResponse::EmailTaken), _) => {
+            FormControlValidation::Invalid("Этот адрес уже подтвердил другой аккаунт.".into())
+        }
+        (
+            Some(SendEmailCodeResponse::TooSoon {
+                retry_after_seconds,
+            }),
+            _,
+        ) => FormControlValidation::Invalid(
+            format!("Код уже отправлен; новый можно запросить через {retry_after_seconds} с.")
+                .into(),
+        ),
+        (None, Some(why)) => {
+            FormControlValidation::Invalid(format!("Ошибка при отправке кода: {why}").into())
+        }
+        (None, None) => FormControlValidation::None,
+    };
+
+    let confirm_validation = match (&confirm_result.data, &confirm_result.error) {
+        (Some(ConfirmEmailCodeResponse::Ok), _)
+        | (Some(ConfirmEmailCodeResponse::AlreadyVerified), _) => {
+            FormControlValidation::Valid(Some("Аккаунт подтверждён.".into()))
+        }
+        (Some(ConfirmEmailCodeResponse::NoCodeSent), _) => FormControlValidation::Invalid(
+            "Код устарел или не был отправлен; запросите новый.".into(),
+        ),
+        (Some(ConfirmEmailCodeResponse::WrongCode { attempts_left }), _) => {
+            FormControlValidation::Invalid(
+                format!("Неверный код; осталось попыток: {attempts_left}.").into(),
+            )
+        }
+        (Some(ConfirmEmailCodeResponse::EmailTaken), _) => {
+            FormControlValidation::Invalid("Этот адрес уже подтвердил другой аккаунт.".into())
+        }
+        (None, Some(why)) => {
+            FormControlValidation::Invalid(format!("Ошибка при проверке кода: {why}").into())
+        }
+        (None, None) => FormControlValidation::None,
+    };
+
+    html! {
+        <>
+            <h5>{"По электронной почте"}</h5>
+            <FormControl id="verification-email" ctype={FormControlType::Email { pattern: None }} class="mb-3" label="Адрес почты" oninput={oninput_email} value={(*email_state).clone()} disabled={send_result.loading} validation={send_validation} />
+            <Button class="mb-3" style={Color::Primary} disabled={send_result.loading} onclick={send}>
+                if send_result.loading {
+                    <Spinner small={true} />
+                }
+                {"Отправить код"}
+            </Button>
+            if matches!(send_result.data, Some(SendEmailCodeResponse::Sent)) {
+                <FormControl id="verification-code" ctype={FormControlType::Text} class="mb-3" label="Код из письма" oninput={oninput_code} value={(*code_state).clone()} disabled={confirm_result.loading} validation={confirm_validation} />
+                <Button class="mb-3" style={Color::Primary} disabled={confirm_result.loading} onclick={confirm}>
+                    {"Подтвердить"}
+                </Button>
+            }
+        </>
+    }
+}
+
+#[autoprops]
+#[function_component(ApprovalVerification)]
+fn approval_verification(request_pending: bool) -> Html {
+    let note_state = use_state(String::new);
+    let oninput = {
+        shadow_clone!(note_state);
+        move |ev: InputEvent| {
+            let target: HtmlInputElement = ev.target().unwrap().dyn_into().unwrap();
+            note_state.set(target.value());
+        }
+    };
+
+    let request_result: yew_hooks::prelude::UseAsyncHandle<ApprovalRequestResponse, String> =
+        use_async({
+            shadow_clone!(note_state);
+            async move {
+                reqwest::Client::default()
+                    .post(url!("/api/me/verification/request-approval"))
+                    .json(&ApprovalRequest {
+                        note: (*note_state).clone(),
+                    })
+                    .send()
+                    .await
+                    .map_err(|v| v.to_string())?
+                    .error_for_status()
+                    .map_err(|v| v.to_string())?
+                    .json::<ApprovalRequestResponse>()
+                    .await
+                    .map_err(|v| v.to_string())
+            }
+        });
+    let request = {
+        shadow_clone!(request_result);
+        move |_ev| request_result.run()
+    };
+
+    let pending = request_pending
+        || matches!(
+            request_result.data,
+            Some(ApprovalRequestResponse::Requested | ApprovalRequestResponse::AlreadyRequested)
+        );
+    if pending {
+        return html! {
+            <>
+                <h5>{"Преподавателем"}</h5>
+                <p>{"Запрос отправлен; аккаунт будет подтверждён, когда преподаватель его одобрит."}</p>
+            </>
+        };
+    }
+
+    let validation = match (&request_result.data, &request_result.error) {
+        (Some(ApprovalRequestResponse::EmptyNote), _) => {
+            FormControlValidation::Invalid("Напишите, кто вы.".into())
+        }
+        (Some(ApprovalRequestResponse::AlreadyVerified), _) => {
+            FormControlValidation::Valid(Some("Аккаунт уже подтверждён.".into()))
+        }
+        (None, Some(why)) => {
+            FormControlValidation::Invalid(format!("Ошибка при отправке запроса: {why}").into())
+        }
+        _ => FormControlValidation::None,
+    };
+
+    html! {
+        <>
+            <h5>{"Преподавателем"}</h5>
+            <FormControl id="verification-note" ctype={FormControlType::Text} class="mb-3" label="Кто вы: например, группа и ФИО" {oninput} value={(*note_state).clone()} disabled={request_result.loading} {validation} />
+            <Button class="mb-3" style={Color::Primary} disabled={request_result.loading} onclick={request}>
+                {"Попросить подтверждения"}
+            </Button>
+        </>
+    }
+}
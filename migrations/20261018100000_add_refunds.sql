@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE refunds (
+    id INTEGER NOT NULL PRIMARY KEY,
+    order_id INTEGER UNIQUE NOT NULL REFERENCES orders(id),
+    amount REAL NOT NULL,
+    reason TEXT NOT NULL,
+    is_automatic BOOLEAN NOT NULL,
+    created_at_unix_time INTEGER NOT NULL
+);
@@ -0,0 +1,33 @@
+-- Add migration script here
+CREATE TABLE promocodes_new (
+    id INTEGER NOT NULL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    money_value INTEGER NOT NULL,
+    created_at_unix_time INTEGER NOT NULL,
+    expires_at_unix_time INTEGER, -- null if the code never expires
+    max_uses INTEGER DEFAULT 1, -- null if the code can be used any number of times
+    max_uses_per_account INTEGER NOT NULL DEFAULT 1,
+    campaign TEXT -- null if the code is not part of a campaign
+);
+
+INSERT INTO promocodes_new (id, code, money_value, created_at_unix_time)
+    SELECT id, code, money_value, created_at_unix_time FROM promocodes;
+
+-- This references the new table, which gets renamed below
+CREATE TABLE promocode_redemptions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    promocode_id INTEGER NOT NULL REFERENCES promocodes_new(id),
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    redeemed_at_unix_time INTEGER NOT NULL
+);
+
+INSERT INTO promocode_redemptions (promocode_id, account_id, redeemed_at_unix_time)
+    SELECT id, claimed_by, claimed_at_unix_time FROM promocodes WHERE claimed_by IS NOT NULL;
+
+DROP INDEX promocodes_code;
+DROP TABLE promocodes;
+ALTER TABLE promocodes_new RENAME TO promocodes;
+
+CREATE INDEX promocodes_code ON promocodes(code);
+CREATE INDEX promocodes_campaign ON promocodes(campaign);
+CREATE INDEX promocode_redemptions_promocode ON promocode_redemptions(promocode_id);
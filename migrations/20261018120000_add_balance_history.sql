@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE balance_history (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    amount REAL NOT NULL, -- negative if money was taken from the account
+    kind TINYINT NOT NULL,
+    counterparty_account_id INTEGER REFERENCES accounts(id), -- null if the change didn't involve another account
+    comment TEXT, -- null if no comment was given
+    created_at_unix_time INTEGER NOT NULL
+);
+
+CREATE INDEX balance_history_account ON balance_history(account_id, created_at_unix_time);
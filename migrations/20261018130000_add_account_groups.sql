@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE account_groups (
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    balance REAL NOT NULL,
+    created_at_unix_time INTEGER NOT NULL
+);
+
+CREATE TABLE account_group_members (
+    account_id INTEGER NOT NULL PRIMARY KEY REFERENCES accounts(id), -- an account is in at most one group
+    group_id INTEGER NOT NULL REFERENCES account_groups(id),
+    spending_limit REAL, -- null if the member may spend the entire group balance
+    spent REAL NOT NULL DEFAULT 0
+);
+
+CREATE INDEX account_group_members_group ON account_group_members(group_id);
@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE allowance_rules (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER REFERENCES accounts(id), -- null if the rule is for a group
+    group_id INTEGER REFERENCES account_groups(id), -- null if the rule is for a single account
+    amount REAL NOT NULL,
+    day_of_month INTEGER NOT NULL DEFAULT 1,
+    is_active BOOLEAN NOT NULL DEFAULT 1,
+    created_at_unix_time INTEGER NOT NULL,
+    CHECK ((account_id IS NULL) != (group_id IS NULL))
+);
+
+CREATE TABLE allowance_grants (
+    id INTEGER NOT NULL PRIMARY KEY,
+    rule_id INTEGER NOT NULL REFERENCES allowance_rules(id),
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    period TEXT NOT NULL, -- the month the grant is for, like 2024-09
+    created_at_unix_time INTEGER NOT NULL,
+    UNIQUE (rule_id, account_id, period)
+);
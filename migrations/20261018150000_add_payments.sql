@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE payments (
+    id TEXT NOT NULL PRIMARY KEY, -- random, also sent to the provider as the reference
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    amount REAL NOT NULL,
+    provider TEXT NOT NULL,
+    provider_payment_id TEXT, -- null until the provider has accepted the payment
+    status TINYINT NOT NULL DEFAULT 0,
+    created_at_unix_time INTEGER NOT NULL,
+    completed_at_unix_time INTEGER -- null while the payment is pending
+);
+
+CREATE INDEX payments_account ON payments(account_id);
+
+CREATE TABLE payment_webhook_events (
+    event_id TEXT NOT NULL PRIMARY KEY,
+    payment_id TEXT NOT NULL REFERENCES payments(id),
+    received_at_unix_time INTEGER NOT NULL
+);
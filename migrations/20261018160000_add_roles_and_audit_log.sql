@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE accounts ADD COLUMN role TINYINT NOT NULL DEFAULT 0;
+
+CREATE TABLE admin_audit_log (
+    id INTEGER NOT NULL PRIMARY KEY,
+    actor_account_id INTEGER REFERENCES accounts(id), -- null if the action was done with the bootstrap key
+    action TEXT NOT NULL,
+    details_json TEXT NOT NULL,
+    created_at_unix_time INTEGER NOT NULL
+);
+
+CREATE INDEX admin_audit_log_time ON admin_audit_log(created_at_unix_time);
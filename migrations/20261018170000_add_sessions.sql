@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE sessions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    token_hash TEXT NOT NULL UNIQUE, -- hex SHA-256 of the token; the token itself is never stored
+    user_agent TEXT, -- null if the client didn't send one
+    created_at_unix_time INTEGER NOT NULL,
+    last_used_at_unix_time INTEGER NOT NULL,
+    expires_at_unix_time INTEGER NOT NULL,
+    revoked_at_unix_time INTEGER -- null if the session was not revoked
+);
+
+CREATE INDEX sessions_account ON sessions(account_id);
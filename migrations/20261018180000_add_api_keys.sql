@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE api_keys (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE, -- hex SHA-256 of the key; the key itself is only shown once
+    key_prefix TEXT NOT NULL, -- the start of the key, so that the user can tell keys apart
+    scopes INTEGER NOT NULL, -- bit set of api::ApiKeyScope
+    spending_limit REAL, -- null if orders made with the key can spend any amount
+    spent REAL NOT NULL DEFAULT 0,
+    created_at_unix_time INTEGER NOT NULL,
+    last_used_at_unix_time INTEGER, -- null if the key was never used
+    expires_at_unix_time INTEGER, -- null if the key doesn't expire
+    revoked_at_unix_time INTEGER -- null if the key was not revoked
+);
+
+CREATE INDEX api_keys_account ON api_keys(account_id);
+
+-- The key that an order was made with, so that its cost counts towards the key's spending limit.
+ALTER TABLE orders ADD COLUMN api_key_id INTEGER REFERENCES api_keys(id);
@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE login_throttle (
+    kind TEXT NOT NULL, -- 'handle' or 'ip'
+    key TEXT NOT NULL, -- the handle or the IP address
+    failures INTEGER NOT NULL, -- failed logins in a row, within the failure window
+    last_failure_at_unix_time INTEGER NOT NULL,
+    locked_until_unix_time INTEGER, -- null if it was never locked out
+    PRIMARY KEY (kind, key)
+);
+
+CREATE TABLE security_events (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    kind INTEGER NOT NULL, -- api::SecurityEventKind
+    ip TEXT, -- the client that caused the event, if known
+    created_at_unix_time INTEGER NOT NULL
+);
+
+CREATE INDEX security_events_account ON security_events(account_id);
@@ -0,0 +1,5 @@
+-- Add migration script here
+CREATE TABLE used_pow_nonces (
+    nonce BLOB NOT NULL PRIMARY KEY, -- the random part of a solved challenge
+    expires_at_unix_time INTEGER NOT NULL -- after this, the challenge is rejected anyway, so the row can go
+);
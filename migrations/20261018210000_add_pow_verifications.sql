@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE pow_verifications (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    ip TEXT NOT NULL, -- the client that solved the challenge
+    difficulty INTEGER NOT NULL,
+    verified_at_unix_time INTEGER NOT NULL
+);
+
+CREATE INDEX pow_verifications_time ON pow_verifications(verified_at_unix_time);
+CREATE INDEX pow_verifications_ip ON pow_verifications(ip, verified_at_unix_time);
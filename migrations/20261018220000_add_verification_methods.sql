@@ -0,0 +1,28 @@
+-- Add migration script here
+ALTER TABLE accounts ADD COLUMN verified_email TEXT; -- the address that verified the account, if it was verified by email
+
+-- Each address can only verify one account.
+CREATE UNIQUE INDEX accounts_verified_email ON accounts(verified_email);
+
+CREATE TABLE email_verification_codes (
+    account_id INTEGER NOT NULL PRIMARY KEY REFERENCES accounts(id), -- only the latest code for each account is kept
+    email TEXT NOT NULL,
+    code_hash TEXT NOT NULL,
+    attempts_left INTEGER NOT NULL,
+    sent_at_unix_time INTEGER NOT NULL,
+    expires_at_unix_time INTEGER NOT NULL
+);
+
+CREATE TABLE verification_requests (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    note TEXT NOT NULL,
+    created_at_unix_time INTEGER NOT NULL,
+    decided_by INTEGER REFERENCES accounts(id), -- null while pending
+    decided_at_unix_time INTEGER, -- null while pending
+    approved BOOLEAN -- null while pending
+);
+
+CREATE INDEX verification_requests_account ON verification_requests(account_id);
+
+ALTER TABLE promocodes ADD COLUMN verifies_account BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE two_factor (
+    account_id INTEGER NOT NULL PRIMARY KEY REFERENCES accounts(id),
+    secret TEXT NOT NULL, -- base32, as shown to the authenticator app
+    confirmed_at_unix_time INTEGER, -- null until a code from the app has been entered; until then, login doesn't ask for codes
+    last_used_step INTEGER -- the time step of the last accepted code, so that a code can't be used twice
+);
+
+CREATE TABLE two_factor_recovery_codes (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    code_hash TEXT NOT NULL,
+    used_at_unix_time INTEGER -- null while unused
+);
+
+CREATE INDEX two_factor_recovery_codes_account ON two_factor_recovery_codes(account_id);
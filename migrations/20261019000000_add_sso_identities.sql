@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE sso_identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL, -- the `sub` claim, which the identity provider never reuses
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    created_at_unix_time INTEGER NOT NULL,
+    last_login_at_unix_time INTEGER NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX sso_identities_account ON sso_identities(account_id);
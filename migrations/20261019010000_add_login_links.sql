@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE login_links (
+    id INTEGER NOT NULL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    token_hash TEXT NOT NULL UNIQUE, -- hex SHA-256 of the token, like sessions
+    created_at_unix_time INTEGER NOT NULL,
+    expires_at_unix_time INTEGER NOT NULL,
+    used_at_unix_time INTEGER -- null until the link is used; each link only works once
+);
+
+CREATE INDEX login_links_account ON login_links(account_id);
@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE invite_codes (
+    id INTEGER NOT NULL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    group_id INTEGER REFERENCES account_groups(id), -- null if new accounts aren't put into a group
+    starting_balance REAL NOT NULL DEFAULT 0,
+    max_uses INTEGER, -- null if the code can be used any number of times
+    created_by_account_id INTEGER NOT NULL REFERENCES accounts(id),
+    created_at_unix_time INTEGER NOT NULL,
+    expires_at_unix_time INTEGER -- null if the code never expires
+);
+
+CREATE TABLE invite_code_uses (
+    id INTEGER NOT NULL PRIMARY KEY,
+    invite_code_id INTEGER NOT NULL REFERENCES invite_codes(id),
+    account_id INTEGER NOT NULL REFERENCES accounts(id),
+    used_at_unix_time INTEGER NOT NULL
+);
+
+CREATE INDEX invite_code_uses_code ON invite_code_uses(invite_code_id);
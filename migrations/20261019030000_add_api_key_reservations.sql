@@ -0,0 +1,5 @@
+-- Add migration script here
+-- While an order made with an API key runs, its budget is set aside from the key's spending limit,
+-- so that orders running at the same time can't go over the limit together.
+ALTER TABLE api_keys ADD COLUMN reserved REAL NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN api_key_reserved REAL NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE orders ADD COLUMN pricing_json TEXT; -- the pricing locked in when the order started, null until then
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE orders ADD COLUMN group_id INTEGER REFERENCES account_groups(id); -- the group that paid for part of the order, null if none did
@@ -1,12 +1,27 @@
-use api::ChangePasswordRequest;
+use api::{OrderRefund, Role, SecurityEventKind};
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, HeaderValue},
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::IntoResponse,
     Json,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Sqlite, SqlitePool, Transaction};
 
-use crate::{result::AppError, AppState};
+use crate::{
+    audit::record_admin_action,
+    auth::{AdminAuth, InstructorAuth},
+    login_throttle::record_security_event,
+    passwords::{hash_password_off_thread, require_password_policy},
+    profile::set_password,
+    result::AppError,
+    roster::ImportRosterOptions,
+    sessions::create_session,
+    two_factor::remove_two_factor,
+    verification::admin_approval::{decide_request, pending_requests, PendingVerificationRequest},
+    AppState,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct RegistrationRequest {
@@ -19,16 +34,232 @@ pub struct RegistrationRequest {
 pub struct ResetPasswordRequest {
     handle: String,
     password: String,
+    /// Also turn off two-factor authentication, for someone who lost their authenticator and recovery codes.
+    #[serde(default)]
+    reset_two_factor: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefundOrderRequest {
+    order_id: i64,
+    reason: String,
+    /// If not given, the entire order cost is refunded.
+    amount: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetRoleRequest {
+    handle: String,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct FetchAuditLogQuery {
+    /// How many of the latest entries to return; defaults to 100.
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditLogEntry {
+    id: i64,
+    /// None if the action was done with the bootstrap key.
+    actor_handle: Option<String>,
+    action: String,
+    details: serde_json::Value,
+    created_at_unix_time: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MakeGroupRequest {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FundGroupRequest {
+    group: String,
+    /// May be negative to take money away from the group, but the group balance can't go below zero.
+    amount: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetGroupMemberRequest {
+    group: String,
+    handle: String,
+    /// How much the member may spend from the group's balance in total; if not given, there is no limit.
+    spending_limit: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveGroupMemberRequest {
+    handle: String,
+}
+
+fn default_day_of_month() -> i64 {
+    1
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MakeAllowanceRuleRequest {
+    /// Exactly one of `handle` and `group` must be given.
+    /// If it's a group, every account that is in the group on the day gets the allowance.
+    handle: Option<String>,
+    group: Option<String>,
+    amount: f64,
+
+    /// The allowance is given on this day of every month, from 1 to 28.
+    #[serde(default = "default_day_of_month")]
+    day_of_month: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeactivateAllowanceRuleRequest {
+    id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AllowanceRule {
+    id: i64,
+    handle: Option<String>,
+    group: Option<String>,
+    amount: f64,
+    day_of_month: i64,
+    created_at_unix_time: i64,
+    times_granted: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DecideVerificationRequest {
+    id: i64,
+    approve: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct UnclaimedPromocode {
+    pub code: String,
+    pub money_value: i64,
+    pub created_at_unix_time: i64,
+    pub expires_at_unix_time: Option<i64>,
+    pub max_uses: Option<i64>,
+    pub max_uses_per_account: i64,
+    pub campaign: Option<String>,
+    pub times_redeemed: i64,
+
+    /// Whether redeeming the promocode also verifies the account.
+    pub verifies_account: bool,
+}
+
+fn default_max_uses() -> Option<i64> {
+    Some(1)
+}
+
+fn default_max_uses_per_account() -> i64 {
+    1
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MakePromocodesRequest {
+    /// One promocode is made for each value.
+    values: Vec<i64>,
+
+    /// If not given, the promocodes never expire.
+    expires_at_unix_time: Option<i64>,
+
+    /// How many times each promocode can be redeemed in total.
+    /// If not given, defaults to 1; if null, there is no limit.
+    #[serde(default = "default_max_uses")]
+    max_uses: Option<i64>,
+
+    /// How many times each promocode can be redeemed by the same account.
+    #[serde(default = "default_max_uses_per_account")]
+    max_uses_per_account: i64,
+
+    /// A label to group promocodes that were given out together.
+    campaign: Option<String>,
+
+    /// If true, redeeming one of these promocodes also verifies an unverified account.
+    #[serde(default)]
+    verifies_account: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MakeInviteCodeRequest {
+    /// Accounts registered with the code are put into this group.
+    group: Option<String>,
+
+    /// How much money every account registered with the code starts with.
+    #[serde(default)]
+    starting_balance: f64,
+
+    /// How many accounts can be registered with the code in total.
+    /// If not given, defaults to 1; if null, there is no limit.
+    #[serde(default = "default_max_uses")]
+    max_uses: Option<i64>,
+
+    /// If not given, the code never expires.
+    expires_at_unix_time: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InviteCode {
+    id: i64,
     code: String,
-    money_value: i64,
+    group: Option<String>,
+    starting_balance: f64,
+    max_uses: Option<i64>,
+    times_used: i64,
     created_at_unix_time: i64,
+    expires_at_unix_time: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeactivateInviteCodeRequest {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct FetchPromocodesQuery {
+    campaign: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SheetFormat {
+    Svg,
+    Pdf,
+}
+
+#[derive(Deserialize)]
+pub struct PrintPromocodesQuery {
+    format: SheetFormat,
+    campaign: Option<String>,
 }
 
 pub async fn make_user(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(RegistrationRequest {
+        name,
+        handle,
+        password,
+    }): Json<RegistrationRequest>,
+) -> Result<Json<String>, AppError> {
+    let (account_id, token) =
+        create_account(state.clone(), &name, &handle, password, Role::Student).await?;
+    record_admin_action(
+        &state.db,
+        Some(&caller),
+        "make_user",
+        json!({"account_id": account_id, "name": name, "handle": handle}),
+    )
+    .await?;
+
+    Ok(Json(token))
+}
+
+/// Make the first admin account.
+/// This is the only thing the `SECRET_KEY` bootstrap key can be used for,
+/// and only while there are no admins yet; after that, admins make other accounts.
+pub async fn bootstrap_admin(
     State(state): State<AppState>,
     headers: HeaderMap,
     Json(RegistrationRequest {
@@ -37,60 +268,126 @@ pub async fn make_user(
         password,
     }): Json<RegistrationRequest>,
 ) -> Result<Json<String>, AppError> {
-    let db = &state.db;
     let h = headers.get("X-AuthToken");
     if h != Some(&HeaderValue::from_str(&std::env::var("SECRET_KEY").unwrap()).unwrap()) {
         Err(anyhow::anyhow!("Secret key invalid, got: {h:?}"))?
     }
 
+    require_password_policy(&password, &handle)?;
+    let password_hash = hash_password_off_thread(&password).await?;
+
+    // The account is made first, so that the transaction holds the write lock while admins are counted,
+    // and two bootstraps at once can't both see that there are none.
+    let mut tx = state.db.begin().await?;
+    let account_id =
+        insert_account(&mut tx, &name, &handle, Some(&password_hash), Role::Admin).await?;
+    let admin_role = Role::Admin as i64;
+    let admins = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM accounts WHERE role=?"#,
+        admin_role
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .count;
+    if admins > 1 {
+        tx.rollback().await?;
+        return Err(anyhow::anyhow!(
+            "There is already an admin, so the bootstrap key can't be used anymore"
+        ))?;
+    }
+    record_admin_action(
+        &mut *tx,
+        None,
+        "bootstrap_admin",
+        json!({"account_id": account_id, "name": name, "handle": handle}),
+    )
+    .await?;
+    tx.commit().await?;
+    let token = create_session(&state.db, account_id, None).await?;
+
+    Ok(Json(token))
+}
+
+/// Returns the new account's ID and its token.
+async fn create_account(
+    state: AppState,
+    name: &str,
+    handle: &str,
+    password: String,
+    role: Role,
+) -> Result<(i64, String), AppError> {
+    let db = &state.db;
+    require_password_policy(&password, handle)?;
+    let password_hash = hash_password_off_thread(&password).await?;
+    let mut tx = db.begin().await?;
+    let account_id = insert_account(&mut tx, name, handle, Some(&password_hash), role).await?;
+    tx.commit().await?;
+    let token = create_session(db, account_id, None).await?;
+
+    Ok((account_id, token))
+}
+
+/// Make a new account that logs in with the handle, and return its ID.
+/// Without a password hash, it can only log in some other way, like single sign-on or a login link.
+pub async fn insert_account(
+    tx: &mut Transaction<'_, Sqlite>,
+    name: &str,
+    handle: &str,
+    password_hash: Option<&str>,
+    role: Role,
+) -> anyhow::Result<i64> {
     use rand::distributions::DistString;
     let token = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let role = role as i64;
+    let password_hash = password_hash.unwrap_or("");
 
     let new_account = sqlx::query!(
-        "INSERT INTO accounts (user_name, token, balance) VALUES (?,?,?) RETURNING *",
+        "INSERT INTO accounts (user_name, token, balance, role) VALUES (?,?,?,?) RETURNING *",
         name,
         token,
-        0.0
+        0.0,
+        role
     )
-    .fetch_one(db)
+    .fetch_one(&mut **tx)
     .await?;
 
     sqlx::query!(
         "INSERT INTO logins (handle, password_hash, account_id) VALUES (?,?,?)",
         handle,
-        "",
+        password_hash,
         new_account.id
     )
-    .execute(db)
+    .execute(&mut **tx)
     .await?;
 
-    let Json(resp) = crate::profile::change_password(
-        State(state),
-        Path(token),
-        Json(ChangePasswordRequest {
-            new_password: password,
-        }),
-    )
-    .await?;
-    let token = match resp {
-        api::ChangePasswordResponse::Ok { new_token } => new_token,
-        api::ChangePasswordResponse::InvalidToken => unreachable!(),
-    };
+    Ok(new_account.id)
+}
 
-    Ok(Json(token))
+/// Make accounts for everyone in a roster CSV sent as the body, and return their credentials as CSV.
+pub async fn import_roster(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Query(options): Query<ImportRosterOptions>,
+    roster: String,
+) -> Result<impl IntoResponse, AppError> {
+    let credentials =
+        crate::roster::import_roster(&state.db, roster.as_bytes(), &options, Some(&caller)).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+        credentials,
+    ))
 }
 
 pub async fn reset_password(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(ResetPasswordRequest { handle, password }): Json<ResetPasswordRequest>,
+    AdminAuth(caller): AdminAuth,
+    Json(ResetPasswordRequest {
+        handle,
+        password,
+        reset_two_factor,
+    }): Json<ResetPasswordRequest>,
 ) -> Result<Json<String>, AppError> {
     let db = &state.db;
-    let h = headers.get("X-AuthToken");
-    if h != Some(&HeaderValue::from_str(&std::env::var("SECRET_KEY").unwrap()).unwrap()) {
-        Err(anyhow::anyhow!("Secret key invalid, got: {h:?}"))?
-    }
-
     let data = match sqlx::query!(
         "SELECT * FROM accounts INNER JOIN logins ON logins.account_id=accounts.id WHERE handle=?",
         handle
@@ -101,59 +398,142 @@ pub async fn reset_password(
         Some(row) => row,
         None => return Err(anyhow::anyhow!("No such handle found"))?,
     };
+    require_password_policy(&password, &handle)?;
 
-    let old_token = data.token;
-
-    let Json(resp) = crate::profile::change_password(
-        State(state),
-        Path(old_token),
-        Json(ChangePasswordRequest {
-            new_password: password,
-        }),
+    if reset_two_factor {
+        let mut tx = db.begin().await?;
+        if remove_two_factor(&mut tx, data.account_id).await? {
+            record_security_event(
+                &mut *tx,
+                data.account_id,
+                SecurityEventKind::TwoFactorReset,
+                None,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+    }
+    set_password(db, data.account_id, &password).await?;
+    record_admin_action(
+        db,
+        Some(&caller),
+        "reset_password",
+        json!({"account_id": data.account_id, "handle": handle, "reset_two_factor": reset_two_factor}),
     )
     .await?;
-    let token = match resp {
-        api::ChangePasswordResponse::Ok { new_token } => new_token,
-        api::ChangePasswordResponse::InvalidToken => unreachable!(),
-    };
+    let token = create_session(db, data.account_id, None).await?;
 
     Ok(Json(token))
 }
 
 pub async fn fetch_promocodes(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    InstructorAuth(caller): InstructorAuth,
+    Query(FetchPromocodesQuery { campaign }): Query<FetchPromocodesQuery>,
 ) -> Result<Json<Vec<UnclaimedPromocode>>, AppError> {
     let db = &state.db;
-    let h = headers.get("X-AuthToken");
-    if h != Some(&HeaderValue::from_str(&std::env::var("SECRET_KEY").unwrap()).unwrap()) {
-        Err(anyhow::anyhow!("Secret key invalid, got: {h:?}"))?
-    }
+    record_admin_action(
+        db,
+        Some(&caller),
+        "fetch_promocodes",
+        json!({"campaign": campaign}),
+    )
+    .await?;
 
-    let data = sqlx::query!("SELECT * FROM promocodes WHERE claimed_by IS NULL")
-        .fetch_all(db)
-        .await?;
-    Ok(Json(
-        data.into_iter()
-            .map(|v| UnclaimedPromocode {
-                code: v.code,
-                money_value: v.money_value,
-                created_at_unix_time: v.created_at_unix_time,
-            })
-            .collect(),
-    ))
+    Ok(Json(query_redeemable_promocodes(db, campaign).await?))
+}
+
+pub async fn print_promocodes(
+    State(state): State<AppState>,
+    InstructorAuth(caller): InstructorAuth,
+    Query(PrintPromocodesQuery { format, campaign }): Query<PrintPromocodesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let db = &state.db;
+    // The QR codes link to the redeem page on the frontend.
+    let frontend_url = std::env::var("FRONTEND_URL").map_err(|_| {
+        anyhow::anyhow!("FRONTEND_URL must be set to link promocodes to the redeem page")
+    })?;
+
+    record_admin_action(
+        db,
+        Some(&caller),
+        "print_promocodes",
+        json!({"campaign": campaign}),
+    )
+    .await?;
+
+    let codes = query_redeemable_promocodes(db, campaign).await?;
+    Ok(match format {
+        SheetFormat::Svg => (
+            [(header::CONTENT_TYPE, "image/svg+xml")],
+            crate::promocode_sheet::render_svg(&codes, &frontend_url)?,
+        )
+            .into_response(),
+        SheetFormat::Pdf => (
+            [(header::CONTENT_TYPE, "application/pdf")],
+            crate::promocode_sheet::render_pdf(&codes, &frontend_url)?,
+        )
+            .into_response(),
+    })
+}
+
+/// Find the promocodes that can still be redeemed by someone, optionally only from one campaign.
+async fn query_redeemable_promocodes(
+    db: &SqlitePool,
+    campaign: Option<String>,
+) -> anyhow::Result<Vec<UnclaimedPromocode>> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let data = sqlx::query!(
+        r#"SELECT promocodes.*, COUNT(promocode_redemptions.id) AS "times_redeemed!: i64"
+        FROM promocodes LEFT JOIN promocode_redemptions ON promocode_redemptions.promocode_id=promocodes.id
+        WHERE (expires_at_unix_time IS NULL OR expires_at_unix_time > ?)
+            AND (? IS NULL OR campaign=?)
+        GROUP BY promocodes.id
+        HAVING max_uses IS NULL OR COUNT(promocode_redemptions.id) < max_uses"#,
+        now,
+        campaign,
+        campaign
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(data
+        .into_iter()
+        .map(|v| UnclaimedPromocode {
+            code: v.code,
+            money_value: v.money_value,
+            created_at_unix_time: v.created_at_unix_time,
+            expires_at_unix_time: v.expires_at_unix_time,
+            max_uses: v.max_uses,
+            max_uses_per_account: v.max_uses_per_account,
+            campaign: v.campaign,
+            times_redeemed: v.times_redeemed,
+            verifies_account: v.verifies_account,
+        })
+        .collect())
 }
 
 pub async fn make_promocodes(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(values): Json<Vec<i64>>,
+    AdminAuth(caller): AdminAuth,
+    Json(MakePromocodesRequest {
+        values,
+        expires_at_unix_time,
+        max_uses,
+        max_uses_per_account,
+        campaign,
+        verifies_account,
+    }): Json<MakePromocodesRequest>,
 ) -> Result<Json<Vec<UnclaimedPromocode>>, AppError> {
     let db = &state.db;
-    let h = headers.get("X-AuthToken");
-    if h != Some(&HeaderValue::from_str(&std::env::var("SECRET_KEY").unwrap()).unwrap()) {
-        Err(anyhow::anyhow!("Secret key invalid, got: {h:?}"))?
-    };
+    if max_uses.is_some_and(|v| v < 1) || max_uses_per_account < 1 {
+        Err(anyhow::anyhow!(
+            "Promocodes must be usable at least once in total and per account"
+        ))?
+    }
 
     let mut tx = db.begin().await?;
     let now = std::time::SystemTime::now()
@@ -163,14 +543,18 @@ pub async fn make_promocodes(
 
     let mut codes = Vec::with_capacity(values.len());
     for val in values {
-        let val = val as i64;
         use rand::distributions::DistString;
         let code = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
         sqlx::query!(
-            "INSERT INTO promocodes (code, money_value, created_at_unix_time) VALUES (?,?,?)",
+            "INSERT INTO promocodes (code, money_value, created_at_unix_time, expires_at_unix_time, max_uses, max_uses_per_account, campaign, verifies_account) VALUES (?,?,?,?,?,?,?,?)",
             code,
             val,
-            now
+            now,
+            expires_at_unix_time,
+            max_uses,
+            max_uses_per_account,
+            campaign,
+            verifies_account
         )
         .execute(&mut *tx)
         .await?;
@@ -178,10 +562,590 @@ pub async fn make_promocodes(
             code,
             money_value: val,
             created_at_unix_time: now,
+            expires_at_unix_time,
+            max_uses,
+            max_uses_per_account,
+            campaign: campaign.clone(),
+            times_redeemed: 0,
+            verifies_account,
         });
     }
 
+    record_admin_action(
+        &mut *tx,
+        Some(&caller),
+        "make_promocodes",
+        json!({
+            "count": codes.len(),
+            "total_value": codes.iter().map(|c| c.money_value).sum::<i64>(),
+            "expires_at_unix_time": expires_at_unix_time,
+            "max_uses": max_uses,
+            "max_uses_per_account": max_uses_per_account,
+            "campaign": campaign,
+            "verifies_account": verifies_account,
+        }),
+    )
+    .await?;
     tx.commit().await?;
 
     Ok(Json(codes))
 }
+
+pub async fn make_invite_code(
+    State(state): State<AppState>,
+    InstructorAuth(caller): InstructorAuth,
+    Json(MakeInviteCodeRequest {
+        group,
+        starting_balance,
+        max_uses,
+        expires_at_unix_time,
+    }): Json<MakeInviteCodeRequest>,
+) -> Result<Json<InviteCode>, AppError> {
+    let db = &state.db;
+    if max_uses.is_some_and(|v| v < 1) {
+        Err(anyhow::anyhow!("Invite codes must be usable at least once"))?
+    }
+    if !starting_balance.is_finite() || starting_balance < 0.0 {
+        Err(anyhow::anyhow!("Starting balance must not be negative"))?
+    }
+    // Instructors can hand out codes for their courses, but only admins can hand out money.
+    if starting_balance > 0.0 && caller.role < Role::Admin {
+        Err(anyhow::anyhow!(
+            "Only admins can make invite codes with a starting balance"
+        ))?
+    }
+
+    let group_id = match &group {
+        Some(group) => match sqlx::query!("SELECT id FROM account_groups WHERE name=?", group)
+            .fetch_optional(db)
+            .await?
+        {
+            Some(row) => Some(row.id),
+            None => Err(anyhow::anyhow!("No such group found"))?,
+        },
+        None => None,
+    };
+
+    use rand::distributions::DistString;
+    let code = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let mut tx = db.begin().await?;
+    let invite = sqlx::query!(
+        "INSERT INTO invite_codes (code, group_id, starting_balance, max_uses, created_by_account_id, created_at_unix_time, expires_at_unix_time) VALUES (?,?,?,?,?,?,?) RETURNING id",
+        code,
+        group_id,
+        starting_balance,
+        max_uses,
+        caller.account_id,
+        now,
+        expires_at_unix_time
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    record_admin_action(
+        &mut *tx,
+        Some(&caller),
+        "make_invite_code",
+        json!({
+            "invite_code_id": invite.id,
+            "group": group,
+            "starting_balance": starting_balance,
+            "max_uses": max_uses,
+            "expires_at_unix_time": expires_at_unix_time,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(InviteCode {
+        id: invite.id,
+        code,
+        group,
+        starting_balance,
+        max_uses,
+        times_used: 0,
+        created_at_unix_time: now,
+        expires_at_unix_time,
+    }))
+}
+
+/// List the invite codes that can still be used to register.
+pub async fn fetch_invite_codes(
+    State(state): State<AppState>,
+    InstructorAuth(_caller): InstructorAuth,
+) -> Result<Json<Vec<InviteCode>>, AppError> {
+    let db = &state.db;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let data = sqlx::query!(
+        r#"SELECT invite_codes.*, account_groups.name AS "group_name?", COUNT(invite_code_uses.id) AS "times_used!: i64"
+        FROM invite_codes
+        LEFT JOIN account_groups ON account_groups.id=invite_codes.group_id
+        LEFT JOIN invite_code_uses ON invite_code_uses.invite_code_id=invite_codes.id
+        WHERE expires_at_unix_time IS NULL OR expires_at_unix_time > ?
+        GROUP BY invite_codes.id
+        HAVING max_uses IS NULL OR COUNT(invite_code_uses.id) < max_uses"#,
+        now
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(Json(
+        data.into_iter()
+            .map(|v| InviteCode {
+                id: v.id,
+                code: v.code,
+                group: v.group_name,
+                starting_balance: v.starting_balance,
+                max_uses: v.max_uses,
+                times_used: v.times_used,
+                created_at_unix_time: v.created_at_unix_time,
+                expires_at_unix_time: v.expires_at_unix_time,
+            })
+            .collect(),
+    ))
+}
+
+/// Stop the invite code from being used. The accounts already registered with it stay.
+pub async fn deactivate_invite_code(
+    State(state): State<AppState>,
+    InstructorAuth(caller): InstructorAuth,
+    Json(DeactivateInviteCodeRequest { id }): Json<DeactivateInviteCodeRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = &state.db;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let result = sqlx::query!(
+        "UPDATE invite_codes SET expires_at_unix_time=? WHERE id=? AND (expires_at_unix_time IS NULL OR expires_at_unix_time>?)",
+        now,
+        id,
+        now
+    )
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        Err(anyhow::anyhow!("No such active invite code found"))?
+    }
+    record_admin_action(
+        db,
+        Some(&caller),
+        "deactivate_invite_code",
+        json!({"invite_code_id": id}),
+    )
+    .await?;
+
+    Ok(Json(()))
+}
+
+pub async fn refund_order(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(RefundOrderRequest {
+        order_id,
+        reason,
+        amount,
+    }): Json<RefundOrderRequest>,
+) -> Result<Json<OrderRefund>, AppError> {
+    let db = &state.db;
+    let refund = crate::refund::refund_order(db, order_id, amount, &reason, false).await?;
+    record_admin_action(
+        db,
+        Some(&caller),
+        "refund_order",
+        json!({"order_id": order_id, "amount": refund.amount, "reason": reason}),
+    )
+    .await?;
+
+    Ok(Json(refund))
+}
+
+pub async fn make_group(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(MakeGroupRequest { name }): Json<MakeGroupRequest>,
+) -> Result<Json<i64>, AppError> {
+    let db = &state.db;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let group = sqlx::query!(
+        "INSERT INTO account_groups (name, balance, created_at_unix_time) VALUES (?,?,?) RETURNING id",
+        name,
+        0.0,
+        now
+    )
+    .fetch_one(db)
+    .await?;
+    record_admin_action(
+        db,
+        Some(&caller),
+        "make_group",
+        json!({"group_id": group.id, "name": name}),
+    )
+    .await?;
+
+    Ok(Json(group.id))
+}
+
+/// Returns the group's new balance.
+pub async fn fund_group(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(FundGroupRequest { group, amount }): Json<FundGroupRequest>,
+) -> Result<Json<f64>, AppError> {
+    let db = &state.db;
+    if !amount.is_finite() {
+        Err(anyhow::anyhow!("Amount must be a number, got {amount}"))?
+    }
+
+    let balance = match sqlx::query!(
+        "UPDATE account_groups SET balance=balance+? WHERE name=? AND balance+?>=0 RETURNING balance",
+        amount,
+        group,
+        amount
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        Some(row) => row.balance,
+        None => Err(anyhow::anyhow!(
+            "No such group found, or its balance would become negative"
+        ))?,
+    };
+
+    record_admin_action(
+        db,
+        Some(&caller),
+        "fund_group",
+        json!({"group": group, "amount": amount, "balance_after": balance}),
+    )
+    .await?;
+
+    Ok(Json(balance))
+}
+
+/// Put the account into the group, or change its spending limit if it is already there.
+/// An account can only be in one group, so this moves it out of any other group.
+pub async fn set_group_member(
+    State(state): State<AppState>,
+    InstructorAuth(caller): InstructorAuth,
+    Json(SetGroupMemberRequest {
+        group,
+        handle,
+        spending_limit,
+    }): Json<SetGroupMemberRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = &state.db;
+    if spending_limit.is_some_and(|v| v.is_nan() || v < 0.0) {
+        Err(anyhow::anyhow!("Spending limit must not be negative"))?
+    }
+
+    let mut tx = db.begin().await?;
+    let group = match sqlx::query!("SELECT id FROM account_groups WHERE name=?", group)
+        .fetch_optional(&mut *tx)
+        .await?
+    {
+        Some(row) => row,
+        None => Err(anyhow::anyhow!("No such group found"))?,
+    };
+    let login = match sqlx::query!("SELECT account_id FROM logins WHERE handle=?", handle)
+        .fetch_optional(&mut *tx)
+        .await?
+    {
+        Some(row) => row,
+        None => Err(anyhow::anyhow!("No such handle found"))?,
+    };
+
+    // The amount spent only carries over if the account stays in the same group.
+    sqlx::query!(
+        "INSERT INTO account_group_members (account_id, group_id, spending_limit, spent) VALUES (?,?,?,0)
+        ON CONFLICT(account_id) DO UPDATE SET
+            spent=CASE WHEN group_id=excluded.group_id THEN spent ELSE 0 END,
+            group_id=excluded.group_id,
+            spending_limit=excluded.spending_limit",
+        login.account_id,
+        group.id,
+        spending_limit
+    )
+    .execute(&mut *tx)
+    .await?;
+    record_admin_action(
+        &mut *tx,
+        Some(&caller),
+        "set_group_member",
+        json!({"group_id": group.id, "account_id": login.account_id, "spending_limit": spending_limit}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(()))
+}
+
+pub async fn remove_group_member(
+    State(state): State<AppState>,
+    InstructorAuth(caller): InstructorAuth,
+    Json(RemoveGroupMemberRequest { handle }): Json<RemoveGroupMemberRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = &state.db;
+    let result = sqlx::query!(
+        "DELETE FROM account_group_members WHERE account_id=(SELECT account_id FROM logins WHERE handle=?)",
+        handle
+    )
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        Err(anyhow::anyhow!("This account is not in any group"))?
+    }
+    record_admin_action(
+        db,
+        Some(&caller),
+        "remove_group_member",
+        json!({"handle": handle}),
+    )
+    .await?;
+
+    Ok(Json(()))
+}
+
+pub async fn make_allowance_rule(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(MakeAllowanceRuleRequest {
+        handle,
+        group,
+        amount,
+        day_of_month,
+    }): Json<MakeAllowanceRuleRequest>,
+) -> Result<Json<i64>, AppError> {
+    let db = &state.db;
+    if amount.is_nan() || amount <= 0.0 {
+        Err(anyhow::anyhow!("Allowance amount must be positive"))?
+    }
+    // Every month has these days, so the allowance is never skipped.
+    if !(1..=28).contains(&day_of_month) {
+        Err(anyhow::anyhow!("Day of month must be from 1 to 28"))?
+    }
+
+    let (account_id, group_id) = match (handle, group) {
+        (Some(handle), None) => {
+            match sqlx::query!("SELECT account_id FROM logins WHERE handle=?", handle)
+                .fetch_optional(db)
+                .await?
+            {
+                Some(row) => (Some(row.account_id), None),
+                None => Err(anyhow::anyhow!("No such handle found"))?,
+            }
+        }
+        (None, Some(group)) => {
+            match sqlx::query!("SELECT id FROM account_groups WHERE name=?", group)
+                .fetch_optional(db)
+                .await?
+            {
+                Some(row) => (None, Some(row.id)),
+                None => Err(anyhow::anyhow!("No such group found"))?,
+            }
+        }
+        _ => Err(anyhow::anyhow!(
+            "Exactly one of handle and group must be given"
+        ))?,
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let rule = sqlx::query!(
+        "INSERT INTO allowance_rules (account_id, group_id, amount, day_of_month, created_at_unix_time) VALUES (?,?,?,?,?) RETURNING id",
+        account_id,
+        group_id,
+        amount,
+        day_of_month,
+        now
+    )
+    .fetch_one(db)
+    .await?;
+
+    record_admin_action(
+        db,
+        Some(&caller),
+        "make_allowance_rule",
+        json!({
+            "rule_id": rule.id,
+            "account_id": account_id,
+            "group_id": group_id,
+            "amount": amount,
+            "day_of_month": day_of_month,
+        }),
+    )
+    .await?;
+
+    // The manager would get to it eventually, but this way it is visible right away.
+    let granted = crate::allowance::apply_due_allowances(db).await?;
+    tracing::info!(
+        "New allowance rule {} gave out {granted} allowances",
+        rule.id
+    );
+
+    Ok(Json(rule.id))
+}
+
+pub async fn fetch_allowance_rules(
+    State(state): State<AppState>,
+    InstructorAuth(_caller): InstructorAuth,
+) -> Result<Json<Vec<AllowanceRule>>, AppError> {
+    let db = &state.db;
+    let data = sqlx::query!(
+        r#"SELECT allowance_rules.*, logins.handle AS "handle?", account_groups.name AS "group_name?",
+            (SELECT COUNT(*) FROM allowance_grants WHERE rule_id=allowance_rules.id) AS "times_granted!: i64"
+        FROM allowance_rules
+        LEFT JOIN logins ON logins.account_id=allowance_rules.account_id
+        LEFT JOIN account_groups ON account_groups.id=allowance_rules.group_id
+        WHERE is_active=1"#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(Json(
+        data.into_iter()
+            .map(|v| AllowanceRule {
+                id: v.id,
+                handle: v.handle,
+                group: v.group_name,
+                amount: v.amount,
+                day_of_month: v.day_of_month,
+                created_at_unix_time: v.created_at_unix_time,
+                times_granted: v.times_granted,
+            })
+            .collect(),
+    ))
+}
+
+/// Stop giving out this allowance. The grants that were already made stay.
+pub async fn deactivate_allowance_rule(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(DeactivateAllowanceRuleRequest { id }): Json<DeactivateAllowanceRuleRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = &state.db;
+    let result = sqlx::query!(
+        "UPDATE allowance_rules SET is_active=0 WHERE id=? AND is_active=1",
+        id
+    )
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        Err(anyhow::anyhow!("No such active allowance rule found"))?
+    }
+    record_admin_action(
+        db,
+        Some(&caller),
+        "deactivate_allowance_rule",
+        json!({"rule_id": id}),
+    )
+    .await?;
+
+    Ok(Json(()))
+}
+
+pub async fn set_role(
+    State(state): State<AppState>,
+    AdminAuth(caller): AdminAuth,
+    Json(SetRoleRequest { handle, role }): Json<SetRoleRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = &state.db;
+    let login = match sqlx::query!("SELECT account_id FROM logins WHERE handle=?", handle)
+        .fetch_optional(db)
+        .await?
+    {
+        Some(row) => row,
+        None => Err(anyhow::anyhow!("No such handle found"))?,
+    };
+    // Otherwise the last admin could lock everyone out.
+    if login.account_id == caller.account_id && role != Role::Admin {
+        Err(anyhow::anyhow!("Admins can't take away their own role"))?
+    }
+
+    let role_value = role as i64;
+    sqlx::query!(
+        "UPDATE accounts SET role=? WHERE id=?",
+        role_value,
+        login.account_id
+    )
+    .execute(db)
+    .await?;
+    record_admin_action(
+        db,
+        Some(&caller),
+        "set_role",
+        json!({"account_id": login.account_id, "handle": handle, "role": role}),
+    )
+    .await?;
+
+    Ok(Json(()))
+}
+
+pub async fn fetch_audit_log(
+    State(state): State<AppState>,
+    AdminAuth(_caller): AdminAuth,
+    Query(FetchAuditLogQuery { limit }): Query<FetchAuditLogQuery>,
+) -> Result<Json<Vec<AuditLogEntry>>, AppError> {
+    let db = &state.db;
+    let limit = limit.unwrap_or(100);
+    let data = sqlx::query!(
+        r#"SELECT admin_audit_log.*, logins.handle AS "actor_handle?" FROM admin_audit_log
+        LEFT JOIN logins ON logins.account_id=admin_audit_log.actor_account_id
+        ORDER BY admin_audit_log.id DESC LIMIT ?"#,
+        limit
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(Json(
+        data.into_iter()
+            .map(|v| AuditLogEntry {
+                id: v.id,
+                actor_handle: v.actor_handle,
+                action: v.action,
+                details: serde_json::from_str(&v.details_json).unwrap_or_default(),
+                created_at_unix_time: v.created_at_unix_time,
+            })
+            .collect(),
+    ))
+}
+
+pub async fn fetch_verification_requests(
+    State(state): State<AppState>,
+    InstructorAuth(_caller): InstructorAuth,
+) -> Result<Json<Vec<PendingVerificationRequest>>, AppError> {
+    Ok(Json(pending_requests(&state.db).await?))
+}
+
+pub async fn decide_verification_request(
+    State(state): State<AppState>,
+    InstructorAuth(caller): InstructorAuth,
+    Json(DecideVerificationRequest { id, approve }): Json<DecideVerificationRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = &state.db;
+    let account_id = decide_request(db, id, approve, caller.account_id).await?;
+    record_admin_action(
+        db,
+        Some(&caller),
+        "decide_verification_request",
+        json!({"request_id": id, "account_id": account_id, "approve": approve}),
+    )
+    .await?;
+
+    Ok(Json(()))
+}
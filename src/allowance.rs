@@ -0,0 +1,101 @@
+use api::BalanceChangeKind;
+use chrono::Datelike;
+use sqlx::SqlitePool;
+
+use crate::balance::record_balance_change;
+
+/// Give out the monthly allowances that are due and haven't been given out yet.
+///
+/// A rule with a given day of the month is due from that day until the end of the month.
+/// Every account gets at most one grant per rule per month,
+/// so this can be run as often as needed, and after the server was down on the day itself.
+/// Past months are never granted retroactively.
+///
+/// Returns how many grants were made.
+pub async fn apply_due_allowances(db: &SqlitePool) -> anyhow::Result<usize> {
+    let today = chrono::Local::now();
+    let period = today.format("%Y-%m").to_string();
+    let day = today.day() as i64;
+
+    let rules = sqlx::query!(
+        "SELECT * FROM allowance_rules WHERE is_active=1 AND day_of_month<=?",
+        day
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut granted = 0;
+    for rule in rules {
+        let accounts = match (rule.account_id, rule.group_id) {
+            (Some(account_id), _) => vec![account_id],
+            (None, Some(group_id)) => sqlx::query!(
+                "SELECT account_id FROM account_group_members WHERE group_id=?",
+                group_id
+            )
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|row| row.account_id)
+            .collect(),
+            (None, None) => vec![],
+        };
+
+        for account_id in accounts {
+            if grant_allowance(db, rule.id, account_id, rule.amount, &period).await? {
+                granted += 1;
+            }
+        }
+    }
+
+    Ok(granted)
+}
+
+/// Returns false if the account already got this allowance for this period.
+async fn grant_allowance(
+    db: &SqlitePool,
+    rule_id: i64,
+    account_id: i64,
+    amount: f64,
+    period: &str,
+) -> anyhow::Result<bool> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let mut tx = db.begin().await?;
+    let inserted = sqlx::query!(
+        "INSERT OR IGNORE INTO allowance_grants (rule_id, account_id, period, created_at_unix_time) VALUES (?,?,?,?)",
+        rule_id,
+        account_id,
+        period,
+        now
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "UPDATE accounts SET balance=balance+? WHERE id=?",
+        amount,
+        account_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    record_balance_change(
+        &mut tx,
+        account_id,
+        amount,
+        BalanceChangeKind::AllowanceGrant,
+        None,
+        Some(period),
+    )
+    .await?;
+    tx.commit().await?;
+
+    tracing::info!("Granted allowance {rule_id} of {amount} to account {account_id} for {period}");
+    Ok(true)
+}
@@ -0,0 +1,305 @@
+//! Long-lived keys for scripts and CI, which can only do what their scopes allow.
+//!
+//! Unlike sessions, a key is not affected by logging in or out, or by changing the password;
+//! it lasts until it expires or is revoked.
+
+use api::{ApiKeyInfo, ApiKeyScope, NewApiKeyRequest, NewApiKeyResponse};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use serde::Deserialize;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+
+use crate::{auth::CurrentAccount, result::AppError, sessions::hash_token, AppState};
+
+/// Every API key starts with this, so that it can be told apart from a session token.
+pub const API_KEY_PREFIX: &str = "pwk_";
+
+/// How much of the key is stored in the clear, so that the user can recognize it.
+const SHOWN_PREFIX_LEN: usize = 8;
+
+/// The last-used time is only written down this often, so that every request isn't a database write.
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+/// A request that was made with an API key.
+#[derive(Debug, Clone)]
+pub struct ApiKeyAccess {
+    pub id: i64,
+    pub scopes: Vec<ApiKeyScope>,
+    pub spending_limit: Option<f64>,
+}
+
+/// Find the live key, and mark it as used.
+/// Returns the key and the account ID.
+pub async fn find_api_key(
+    db: &SqlitePool,
+    key: &str,
+) -> anyhow::Result<Option<(ApiKeyAccess, i64)>> {
+    let key_hash = hash_token(key);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let row = match sqlx::query!(
+        "SELECT * FROM api_keys WHERE key_hash=? AND revoked_at_unix_time IS NULL AND (expires_at_unix_time IS NULL OR expires_at_unix_time>?)",
+        key_hash,
+        now
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let stale_before = now - LAST_USED_RESOLUTION_SECONDS;
+    sqlx::query!(
+        "UPDATE api_keys SET last_used_at_unix_time=? WHERE id=? AND (last_used_at_unix_time IS NULL OR last_used_at_unix_time<?)",
+        now,
+        row.id,
+        stale_before
+    )
+    .execute(db)
+    .await?;
+
+    Ok(Some((
+        ApiKeyAccess {
+            id: row.id,
+            scopes: ApiKeyScope::from_bits(row.scopes),
+            spending_limit: row.spending_limit,
+        },
+        row.account_id,
+    )))
+}
+
+/// How many times to try setting money aside when other orders keep taking it first.
+const RESERVE_ATTEMPTS: usize = 5;
+
+/// What an order made with a key may spend.
+pub enum KeyBudget {
+    /// The key has no spending limit.
+    Unlimited,
+    /// This much was set aside from the key's limit for the order.
+    Reserved(f64),
+    /// Nothing is left of the key's limit.
+    UsedUp,
+}
+
+/// Whether anything is left of the key's spending limit, without setting any of it aside.
+pub async fn has_spending_left(db: &SqlitePool, key: &ApiKeyAccess) -> anyhow::Result<bool> {
+    if key.spending_limit.is_none() {
+        return Ok(true);
+    }
+    let row = sqlx::query!(
+        "SELECT spending_limit, spent, reserved FROM api_keys WHERE id=?",
+        key.id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(match row.spending_limit {
+        Some(limit) => limit - row.spent - row.reserved > 0.0,
+        None => true,
+    })
+}
+
+/// Set aside up to `amount` of the key's limit for the order,
+/// so that orders running at the same time can't go over the key's limit together.
+/// The reservation is written on the order together with taking it from the key,
+/// and is given back by [`record_api_key_spending`] when the order finishes.
+pub async fn reserve_spending(
+    db: &SqlitePool,
+    key: &ApiKeyAccess,
+    order_id: i64,
+    amount: f64,
+) -> anyhow::Result<KeyBudget> {
+    if key.spending_limit.is_none() {
+        return Ok(KeyBudget::Unlimited);
+    }
+    for _ in 0..RESERVE_ATTEMPTS {
+        let row = sqlx::query!(
+            "SELECT spending_limit, spent, reserved FROM api_keys WHERE id=?",
+            key.id
+        )
+        .fetch_one(db)
+        .await?;
+        let limit = match row.spending_limit {
+            Some(limit) => limit,
+            None => return Ok(KeyBudget::Unlimited),
+        };
+        let remaining = limit - row.spent - row.reserved;
+        if remaining <= 0.0 {
+            return Ok(KeyBudget::UsedUp);
+        }
+        let amount = amount.min(remaining);
+
+        // This only goes through if nobody else took the money since it was read.
+        let mut tx = db.begin().await?;
+        let reserved = sqlx::query!(
+            "UPDATE api_keys SET reserved=reserved+? WHERE id=? AND spent=? AND reserved=?",
+            amount,
+            key.id,
+            row.spent,
+            row.reserved
+        )
+        .execute(&mut *tx)
+        .await?;
+        if reserved.rows_affected() == 1 {
+            sqlx::query!(
+                "UPDATE orders SET api_key_reserved=? WHERE id=?",
+                amount,
+                order_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Ok(KeyBudget::Reserved(amount));
+        }
+        tx.rollback().await?;
+    }
+    Err(anyhow::anyhow!(
+        "Too many orders are being made with this API key at once, try again"
+    ))
+}
+
+/// Count the order's cost towards the limit of the key it was made with, if any,
+/// and give back what was set aside for it.
+pub async fn record_api_key_spending(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    cost: f64,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE api_keys SET spent=spent+?, reserved=MAX(reserved-(SELECT api_key_reserved FROM orders WHERE id=?), 0)
+        WHERE id=(SELECT api_key_id FROM orders WHERE id=?)",
+        cost,
+        order_id,
+        order_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!("UPDATE orders SET api_key_reserved=0 WHERE id=?", order_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_api_keys(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+) -> Result<Json<Vec<ApiKeyInfo>>, AppError> {
+    let rows = sqlx::query!(
+        "SELECT * FROM api_keys WHERE account_id=? AND revoked_at_unix_time IS NULL ORDER BY created_at_unix_time DESC",
+        account.account_id
+    )
+    .fetch_all(&db)
+    .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| ApiKeyInfo {
+                id: row.id,
+                name: row.name,
+                key_prefix: row.key_prefix,
+                scopes: ApiKeyScope::from_bits(row.scopes),
+                spending_limit: row.spending_limit,
+                spent: row.spent,
+                created_at_unix_time: row.created_at_unix_time as u64,
+                last_used_at_unix_time: row.last_used_at_unix_time.map(|t| t as u64),
+                expires_at_unix_time: row.expires_at_unix_time.map(|t| t as u64),
+            })
+            .collect(),
+    ))
+}
+
+pub async fn new_api_key(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Json(NewApiKeyRequest {
+        name,
+        scopes,
+        expires_at_unix_time,
+        spending_limit,
+    }): Json<NewApiKeyRequest>,
+) -> Result<Json<NewApiKeyResponse>, AppError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(Json(NewApiKeyResponse::EmptyName));
+    }
+    if scopes.is_empty() {
+        return Ok(Json(NewApiKeyResponse::NoScopes));
+    }
+    if let Some(limit) = spending_limit {
+        if limit.is_nan() || limit <= 0.0 {
+            return Ok(Json(NewApiKeyResponse::InvalidSpendingLimit));
+        }
+    }
+    let expires_at_unix_time = expires_at_unix_time.map(|t| t as i64);
+    if let Some(expiry) = expires_at_unix_time {
+        if expiry <= now {
+            return Ok(Json(NewApiKeyResponse::AlreadyExpired));
+        }
+    }
+
+    use rand::distributions::DistString;
+    let key = format!(
+        "{API_KEY_PREFIX}{}",
+        rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 40)
+    );
+    let key_hash = hash_token(&key);
+    let key_prefix = &key[..API_KEY_PREFIX.len() + SHOWN_PREFIX_LEN];
+    let scope_bits = ApiKeyScope::to_bits(&scopes);
+
+    let id = sqlx::query!(
+        "INSERT INTO api_keys (account_id, name, key_hash, key_prefix, scopes, spending_limit, created_at_unix_time, expires_at_unix_time) VALUES (?,?,?,?,?,?,?,?) RETURNING id",
+        account.account_id,
+        name,
+        key_hash,
+        key_prefix,
+        scope_bits,
+        spending_limit,
+        now,
+        expires_at_unix_time
+    )
+    .fetch_one(&db)
+    .await?
+    .id;
+
+    Ok(Json(NewApiKeyResponse::Ok { id, key }))
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyPath {
+    id: i64,
+}
+
+pub async fn revoke_api_key(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Path(ApiKeyPath { id }): Path<ApiKeyPath>,
+) -> Result<Json<()>, AppError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let result = sqlx::query!(
+        "UPDATE api_keys SET revoked_at_unix_time=? WHERE id=? AND account_id=? AND revoked_at_unix_time IS NULL",
+        now,
+        id,
+        account.account_id
+    )
+    .execute(&db)
+    .await?;
+    if result.rows_affected() == 0 {
+        Err(anyhow::anyhow!("No such API key found"))?
+    }
+
+    Ok(Json(()))
+}
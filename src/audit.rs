@@ -0,0 +1,32 @@
+use sqlx::SqliteExecutor;
+
+use crate::auth::Caller;
+
+/// Write down that a staff member did something.
+/// If there is no caller, the action was done with the bootstrap key.
+pub async fn record_admin_action<'c>(
+    db: impl SqliteExecutor<'c>,
+    caller: Option<&Caller>,
+    action: &str,
+    details: serde_json::Value,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let actor = caller.map(|c| c.account_id);
+    let details_json = details.to_string();
+
+    sqlx::query!(
+        "INSERT INTO admin_audit_log (actor_account_id, action, details_json, created_at_unix_time) VALUES (?,?,?,?)",
+        actor,
+        action,
+        details_json,
+        now
+    )
+    .execute(db)
+    .await?;
+
+    tracing::info!("Admin action by {actor:?}: {action} {details_json}");
+    Ok(())
+}
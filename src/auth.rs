@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use api::{ApiKeyScope, Role};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{header, request::Parts, StatusCode},
+};
+
+use crate::{
+    api_keys::{find_api_key, ApiKeyAccess, API_KEY_PREFIX},
+    sessions::find_session,
+    AppState,
+};
+
+/// The name of the cookie that can hold the session token.
+pub const SESSION_COOKIE: &str = "session";
+
+/// The account that a request was made by.
+///
+/// The token is looked for, in order:
+/// - in the `:token` part of the path, for the deprecated routes that have it;
+/// - in the `Authorization: Bearer` header;
+/// - in the `X-AuthToken` header, which staff tools use;
+/// - in the session cookie.
+///
+/// It can be either a session token, or the account's own legacy token.
+/// API keys are not accepted here: handlers that allow them take one of the scoped extractors,
+/// like [`ReadOrdersAccess`], instead.
+#[derive(Debug, Clone)]
+pub struct CurrentAccount {
+    pub account_id: i64,
+
+    /// None if the request used the account's legacy token or an API key instead of a session.
+    pub session_id: Option<i64>,
+
+    /// The key that the request was made with, if it was made with one.
+    pub api_key: Option<ApiKeyAccess>,
+}
+
+async fn request_token(parts: &mut Parts, state: &AppState) -> Option<String> {
+    if let Ok(Path(params)) =
+        Path::<HashMap<String, String>>::from_request_parts(parts, state).await
+    {
+        if let Some(token) = params.get("token") {
+            return Some(token.clone());
+        }
+    }
+
+    let header_value = |name: header::HeaderName| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string)
+    };
+    if let Some(token) = header_value(header::AUTHORIZATION)
+        .as_deref()
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        return Some(token.trim().to_string());
+    }
+    if let Some(token) = header_value(header::HeaderName::from_static("x-authtoken")) {
+        return Some(token);
+    }
+
+    parts
+        .headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Find the account that made the request.
+/// If the request was made with an API key, it must have the given scope.
+async fn resolve_account(
+    parts: &mut Parts,
+    state: &AppState,
+    scope: Option<ApiKeyScope>,
+) -> Result<CurrentAccount, (StatusCode, String)> {
+    let token = request_token(parts, state).await.ok_or((
+        StatusCode::UNAUTHORIZED,
+        "No session token was given".to_string(),
+    ))?;
+    let internal = |why: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, why.to_string());
+
+    if token.starts_with(API_KEY_PREFIX) {
+        let (key, account_id) = find_api_key(&state.db, &token)
+            .await
+            .map_err(internal)?
+            .ok_or((
+                StatusCode::UNAUTHORIZED,
+                "The API key is invalid, revoked or expired".to_string(),
+            ))?;
+        return match scope {
+            Some(scope) if key.scopes.contains(&scope) => Ok(CurrentAccount {
+                account_id,
+                session_id: None,
+                api_key: Some(key),
+            }),
+            Some(scope) => Err((
+                StatusCode::FORBIDDEN,
+                format!("This API key doesn't have the {scope:?} scope"),
+            )),
+            None => Err((
+                StatusCode::FORBIDDEN,
+                "API keys can't be used for this; log in instead".to_string(),
+            )),
+        };
+    }
+
+    if let Some((session_id, account_id)) =
+        find_session(&state.db, &token).await.map_err(internal)?
+    {
+        return Ok(CurrentAccount {
+            account_id,
+            session_id: Some(session_id),
+            api_key: None,
+        });
+    }
+
+    match sqlx::query!("SELECT id FROM accounts WHERE token=?", token)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|why| internal(why.into()))?
+    {
+        Some(account) => Ok(CurrentAccount {
+            account_id: account.id,
+            session_id: None,
+            api_key: None,
+        }),
+        None => Err((
+            StatusCode::UNAUTHORIZED,
+            "The session is invalid or has expired".to_string(),
+        )),
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for CurrentAccount {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        resolve_account(parts, state, None).await
+    }
+}
+
+/// Extracting this allows the request to be made with an API key that can upload orders.
+pub struct UploadOrdersAccess(pub CurrentAccount);
+
+#[async_trait]
+impl FromRequestParts<AppState> for UploadOrdersAccess {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        resolve_account(parts, state, Some(ApiKeyScope::UploadOrders))
+            .await
+            .map(Self)
+    }
+}
+
+/// Extracting this allows the request to be made with an API key that can read orders.
+pub struct ReadOrdersAccess(pub CurrentAccount);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ReadOrdersAccess {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        resolve_account(parts, state, Some(ApiKeyScope::ReadOrders))
+            .await
+            .map(Self)
+    }
+}
+
+/// Extracting this allows the request to be made with an API key that can read the profile.
+pub struct ReadProfileAccess(pub CurrentAccount);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ReadProfileAccess {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        resolve_account(parts, state, Some(ApiKeyScope::ReadProfile))
+            .await
+            .map(Self)
+    }
+}
+
+/// The account that is making a staff request.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub account_id: i64,
+    pub role: Role,
+}
+
+/// Find the account that made the request, and check that it has at least the given role.
+async fn authorize(
+    parts: &mut Parts,
+    state: &AppState,
+    min_role: Role,
+) -> Result<Caller, (StatusCode, String)> {
+    let account = resolve_account(parts, state, None).await?;
+
+    let role = sqlx::query!("SELECT role FROM accounts WHERE id=?", account.account_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|why| (StatusCode::INTERNAL_SERVER_ERROR, why.to_string()))?
+        .role;
+    let role = Role::from(role);
+    if role < min_role {
+        tracing::warn!(
+            "Account {} with role {role:?} tried to do something that needs {min_role:?}",
+            account.account_id
+        );
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("This needs the {min_role:?} role, but you are {role:?}"),
+        ));
+    }
+
+    Ok(Caller {
+        account_id: account.account_id,
+        role,
+    })
+}
+
+/// Extracting this makes sure the request was made by an admin.
+pub struct AdminAuth(pub Caller);
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        authorize(parts, state, Role::Admin).await.map(Self)
+    }
+}
+
+/// Extracting this makes sure the request was made by an instructor or an admin.
+pub struct InstructorAuth(pub Caller);
+
+#[async_trait]
+impl FromRequestParts<AppState> for InstructorAuth {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        authorize(parts, state, Role::Instructor).await.map(Self)
+    }
+}
@@ -0,0 +1,212 @@
+use api::{BalanceChangeKind, BalanceHistoryEntry, TransferRequest, TransferResponse};
+use axum::{extract::State, Json};
+use sqlx::{Sqlite, Transaction};
+
+use crate::{
+    auth::{CurrentAccount, ReadProfileAccess},
+    result::AppError,
+    AppState,
+};
+
+/// How many entries of the balance history are shown.
+const BALANCE_HISTORY_DEPTH: i64 = 100;
+
+/// Write down a change to an account's balance.
+/// This does not change the balance itself: the caller does that in the same transaction.
+pub async fn record_balance_change(
+    tx: &mut Transaction<'_, Sqlite>,
+    account_id: i64,
+    amount: f64,
+    kind: BalanceChangeKind,
+    counterparty_account_id: Option<i64>,
+    comment: Option<&str>,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let kind = kind as i64;
+
+    sqlx::query!(
+        "INSERT INTO balance_history (account_id, amount, kind, counterparty_account_id, comment, created_at_unix_time) VALUES (?,?,?,?,?,?)",
+        account_id,
+        amount,
+        kind,
+        counterparty_account_id,
+        comment,
+        now
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_balance_history(
+    State(AppState { db, .. }): State<AppState>,
+    ReadProfileAccess(account): ReadProfileAccess,
+) -> Result<Json<Vec<BalanceHistoryEntry>>, AppError> {
+    let rows = sqlx::query!(
+        "SELECT balance_history.*, accounts.user_name AS \"counterparty_name?\" FROM balance_history
+        LEFT JOIN accounts ON accounts.id = balance_history.counterparty_account_id
+        WHERE account_id=? ORDER BY created_at_unix_time DESC, balance_history.id DESC LIMIT ?",
+        account.account_id,
+        BALANCE_HISTORY_DEPTH
+    )
+    .fetch_all(&db)
+    .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| BalanceHistoryEntry {
+                kind: row.kind.into(),
+                amount: row.amount,
+                counterparty_name: row.counterparty_name,
+                comment: row.comment,
+                when_unix_time: row.created_at_unix_time as u64,
+            })
+            .collect(),
+    ))
+}
+
+/// Limits on transfers, configured with environment variables:
+/// - `TRANSFER_MAX_AMOUNT`: the largest single transfer (default 10000);
+/// - `TRANSFER_DAILY_LIMIT`: the most that one account can transfer out in 24 hours (default 50000).
+struct TransferLimits {
+    max_amount: f64,
+    daily_limit: f64,
+}
+
+impl TransferLimits {
+    fn from_env() -> Self {
+        fn env_or(name: &str, default: f64) -> f64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        Self {
+            max_amount: env_or("TRANSFER_MAX_AMOUNT", 10000.0),
+            daily_limit: env_or("TRANSFER_DAILY_LIMIT", 50000.0),
+        }
+    }
+}
+
+pub async fn transfer(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Json(TransferRequest {
+        recipient_handle,
+        amount,
+        comment,
+        confirm,
+    }): Json<TransferRequest>,
+) -> Result<Json<TransferResponse>, AppError> {
+    let limits = TransferLimits::from_env();
+    if amount.is_nan() || amount <= 0.0 {
+        return Ok(Json(TransferResponse::InvalidAmount));
+    }
+    if amount > limits.max_amount {
+        return Ok(Json(TransferResponse::OverTransferLimit {
+            max_amount: limits.max_amount,
+        }));
+    }
+    let comment = comment.filter(|c| !c.trim().is_empty());
+
+    // All the checks happen in the same transaction as the transfer,
+    // so that concurrent transfers can't go over the balance or the limits.
+    let mut tx = db.begin().await?;
+
+    let sender = sqlx::query!("SELECT * FROM accounts WHERE id=?", account.account_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let recipient = match sqlx::query!(
+        "SELECT accounts.* FROM logins INNER JOIN accounts ON accounts.id = logins.account_id WHERE handle=?",
+        recipient_handle
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(Json(TransferResponse::NoSuchRecipient)),
+    };
+    if recipient.id == sender.id {
+        return Ok(Json(TransferResponse::CannotTransferToSelf));
+    }
+
+    if sender.balance < amount {
+        return Ok(Json(TransferResponse::InsufficientBalance {
+            balance: sender.balance,
+        }));
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let day_ago = now - 24 * 60 * 60;
+    let transfer_out = BalanceChangeKind::TransferOut as i64;
+    let used = -sqlx::query!(
+        "SELECT COALESCE(SUM(amount), 0.0) AS \"total!: f64\" FROM balance_history WHERE account_id=? AND kind=? AND created_at_unix_time>?",
+        sender.id,
+        transfer_out,
+        day_ago
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .total;
+    if used + amount > limits.daily_limit {
+        return Ok(Json(TransferResponse::OverDailyLimit {
+            limit: limits.daily_limit,
+            used,
+        }));
+    }
+
+    if !confirm {
+        return Ok(Json(TransferResponse::NeedsConfirmation {
+            recipient_name: recipient.user_name,
+            amount,
+            balance_after: sender.balance - amount,
+        }));
+    }
+
+    let balance_after = sqlx::query!(
+        "UPDATE accounts SET balance=balance-? WHERE id=? RETURNING balance",
+        amount,
+        sender.id
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .balance;
+    sqlx::query!(
+        "UPDATE accounts SET balance=balance+? WHERE id=?",
+        amount,
+        recipient.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_balance_change(
+        &mut tx,
+        sender.id,
+        -amount,
+        BalanceChangeKind::TransferOut,
+        Some(recipient.id),
+        comment.as_deref(),
+    )
+    .await?;
+    record_balance_change(
+        &mut tx,
+        recipient.id,
+        amount,
+        BalanceChangeKind::TransferIn,
+        Some(sender.id),
+        comment.as_deref(),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(TransferResponse::Ok { balance_after }))
+}
@@ -0,0 +1,139 @@
+use api::GroupInfo;
+use sqlx::{Sqlite, SqliteExecutor, Transaction};
+
+/// What an account may spend from its group's balance.
+pub struct GroupFunds {
+    pub group_id: i64,
+    pub info: GroupInfo,
+}
+
+/// Find the group that the account is in, and how much it may spend from it right now.
+pub async fn get_group_funds<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+) -> anyhow::Result<Option<GroupFunds>> {
+    let row = match sqlx::query!(
+        "SELECT account_groups.id, account_groups.name, account_groups.balance, account_group_members.spending_limit, account_group_members.spent
+        FROM account_group_members INNER JOIN account_groups ON account_groups.id=account_group_members.group_id
+        WHERE account_id=?",
+        account_id
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let remaining_limit = row
+        .spending_limit
+        .map_or(f64::INFINITY, |limit| limit - row.spent);
+    let available = row.balance.min(remaining_limit).max(0.0);
+
+    Ok(Some(GroupFunds {
+        group_id: row.id,
+        info: GroupInfo {
+            name: row.name,
+            available,
+            spending_limit: row.spending_limit,
+            spent: row.spent,
+        },
+    }))
+}
+
+/// The personal balance plus what the account may spend from its group.
+pub async fn get_available_funds<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+    personal_balance: f64,
+) -> anyhow::Result<f64> {
+    let group = get_group_funds(db, account_id).await?;
+    Ok(personal_balance + group.map_or(0.0, |g| g.info.available))
+}
+
+/// Take the cost of an order from the group's balance as far as the member's limit allows,
+/// and the rest from the personal balance.
+/// The group is written down on the order, so that a refund goes back to it.
+/// Returns how much was taken from the group.
+pub async fn charge_order_cost(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    account_id: i64,
+    cost: f64,
+) -> anyhow::Result<f64> {
+    let group_charge = match get_group_funds(&mut **tx, account_id).await? {
+        Some(funds) if cost > 0.0 => {
+            let group_charge = cost.min(funds.info.available);
+            sqlx::query!(
+                "UPDATE account_groups SET balance=balance-? WHERE id=?",
+                group_charge,
+                funds.group_id
+            )
+            .execute(&mut **tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE account_group_members SET spent=spent+? WHERE account_id=?",
+                group_charge,
+                account_id
+            )
+            .execute(&mut **tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE orders SET group_id=? WHERE id=?",
+                funds.group_id,
+                order_id
+            )
+            .execute(&mut **tx)
+            .await?;
+            group_charge
+        }
+        _ => 0.0,
+    };
+
+    let personal_charge = cost - group_charge;
+    sqlx::query!(
+        "UPDATE accounts SET balance=balance-? WHERE id=?",
+        personal_charge,
+        account_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(group_charge)
+}
+
+/// Give money back to the group that paid for an order, even if the account has moved on from it since.
+/// The member's spending only goes down if they are still in that group.
+/// If no group paid for the order, this returns false and nothing is changed,
+/// so that the caller can give the money to the account instead.
+pub async fn return_to_group(
+    tx: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    amount: f64,
+) -> anyhow::Result<bool> {
+    let order = sqlx::query!("SELECT user_id, group_id FROM orders WHERE id=?", order_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    let group_id = match order.group_id {
+        Some(group_id) => group_id,
+        None => return Ok(false),
+    };
+
+    sqlx::query!(
+        "UPDATE account_groups SET balance=balance+? WHERE id=?",
+        amount,
+        group_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE account_group_members SET spent=MAX(spent-?, 0) WHERE account_id=? AND group_id=?",
+        amount,
+        order.user_id,
+        group_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(true)
+}
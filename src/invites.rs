@@ -0,0 +1,170 @@
+//! Registering an account without an admin, with an invite code from an instructor.
+//!
+//! The code decides what the new account starts with: a group and some money.
+//! Registered accounts still have to be verified, like any other.
+
+use std::net::SocketAddr;
+
+use api::{BalanceChangeKind, RegisterRequest, RegisterResponse, Role};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::SqliteExecutor;
+
+use crate::{
+    admin::insert_account,
+    balance::record_balance_change,
+    login_throttle::{client_ip, record_registration_failure, registration_locked_until},
+    passwords::{check_password_policy, hash_password_off_thread},
+    result::AppError,
+    sessions::{create_session, session_cookie, user_agent},
+    AppState,
+};
+
+struct UsableInvite {
+    id: i64,
+    group_id: Option<i64>,
+    starting_balance: f64,
+}
+
+/// Find the invite code, if it can still be used to register.
+async fn find_usable_invite<'c>(
+    db: impl SqliteExecutor<'c>,
+    code: &str,
+) -> anyhow::Result<Option<UsableInvite>> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    Ok(sqlx::query!(
+        "SELECT id, group_id, starting_balance FROM invite_codes
+        WHERE code=? AND (expires_at_unix_time IS NULL OR expires_at_unix_time>?)
+            AND (max_uses IS NULL OR max_uses>(SELECT COUNT(*) FROM invite_code_uses WHERE invite_code_id=invite_codes.id))",
+        code,
+        now
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|row| UsableInvite {
+        id: row.id,
+        group_id: row.group_id,
+        starting_balance: row.starting_balance,
+    }))
+}
+
+/// Handles are shown to other people and typed in by hand, so they are kept simple.
+fn is_valid_handle(handle: &str) -> bool {
+    (3..=32).contains(&handle.len())
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+pub async fn register(
+    State(AppState { db, .. }): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(RegisterRequest {
+        invite_code,
+        name,
+        handle,
+        password,
+    }): Json<RegisterRequest>,
+) -> Result<Response, AppError> {
+    let name = name.trim();
+    let handle = handle.trim();
+    if name.is_empty() {
+        return Ok(Json(RegisterResponse::InvalidName).into_response());
+    }
+    if !is_valid_handle(handle) {
+        return Ok(Json(RegisterResponse::InvalidHandle).into_response());
+    }
+    if let Err(problem) = check_password_policy(&password, handle) {
+        return Ok(Json(RegisterResponse::BadPassword(problem)).into_response());
+    }
+
+    // Wrong codes are counted per address, so that they can't be guessed.
+    let ip = client_ip(&headers, peer);
+    if let Some(until) = registration_locked_until(&db, &ip).await? {
+        return Ok(Json(RegisterResponse::LockedOut {
+            until_unix_time: until as u64,
+        })
+        .into_response());
+    }
+    // Hashing is slow on purpose, so it's only done for requests with a real code.
+    if find_usable_invite(&db, invite_code.trim()).await?.is_none() {
+        record_registration_failure(&db, &ip).await?;
+        return Ok(Json(RegisterResponse::InvalidInviteCode).into_response());
+    }
+    let password_hash = hash_password_off_thread(&password).await?;
+
+    // The code is checked again in the transaction, so that concurrent registrations can't go over its limit.
+    let mut tx = db.begin().await?;
+    let invite = match find_usable_invite(&mut *tx, invite_code.trim()).await? {
+        Some(invite) => invite,
+        None => return Ok(Json(RegisterResponse::InvalidInviteCode).into_response()),
+    };
+    if sqlx::query!("SELECT account_id FROM logins WHERE handle=?", handle)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some()
+    {
+        return Ok(Json(RegisterResponse::HandleTaken).into_response());
+    }
+
+    let account_id =
+        insert_account(&mut tx, name, handle, Some(&password_hash), Role::Student).await?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    sqlx::query!(
+        "INSERT INTO invite_code_uses (invite_code_id, account_id, used_at_unix_time) VALUES (?,?,?)",
+        invite.id,
+        account_id,
+        now
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(group_id) = invite.group_id {
+        sqlx::query!(
+            "INSERT INTO account_group_members (account_id, group_id, spending_limit, spent) VALUES (?,?,NULL,0)",
+            account_id,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if invite.starting_balance > 0.0 {
+        sqlx::query!(
+            "UPDATE accounts SET balance=? WHERE id=?",
+            invite.starting_balance,
+            account_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        record_balance_change(
+            &mut tx,
+            account_id,
+            invite.starting_balance,
+            BalanceChangeKind::StartingBalance,
+            None,
+            None,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    let token = create_session(&db, account_id, user_agent(&headers)).await?;
+    Ok((
+        [(header::SET_COOKIE, session_cookie(&token))],
+        Json(RegisterResponse::Ok { token }),
+    )
+        .into_response())
+}
@@ -0,0 +1,128 @@
+//! One-time login links, for handing out accounts without making up passwords for them.
+//!
+//! The link points at a frontend page, which posts the token here:
+//! a plain `GET` would be used up by mail scanners that open every link they see.
+
+use api::{LoginLinkRequest, LoginLinkResponse};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::SqliteExecutor;
+
+use crate::{
+    result::AppError,
+    sessions::{create_session, hash_token, session_cookie, user_agent},
+    two_factor, AppState,
+};
+
+/// How long a link works for, configured with `LOGIN_LINK_LIFETIME_DAYS` (default 14).
+fn login_link_lifetime_seconds() -> i64 {
+    let days: i64 = std::env::var("LOGIN_LINK_LIFETIME_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(14);
+    days * 24 * 60 * 60
+}
+
+/// Make a new login link for the account, and return its token.
+pub async fn create_login_link<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+) -> anyhow::Result<String> {
+    use rand::distributions::DistString;
+    let token = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+    let token_hash = hash_token(&token);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let expires = now + login_link_lifetime_seconds();
+
+    sqlx::query!(
+        "INSERT INTO login_links (account_id, token_hash, created_at_unix_time, expires_at_unix_time) VALUES (?,?,?,?)",
+        account_id,
+        token_hash,
+        now,
+        expires
+    )
+    .execute(db)
+    .await?;
+
+    Ok(token)
+}
+
+/// The address of the frontend page that logs in with the token.
+pub fn login_link_url(frontend_url: &str, token: &str) -> String {
+    format!("{frontend_url}/login-link/{token}")
+}
+
+/// Make every unused login link of the account stop working.
+pub async fn revoke_login_links<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    sqlx::query!(
+        "UPDATE login_links SET expires_at_unix_time=? WHERE account_id=? AND used_at_unix_time IS NULL AND expires_at_unix_time>?",
+        now,
+        account_id,
+        now
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn use_login_link(
+    State(AppState { db, .. }): State<AppState>,
+    headers: HeaderMap,
+    Json(LoginLinkRequest { token }): Json<LoginLinkRequest>,
+) -> Result<Response, AppError> {
+    let token_hash = hash_token(&token);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let link = match sqlx::query!(
+        "SELECT account_id FROM login_links WHERE token_hash=? AND used_at_unix_time IS NULL AND expires_at_unix_time>?",
+        token_hash,
+        now
+    )
+    .fetch_optional(&db)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(Json(LoginLinkResponse::InvalidLink).into_response()),
+    };
+    // A link is made before the account has a second factor, so it must not get around one added later.
+    if two_factor::is_enabled(&db, link.account_id).await? {
+        return Ok(Json(LoginLinkResponse::InvalidLink).into_response());
+    }
+
+    // Only one of two concurrent requests with the same link gets to use it.
+    let used = sqlx::query!(
+        "UPDATE login_links SET used_at_unix_time=? WHERE token_hash=? AND used_at_unix_time IS NULL",
+        now,
+        token_hash
+    )
+    .execute(&db)
+    .await?;
+    if used.rows_affected() == 0 {
+        return Ok(Json(LoginLinkResponse::InvalidLink).into_response());
+    }
+
+    let token = create_session(&db, link.account_id, user_agent(&headers)).await?;
+    Ok((
+        [(header::SET_COOKIE, session_cookie(&token))],
+        Json(LoginLinkResponse::Ok { token }),
+    )
+        .into_response())
+}
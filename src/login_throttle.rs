@@ -0,0 +1,342 @@
+//! Slowing down password guessing.
+//!
+//! Failed logins are counted both per handle and per client IP.
+//! After a few failures, every further attempt needs a solved proof-of-work challenge,
+//! which gets harder with each failure; after many more, logging in is locked out for a while,
+//! and the owner of the account is told about it.
+//! Wrong two-factor codes given outside of logging in are counted too, per account, with kind `two_factor`,
+//! and so are registrations with a wrong invite code, per client IP, with kind `register`.
+//!
+//! The limits are configured with environment variables:
+//! - `LOGIN_POW_AFTER_FAILURES`: failures before a challenge is needed (default 3);
+//! - `LOGIN_LOCKOUT_AFTER_FAILURES`: failures before a lockout (default 10);
+//! - `LOGIN_LOCKOUT_SECONDS`: how long the first lockout lasts; each further failure adds as much (default 900);
+//! - `LOGIN_FAILURE_WINDOW_SECONDS`: failures older than this are forgotten (default 86400);
+//! - `TRUSTED_IP_HEADER`: if the server is behind a proxy, the header with the client's IP, like `X-Real-IP`.
+
+use std::net::SocketAddr;
+
+use api::{verification::ProofOfWorkAttempt, LoginResponse, SecurityEvent, SecurityEventKind};
+use axum::{extract::State, http::HeaderMap, Json};
+use sqlx::{SqliteExecutor, SqlitePool};
+
+use crate::{
+    auth::CurrentAccount,
+    result::AppError,
+    verification::proof_of_work::{check_attempt, sign_challenge, PowSettings},
+    AppState,
+};
+
+// Login challenges are signed with their own salt, so that they can't be used for verification.
+const LOGIN_POW_SALT: &str = "pandoc-login-proof-of-work";
+
+/// The first login challenge is as hard as the verification one (see [`PowSettings`]);
+/// each further failure adds [`POW_DIFFICULTY_STEP`], up to the algorithm's `max_difficulty`,
+/// so that an expensive algorithm like Argon2id doesn't get out of reach of the account's owner.
+const POW_DIFFICULTY_STEP: u64 = 1;
+
+/// How many of the most recent security events are shown.
+const SECURITY_EVENTS_DEPTH: i64 = 20;
+
+struct ThrottleSettings {
+    pow_after_failures: i64,
+    lockout_after_failures: i64,
+    lockout_seconds: i64,
+    failure_window_seconds: i64,
+}
+
+impl ThrottleSettings {
+    fn from_env() -> Self {
+        fn env_or(name: &str, default: i64) -> i64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        Self {
+            pow_after_failures: env_or("LOGIN_POW_AFTER_FAILURES", 3),
+            lockout_after_failures: env_or("LOGIN_LOCKOUT_AFTER_FAILURES", 10),
+            lockout_seconds: env_or("LOGIN_LOCKOUT_SECONDS", 900),
+            failure_window_seconds: env_or("LOGIN_FAILURE_WINDOW_SECONDS", 86400),
+        }
+    }
+
+    fn pow_difficulty(&self, pow: &PowSettings, failures: i64) -> u64 {
+        let extra = (failures - self.pow_after_failures).max(0) as u64;
+        (pow.difficulty + extra * POW_DIFFICULTY_STEP).min(pow.max_difficulty)
+    }
+}
+
+/// The client's IP address, as seen through the trusted proxy if there is one.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> String {
+    std::env::var("TRUSTED_IP_HEADER")
+        .ok()
+        .and_then(|name| headers.get(name))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_else(|| peer.ip().to_string())
+}
+
+#[derive(Default)]
+struct Throttle {
+    failures: i64,
+    locked_until: Option<i64>,
+}
+
+async fn load_throttle(
+    db: &SqlitePool,
+    kind: &str,
+    key: &str,
+    settings: &ThrottleSettings,
+    now: i64,
+) -> anyhow::Result<Throttle> {
+    let window_start = now - settings.failure_window_seconds;
+    Ok(sqlx::query!(
+        "SELECT failures, locked_until_unix_time FROM login_throttle WHERE kind=? AND key=? AND last_failure_at_unix_time>=?",
+        kind,
+        key,
+        window_start
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|row| Throttle {
+        failures: row.failures,
+        locked_until: row.locked_until_unix_time,
+    })
+    .unwrap_or_default())
+}
+
+/// Count one more failure, and lock out if there have been too many.
+/// Returns whether this failure caused a lockout.
+async fn record_failure(
+    db: &SqlitePool,
+    kind: &str,
+    key: &str,
+    settings: &ThrottleSettings,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let window_start = now - settings.failure_window_seconds;
+    let failures = sqlx::query!(
+        "INSERT INTO login_throttle (kind, key, failures, last_failure_at_unix_time) VALUES (?,?,1,?)
+        ON CONFLICT (kind, key) DO UPDATE SET
+            failures = CASE WHEN last_failure_at_unix_time<? THEN 1 ELSE failures+1 END,
+            last_failure_at_unix_time = excluded.last_failure_at_unix_time
+        RETURNING failures",
+        kind,
+        key,
+        now,
+        window_start
+    )
+    .fetch_one(db)
+    .await?
+    .failures;
+
+    if failures < settings.lockout_after_failures {
+        return Ok(false);
+    }
+    let lockouts = failures - settings.lockout_after_failures + 1;
+    let locked_until = now + settings.lockout_seconds * lockouts;
+    sqlx::query!(
+        "UPDATE login_throttle SET locked_until_unix_time=? WHERE kind=? AND key=?",
+        locked_until,
+        kind,
+        key
+    )
+    .execute(db)
+    .await?;
+    tracing::warn!(
+        "Login for {kind} {key} locked out until {locked_until} after {failures} failures"
+    );
+    Ok(true)
+}
+
+/// Decide whether a login attempt may go on to check the password.
+/// If not, returns what to respond with instead.
+pub async fn check_login_allowed(
+    db: &SqlitePool,
+    handle: &str,
+    ip: &str,
+    proof_of_work: Option<&ProofOfWorkAttempt>,
+) -> anyhow::Result<Option<LoginResponse>> {
+    let settings = ThrottleSettings::from_env();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let by_handle = load_throttle(db, "handle", handle, &settings, now).await?;
+    let by_ip = load_throttle(db, "ip", ip, &settings, now).await?;
+
+    let locked_until = by_handle.locked_until.max(by_ip.locked_until);
+    if let Some(until) = locked_until.filter(|until| *until > now) {
+        return Ok(Some(LoginResponse::LockedOut {
+            until_unix_time: until as u64,
+        }));
+    }
+
+    let failures = by_handle.failures.max(by_ip.failures);
+    if failures < settings.pow_after_failures {
+        return Ok(None);
+    }
+    let pow = PowSettings::from_env();
+    let required_difficulty = settings.pow_difficulty(&pow, failures);
+    let subject = format!("login:{handle}");
+    let solved_difficulty = match proof_of_work {
+        Some(attempt) => match check_attempt(db, LOGIN_POW_SALT, &subject, attempt).await {
+            Ok(difficulty) => difficulty,
+            Err(why) => {
+                // Checking costs us a hash, so a bad proof counts like a wrong password,
+                // and enough of them lead to a lockout that is checked before any hashing.
+                // Anyone can send a bad proof for any handle, so it only counts against the IP.
+                tracing::info!("Login for {handle} from {ip} sent a bad proof of work: {why:#}");
+                record_failure(db, "ip", ip, &settings, now).await?;
+                0
+            }
+        },
+        None => 0,
+    };
+    if solved_difficulty < required_difficulty {
+        return Ok(Some(LoginResponse::ProofOfWorkRequired {
+            challenge: sign_challenge(LOGIN_POW_SALT, &subject, pow.algorithm, required_difficulty),
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Count a wrong password, and tell the account's owner if it led to a lockout.
+pub async fn record_login_failure(
+    db: &SqlitePool,
+    handle: &str,
+    ip: &str,
+    account_id: Option<i64>,
+) -> anyhow::Result<()> {
+    let settings = ThrottleSettings::from_env();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let handle_locked = record_failure(db, "handle", handle, &settings, now).await?;
+    record_failure(db, "ip", ip, &settings, now).await?;
+
+    if let (true, Some(account_id)) = (handle_locked, account_id) {
+        record_security_event(db, account_id, SecurityEventKind::LoginLockout, Some(ip)).await?;
+    }
+
+    Ok(())
+}
+
+/// Wrong two-factor codes outside of logging in, like when turning it off, are counted per account,
+/// with the same limits as logins. There is no proof of work for them: past the limit, they are locked out.
+/// Returns until when, if they are.
+pub async fn two_factor_locked_until(
+    db: &SqlitePool,
+    account_id: i64,
+) -> anyhow::Result<Option<i64>> {
+    let settings = ThrottleSettings::from_env();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let throttle = load_throttle(db, "two_factor", &account_id.to_string(), &settings, now).await?;
+    Ok(throttle.locked_until.filter(|until| *until > now))
+}
+
+/// Count a wrong two-factor code given outside of logging in, and tell the owner if it led to a lockout.
+pub async fn record_two_factor_failure(db: &SqlitePool, account_id: i64) -> anyhow::Result<()> {
+    let settings = ThrottleSettings::from_env();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    if record_failure(db, "two_factor", &account_id.to_string(), &settings, now).await? {
+        record_security_event(db, account_id, SecurityEventKind::LoginLockout, None).await?;
+    }
+    Ok(())
+}
+
+/// Registering with a wrong invite code is counted per client IP, with the same limits as logins,
+/// so that codes can't be guessed. Past the limit, registering from that IP is locked out.
+/// Returns until when, if it is.
+pub async fn registration_locked_until(db: &SqlitePool, ip: &str) -> anyhow::Result<Option<i64>> {
+    let settings = ThrottleSettings::from_env();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let throttle = load_throttle(db, "register", ip, &settings, now).await?;
+    Ok(throttle.locked_until.filter(|until| *until > now))
+}
+
+/// Count a registration with a wrong invite code.
+pub async fn record_registration_failure(db: &SqlitePool, ip: &str) -> anyhow::Result<()> {
+    let settings = ThrottleSettings::from_env();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    record_failure(db, "register", ip, &settings, now).await?;
+    Ok(())
+}
+
+/// Tell the account's owner about something that happened to its security, next time they look.
+pub async fn record_security_event(
+    executor: impl SqliteExecutor<'_>,
+    account_id: i64,
+    kind: SecurityEventKind,
+    ip: Option<&str>,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let kind = kind as i64;
+    sqlx::query!(
+        "INSERT INTO security_events (account_id, kind, ip, created_at_unix_time) VALUES (?,?,?,?)",
+        account_id,
+        kind,
+        ip,
+        now
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Forget the handle's failures after a successful login.
+/// The IP's failures are kept until they age out of the window,
+/// so that logging into one's own account doesn't reset guessing at others from the same IP.
+pub async fn record_login_success(db: &SqlitePool, handle: &str) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM login_throttle WHERE kind='handle' AND key=?",
+        handle
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_security_events(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+) -> Result<Json<Vec<SecurityEvent>>, AppError> {
+    let rows = sqlx::query!(
+        "SELECT * FROM security_events WHERE account_id=? ORDER BY created_at_unix_time DESC LIMIT ?",
+        account.account_id,
+        SECURITY_EVENTS_DEPTH
+    )
+    .fetch_all(&db)
+    .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| SecurityEvent {
+                kind: row.kind.into(),
+                ip: row.ip,
+                when_unix_time: row.created_at_unix_time as u64,
+            })
+            .collect(),
+    ))
+}
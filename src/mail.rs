@@ -0,0 +1,41 @@
+//! Sending email to users, like verification codes.
+//!
+//! The sender is chosen with the `MAIL_SENDER` environment variable:
+//! - `smtp`: send through an SMTP server, see [`smtp`] for its settings;
+//! - `log`: don't send anything, only write the mail to the log, for development.
+//!
+//! If it isn't set, features that need email are turned off.
+
+pub mod smtp;
+
+#[async_trait::async_trait]
+pub trait MailSender: Send + Sync {
+    /// Send a plain text mail to one address.
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()>;
+}
+
+/// Whether a mail sender has been set up at all.
+pub fn is_configured() -> bool {
+    std::env::var("MAIL_SENDER").is_ok_and(|v| !v.is_empty())
+}
+
+pub fn configured_sender() -> anyhow::Result<Box<dyn MailSender>> {
+    match std::env::var("MAIL_SENDER").as_deref() {
+        Ok("smtp") => Ok(Box::new(smtp::SmtpSender::from_env()?)),
+        Ok("log") => Ok(Box::new(LogSender)),
+        Ok(other) => Err(anyhow::anyhow!("Unknown MAIL_SENDER {other:?}")),
+        Err(_) => Err(anyhow::anyhow!(
+            "MAIL_SENDER is not set, so mail can't be sent"
+        )),
+    }
+}
+
+struct LogSender;
+
+#[async_trait::async_trait]
+impl MailSender for LogSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        tracing::info!("Mail to {to}: {subject}\n{body}");
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! Sending mail through an SMTP server.
+//!
+//! It is configured with environment variables:
+//! - `SMTP_HOST`: the server to connect to;
+//! - `SMTP_TLS`: `starttls` (the default), `tls`, or `none`; `none` is only for local mail sinks,
+//!   like MailHog on `SMTP_HOST=localhost SMTP_PORT=1025`;
+//! - `SMTP_PORT`: defaults to 587 for `starttls`, 465 for `tls` and 25 for `none`;
+//! - `SMTP_USERNAME` and `SMTP_PASSWORD`: if the server needs a login;
+//! - `MAIL_FROM`: the sender address, like `Pandoc <noreply@example.com>`.
+
+use lettre::{
+    message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+
+use super::MailSender;
+
+pub struct SmtpSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: lettre::message::Mailbox,
+}
+
+impl SmtpSender {
+    pub fn from_env() -> anyhow::Result<Self> {
+        fn required(name: &str) -> anyhow::Result<String> {
+            std::env::var(name).map_err(|_| anyhow::anyhow!("{name} must be set to send mail"))
+        }
+        let host = required("SMTP_HOST")?;
+        let tls = std::env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string());
+
+        let (builder, default_port) = match tls.as_str() {
+            "starttls" => (
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+                587,
+            ),
+            "tls" => (AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?, 465),
+            "none" => (
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+                25,
+            ),
+            other => Err(anyhow::anyhow!(
+                "Unknown SMTP_TLS {other:?}, expected starttls, tls or none"
+            ))?,
+        };
+        let port = match std::env::var("SMTP_PORT") {
+            Ok(port) => port.parse()?,
+            Err(_) => default_port,
+        };
+        let mut builder = builder.port(port);
+        if let (Ok(username), Ok(password)) = (
+            std::env::var("SMTP_USERNAME"),
+            std::env::var("SMTP_PASSWORD"),
+        ) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: required("MAIL_FROM")?.parse()?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl MailSender for SmtpSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
@@ -1,20 +1,46 @@
 mod admin;
+mod allowance;
+mod api_keys;
+mod audit;
+mod auth;
+mod balance;
+mod groups;
+mod invites;
+mod login_links;
+mod login_throttle;
+mod mail;
 mod manager;
+mod passwords;
+mod payments;
 mod pricing;
 mod profile;
+mod promocode_sheet;
+mod refund;
 mod result;
+mod roster;
+mod sessions;
+mod sso;
+#[cfg(test)]
+mod testing;
+mod two_factor;
 mod upload;
 mod verification;
 mod worker;
 
-use api::{OrderInfo, PricingInfo};
+use std::path::PathBuf;
+
+use api::PricingInfo;
 use axum::{
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, State},
+    http::HeaderValue,
+    middleware,
+    response::Response,
     routing::{get, post},
     Json, Router,
 };
+use clap::Parser;
 use manager::{run_manager, ManagerRequest};
-use pricing::get_current_pricing;
+use pricing::{get_current_pricing, get_surged_pricing};
 use tokio::sync::mpsc;
 
 #[derive(Clone)]
@@ -23,16 +49,44 @@ struct AppState {
     manager_connection: mpsc::Sender<ManagerRequest>,
 }
 
+/// Without a command, the server is started.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Make accounts from a roster CSV with `name` and `handle` columns,
+    /// and print their credentials as CSV.
+    ImportRoster {
+        /// The roster file, or `-` for standard input.
+        file: PathBuf,
+        #[command(flatten)]
+        options: roster::ImportRosterOptions,
+    },
+}
+
 #[tokio::main]
 async fn main() {
     let _ = dotenvy::dotenv(); // Try loading values, ignoring missing files.
+    let cli = Cli::parse();
 
-    tracing_subscriber::fmt::init();
+    // Commands print their results to standard output, so their logs go elsewhere.
+    if cli.command.is_some() {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
 
     let url = std::env::var("DATABASE_URL").expect("DATABASE_URL should point at a sqlite db");
     if let Some(prefix) = url.strip_prefix("sqlite://") {
         std::fs::File::options()
             .create(true)
+            .truncate(false)
             .write(true)
             .open(prefix)
             .expect("Could not create db file");
@@ -46,23 +100,36 @@ async fn main() {
         .await
         .expect("Failed to apply migrations");
 
+    if let Some(Command::ImportRoster { file, options }) = cli.command {
+        if let Err(why) = roster::run_cli(&db, file, options).await {
+            eprintln!("Import failed: {why:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Mark all orders that were running before with an abnormal termination.
-    let abnormal = serde_json::to_string(&OrderInfo {
-        balance_before: 0.0,
-        order_cost: 0.0,
-        pricing_applied: get_current_pricing(),
-        termination: api::JobTerminationStatus::VeryAbnormalTermination(format!(
-            "Job was marked as running across application restart"
-        )),
-    })
-    .unwrap();
-    sqlx::query!(
-        "UPDATE orders SET status_json=?, is_running=0 WHERE is_running=1",
-        abnormal
-    )
-    .execute(&db)
-    .await
-    .unwrap();
+    // They are written down with the pricing they locked in, if they got as far as that.
+    let interrupted = sqlx::query!("SELECT id, pricing_json FROM orders WHERE is_running=1")
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    for order in interrupted {
+        let pricing = order
+            .pricing_json
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_else(get_current_pricing);
+        worker::finish_failed_order(
+            &db,
+            order.id,
+            pricing,
+            api::JobTerminationStatus::VeryAbnormalTermination(
+                "Job was marked as running across application restart".to_string(),
+            ),
+        )
+        .await
+        .unwrap();
+    }
 
     let (manager_connection, manager_rx) = mpsc::channel(100);
     let cancel = tokio_util::sync::CancellationToken::new();
@@ -74,10 +141,10 @@ async fn main() {
         cancel.clone(),
     ));
 
-    // build our application with a single route
-    let app = Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
-        .route("/pricing", get(get_quote))
+    // These routes take the token as part of the path, which ends up in logs and browser history.
+    // They are kept for old clients; new ones should use the `/me` routes
+    // with the token in the `Authorization` header or the session cookie.
+    let legacy_token_routes = Router::new()
         .route("/user-info/:token", get(profile::get_user))
         .route(
             "/user-info/:token/redeem/:code",
@@ -91,12 +158,25 @@ async fn main() {
             "/user-info/:token/verification/proof-of-work/verify-challenge",
             post(verification::proof_of_work::verify_challenge),
         )
-        .route("/user-info/login", post(profile::login))
+        .route("/user-info/:token/transfer", post(balance::transfer))
+        .route(
+            "/user-info/:token/balance-history",
+            get(balance::get_balance_history),
+        )
+        .route(
+            "/user-info/:token/payments/new",
+            post(payments::new_payment),
+        )
+        .route(
+            "/user-info/:token/payments/:id",
+            get(payments::get_payment),
+        )
         .route(
             "/user-info/:token/change-password",
             post(profile::change_password),
         )
         .route("/orders/:token/new", post(upload::upload_order))
+        .route("/orders/:token/estimate", post(upload::estimate_order_cost))
         .route("/orders/:token/:id", get(upload::get_order_status))
         .route("/orders/:token/:id/files", get(upload::get_order_file_list))
         .route(
@@ -108,10 +188,111 @@ async fn main() {
             "/orders/:token/:id/stream/:stream",
             get(upload::get_live_order_stream),
         )
+        .layer(middleware::map_response(mark_deprecated));
+
+    // build our application with a single route
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .route("/pricing", get(get_quote))
+        .route("/me", get(profile::get_user))
+        .route("/me/redeem/:code", post(profile::redeem_promocode))
+        .route(
+            "/me/verification/proof-of-work/get-challenge",
+            get(verification::proof_of_work::get_challenge),
+        )
+        .route(
+            "/me/verification/proof-of-work/verify-challenge",
+            post(verification::proof_of_work::verify_challenge),
+        )
+        .route(
+            "/me/verification/email/send-code",
+            post(verification::email_code::send_code),
+        )
+        .route(
+            "/me/verification/email/confirm-code",
+            post(verification::email_code::confirm_code),
+        )
+        .route(
+            "/me/verification/request-approval",
+            post(verification::admin_approval::request_approval),
+        )
+        .route("/me/transfer", post(balance::transfer))
+        .route("/me/balance-history", get(balance::get_balance_history))
+        .route("/me/payments/new", post(payments::new_payment))
+        .route("/me/payments/:id", get(payments::get_payment))
+        .route("/me/change-password", post(profile::change_password))
+        .route("/me/sessions", get(sessions::list_sessions))
+        .route("/me/sessions/:id/revoke", post(sessions::revoke_session))
+        .route("/me/logout", post(sessions::logout))
+        .route("/me/security-events", get(login_throttle::get_security_events))
+        .route("/me/two-factor", get(two_factor::get_status))
+        .route("/me/two-factor/begin", post(two_factor::begin))
+        .route("/me/two-factor/confirm", post(two_factor::confirm))
+        .route("/me/two-factor/disable", post(two_factor::disable))
+        .route("/me/api-keys", get(api_keys::list_api_keys))
+        .route("/me/api-keys/new", post(api_keys::new_api_key))
+        .route("/me/api-keys/:id/revoke", post(api_keys::revoke_api_key))
+        .route("/me/orders/new", post(upload::upload_order))
+        .route("/me/orders/estimate", post(upload::estimate_order_cost))
+        .route("/me/orders/:id", get(upload::get_order_status))
+        .route("/me/orders/:id/files", get(upload::get_order_file_list))
+        .route(
+            "/me/orders/:id/files/download/:name",
+            get(upload::fetch_file),
+        )
+        .route("/me/orders/:id/ws", get(upload::get_live_order_status))
+        .route(
+            "/me/orders/:id/stream/:stream",
+            get(upload::get_live_order_stream),
+        )
+        .route("/payments/webhook", post(payments::webhook))
+        .route("/user-info/login", post(profile::login))
+        .route("/login-link", post(login_links::use_login_link))
+        .route("/register", post(invites::register))
+        .route("/sso", get(sso::get_info))
+        .route("/sso/login", get(sso::login))
+        .route("/sso/callback", get(sso::callback))
+        .merge(legacy_token_routes)
+        .route("/admin/bootstrap-admin", post(admin::bootstrap_admin))
         .route("/admin/make-user", post(admin::make_user))
+        .route("/admin/import-roster", post(admin::import_roster))
+        .route("/admin/set-role", post(admin::set_role))
+        .route("/admin/fetch-audit-log", get(admin::fetch_audit_log))
         .route("/admin/fetch-promocodes", get(admin::fetch_promocodes))
         .route("/admin/make-promocodes", post(admin::make_promocodes))
+        .route("/admin/print-promocodes", get(admin::print_promocodes))
+        .route("/admin/make-invite-code", post(admin::make_invite_code))
+        .route("/admin/fetch-invite-codes", get(admin::fetch_invite_codes))
+        .route(
+            "/admin/deactivate-invite-code",
+            post(admin::deactivate_invite_code),
+        )
         .route("/admin/reset-password", post(admin::reset_password))
+        .route("/admin/refund-order", post(admin::refund_order))
+        .route("/admin/make-group", post(admin::make_group))
+        .route("/admin/fund-group", post(admin::fund_group))
+        .route("/admin/set-group-member", post(admin::set_group_member))
+        .route("/admin/remove-group-member", post(admin::remove_group_member))
+        .route(
+            "/admin/make-allowance-rule",
+            post(admin::make_allowance_rule),
+        )
+        .route(
+            "/admin/fetch-allowance-rules",
+            get(admin::fetch_allowance_rules),
+        )
+        .route(
+            "/admin/deactivate-allowance-rule",
+            post(admin::deactivate_allowance_rule),
+        )
+        .route(
+            "/admin/fetch-verification-requests",
+            get(admin::fetch_verification_requests),
+        )
+        .route(
+            "/admin/decide-verification-request",
+            post(admin::decide_verification_request),
+        )
         .layer(DefaultBodyLimit::max(100 * 1024 * 1024)) // 100MB
         .with_state(AppState {
             db,
@@ -128,12 +309,25 @@ async fn main() {
 
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // The client's address is needed to slow down password guessing.
+    let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
     axum::serve(listener, app)
         .with_graceful_shutdown(cancel.cancelled_owned())
         .await
         .unwrap();
 }
 
-async fn get_quote() -> Json<PricingInfo> {
-    Json(get_current_pricing())
+async fn mark_deprecated(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert("Deprecation", HeaderValue::from_static("true"));
+    response
+}
+
+async fn get_quote(
+    State(AppState {
+        manager_connection, ..
+    }): State<AppState>,
+) -> Json<PricingInfo> {
+    Json(get_surged_pricing(&manager_connection).await)
 }
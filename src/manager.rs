@@ -1,10 +1,10 @@
 use std::collections::HashMap;
-use api::{JobTerminationStatus, OrderInfo};
+use api::JobTerminationStatus;
 use sqlx::SqlitePool;
 use tokio::{sync::{broadcast::error::TryRecvError, mpsc, oneshot}, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 
-use crate::{pricing::get_current_pricing, worker::{self, RunningJobHandle}};
+use crate::{allowance, pricing::get_current_pricing, worker::{self, finish_failed_order, RunningJobHandle}};
 
 #[derive(Debug)]
 pub enum ManagerRequest {
@@ -23,6 +23,7 @@ pub enum ManagerRequest {
         order_id: i64,
         file_list: Vec<String>,
         file_size_mb: f64,
+        budget: Option<f64>,
     },
 
     /// Sent by a worker thread in order to announce its existence and register its comms.
@@ -43,8 +44,17 @@ pub enum ManagerRequest {
         recv: oneshot::Sender<Option<RunningJobHandle>>
     },
 
+    /// Asks how many orders are currently executing.
+    /// Used to compute the surge pricing multiplier.
+    QueryRunningJobs {
+        recv: oneshot::Sender<usize>,
+    },
+
     /// An internal message sent occasionally asking the manager to garbage-collect dead jobs.
     PruneDeadJobs,
+
+    /// An internal message sent occasionally asking the manager to give out the monthly allowances that are due.
+    ApplyAllowances,
 }
 
 impl ManagerRequest {
@@ -60,8 +70,8 @@ impl ManagerRequest {
         recv.await
             .expect("Manager didn't respond to allocate_order")
     }
-    pub async fn uploaded_files(sender: &mpsc::Sender<ManagerRequest>, order_id: i64, file_list: Vec<String>, file_size_mb: f64) {
-        sender.send(Self::UploadFiles { order_id, file_list, file_size_mb }).await.expect("Manager thread closed");
+    pub async fn uploaded_files(sender: &mpsc::Sender<ManagerRequest>, order_id: i64, file_list: Vec<String>, file_size_mb: f64, budget: Option<f64>) {
+        sender.send(Self::UploadFiles { order_id, file_list, file_size_mb, budget }).await.expect("Manager thread closed");
     }
 
     pub async fn query_live_status(sender: &mpsc::Sender<ManagerRequest>, order_id: i64) -> Option<RunningJobHandle> {
@@ -77,6 +87,16 @@ impl ManagerRequest {
             .expect("Manager didn't respond to query_live_status")
     }
 
+    pub async fn query_running_jobs(sender: &mpsc::Sender<ManagerRequest>) -> usize {
+        let (send, recv) = oneshot::channel();
+        sender
+            .send(Self::QueryRunningJobs { recv: send })
+            .await
+            .expect("Manager thread closed");
+        recv.await
+            .expect("Manager didn't respond to query_running_jobs")
+    }
+
 }
 
 
@@ -97,6 +117,14 @@ pub async fn run_manager(
         }
     });
 
+    let send_out = send.clone();
+    tokio::spawn(async move {
+        loop {
+            send_out.send(ManagerRequest::ApplyAllowances).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(10 * 60)).await;
+        }
+    });
+
     loop {
         tokio::select! {
             _ = cancel.cancelled() => panic!("Cancellation token caused manager thread to stop"),
@@ -139,12 +167,12 @@ async fn handle_msg(msg: ManagerRequest, db: &SqlitePool, running_handles: &mut
                 std::fs::remove_dir_all(format!("/compile/{id}"))?;
             }
         },
-        ManagerRequest::UploadFiles { order_id, file_list, file_size_mb } => {
+        ManagerRequest::UploadFiles { order_id, file_list, file_size_mb, budget } => {
             let file_list_json = serde_json::to_string(&file_list)?;
             let file_count = file_list.len();
             sqlx::query!("UPDATE orders SET src_file_list=? WHERE id=?", file_list_json, order_id).execute(db).await?;
             tracing::debug!("Spawning a new task to work on order {order_id}");
-            join_handles.insert(order_id, tokio::task::spawn(worker::run_order_work(order_id, db.clone(), sender.clone(), (file_count, file_size_mb))));
+            join_handles.insert(order_id, tokio::task::spawn(worker::run_order_work(order_id, db.clone(), sender.clone(), (file_count, file_size_mb), budget)));
         },
 
         ManagerRequest::BeginWork { order_id, handle } => {
@@ -174,6 +202,17 @@ async fn handle_msg(msg: ManagerRequest, db: &SqlitePool, running_handles: &mut
             }
         },
 
+        ManagerRequest::QueryRunningJobs { recv } => {
+            let _ = recv.send(running_handles.len());
+        },
+
+        ManagerRequest::ApplyAllowances => {
+            let granted = allowance::apply_due_allowances(db).await?;
+            if granted > 0 {
+                tracing::info!("Gave out {granted} monthly allowances");
+            }
+        },
+
         ManagerRequest::PruneDeadJobs => {
             // Loop through the job join handles.
             // If any have finished, check their status to know what to write.
@@ -186,7 +225,10 @@ async fn handle_msg(msg: ManagerRequest, db: &SqlitePool, running_handles: &mut
 
             for id in to_take {
                 let handle = join_handles.remove(&id).unwrap();
-                running_handles.remove(&id);
+                let pricing = match running_handles.remove(&id) {
+                    Some(running) => running.pricing,
+                    None => get_current_pricing(),
+                };
                 let status = match handle.await {
                     Err(panic) => Some(JobTerminationStatus::VeryAbnormalTermination(format!("Task panic: {panic}"))),
                     Ok(Err(result_err)) => Some(JobTerminationStatus::VeryAbnormalTermination(format!("Task returned Err: {result_err}"))),
@@ -194,9 +236,7 @@ async fn handle_msg(msg: ManagerRequest, db: &SqlitePool, running_handles: &mut
                 };
 
                 if let Some(status) = status {
-                    let info = OrderInfo { balance_before: 0.0, order_cost: 0.0, pricing_applied: get_current_pricing(), termination: status };
-                    let status_json = serde_json::to_string(&info).unwrap();
-                    sqlx::query!("UPDATE orders SET is_running=0, status_json=? WHERE id=?", status_json, id).execute(db).await?;
+                    finish_failed_order(db, id, pricing, status).await?;
                 }
             }
         }
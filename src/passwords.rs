@@ -0,0 +1,162 @@
+//! Hashing and checking login passwords.
+//!
+//! Hashes are stored as PHC strings, which carry their own parameters:
+//! after the parameters are changed, old hashes still work, and are redone on the next login.
+
+use api::PasswordProblem;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+
+/// Passwords longer than this are refused: there's no point in hashing megabytes.
+const MAX_PASSWORD_LENGTH: usize = 256;
+
+/// How passwords are hashed, and which ones are accepted.
+///
+/// Configured with environment variables:
+/// - `PASSWORD_ARGON2_MEMORY_KIB`, `PASSWORD_ARGON2_ITERATIONS` and `PASSWORD_ARGON2_PARALLELISM`:
+///   the Argon2id cost (default 19456 KiB, 2 iterations and 1 lane);
+/// - `PASSWORD_MIN_LENGTH`: the shortest password accepted (default 8).
+#[derive(Debug, Clone)]
+pub struct PasswordSettings {
+    pub params: Params,
+    pub min_length: usize,
+}
+
+impl PasswordSettings {
+    pub fn from_env() -> Self {
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        let params = match Params::new(
+            env_or("PASSWORD_ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+            env_or("PASSWORD_ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+            env_or("PASSWORD_ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+            None,
+        ) {
+            Ok(params) => params,
+            Err(why) => panic!("Invalid Argon2 password hashing parameters: {why}"),
+        };
+        Self {
+            params,
+            min_length: env_or("PASSWORD_MIN_LENGTH", 8),
+        }
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Whether the hash was made some other way than it would be now.
+    fn is_outdated(&self, hash: &PasswordHash) -> bool {
+        let same_params = Params::try_from(hash).is_ok_and(|params| {
+            params.m_cost() == self.params.m_cost()
+                && params.t_cost() == self.params.t_cost()
+                && params.p_cost() == self.params.p_cost()
+        });
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || !same_params
+    }
+}
+
+/// Hash a password in the form that is stored in the `logins` table.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = PasswordSettings::from_env()
+        .hasher()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|why| anyhow::anyhow!("Failed to hash the password: {why}"))?;
+    Ok(hash.to_string())
+}
+
+/// [`hash_password`] for the request handlers: with the configured costs, hashing can take long enough
+/// to hold up other requests, so it is done on the blocking thread pool.
+pub async fn hash_password_off_thread(password: &str) -> anyhow::Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash_password(&password)).await?
+}
+
+pub enum PasswordCheck {
+    /// The account has no password: it was made for single sign-on or with a login link,
+    /// and nobody has set one yet.
+    NotSet,
+    Wrong,
+    /// The password is right; if the hash is outdated, it should be made again while the password is at hand.
+    Correct {
+        needs_rehash: bool,
+    },
+}
+
+/// Check a password against the hash stored in the `logins` table.
+pub fn check_password(stored_hash: &str, password: &str) -> PasswordCheck {
+    if stored_hash.is_empty() {
+        return PasswordCheck::NotSet;
+    }
+    let hash = match PasswordHash::new(stored_hash) {
+        Ok(hash) => hash,
+        Err(why) => {
+            // Nothing can log in with it, but it shouldn't take the server down either.
+            tracing::error!("A stored password hash is malformed: {why}");
+            return PasswordCheck::Wrong;
+        }
+    };
+    // Verifying uses the parameters from the hash, not the current ones.
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_err()
+    {
+        return PasswordCheck::Wrong;
+    }
+    PasswordCheck::Correct {
+        needs_rehash: PasswordSettings::from_env().is_outdated(&hash),
+    }
+}
+
+/// [`check_password`] for the request handlers, on the blocking thread pool like [`hash_password_off_thread`].
+pub async fn check_password_off_thread(
+    stored_hash: &str,
+    password: &str,
+) -> anyhow::Result<PasswordCheck> {
+    let stored_hash = stored_hash.to_string();
+    let password = password.to_string();
+    Ok(tokio::task::spawn_blocking(move || check_password(&stored_hash, &password)).await?)
+}
+
+/// Check a new password against the rules.
+pub fn check_password_policy(password: &str, handle: &str) -> Result<(), PasswordProblem> {
+    let min_length = PasswordSettings::from_env().min_length;
+    let length = password.chars().count();
+    if length < min_length {
+        return Err(PasswordProblem::TooShort { min_length });
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(PasswordProblem::TooLong {
+            max_length: MAX_PASSWORD_LENGTH,
+        });
+    }
+    if password.eq_ignore_ascii_case(handle) {
+        return Err(PasswordProblem::SameAsHandle);
+    }
+    Ok(())
+}
+
+/// The same check, for the admin routes, which report problems as errors.
+pub fn require_password_policy(password: &str, handle: &str) -> anyhow::Result<()> {
+    check_password_policy(password, handle).map_err(|problem| match problem {
+        PasswordProblem::TooShort { min_length } => {
+            anyhow::anyhow!("The password must be at least {min_length} characters long")
+        }
+        PasswordProblem::TooLong { max_length } => {
+            anyhow::anyhow!("The password must be at most {max_length} characters long")
+        }
+        PasswordProblem::SameAsHandle => {
+            anyhow::anyhow!("The password must not be the same as the handle")
+        }
+    })
+}
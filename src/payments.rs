@@ -0,0 +1,577 @@
+//! Adding money to the balance through an external payment provider.
+//!
+//! The user makes a payment intent for an amount, and gets sent to the provider's checkout page.
+//! The provider then calls the webhook with a signed message saying how the payment went,
+//! and the account is credited when the payment has succeeded.
+//! Providers may deliver the same callback many times, so every callback is only acted on once.
+
+pub mod http_provider;
+
+use api::{BalanceChangeKind, NewPaymentRequest, NewPaymentResponse, PaymentInfo, PaymentStatus};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    auth::{CurrentAccount, ReadProfileAccess},
+    balance::record_balance_change,
+    result::AppError,
+    AppState,
+};
+
+/// What the provider needs to know to make a payment.
+pub struct PaymentIntent {
+    /// Our ID for the payment, which the provider gives back in the webhook.
+    pub id: String,
+    pub amount: f64,
+
+    /// Where the provider should send the user after they pay.
+    pub return_url: String,
+
+    /// Where the provider should send the webhook callbacks.
+    pub callback_url: String,
+}
+
+pub struct CreatedPayment {
+    /// The provider's ID for the payment.
+    pub provider_payment_id: String,
+
+    /// Where to send the user to pay.
+    pub redirect_url: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PaymentOutcome {
+    Succeeded,
+    Failed,
+}
+
+/// A webhook callback whose signature has been checked.
+#[derive(Debug)]
+pub struct WebhookEvent {
+    /// Unique for each event; a repeated delivery of the same event has the same ID.
+    pub event_id: String,
+
+    /// Our ID for the payment, from [`PaymentIntent::id`].
+    pub payment_id: String,
+    pub amount: f64,
+    pub outcome: PaymentOutcome,
+}
+
+#[async_trait::async_trait]
+pub trait PaymentProvider: Send + Sync {
+    /// Stored with each payment, so that callbacks from a different provider are not accepted for it.
+    fn name(&self) -> &'static str;
+
+    /// Register the payment with the provider.
+    async fn create_payment(&self, intent: &PaymentIntent) -> anyhow::Result<CreatedPayment>;
+
+    /// Check that the callback really came from the provider, and parse it.
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<WebhookEvent>;
+}
+
+/// The provider is chosen with the `PAYMENT_PROVIDER` environment variable.
+/// The only one right now is `http`: see [`http_provider`] for its settings.
+pub fn configured_provider() -> anyhow::Result<Box<dyn PaymentProvider>> {
+    match std::env::var("PAYMENT_PROVIDER").as_deref() {
+        Ok("http") => Ok(Box::new(http_provider::HttpProvider::from_env()?)),
+        Ok(other) => Err(anyhow::anyhow!("Unknown payment provider: {other}")),
+        Err(_) => Err(anyhow::anyhow!("PAYMENT_PROVIDER is not set")),
+    }
+}
+
+/// The smallest and largest payment allowed,
+/// configured with `PAYMENT_MIN_AMOUNT` (default 100) and `PAYMENT_MAX_AMOUNT` (default 100000).
+fn amount_limits() -> (f64, f64) {
+    fn env_or(name: &str, default: f64) -> f64 {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    }
+    (
+        env_or("PAYMENT_MIN_AMOUNT", 100.0),
+        env_or("PAYMENT_MAX_AMOUNT", 100000.0),
+    )
+}
+
+pub async fn new_payment(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Json(NewPaymentRequest { amount }): Json<NewPaymentRequest>,
+) -> Result<Json<NewPaymentResponse>, AppError> {
+    let provider = match configured_provider() {
+        Ok(provider) => provider,
+        Err(why) => {
+            tracing::warn!("Payment requested, but payments are unavailable: {why}");
+            return Ok(Json(NewPaymentResponse::Unavailable));
+        }
+    };
+    let frontend_url = match std::env::var("FRONTEND_URL") {
+        Ok(url) => url.trim_end_matches('/').to_string(),
+        Err(_) => {
+            tracing::warn!("Payment requested, but FRONTEND_URL is not set");
+            return Ok(Json(NewPaymentResponse::Unavailable));
+        }
+    };
+
+    let (min, max) = amount_limits();
+    if amount.is_nan() || amount < min || amount > max {
+        return Ok(Json(NewPaymentResponse::InvalidAmount { min, max }));
+    }
+
+    use rand::distributions::DistString;
+    let id = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let provider_name = provider.name();
+
+    sqlx::query!(
+        "INSERT INTO payments (id, account_id, amount, provider, created_at_unix_time) VALUES (?,?,?,?,?)",
+        id,
+        account.account_id,
+        amount,
+        provider_name,
+        now
+    )
+    .execute(&db)
+    .await?;
+
+    let intent = PaymentIntent {
+        id: id.clone(),
+        amount,
+        return_url: format!("{frontend_url}/payment/{id}"),
+        callback_url: format!("{frontend_url}/api/payments/webhook"),
+    };
+    let created = match provider.create_payment(&intent).await {
+        Ok(created) => created,
+        Err(why) => {
+            let failed = PaymentStatus::Failed as i64;
+            sqlx::query!(
+                "UPDATE payments SET status=?, completed_at_unix_time=? WHERE id=?",
+                failed,
+                now,
+                id
+            )
+            .execute(&db)
+            .await?;
+            return Err(why.context("The payment provider did not accept the payment"))?;
+        }
+    };
+
+    sqlx::query!(
+        "UPDATE payments SET provider_payment_id=? WHERE id=?",
+        created.provider_payment_id,
+        id
+    )
+    .execute(&db)
+    .await?;
+
+    Ok(Json(NewPaymentResponse::Redirect {
+        payment_id: id,
+        redirect_url: created.redirect_url,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PaymentPath {
+    id: String,
+}
+
+pub async fn get_payment(
+    State(AppState { db, .. }): State<AppState>,
+    ReadProfileAccess(account): ReadProfileAccess,
+    Path(PaymentPath { id: payment_id }): Path<PaymentPath>,
+) -> Result<Json<Option<PaymentInfo>>, AppError> {
+    let payment = sqlx::query!(
+        "SELECT * FROM payments WHERE account_id=? AND id=?",
+        account.account_id,
+        payment_id
+    )
+    .fetch_optional(&db)
+    .await?;
+
+    Ok(Json(payment.map(|row| PaymentInfo {
+        id: row.id,
+        amount: row.amount,
+        status: row.status.into(),
+        created_at_unix_time: row.created_at_unix_time as u64,
+    })))
+}
+
+pub async fn webhook(
+    State(AppState { db, .. }): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, AppError> {
+    let provider = configured_provider()?;
+    let event = match provider.verify_webhook(&headers, &body) {
+        Ok(event) => event,
+        Err(why) => {
+            tracing::warn!("Rejected payment webhook: {why:#}");
+            return Ok(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let mut tx = db.begin().await?;
+
+    let payment = match sqlx::query!("SELECT * FROM payments WHERE id=?", event.payment_id)
+        .fetch_optional(&mut *tx)
+        .await?
+    {
+        Some(row) => row,
+        None => Err(anyhow::anyhow!(
+            "Payment webhook for unknown payment: {event:?}"
+        ))?,
+    };
+    if payment.provider != provider.name() {
+        Err(anyhow::anyhow!(
+            "Payment {} was made with provider {}, but the webhook came from {}",
+            payment.id,
+            payment.provider,
+            provider.name()
+        ))?
+    }
+
+    // A replayed event is acknowledged, so that the provider stops sending it, but nothing else is done.
+    let is_new_event = sqlx::query!(
+        "INSERT OR IGNORE INTO payment_webhook_events (event_id, payment_id, received_at_unix_time) VALUES (?,?,?)",
+        event.event_id,
+        payment.id,
+        now
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !is_new_event {
+        tracing::info!("Ignoring repeated payment webhook event {}", event.event_id);
+        // Ended here rather than on drop, so that its lock is gone before the provider's next callback.
+        tx.commit().await?;
+        return Ok(StatusCode::OK);
+    }
+
+    // Only a pending payment can change its status,
+    // so different events about the same payment can't credit it twice.
+    let pending = PaymentStatus::Pending as i64;
+    match event.outcome {
+        PaymentOutcome::Succeeded => {
+            if (event.amount - payment.amount).abs() > 1e-6 {
+                Err(anyhow::anyhow!(
+                    "Payment {} is for {}, but the provider says {} was paid",
+                    payment.id,
+                    payment.amount,
+                    event.amount
+                ))?
+            }
+
+            let succeeded = PaymentStatus::Succeeded as i64;
+            let changed = sqlx::query!(
+                "UPDATE payments SET status=?, completed_at_unix_time=? WHERE id=? AND status=?",
+                succeeded,
+                now,
+                payment.id,
+                pending
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            if changed > 0 {
+                sqlx::query!(
+                    "UPDATE accounts SET balance=balance+? WHERE id=?",
+                    payment.amount,
+                    payment.account_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                record_balance_change(
+                    &mut tx,
+                    payment.account_id,
+                    payment.amount,
+                    BalanceChangeKind::Payment,
+                    None,
+                    None,
+                )
+                .await?;
+                tracing::info!(
+                    "Payment {} credited {} to account {}",
+                    payment.id,
+                    payment.amount,
+                    payment.account_id
+                );
+            }
+        }
+        PaymentOutcome::Failed => {
+            let failed = PaymentStatus::Failed as i64;
+            sqlx::query!(
+                "UPDATE payments SET status=?, completed_at_unix_time=? WHERE id=? AND status=?",
+                failed,
+                now,
+                payment.id,
+                pending
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use api::Role;
+    use axum::{http::HeaderValue, routing::post, Router};
+    use hmac::{Hmac, Mac};
+    use serde_json::{json, Value};
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::testing::{serve, set_env, TestEnv, TestServer};
+
+    const API_KEY: &str = "test-api-key";
+    const WEBHOOK_SECRET: &str = "test-webhook-secret";
+
+    /// The payments that the mock provider was asked to make.
+    type Created = Arc<Mutex<Vec<Value>>>;
+
+    /// A stand-in for the provider's API, which accepts every payment made with the right key.
+    async fn start_provider() -> (String, Created) {
+        let created = Created::default();
+        let router = Router::new()
+            .route(
+                "/payments",
+                post(
+                    |State(created): State<Created>,
+                     headers: HeaderMap,
+                     Json(request): Json<Value>| async move {
+                        if headers[axum::http::header::AUTHORIZATION] != format!("Bearer {API_KEY}")
+                        {
+                            return Err(StatusCode::UNAUTHORIZED);
+                        }
+                        let reference = request["reference"].as_str().unwrap().to_string();
+                        created.lock().unwrap().push(request);
+                        Ok(Json(json!({
+                            "id": format!("provider-{reference}"),
+                            "checkout_url": format!("http://provider.test/checkout/{reference}"),
+                        })))
+                    },
+                ),
+            )
+            .with_state(created.clone());
+        (serve(router).await, created)
+    }
+
+    async fn payment_env(provider_url: &str) -> TestEnv {
+        set_env(&[
+            ("PAYMENT_PROVIDER", "http"),
+            ("PAYMENT_PROVIDER_URL", provider_url),
+            ("PAYMENT_PROVIDER_API_KEY", API_KEY),
+            ("PAYMENT_WEBHOOK_SECRET", WEBHOOK_SECRET),
+        ])
+        .await
+    }
+
+    fn now() -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn signature(secret: &str, timestamp: i64, body: &[u8]) -> HeaderValue {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        let mac = hex::encode(mac.finalize().into_bytes());
+        format!("t={timestamp},v1={mac}").parse().unwrap()
+    }
+
+    fn event_body(event_id: &str, payment_id: &str, amount: f64, status: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "event_id": event_id,
+            "reference": payment_id,
+            "amount": amount,
+            "status": status,
+        }))
+        .unwrap()
+    }
+
+    async fn deliver(
+        server: &TestServer,
+        signature: Option<HeaderValue>,
+        body: Vec<u8>,
+    ) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        if let Some(signature) = signature {
+            headers.insert("X-Payment-Signature", signature);
+        }
+        webhook(State(server.state.clone()), headers, Bytes::from(body))
+            .await
+            .unwrap()
+    }
+
+    /// Deliver the event the way the provider would, signed just now.
+    async fn deliver_signed(server: &TestServer, body: Vec<u8>) -> StatusCode {
+        let signature = signature(WEBHOOK_SECRET, now(), &body);
+        deliver(server, Some(signature), body).await
+    }
+
+    async fn pay(server: &TestServer, account_id: i64, amount: f64) -> String {
+        let response = new_payment(
+            State(server.state.clone()),
+            CurrentAccount {
+                account_id,
+                session_id: None,
+                api_key: None,
+            },
+            Json(NewPaymentRequest { amount }),
+        )
+        .await
+        .unwrap()
+        .0;
+        match response {
+            NewPaymentResponse::Redirect {
+                payment_id,
+                redirect_url,
+            } => {
+                assert_eq!(
+                    redirect_url,
+                    format!("http://provider.test/checkout/{payment_id}")
+                );
+                payment_id
+            }
+            other => panic!("The payment wasn't made: {other:?}"),
+        }
+    }
+
+    async fn balance(server: &TestServer, account_id: i64) -> f64 {
+        sqlx::query_scalar("SELECT balance FROM accounts WHERE id=?")
+            .bind(account_id)
+            .fetch_one(server.db())
+            .await
+            .unwrap()
+    }
+
+    async fn status(server: &TestServer, payment_id: &str) -> PaymentStatus {
+        sqlx::query_scalar::<_, i64>("SELECT status FROM payments WHERE id=?")
+            .bind(payment_id)
+            .fetch_one(server.db())
+            .await
+            .unwrap()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn paid_payment_is_credited_once() {
+        let (provider_url, created) = start_provider().await;
+        let _env = payment_env(&provider_url).await;
+        let server = TestServer::new().await;
+        let student = server.make_account("student", Role::Student, None).await;
+
+        let payment_id = pay(&server, student, 500.0).await;
+        {
+            let created = created.lock().unwrap();
+            assert_eq!(created.len(), 1);
+            assert_eq!(created[0]["reference"], payment_id.as_str());
+            assert_eq!(
+                created[0]["callback_url"],
+                "http://frontend.test/api/payments/webhook"
+            );
+        }
+        assert_eq!(status(&server, &payment_id).await, PaymentStatus::Pending);
+
+        let paid = event_body("event-1", &payment_id, 500.0, "succeeded");
+        assert_eq!(deliver_signed(&server, paid.clone()).await, StatusCode::OK);
+        assert_eq!(balance(&server, student).await, 500.0);
+        assert_eq!(status(&server, &payment_id).await, PaymentStatus::Succeeded);
+
+        // The provider retries deliveries, and may say the same thing in a new event.
+        assert_eq!(deliver_signed(&server, paid).await, StatusCode::OK);
+        let again = event_body("event-2", &payment_id, 500.0, "succeeded");
+        assert_eq!(deliver_signed(&server, again).await, StatusCode::OK);
+        assert_eq!(balance(&server, student).await, 500.0);
+
+        let history: Vec<f64> =
+            sqlx::query_scalar("SELECT amount FROM balance_history WHERE account_id=? AND kind=?")
+                .bind(student)
+                .bind(BalanceChangeKind::Payment as i64)
+                .fetch_all(server.db())
+                .await
+                .unwrap();
+        assert_eq!(history, vec![500.0]);
+    }
+
+    #[tokio::test]
+    async fn badly_signed_callbacks_are_rejected() {
+        let (provider_url, _) = start_provider().await;
+        let _env = payment_env(&provider_url).await;
+        let server = TestServer::new().await;
+        let student = server.make_account("student", Role::Student, None).await;
+        let payment_id = pay(&server, student, 500.0).await;
+        let paid = event_body("event-1", &payment_id, 500.0, "succeeded");
+
+        let unsigned = deliver(&server, None, paid.clone()).await;
+        let wrong_secret = deliver(
+            &server,
+            Some(signature("not-the-secret", now(), &paid)),
+            paid.clone(),
+        )
+        .await;
+        let stale = deliver(
+            &server,
+            Some(signature(WEBHOOK_SECRET, now() - 3600, &paid)),
+            paid.clone(),
+        )
+        .await;
+        let tampered = deliver(
+            &server,
+            Some(signature(WEBHOOK_SECRET, now(), &paid)),
+            event_body("event-1", &payment_id, 5000.0, "succeeded"),
+        )
+        .await;
+        for status in [unsigned, wrong_secret, stale, tampered] {
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(balance(&server, student).await, 0.0);
+        assert_eq!(status(&server, &payment_id).await, PaymentStatus::Pending);
+
+        // Rejected callbacks don't use up the event, so the real one still goes through.
+        assert_eq!(deliver_signed(&server, paid).await, StatusCode::OK);
+        assert_eq!(balance(&server, student).await, 500.0);
+    }
+
+    #[tokio::test]
+    async fn failed_payment_is_never_credited() {
+        let (provider_url, _) = start_provider().await;
+        let _env = payment_env(&provider_url).await;
+        let server = TestServer::new().await;
+        let student = server.make_account("student", Role::Student, None).await;
+        let payment_id = pay(&server, student, 500.0).await;
+
+        let failed = event_body("event-1", &payment_id, 500.0, "failed");
+        assert_eq!(deliver_signed(&server, failed).await, StatusCode::OK);
+        assert_eq!(status(&server, &payment_id).await, PaymentStatus::Failed);
+
+        let paid = event_body("event-2", &payment_id, 500.0, "succeeded");
+        assert_eq!(deliver_signed(&server, paid).await, StatusCode::OK);
+        assert_eq!(balance(&server, student).await, 0.0);
+        assert_eq!(status(&server, &payment_id).await, PaymentStatus::Failed);
+    }
+}
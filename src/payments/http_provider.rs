@@ -0,0 +1,152 @@
+//! A payment provider with a simple JSON-over-HTTP API.
+//!
+//! It is configured with environment variables:
+//! - `PAYMENT_PROVIDER_URL`: the base URL of the provider's API;
+//! - `PAYMENT_PROVIDER_API_KEY`: sent as a bearer token when making payments;
+//! - `PAYMENT_WEBHOOK_SECRET`: the key that webhook callbacks are signed with;
+//! - `PAYMENT_WEBHOOK_TOLERANCE_SECONDS`: how old a callback's signature may be (default 300).
+//!
+//! To make a payment, we send `POST {PAYMENT_PROVIDER_URL}/payments`
+//! with a JSON body of `{"reference", "amount", "return_url", "callback_url"}`,
+//! and the provider replies with `{"id", "checkout_url"}`.
+//!
+//! The callback is a JSON body of `{"event_id", "reference", "amount", "status"}`,
+//! where the status is either `succeeded` or `failed`.
+//! It has a header `X-Payment-Signature: t=<unix time>,v1=<hex>`,
+//! where the hex is the HMAC-SHA256 of `<unix time>.<body>` with the webhook secret.
+
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{CreatedPayment, PaymentIntent, PaymentOutcome, PaymentProvider, WebhookEvent};
+
+pub struct HttpProvider {
+    base_url: String,
+    api_key: String,
+    webhook_secret: String,
+    webhook_tolerance_seconds: i64,
+}
+
+#[derive(Serialize)]
+struct CreatePaymentRequest<'a> {
+    reference: &'a str,
+    amount: f64,
+    return_url: &'a str,
+    callback_url: &'a str,
+}
+
+#[derive(Deserialize)]
+struct CreatePaymentResponse {
+    id: String,
+    checkout_url: String,
+}
+
+#[derive(Deserialize)]
+struct WebhookBody {
+    event_id: String,
+    reference: String,
+    amount: f64,
+    status: String,
+}
+
+impl HttpProvider {
+    pub fn from_env() -> anyhow::Result<Self> {
+        fn required(name: &str) -> anyhow::Result<String> {
+            std::env::var(name).map_err(|_| anyhow::anyhow!("{name} must be set for payments"))
+        }
+        Ok(Self {
+            base_url: required("PAYMENT_PROVIDER_URL")?
+                .trim_end_matches('/')
+                .to_string(),
+            api_key: required("PAYMENT_PROVIDER_API_KEY")?,
+            webhook_secret: required("PAYMENT_WEBHOOK_SECRET")?,
+            webhook_tolerance_seconds: std::env::var("PAYMENT_WEBHOOK_TOLERANCE_SECONDS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentProvider for HttpProvider {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn create_payment(&self, intent: &PaymentIntent) -> anyhow::Result<CreatedPayment> {
+        let resp: CreatePaymentResponse = reqwest::Client::new()
+            .post(format!("{}/payments", self.base_url))
+            .bearer_auth(&self.api_key)
+            .json(&CreatePaymentRequest {
+                reference: &intent.id,
+                amount: intent.amount,
+                return_url: &intent.return_url,
+                callback_url: &intent.callback_url,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(CreatedPayment {
+            provider_payment_id: resp.id,
+            redirect_url: resp.checkout_url,
+        })
+    }
+
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<WebhookEvent> {
+        let signature = headers
+            .get("X-Payment-Signature")
+            .ok_or_else(|| anyhow::anyhow!("Missing signature header"))?
+            .to_str()?;
+
+        let mut timestamp = None;
+        let mut mac_hex = None;
+        for part in signature.split(',') {
+            match part.split_once('=') {
+                Some(("t", v)) => timestamp = Some(v.parse::<i64>()?),
+                Some(("v1", v)) => mac_hex = Some(v),
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.ok_or_else(|| anyhow::anyhow!("Signature has no timestamp"))?;
+        let mac_bytes =
+            hex::decode(mac_hex.ok_or_else(|| anyhow::anyhow!("Signature has no MAC"))?)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes())?;
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.verify_slice(&mac_bytes)
+            .map_err(|_| anyhow::anyhow!("Signature does not match"))?;
+
+        // Old signatures are not accepted, so that a leaked callback can't be replayed much later.
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        if (now - timestamp).abs() > self.webhook_tolerance_seconds {
+            return Err(anyhow::anyhow!(
+                "Signature timestamp {timestamp} is too far from now ({now})"
+            ));
+        }
+
+        let body: WebhookBody = serde_json::from_slice(body)?;
+        let outcome = match body.status.as_str() {
+            "succeeded" => PaymentOutcome::Succeeded,
+            "failed" => PaymentOutcome::Failed,
+            other => return Err(anyhow::anyhow!("Unknown payment status: {other}")),
+        };
+
+        Ok(WebhookEvent {
+            event_id: body.event_id,
+            payment_id: body.reference,
+            amount: body.amount,
+            outcome,
+        })
+    }
+}
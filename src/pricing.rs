@@ -1,4 +1,7 @@
 use api::PricingInfo;
+use tokio::sync::mpsc;
+
+use crate::manager::ManagerRequest;
 
 pub fn get_current_pricing() -> PricingInfo {
     PricingInfo {
@@ -9,5 +12,83 @@ pub fn get_current_pricing() -> PricingInfo {
         upload_file_factor: 0.5,
         overdraft_seconds_allowed: 60.0,
         error_order_cost: 100.0,
+        surge_multiplier: 1.0,
+    }
+}
+
+/// The base pricing with the current surge multiplier applied on top.
+/// This is what a new order would be charged if it started right now.
+pub async fn get_surged_pricing(manager_connection: &mpsc::Sender<ManagerRequest>) -> PricingInfo {
+    let multiplier = get_surge_multiplier(manager_connection).await;
+    apply_surge(get_current_pricing(), multiplier)
+}
+
+fn apply_surge(pricing: PricingInfo, multiplier: f64) -> PricingInfo {
+    PricingInfo {
+        cpu_time_factor: pricing.cpu_time_factor * multiplier,
+        process_fork_cost: pricing.process_fork_cost * multiplier,
+        wall_time_factor: pricing.wall_time_factor * multiplier,
+        upload_mb_factor: pricing.upload_mb_factor * multiplier,
+        upload_file_factor: pricing.upload_file_factor * multiplier,
+        surge_multiplier: pricing.surge_multiplier * multiplier,
+        ..pricing
+    }
+}
+
+/// What is used to measure how busy the server is.
+enum SurgeSource {
+    /// The number of orders that are currently executing.
+    RunningJobs,
+
+    /// The host's 1-minute load average.
+    LoadAverage,
+}
+
+/// Compute the current surge multiplier.
+///
+/// Surge pricing is configured with environment variables:
+/// - `SURGE_PRICING`: either `jobs` or `loadavg`; if unset or anything else, the multiplier is always 1.0.
+/// - `SURGE_THRESHOLD`: the load at which surge pricing begins (default 4).
+/// - `SURGE_STEP`: how much the multiplier grows for each unit of load over the threshold (default 0.25).
+/// - `SURGE_MAX_MULTIPLIER`: the multiplier will never exceed this (default 3).
+pub async fn get_surge_multiplier(manager_connection: &mpsc::Sender<ManagerRequest>) -> f64 {
+    let source = match std::env::var("SURGE_PRICING").as_deref() {
+        Ok("jobs") => SurgeSource::RunningJobs,
+        Ok("loadavg") => SurgeSource::LoadAverage,
+        _ => return 1.0,
+    };
+
+    fn env_or(name: &str, default: f64) -> f64 {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
     }
+    let threshold = env_or("SURGE_THRESHOLD", 4.0);
+    let step = env_or("SURGE_STEP", 0.25);
+    let max_multiplier = env_or("SURGE_MAX_MULTIPLIER", 3.0).max(1.0);
+
+    let load = match source {
+        SurgeSource::RunningJobs => {
+            ManagerRequest::query_running_jobs(manager_connection).await as f64
+        }
+        SurgeSource::LoadAverage => match read_load_average().await {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("Could not read load average for surge pricing: {why}");
+                return 1.0;
+            }
+        },
+    };
+
+    (1.0 + (load - threshold) * step).clamp(1.0, max_multiplier)
+}
+
+async fn read_load_average() -> anyhow::Result<f64> {
+    let data = tokio::fs::read_to_string("/proc/loadavg").await?;
+    let first = data
+        .split_ascii_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("/proc/loadavg is empty"))?;
+    Ok(first.parse()?)
 }
@@ -1,28 +1,55 @@
+use std::net::SocketAddr;
+
 use api::{
-    ChangePasswordRequest, ChangePasswordResponse, LoginRequest, RedeemPromocodeResponse, UserInfo,
-    UserInfoResult,
+    ChangePasswordRequest, ChangePasswordResponse, LoginRequest, LoginResponse,
+    RedeemPromocodeResponse, UserInfo, UserInfoResult, VerificationMethod,
 };
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
     Json,
 };
-use base64::Engine;
-use password_hash::PasswordHashString;
+use serde::Deserialize;
+use sqlx::SqlitePool;
 
-use crate::{result::AppError, AppState};
+use crate::{
+    auth::{CurrentAccount, ReadProfileAccess},
+    groups::get_group_funds,
+    login_links::revoke_login_links,
+    login_throttle::{check_login_allowed, client_ip, record_login_failure, record_login_success},
+    passwords::{
+        check_password_off_thread, check_password_policy, hash_password_off_thread, PasswordCheck,
+    },
+    result::AppError,
+    sessions::{create_session, revoke_all_sessions, session_cookie, user_agent},
+    sso, two_factor,
+    verification::{
+        admin_approval::has_pending_request, enabled_methods, is_enabled, mark_verified,
+    },
+    AppState,
+};
 
 pub async fn get_user(
     State(AppState { db, .. }): State<AppState>,
-    Path(token): Path<String>,
+    account: Option<ReadProfileAccess>,
 ) -> Result<Json<UserInfoResult>, AppError> {
-    let data = match sqlx::query!("SELECT * FROM accounts WHERE token=?", token)
+    let account = match account {
+        Some(ReadProfileAccess(account)) => account,
+        None => return Ok(Json(UserInfoResult::NoSuchToken)),
+    };
+    let data = match sqlx::query!("SELECT * FROM accounts WHERE id=?", account.account_id)
         .fetch_optional(&db)
         .await?
     {
         Some(v) => UserInfoResult::Ok(UserInfo {
+            group: get_group_funds(&db, v.id).await?.map(|g| g.info),
             name: v.user_name,
             balance: v.balance,
             verification: v.verification_method.into(),
+            available_verification_methods: enabled_methods(),
+            verification_request_pending: has_pending_request(&db, v.id).await?,
+            role: v.role.into(),
         }),
         None => UserInfoResult::NoSuchToken,
     };
@@ -33,132 +60,240 @@ pub async fn get_user(
 #[axum_macros::debug_handler]
 pub async fn login(
     State(AppState { db, .. }): State<AppState>,
-    Json(LoginRequest { handle, password }): Json<LoginRequest>,
-) -> Result<Json<Option<String>>, AppError> {
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(LoginRequest {
+        handle,
+        password,
+        proof_of_work,
+        two_factor_code,
+    }): Json<LoginRequest>,
+) -> Result<Response, AppError> {
+    // Too many failures make the client do more work before the password is even checked.
+    let ip = client_ip(&headers, peer);
+    if let Some(resp) = check_login_allowed(&db, &handle, &ip, proof_of_work.as_ref()).await? {
+        return Ok(Json(resp).into_response());
+    }
+
     // First fetch the login corresponding to the handle.
     let login = match sqlx::query!("SELECT * FROM logins WHERE handle=?", handle)
         .fetch_optional(&db)
         .await?
     {
         Some(row) => row,
-        None => return Ok(Json(None)),
+        None => {
+            record_login_failure(&db, &handle, &ip, None).await?;
+            return Ok(Json(LoginResponse::InvalidCredentials).into_response());
+        }
     };
 
-    // Load the stored hashed password
-    let true_hash = PasswordHashString::new(&login.password_hash)
-        .expect("Database stored password hash string invalid");
-
-    /// This is separated into a function because `&dyn PasswordVerifier` is not [`Sync`];
-    /// because of this, such objects may not exist across await points.
-    /// This is a fully-sync function so it avoids that issue.
-    fn check_password(true_hash: PasswordHashString, tested_password: String) -> bool {
-        let hashers: &[&dyn password_hash::PasswordVerifier] = &[&argon2::Argon2::default()];
-        true_hash
-            .password_hash()
-            .verify_password(hashers, tested_password)
-            .is_ok()
+    // Accounts made for single sign-on or with a login link have no password until one is set,
+    // so no password logs into them.
+    let needs_rehash = match check_password_off_thread(&login.password_hash, &password).await? {
+        PasswordCheck::Correct { needs_rehash } => needs_rehash,
+        PasswordCheck::NotSet | PasswordCheck::Wrong => {
+            record_login_failure(&db, &handle, &ip, Some(login.account_id)).await?;
+            return Ok(Json(LoginResponse::InvalidCredentials).into_response());
+        }
+    };
+
+    // With two-factor authentication on, the password alone isn't enough.
+    // A wrong code counts as a failed login, so that codes can't be guessed any faster than passwords.
+    if two_factor::is_enabled(&db, login.account_id).await? {
+        let code = match two_factor_code {
+            Some(code) => code,
+            None => return Ok(Json(LoginResponse::TwoFactorRequired).into_response()),
+        };
+        if !two_factor::check_code(&db, login.account_id, &code, Some(&ip)).await? {
+            record_login_failure(&db, &handle, &ip, Some(login.account_id)).await?;
+            return Ok(Json(LoginResponse::InvalidTwoFactorCode).into_response());
+        }
     }
 
-    if !check_password(true_hash, password) {
-        return Ok(Json(None));
+    // At this time, we know that the password (and the code, if needed) is correct.
+    record_login_success(&db, &handle).await?;
+
+    // The hashing parameters have changed since the hash was made, and now is the only time the password is known.
+    // Unlike setting a new password, this leaves the sessions alone.
+    if needs_rehash {
+        let new_hash = hash_password_off_thread(&password).await?;
+        sqlx::query!(
+            "UPDATE logins SET password_hash=? WHERE account_id=? AND password_hash=?",
+            new_hash,
+            login.account_id,
+            login.password_hash
+        )
+        .execute(&db)
+        .await?;
     }
 
-    // At this time, we know that the password is correct
-    // Rotate the token for the associated account, then return it.
-    use rand::distributions::DistString;
-    let token = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    // Start a new session for this device, leaving the others logged in.
+    // Browsers get it as a cookie, and other clients use the returned token.
+    let token = create_session(&db, login.account_id, user_agent(&headers)).await?;
+    let mut response = (
+        [(header::SET_COOKIE, session_cookie(&token))],
+        Json(LoginResponse::Ok { token }),
+    )
+        .into_response();
 
+    // Logging in with the password is what proves that an identity from single sign-on may be linked.
+    if let Some(cookie) = sso::link_after_login(&db, &headers, login.account_id).await? {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+/// Set a new password for the account.
+/// This also rotates its legacy token, logs out all of its sessions and cancels its login links.
+pub async fn set_password(
+    db: &SqlitePool,
+    account_id: i64,
+    new_password: &str,
+) -> anyhow::Result<()> {
+    // First make a new password hash
+    let hash_str = hash_password_off_thread(new_password).await?;
+
+    // Store it into the database with the user's data
+    let mut tx = db.begin().await?;
     sqlx::query!(
-        "UPDATE accounts SET token=? WHERE id=?",
-        token,
-        login.account_id
+        "UPDATE logins SET password_hash=? WHERE account_id=?",
+        hash_str,
+        account_id
     )
-    .execute(&db)
+    .execute(&mut *tx)
     .await?;
 
-    Ok(Json(Some(token)))
+    // Rotate the account token, and end every session
+    use rand::distributions::DistString;
+    let token = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    sqlx::query!("UPDATE accounts SET token=? WHERE id=?", token, account_id)
+        .execute(&mut *tx)
+        .await?;
+    revoke_all_sessions(&mut *tx, account_id, None).await?;
+    revoke_login_links(&mut *tx, account_id).await?;
+
+    tx.commit().await?;
+    Ok(())
 }
 
 pub async fn change_password(
     State(AppState { db, .. }): State<AppState>,
-    Path(token): Path<String>,
+    account: Option<CurrentAccount>,
+    headers: HeaderMap,
     Json(ChangePasswordRequest { new_password }): Json<ChangePasswordRequest>,
-) -> Result<Json<ChangePasswordResponse>, AppError> {
-    // First find the account corresponding to the token
-    let data = match sqlx::query!("SELECT * FROM accounts WHERE token=?", token)
-        .fetch_optional(&db)
-        .await?
-    {
-        Some(row) => row,
-        None => return Ok(Json(ChangePasswordResponse::InvalidToken)),
+) -> Result<Response, AppError> {
+    let account = match account {
+        Some(account) => account,
+        None => return Ok(Json(ChangePasswordResponse::InvalidToken).into_response()),
     };
 
-    // Now make a new password hash
-    let salt = uuid::Uuid::new_v4();
-    let salt = salt.as_bytes();
-    let salt = base64::prelude::BASE64_STANDARD_NO_PAD.encode(&salt);
-    let hash = password_hash::PasswordHash::generate(
-        argon2::Argon2::default(),
-        new_password.as_bytes(),
-        password_hash::Salt::from_b64(&salt).expect("Failed to parse generated salt"),
+    let login = sqlx::query!(
+        "SELECT handle FROM logins WHERE account_id=?",
+        account.account_id
     )
-    .expect("Failed to hash new user password");
-    let hash_str = hash.to_string();
-
-    // Store it into the database with the user's data
-
-    sqlx::query!(
-        "UPDATE logins SET password_hash=? WHERE account_id=?",
-        hash_str,
-        data.id
-    )
-    .execute(&db)
+    .fetch_one(&db)
     .await?;
+    if let Err(problem) = check_password_policy(&new_password, &login.handle) {
+        return Ok(Json(ChangePasswordResponse::BadPassword(problem)).into_response());
+    }
 
-    // Rotate the account token
-    use rand::distributions::DistString;
-    let token = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    set_password(&db, account.account_id, &new_password).await?;
 
-    sqlx::query!("UPDATE accounts SET token=? WHERE id=?", token, data.id)
-        .execute(&db)
-        .await?;
+    // All the old sessions are gone, so this device gets a new one.
+    let token = create_session(&db, account.account_id, user_agent(&headers)).await?;
+
+    Ok((
+        [(header::SET_COOKIE, session_cookie(&token))],
+        Json(ChangePasswordResponse::Ok { new_token: token }),
+    )
+        .into_response())
+}
 
-    Ok(Json(ChangePasswordResponse::Ok { new_token: token }))
+#[derive(Deserialize)]
+pub struct PromocodePath {
+    code: String,
 }
 
 pub async fn redeem_promocode(
     State(AppState { db, .. }): State<AppState>,
-    Path((token, code)): Path<(String, String)>,
+    current: CurrentAccount,
+    Path(PromocodePath { code }): Path<PromocodePath>,
 ) -> Result<Json<RedeemPromocodeResponse>, AppError> {
-    // First find the account corresponding to the token
-    let account = match sqlx::query!("SELECT * FROM accounts WHERE token=?", token)
-        .fetch_optional(&db)
-        .await?
-    {
-        Some(row) => row,
-        None => return Err(anyhow::anyhow!("User token is invalid"))?,
-    };
+    let account = sqlx::query!("SELECT * FROM accounts WHERE id=?", current.account_id)
+        .fetch_one(&db)
+        .await?;
+
+    // The checks and the balance change happen in one transaction,
+    // so that concurrent redemptions can't go over the limits.
+    let mut tx = db.begin().await?;
 
     // Then find the promocode
     let promocode = match sqlx::query!("SELECT * FROM promocodes WHERE code=?", code)
-        .fetch_optional(&db)
+        .fetch_optional(&mut *tx)
         .await?
     {
         Some(row) => row,
         None => return Ok(Json(RedeemPromocodeResponse::NotFound)),
     };
 
-    // Check if it's been claimed.
-    if let (Some(by_id), Some(when)) = (promocode.claimed_by, promocode.claimed_at_unix_time) {
-        return Ok(Json(RedeemPromocodeResponse::AlreadyRedeemed {
-            when_unix_time: when as u64,
-            by_me: by_id == account.id,
-        }));
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    // Check if it's expired.
+    if let Some(expiry) = promocode.expires_at_unix_time {
+        if expiry <= now {
+            return Ok(Json(RedeemPromocodeResponse::Expired {
+                when_unix_time: expiry as u64,
+            }));
+        }
     }
 
-    // Transactionally alter the balance, and also mark the promocode as claimed.
-    let mut tx = db.begin().await?;
+    // Check if this account has used it up.
+    let my_redemptions = sqlx::query!(
+        "SELECT redeemed_at_unix_time FROM promocode_redemptions WHERE promocode_id=? AND account_id=? ORDER BY redeemed_at_unix_time DESC",
+        promocode.id,
+        account.id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if my_redemptions.len() as i64 >= promocode.max_uses_per_account {
+        if let Some(last) = my_redemptions.first() {
+            return Ok(Json(RedeemPromocodeResponse::AlreadyRedeemed {
+                when_unix_time: last.redeemed_at_unix_time as u64,
+                by_me: true,
+            }));
+        }
+    }
+
+    // Check if everyone together has used it up.
+    if let Some(max_uses) = promocode.max_uses {
+        let all_redemptions = sqlx::query!(
+            "SELECT * FROM promocode_redemptions WHERE promocode_id=? ORDER BY redeemed_at_unix_time DESC",
+            promocode.id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if all_redemptions.len() as i64 >= max_uses {
+            return Ok(Json(match all_redemptions.first() {
+                Some(last) if max_uses == 1 => RedeemPromocodeResponse::AlreadyRedeemed {
+                    when_unix_time: last.redeemed_at_unix_time as u64,
+                    by_me: last.account_id == account.id,
+                },
+                _ => RedeemPromocodeResponse::ExhaustedUses {
+                    max_uses: max_uses as u64,
+                },
+            }));
+        }
+    }
+
+    // Some promocodes are handed out in person, so they also prove that the account is real.
+    let verified_account = promocode.verifies_account
+        && is_enabled(VerificationMethod::Promocode)
+        && mark_verified(&mut *tx, account.id, VerificationMethod::Promocode).await?;
 
+    // Alter the balance, and also record the redemption.
     let user_balance_after = sqlx::query!(
         "UPDATE accounts SET balance=balance+? WHERE id=? RETURNING balance",
         promocode.money_value,
@@ -167,16 +302,12 @@ pub async fn redeem_promocode(
     .fetch_one(&mut *tx)
     .await?
     .balance;
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
 
     sqlx::query!(
-        "UPDATE promocodes SET claimed_by=?, claimed_at_unix_time=? WHERE id=?",
+        "INSERT INTO promocode_redemptions (promocode_id, account_id, redeemed_at_unix_time) VALUES (?,?,?)",
+        promocode.id,
         account.id,
-        now,
-        promocode.id
+        now
     )
     .execute(&mut *tx)
     .await?;
@@ -185,5 +316,6 @@ pub async fn redeem_promocode(
     Ok(Json(RedeemPromocodeResponse::Ok {
         promocode_value: promocode.money_value as f64,
         user_balance_after,
+        verified_account,
     }))
 }
@@ -0,0 +1,323 @@
+//! Printable sheets of promocodes, to be cut up and handed out in class.
+//!
+//! The layout is computed once as a list of [`Shape`]s in millimeters,
+//! and then rendered into either SVG or PDF.
+//! The PDF uses the built-in Helvetica and Courier fonts, which only cover Latin text,
+//! so all the text on the cards is kept ASCII.
+
+use pdf_writer::{Content, Name, Pdf, Rect, Ref, Str};
+use qrcode::{Color, QrCode};
+
+use crate::admin::UnclaimedPromocode;
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 10.0;
+const COLUMNS: usize = 3;
+const ROWS: usize = 6;
+const CARD_WIDTH: f32 = (PAGE_WIDTH - 2.0 * MARGIN) / COLUMNS as f32;
+const CARD_HEIGHT: f32 = 40.0;
+const QR_SIZE: f32 = 28.0;
+const PADDING: f32 = 3.0;
+
+/// Points per millimeter, for PDF coordinates.
+const PT_PER_MM: f32 = 72.0 / 25.4;
+
+enum Shape {
+    /// A filled black rectangle.
+    Rect { x: f32, y: f32, w: f32, h: f32 },
+
+    /// A dashed line to cut along.
+    CutLine { x1: f32, y1: f32, x2: f32, y2: f32 },
+
+    /// A line of text, with the baseline starting at the given point.
+    Text {
+        x: f32,
+        y: f32,
+        size_pt: f32,
+        monospace: bool,
+        text: String,
+    },
+}
+
+/// Lay out the cards onto pages.
+/// Coordinates are in millimeters, with the origin at the top left of the page.
+fn layout(codes: &[UnclaimedPromocode], frontend_url: &str) -> anyhow::Result<Vec<Vec<Shape>>> {
+    let mut pages = vec![];
+    for page_codes in codes.chunks(COLUMNS * ROWS) {
+        let mut shapes = vec![];
+        let rows = page_codes.len().div_ceil(COLUMNS);
+
+        // Cut lines go along the entire grid, including its outer border.
+        let grid_bottom = MARGIN + rows as f32 * CARD_HEIGHT;
+        for row in 0..=rows {
+            let y = MARGIN + row as f32 * CARD_HEIGHT;
+            shapes.push(Shape::CutLine {
+                x1: MARGIN,
+                y1: y,
+                x2: PAGE_WIDTH - MARGIN,
+                y2: y,
+            });
+        }
+        for column in 0..=COLUMNS {
+            let x = MARGIN + column as f32 * CARD_WIDTH;
+            shapes.push(Shape::CutLine {
+                x1: x,
+                y1: MARGIN,
+                x2: x,
+                y2: grid_bottom,
+            });
+        }
+
+        for (idx, code) in page_codes.iter().enumerate() {
+            let x = MARGIN + (idx % COLUMNS) as f32 * CARD_WIDTH;
+            let y = MARGIN + (idx / COLUMNS) as f32 * CARD_HEIGHT;
+            layout_card(&mut shapes, x, y, code, frontend_url)?;
+        }
+        pages.push(shapes);
+    }
+
+    Ok(pages)
+}
+
+fn layout_card(
+    shapes: &mut Vec<Shape>,
+    x: f32,
+    y: f32,
+    code: &UnclaimedPromocode,
+    frontend_url: &str,
+) -> anyhow::Result<()> {
+    let link = format!(
+        "{}/redeem/{}",
+        frontend_url.trim_end_matches('/'),
+        urlencoding::encode(&code.code)
+    );
+    let qr = QrCode::new(link.as_bytes())?;
+    let modules = qr.width();
+    let colors = qr.to_colors();
+    let module_size = QR_SIZE / modules as f32;
+    let qr_x = x + PADDING;
+    let qr_y = y + PADDING;
+
+    // Dark modules next to each other in a row are merged into one rectangle,
+    // so that there are fewer shapes to draw and no hairline gaps between them.
+    for row in 0..modules {
+        let mut run_start = None;
+        for column in 0..=modules {
+            let is_dark = column < modules && colors[row * modules + column] == Color::Dark;
+            match (is_dark, run_start) {
+                (true, None) => run_start = Some(column),
+                (false, Some(start)) => {
+                    shapes.push(Shape::Rect {
+                        x: qr_x + start as f32 * module_size,
+                        y: qr_y + row as f32 * module_size,
+                        w: (column - start) as f32 * module_size,
+                        h: module_size,
+                    });
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let text_x = qr_x + QR_SIZE + PADDING;
+    shapes.push(Shape::Text {
+        x: text_x,
+        y: y + PADDING + 8.0,
+        size_pt: 20.0,
+        monospace: false,
+        text: format!("+{}", code.money_value),
+    });
+
+    let mut line_y = y + PADDING + 15.0;
+    if let Some(ref campaign) = code.campaign {
+        shapes.push(Shape::Text {
+            x: text_x,
+            y: line_y,
+            size_pt: 7.0,
+            monospace: false,
+            text: ascii_only(campaign, 24),
+        });
+        line_y += 4.0;
+    }
+    if let Some(expiry) = code.expires_at_unix_time {
+        if let Some(date) = chrono::DateTime::from_timestamp(expiry, 0) {
+            shapes.push(Shape::Text {
+                x: text_x,
+                y: line_y,
+                size_pt: 7.0,
+                monospace: false,
+                text: format!("valid until {}", date.format("%Y-%m-%d")),
+            });
+        }
+    }
+
+    shapes.push(Shape::Text {
+        x: x + PADDING,
+        y: y + CARD_HEIGHT - PADDING - 1.0,
+        size_pt: 9.0,
+        monospace: true,
+        text: ascii_only(&code.code, 32),
+    });
+
+    Ok(())
+}
+
+/// Replace everything the built-in PDF fonts can't show, and cut the text to the given length.
+fn ascii_only(text: &str, max_chars: usize) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '?'
+            }
+        })
+        .take(max_chars)
+        .collect()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render the promocodes into one SVG image, with the pages stacked on top of each other.
+pub fn render_svg(codes: &[UnclaimedPromocode], frontend_url: &str) -> anyhow::Result<String> {
+    let pages = layout(codes, frontend_url)?;
+    let total_height = PAGE_HEIGHT * pages.len().max(1) as f32;
+
+    let mut out = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{PAGE_WIDTH}mm" height="{total_height}mm" viewBox="0 0 {PAGE_WIDTH} {total_height}">"#
+    );
+    out.push('\n');
+    for (page_idx, shapes) in pages.iter().enumerate() {
+        let offset = page_idx as f32 * PAGE_HEIGHT;
+        out.push_str(&format!(r#"<g transform="translate(0 {offset})">"#));
+        out.push('\n');
+        for shape in shapes {
+            match shape {
+                Shape::Rect { x, y, w, h } => out.push_str(&format!(
+                    r#"<rect x="{x}" y="{y}" width="{w}" height="{h}" fill="black" shape-rendering="crispEdges"/>"#
+                )),
+                Shape::CutLine { x1, y1, x2, y2 } => out.push_str(&format!(
+                    r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="gray" stroke-width="0.2" stroke-dasharray="1.5 1.5"/>"#
+                )),
+                Shape::Text {
+                    x,
+                    y,
+                    size_pt,
+                    monospace,
+                    text,
+                } => {
+                    let family = if *monospace { "Courier, monospace" } else { "Helvetica, Arial, sans-serif" };
+                    let size_mm = size_pt / PT_PER_MM;
+                    out.push_str(&format!(
+                        r#"<text x="{x}" y="{y}" font-family="{family}" font-size="{size_mm}">{}</text>"#,
+                        escape_xml(text)
+                    ));
+                }
+            }
+            out.push('\n');
+        }
+        out.push_str("</g>\n");
+    }
+    out.push_str("</svg>\n");
+
+    Ok(out)
+}
+
+/// Render the promocodes into an A4 PDF document.
+pub fn render_pdf(codes: &[UnclaimedPromocode], frontend_url: &str) -> anyhow::Result<Vec<u8>> {
+    // A PDF with no pages isn't something that can be printed.
+    if codes.is_empty() {
+        return Err(anyhow::anyhow!("no promocodes to print"));
+    }
+    let pages = layout(codes, frontend_url)?;
+
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let font_id = Ref::new(3);
+    let mono_font_id = Ref::new(4);
+    let font_name = Name(b"F1");
+    let mono_font_name = Name(b"F2");
+
+    // Each page takes two objects: the page itself, and its content stream.
+    let page_ids: Vec<Ref> = (0..pages.len())
+        .map(|i| Ref::new(5 + 2 * i as i32))
+        .collect();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(page_ids.len() as i32);
+    pdf.type1_font(font_id).base_font(Name(b"Helvetica"));
+    pdf.type1_font(mono_font_id).base_font(Name(b"Courier"));
+
+    // PDF coordinates are in points, with the origin at the bottom left.
+    let px = |x: f32| x * PT_PER_MM;
+    let py = |y: f32| (PAGE_HEIGHT - y) * PT_PER_MM;
+
+    for (page_id, shapes) in page_ids.iter().zip(pages.iter()) {
+        let content_id = Ref::new(page_id.get() + 1);
+
+        // The page is written out when its writer is dropped at the end of this block.
+        {
+            let mut page = pdf.page(*page_id);
+            page.media_box(Rect::new(0.0, 0.0, px(PAGE_WIDTH), px(PAGE_HEIGHT)));
+            page.parent(page_tree_id);
+            page.contents(content_id);
+            page.resources()
+                .fonts()
+                .pair(font_name, font_id)
+                .pair(mono_font_name, mono_font_id);
+        }
+
+        let mut content = Content::new();
+        for shape in shapes {
+            match shape {
+                Shape::Rect { x, y, w, h } => {
+                    content.set_fill_gray(0.0);
+                    content.rect(px(*x), py(y + h), px(*w), px(*h));
+                    content.fill_nonzero();
+                }
+                Shape::CutLine { x1, y1, x2, y2 } => {
+                    content.set_stroke_gray(0.5);
+                    content.set_line_width(0.5);
+                    content.set_dash_pattern([4.0, 4.0], 0.0);
+                    content.move_to(px(*x1), py(*y1));
+                    content.line_to(px(*x2), py(*y2));
+                    content.stroke();
+                }
+                Shape::Text {
+                    x,
+                    y,
+                    size_pt,
+                    monospace,
+                    text,
+                } => {
+                    content.set_fill_gray(0.0);
+                    content.begin_text();
+                    content.set_font(
+                        if *monospace {
+                            mono_font_name
+                        } else {
+                            font_name
+                        },
+                        *size_pt,
+                    );
+                    content.next_line(px(*x), py(*y));
+                    content.show(Str(text.as_bytes()));
+                    content.end_text();
+                }
+            }
+        }
+        pdf.stream(content_id, &content.finish());
+    }
+
+    Ok(pdf.finish())
+}
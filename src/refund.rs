@@ -0,0 +1,172 @@
+use api::{BalanceChangeKind, JobTerminationStatus, OrderInfo, OrderRefund};
+use sqlx::SqlitePool;
+
+use crate::balance::record_balance_change;
+
+/// Whether orders that failed on the server's side get their cost refunded automatically.
+///
+/// This is configured with the `AUTO_REFUND_POLICY` environment variable:
+/// - `off`: never refund automatically;
+/// - `on` (the default): refund orders that ended in [`JobTerminationStatus::AbnormalTermination`].
+///
+/// Orders that ended in [`JobTerminationStatus::VeryAbnormalTermination`], like those cut off by a restart,
+/// are never charged at all, so there is nothing to refund for them whatever the policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoRefundPolicy {
+    Off,
+    On,
+}
+
+impl AutoRefundPolicy {
+    pub fn from_env() -> Self {
+        match std::env::var("AUTO_REFUND_POLICY").as_deref() {
+            Ok("off") => Self::Off,
+            _ => Self::On,
+        }
+    }
+
+    /// Whether an order that ended this way should be refunded.
+    /// Orders where the process exited (even if it was killed) are the user's responsibility, so they are never covered.
+    pub fn covers(&self, termination: &JobTerminationStatus) -> bool {
+        matches!(
+            (self, termination),
+            (Self::On, JobTerminationStatus::AbnormalTermination(_))
+        )
+    }
+}
+
+/// Refund the order if the automatic refund policy says so.
+/// Errors are logged rather than returned, because the order itself has already completed.
+pub async fn apply_auto_refund(db: &SqlitePool, order_id: i64, info: &OrderInfo) {
+    if info.order_cost <= 0.0 || !AutoRefundPolicy::from_env().covers(&info.termination) {
+        return;
+    }
+
+    let reason = "The order failed because of a server-side error";
+    match refund_order(db, order_id, None, reason, true).await {
+        Ok(refund) => tracing::info!("Automatically refunded order {order_id}: {refund:?}"),
+        Err(why) => tracing::error!("Failed to automatically refund order {order_id}: {why}"),
+    }
+}
+
+/// Give the cost of a completed order back to the user who made it.
+/// If the amount is not given, the entire order cost is refunded.
+/// Every order may only be refunded once.
+pub async fn refund_order(
+    db: &SqlitePool,
+    order_id: i64,
+    amount: Option<f64>,
+    reason: &str,
+    is_automatic: bool,
+) -> anyhow::Result<OrderRefund> {
+    let mut tx = db.begin().await?;
+
+    let order = match sqlx::query!(
+        "SELECT user_id, is_running, status_json FROM orders WHERE id=?",
+        order_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        Some(row) => row,
+        None => return Err(anyhow::anyhow!("No such order found")),
+    };
+    if order.is_running {
+        return Err(anyhow::anyhow!("The order is still running"));
+    }
+    let info: OrderInfo = serde_json::from_str(&order.status_json.ok_or(anyhow::anyhow!(
+        "Database row didn't have data for a completed job"
+    ))?)?;
+
+    let amount = amount.unwrap_or(info.order_cost);
+    if amount.is_nan() || amount <= 0.0 || amount > info.order_cost {
+        return Err(anyhow::anyhow!(
+            "Refund amount must be positive and at most the order cost of {}, got {amount}",
+            info.order_cost
+        ));
+    }
+
+    if let Some(existing) = sqlx::query!("SELECT * FROM refunds WHERE order_id=?", order_id)
+        .fetch_optional(&mut *tx)
+        .await?
+    {
+        return Err(anyhow::anyhow!(
+            "The order was already refunded {} at {}",
+            existing.amount,
+            existing.created_at_unix_time
+        ));
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    sqlx::query!(
+        "INSERT INTO refunds (order_id, amount, reason, is_automatic, created_at_unix_time) VALUES (?,?,?,?,?)",
+        order_id,
+        amount,
+        reason,
+        is_automatic,
+        now
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Whatever the group paid for is given back to the group first.
+    let group_refund = amount.min(info.group_charge);
+    let personal_refund = if group_refund > 0.0
+        && crate::groups::return_to_group(&mut tx, order_id, group_refund).await?
+    {
+        amount - group_refund
+    } else {
+        amount
+    };
+
+    sqlx::query!(
+        "UPDATE accounts SET balance=balance+? WHERE id=?",
+        personal_refund,
+        order.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    // The history is of the personal balance, so what went back to the group isn't in it.
+    if personal_refund > 0.0 {
+        record_balance_change(
+            &mut tx,
+            order.user_id,
+            personal_refund,
+            BalanceChangeKind::Refund,
+            None,
+            Some(reason),
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(OrderRefund {
+        amount,
+        reason: reason.to_string(),
+        is_automatic,
+        refunded_at_unix_time: now as u64,
+    })
+}
+
+/// Find the refund for an order, if there was one.
+pub async fn get_order_refund(
+    db: &SqlitePool,
+    order_id: i64,
+) -> anyhow::Result<Option<OrderRefund>> {
+    Ok(
+        sqlx::query!("SELECT * FROM refunds WHERE order_id=?", order_id)
+            .fetch_optional(db)
+            .await?
+            .map(|row| OrderRefund {
+                amount: row.amount,
+                reason: row.reason,
+                is_automatic: row.is_automatic,
+                refunded_at_unix_time: row.created_at_unix_time as u64,
+            }),
+    )
+}
@@ -6,6 +6,7 @@ use axum::{
 //https://github.com/tokio-rs/axum/blob/main/examples/anyhow-error-response/src/main.rs
 
 // Make our own error that wraps `anyhow::Error`.
+#[derive(Debug)]
 pub struct AppError(anyhow::Error);
 
 // Tell axum how to convert `AppError` into a response.
@@ -0,0 +1,263 @@
+//! Making accounts for a whole course at once, from a roster CSV with `name` and `handle` columns.
+//!
+//! Either way the import is run, through the admin API or from the command line,
+//! it happens in one transaction: if any row is wrong, no accounts are made.
+//! The result is another CSV, with a password or a one-time login link for every account.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use api::{BalanceChangeKind, Role};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::SqlitePool;
+
+use crate::{
+    admin::insert_account,
+    audit::record_admin_action,
+    auth::Caller,
+    balance::record_balance_change,
+    login_links::create_login_link,
+    login_links::login_link_url,
+    passwords::{hash_password, PasswordSettings},
+};
+
+/// Characters for generated passwords, leaving out the ones that are easy to mix up on paper.
+const PASSWORD_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PASSWORD_LENGTH: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RosterCredentials {
+    /// Every account gets a random password.
+    #[default]
+    Password,
+    /// Every account gets a link that logs in once; the student then sets their own password.
+    LoginLink,
+}
+
+#[derive(Deserialize, clap::Args)]
+pub struct ImportRosterOptions {
+    /// Put all the new accounts into this group.
+    #[arg(long)]
+    pub group: Option<String>,
+
+    /// Give every new account this much money to start with.
+    #[serde(default)]
+    #[arg(long, default_value_t = 0.0)]
+    pub initial_balance: f64,
+
+    /// What to give out to log in with.
+    #[serde(default)]
+    #[arg(long, value_enum, default_value_t)]
+    pub credentials: RosterCredentials,
+}
+
+#[derive(Deserialize)]
+struct RosterEntry {
+    name: String,
+    handle: String,
+}
+
+/// Read the roster, checking that every row has a name and a handle, and that no handle is repeated.
+fn parse_roster(roster: &[u8]) -> anyhow::Result<Vec<RosterEntry>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(roster);
+    let mut entries = vec![];
+    let mut handles = HashSet::new();
+    for row in reader.deserialize() {
+        let entry: RosterEntry = row?;
+        // The header is on the first line.
+        let line = entries.len() + 2;
+        if entry.name.is_empty() || entry.handle.is_empty() {
+            anyhow::bail!("Line {line}: both the name and the handle must be given");
+        }
+        if !handles.insert(entry.handle.clone()) {
+            anyhow::bail!("Line {line}: the handle {:?} is repeated", entry.handle);
+        }
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        anyhow::bail!("The roster has no rows");
+    }
+    Ok(entries)
+}
+
+fn generate_password() -> String {
+    let mut rng = rand::thread_rng();
+    let length = PASSWORD_LENGTH.max(PasswordSettings::from_env().min_length);
+    (0..length)
+        .map(|_| *PASSWORD_ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+/// Make the accounts in the roster, and return a CSV of their credentials.
+/// If there is no caller, the import was run from the command line.
+pub async fn import_roster(
+    db: &SqlitePool,
+    roster: &[u8],
+    options: &ImportRosterOptions,
+    caller: Option<&Caller>,
+) -> anyhow::Result<Vec<u8>> {
+    let entries = parse_roster(roster)?;
+    if !options.initial_balance.is_finite() || options.initial_balance < 0.0 {
+        anyhow::bail!("The initial balance must not be negative");
+    }
+    // Links point at the frontend, so it must be known before anything is made.
+    let frontend_url = match options.credentials {
+        RosterCredentials::LoginLink => Some(
+            std::env::var("FRONTEND_URL")
+                .map_err(|_| anyhow::anyhow!("FRONTEND_URL must be set to make login links"))?,
+        ),
+        RosterCredentials::Password => None,
+    };
+
+    // Hashing takes a while on purpose, so it is done before the transaction, off the async threads.
+    let passwords = match options.credentials {
+        RosterCredentials::Password => {
+            let count = entries.len();
+            tokio::task::spawn_blocking(move || {
+                (0..count)
+                    .map(|_| {
+                        let password = generate_password();
+                        let hash = hash_password(&password)?;
+                        Ok((password, hash))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .await??
+        }
+        RosterCredentials::LoginLink => vec![],
+    };
+
+    let mut tx = db.begin().await?;
+
+    let group_id = match &options.group {
+        Some(group) => match sqlx::query!("SELECT id FROM account_groups WHERE name=?", group)
+            .fetch_optional(&mut *tx)
+            .await?
+        {
+            Some(row) => Some(row.id),
+            None => anyhow::bail!("No such group found: {group:?}"),
+        },
+        None => None,
+    };
+
+    // Report every handle that is taken at once, so that the roster can be fixed in one go.
+    let mut taken = vec![];
+    for entry in &entries {
+        if sqlx::query!("SELECT account_id FROM logins WHERE handle=?", entry.handle)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some()
+        {
+            taken.push(entry.handle.as_str());
+        }
+    }
+    if !taken.is_empty() {
+        anyhow::bail!("These handles are already taken: {}", taken.join(", "));
+    }
+
+    let mut output = csv::Writer::from_writer(vec![]);
+    output.write_record([
+        "name",
+        "handle",
+        match options.credentials {
+            RosterCredentials::Password => "password",
+            RosterCredentials::LoginLink => "login_link",
+        },
+    ])?;
+
+    let mut account_ids = Vec::with_capacity(entries.len());
+    for (idx, entry) in entries.iter().enumerate() {
+        let password = passwords.get(idx);
+        let account_id = insert_account(
+            &mut tx,
+            &entry.name,
+            &entry.handle,
+            password.map(|(_, hash)| hash.as_str()),
+            Role::Student,
+        )
+        .await?;
+        account_ids.push(account_id);
+
+        if let Some(group_id) = group_id {
+            sqlx::query!(
+                "INSERT INTO account_group_members (account_id, group_id, spending_limit, spent) VALUES (?,?,NULL,0)",
+                account_id,
+                group_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if options.initial_balance > 0.0 {
+            sqlx::query!(
+                "UPDATE accounts SET balance=? WHERE id=?",
+                options.initial_balance,
+                account_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            record_balance_change(
+                &mut tx,
+                account_id,
+                options.initial_balance,
+                BalanceChangeKind::StartingBalance,
+                None,
+                None,
+            )
+            .await?;
+        }
+
+        let credential = match (password, &frontend_url) {
+            (Some((password, _)), _) => password.clone(),
+            (None, Some(frontend_url)) => {
+                let token = create_login_link(&mut *tx, account_id).await?;
+                login_link_url(frontend_url, &token)
+            }
+            (None, None) => unreachable!("Every account gets either a password or a login link"),
+        };
+        output.write_record([&entry.name, &entry.handle, &credential])?;
+    }
+
+    record_admin_action(
+        &mut *tx,
+        caller,
+        "import_roster",
+        json!({
+            "account_ids": account_ids,
+            "group": options.group,
+            "initial_balance": options.initial_balance,
+            "credentials": options.credentials,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(output.into_inner()?)
+}
+
+/// Run the import from the command line, reading the roster from a file or `-` for standard input,
+/// and printing the credentials to standard output.
+pub async fn run_cli(
+    db: &SqlitePool,
+    file: PathBuf,
+    options: ImportRosterOptions,
+) -> anyhow::Result<()> {
+    use std::io::{Read, Write};
+
+    let roster = if file.as_os_str() == "-" {
+        let mut roster = vec![];
+        std::io::stdin().read_to_end(&mut roster)?;
+        roster
+    } else {
+        std::fs::read(&file)?
+    };
+
+    let output = import_roster(db, &roster, &options, None).await?;
+    std::io::stdout().write_all(&output)?;
+    Ok(())
+}
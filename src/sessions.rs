@@ -0,0 +1,235 @@
+//! Login sessions: every device that logs in gets its own token,
+//! which expires on its own and can be revoked without affecting the others.
+
+use api::SessionInfo;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sha2::{Digest, Sha256};
+use sqlx::SqliteExecutor;
+
+use crate::{
+    auth::{CurrentAccount, SESSION_COOKIE},
+    result::AppError,
+    AppState,
+};
+
+/// How long a session lasts after logging in, configured with `SESSION_LIFETIME_DAYS` (default 30).
+fn session_lifetime_seconds() -> i64 {
+    let days: i64 = std::env::var("SESSION_LIFETIME_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    days * 24 * 60 * 60
+}
+
+/// The last-used time is only written down this often, so that every request isn't a database write.
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Start a new session for the account, and return its token.
+pub async fn create_session<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+    user_agent: Option<&str>,
+) -> anyhow::Result<String> {
+    use rand::distributions::DistString;
+    let token = rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+    let token_hash = hash_token(&token);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let expires = now + session_lifetime_seconds();
+
+    sqlx::query!(
+        "INSERT INTO sessions (account_id, token_hash, user_agent, created_at_unix_time, last_used_at_unix_time, expires_at_unix_time) VALUES (?,?,?,?,?,?)",
+        account_id,
+        token_hash,
+        user_agent,
+        now,
+        now,
+        expires
+    )
+    .execute(db)
+    .await?;
+
+    Ok(token)
+}
+
+/// Find the live session with this token, and mark it as used.
+/// Returns the session ID and the account ID.
+pub async fn find_session(
+    db: &sqlx::SqlitePool,
+    token: &str,
+) -> anyhow::Result<Option<(i64, i64)>> {
+    let token_hash = hash_token(token);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let session = match sqlx::query!(
+        "SELECT id, account_id FROM sessions WHERE token_hash=? AND revoked_at_unix_time IS NULL AND expires_at_unix_time>?",
+        token_hash,
+        now
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let stale_before = now - LAST_USED_RESOLUTION_SECONDS;
+    sqlx::query!(
+        "UPDATE sessions SET last_used_at_unix_time=? WHERE id=? AND last_used_at_unix_time<?",
+        now,
+        session.id,
+        stale_before
+    )
+    .execute(db)
+    .await?;
+
+    Ok(Some((session.id, session.account_id)))
+}
+
+/// Revoke every session of the account, except possibly one.
+pub async fn revoke_all_sessions<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+    except_session_id: Option<i64>,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at_unix_time=? WHERE account_id=? AND revoked_at_unix_time IS NULL AND id IS NOT ?",
+        now,
+        account_id,
+        except_session_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A `Set-Cookie` value that stores the session token where the browser's scripts can't read it.
+/// The cookie is only marked `Secure` if `FRONTEND_URL` is not a plain `http://` address,
+/// so that it also works in local development.
+pub fn session_cookie(token: &str) -> HeaderValue {
+    let secure = match std::env::var("FRONTEND_URL") {
+        Ok(url) if url.starts_with("http://") => "",
+        _ => "; Secure",
+    };
+    let max_age = session_lifetime_seconds();
+    HeaderValue::from_str(&format!(
+        "{SESSION_COOKIE}={token}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Strict{secure}"
+    ))
+    .expect("Session token should be a valid header value")
+}
+
+/// A `Set-Cookie` value that removes the session cookie.
+pub fn clear_session_cookie() -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict"
+    ))
+    .unwrap()
+}
+
+pub fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+}
+
+pub async fn list_sessions(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+) -> Result<Json<Vec<SessionInfo>>, AppError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let rows = sqlx::query!(
+        "SELECT * FROM sessions WHERE account_id=? AND revoked_at_unix_time IS NULL AND expires_at_unix_time>? ORDER BY last_used_at_unix_time DESC",
+        account.account_id,
+        now
+    )
+    .fetch_all(&db)
+    .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| SessionInfo {
+                is_current: Some(row.id) == account.session_id,
+                id: row.id,
+                user_agent: row.user_agent,
+                created_at_unix_time: row.created_at_unix_time as u64,
+                last_used_at_unix_time: row.last_used_at_unix_time as u64,
+                expires_at_unix_time: row.expires_at_unix_time as u64,
+            })
+            .collect(),
+    ))
+}
+
+pub async fn revoke_session(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Path(session_id): Path<i64>,
+) -> Result<Json<()>, AppError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let result = sqlx::query!(
+        "UPDATE sessions SET revoked_at_unix_time=? WHERE id=? AND account_id=? AND revoked_at_unix_time IS NULL",
+        now,
+        session_id,
+        account.account_id
+    )
+    .execute(&db)
+    .await?;
+    if result.rows_affected() == 0 {
+        Err(anyhow::anyhow!("No such session found"))?
+    }
+
+    Ok(Json(()))
+}
+
+/// Revoke the session that this request was made with, and remove the session cookie if there is one.
+pub async fn logout(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+) -> Result<Response, AppError> {
+    let session_id = match account.session_id {
+        Some(id) => id,
+        None => Err(anyhow::anyhow!(
+            "This request was not made with a session token, so there is nothing to log out of"
+        ))?,
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at_unix_time=? WHERE id=?",
+        now,
+        session_id
+    )
+    .execute(&db)
+    .await?;
+
+    Ok(([(header::SET_COOKIE, clear_session_cookie())], Json(())).into_response())
+}
@@ -0,0 +1,904 @@
+//! Logging in with the university's accounts, through OpenID Connect.
+//!
+//! The login button sends the browser to [`login`], which redirects to the identity provider;
+//! the provider sends it back to [`callback`] with a code, which is exchanged for an ID token.
+//! The first time someone logs in, a new account is made for them, unless their handle is taken.
+//! If it is, and linking is turned on, the identity is only linked to that account
+//! once they log in to it with its password (see [`link_after_login`]);
+//! handles come from the provider, and anyone there could have picked a handle that is taken here.
+//! Instructors, admins and accounts with two-factor authentication are never linked this way.
+//!
+//! It is turned on by setting these environment variables:
+//! - `OIDC_ISSUER`: the identity provider's issuer URL, where `/.well-known/openid-configuration` is found;
+//! - `OIDC_CLIENT_ID` and `OIDC_CLIENT_SECRET`: how this server is registered with the provider;
+//!   the secret can be left out for a public client, since the flow uses PKCE;
+//! - `OIDC_REDIRECT_URL`: the public address of [`callback`], which must be registered with the provider;
+//! - `FRONTEND_URL`: where to send the browser once it has logged in.
+//!
+//! And optionally:
+//! - `OIDC_PROVIDER_NAME`: what to call the provider on the login button (default `университетский аккаунт`);
+//! - `OIDC_SCOPES`: the scopes to ask for (default `openid profile email`);
+//! - `OIDC_HANDLE_CLAIM`: the claim with the login handle (default `preferred_username`);
+//! - `OIDC_NAME_CLAIM`: the claim with the display name (default `name`; the handle if missing);
+//! - `OIDC_LINK_EXISTING`: whether a new identity may be linked to the account with the same handle,
+//!   after logging in to it with its password (default `false`);
+//! - `OIDC_MARK_VERIFIED`: whether accounts that log in this way count as verified (default `false`).
+//!
+//! Logging in this way doesn't ask for a two-factor code: the provider is trusted with that.
+
+use api::{Role, SingleSignOnInfo, VerificationMethod};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use base64::Engine;
+use itsdangerous::{IntoTimestampSigner, TimestampSigner};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::Digest;
+use sqlx::SqlitePool;
+
+use crate::{
+    admin::insert_account,
+    audit::record_admin_action,
+    result::AppError,
+    sessions::{create_session, session_cookie, user_agent},
+    two_factor,
+    verification::mark_verified,
+    AppState,
+};
+
+/// The cookie that remembers the login in progress, while the browser is away at the provider.
+const LOGIN_COOKIE: &str = "sso_login";
+
+const LOGIN_SALT: &str = "pandoc-sso-login";
+
+/// How long the user has to log in at the provider.
+const LOGIN_LIFETIME: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// The cookie that remembers an identity to link, until the user logs in with the password of its account.
+const LINK_COOKIE: &str = "sso_link";
+
+const LINK_SALT: &str = "pandoc-sso-link";
+
+/// How long the user has to log in with their password to finish linking.
+const LINK_LIFETIME: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+
+struct SsoSettings {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    frontend_url: String,
+    provider_name: String,
+    scopes: String,
+    handle_claim: String,
+    name_claim: String,
+    link_existing: bool,
+    mark_verified: bool,
+}
+
+impl SsoSettings {
+    /// Returns None if single sign-on isn't set up.
+    fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let var_or = |name: &str, default: &str| var(name).unwrap_or_else(|| default.to_string());
+        let flag = |name: &str, default: bool| match var(name).as_deref() {
+            Some("1" | "true" | "yes") => true,
+            Some(_) => false,
+            None => default,
+        };
+        Some(Self {
+            issuer: var("OIDC_ISSUER")?.trim_end_matches('/').to_string(),
+            client_id: var("OIDC_CLIENT_ID")?,
+            client_secret: var("OIDC_CLIENT_SECRET"),
+            redirect_url: var("OIDC_REDIRECT_URL")?,
+            frontend_url: var("FRONTEND_URL")?.trim_end_matches('/').to_string(),
+            provider_name: var_or("OIDC_PROVIDER_NAME", "университетский аккаунт"),
+            scopes: var_or("OIDC_SCOPES", "openid profile email"),
+            handle_claim: var_or("OIDC_HANDLE_CLAIM", "preferred_username"),
+            name_claim: var_or("OIDC_NAME_CLAIM", "name"),
+            link_existing: flag("OIDC_LINK_EXISTING", false),
+            mark_verified: flag("OIDC_MARK_VERIFIED", false),
+        })
+    }
+
+    fn require() -> anyhow::Result<Self> {
+        Self::from_env()
+            .ok_or_else(|| anyhow::anyhow!("Single sign-on is not set up on this server"))
+    }
+}
+
+/// The parts of the provider's configuration that the login needs.
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+async fn discover(settings: &SsoSettings) -> anyhow::Result<ProviderMetadata> {
+    let metadata: ProviderMetadata = reqwest::get(format!(
+        "{}/.well-known/openid-configuration",
+        settings.issuer
+    ))
+    .await?
+    .error_for_status()?
+    .json()
+    .await?;
+    if metadata.issuer.trim_end_matches('/') != settings.issuer {
+        return Err(anyhow::anyhow!(
+            "The provider says its issuer is {:?}, but {:?} was configured",
+            metadata.issuer,
+            settings.issuer
+        ));
+    }
+    Ok(metadata)
+}
+
+fn signer(salt: &'static str) -> impl TimestampSigner {
+    let secret_key = std::env::var("SECRET_KEY").unwrap();
+    itsdangerous::default_builder(secret_key)
+        .with_salt(salt)
+        .build()
+        .into_timestamp_signer()
+}
+
+fn random_string(len: usize) -> String {
+    use rand::distributions::DistString;
+    rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), len)
+}
+
+/// The `Set-Cookie` value for the login in progress, or the identity to link.
+/// It has to be `SameSite=Lax`, so that it comes back with the redirect from the provider.
+fn sso_cookie(name: &str, value: &str, max_age: u64) -> HeaderValue {
+    let secure = match std::env::var("FRONTEND_URL") {
+        Ok(url) if url.starts_with("http://") => "",
+        _ => "; Secure",
+    };
+    HeaderValue::from_str(&format!(
+        "{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}"
+    ))
+    .expect("Single sign-on cookies should be valid header values")
+}
+
+fn read_cookie<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+pub async fn get_info() -> Json<Option<SingleSignOnInfo>> {
+    Json(SsoSettings::from_env().map(|settings| SingleSignOnInfo {
+        provider_name: settings.provider_name,
+    }))
+}
+
+pub async fn login() -> Result<Response, AppError> {
+    let settings = SsoSettings::require()?;
+    let metadata = discover(&settings).await?;
+
+    // The state ties the callback to this browser, the nonce ties the ID token to this login,
+    // and the PKCE verifier ties the code to this server.
+    let state = random_string(32);
+    let nonce = random_string(32);
+    let verifier = random_string(64);
+    let challenge = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(&verifier));
+
+    let mut url = reqwest::Url::parse(&metadata.authorization_endpoint)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &settings.client_id)
+        .append_pair("redirect_uri", &settings.redirect_url)
+        .append_pair("scope", &settings.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    let cookie = signer(LOGIN_SALT).sign(format!("{state}:{nonce}:{verifier}"));
+    Ok((
+        [(
+            header::SET_COOKIE,
+            sso_cookie(LOGIN_COOKIE, &cookie, LOGIN_LIFETIME.as_secs()),
+        )],
+        Redirect::to(url.as_str()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Check the ID token's signature and claims, and return the claims.
+async fn validate_id_token(
+    settings: &SsoSettings,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    nonce: &str,
+) -> anyhow::Result<Map<String, Value>> {
+    let token_header = jsonwebtoken::decode_header(id_token)?;
+    let key = match token_header.alg {
+        // Symmetric signatures use the client secret as the key.
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = settings.client_secret.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("The ID token is signed with the client secret, but there is none")
+            })?;
+            DecodingKey::from_secret(secret.as_bytes())
+        }
+        _ => {
+            let jwks: JwkSet = reqwest::get(&metadata.jwks_uri)
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let jwk = match &token_header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .ok_or_else(|| anyhow::anyhow!("The provider has no key for the ID token"))?;
+            DecodingKey::from_jwk(jwk)?
+        }
+    };
+
+    let mut validation = Validation::new(token_header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&settings.client_id]);
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(id_token, &key, &validation)?.claims;
+
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        return Err(anyhow::anyhow!("The ID token is not for this login"));
+    }
+    Ok(claims)
+}
+
+/// What logging in with the identity leads to.
+enum SsoOutcome {
+    LoggedIn(i64),
+    /// The handle belongs to an account that the identity may be linked to,
+    /// once the user proves it is theirs by logging in with its password.
+    NeedsPassword {
+        account_id: i64,
+        handle: String,
+        subject: String,
+    },
+}
+
+/// Whether an identity may be linked to the account, which only ever happens after a password login.
+/// Accounts that can do more than a student, or that are guarded by a second factor,
+/// are never linked: a login through the provider would skip their second factor from then on.
+async fn is_linkable(db: &SqlitePool, account_id: i64) -> anyhow::Result<bool> {
+    let role = sqlx::query!("SELECT role FROM accounts WHERE id=?", account_id)
+        .fetch_one(db)
+        .await?
+        .role;
+    Ok(Role::from(role) == Role::Student && !two_factor::is_enabled(db, account_id).await?)
+}
+
+/// Find the account linked to the identity, making one if this is its first login.
+async fn find_or_provision_account(
+    db: &SqlitePool,
+    settings: &SsoSettings,
+    claims: &Map<String, Value>,
+) -> anyhow::Result<SsoOutcome> {
+    let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(str::trim);
+    let subject = claim("sub").ok_or_else(|| anyhow::anyhow!("The ID token has no subject"))?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    if let Some(row) = sqlx::query!(
+        "UPDATE sso_identities SET last_login_at_unix_time=? WHERE issuer=? AND subject=? RETURNING account_id",
+        now,
+        settings.issuer,
+        subject
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        return Ok(SsoOutcome::LoggedIn(row.account_id));
+    }
+
+    let handle = claim(&settings.handle_claim)
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("The ID token has no {:?} claim", settings.handle_claim))?;
+    let existing = sqlx::query!("SELECT account_id FROM logins WHERE handle=?", handle)
+        .fetch_optional(db)
+        .await?;
+    let account_id = match existing {
+        Some(row) if settings.link_existing && is_linkable(db, row.account_id).await? => {
+            return Ok(SsoOutcome::NeedsPassword {
+                account_id: row.account_id,
+                handle: handle.to_string(),
+                subject: subject.to_string(),
+            })
+        }
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "The handle {handle:?} is already taken by an account that isn't linked to this identity"
+            ))
+        }
+        None => {
+            let name = claim(&settings.name_claim)
+                .filter(|n| !n.is_empty())
+                .unwrap_or(handle);
+            let mut tx = db.begin().await?;
+            let account_id = insert_account(&mut tx, name, handle, None, Role::Student).await?;
+            record_admin_action(
+                &mut *tx,
+                None,
+                "sso_make_user",
+                json!({"account_id": account_id, "name": name, "handle": handle, "issuer": settings.issuer, "subject": subject}),
+            )
+            .await?;
+            tx.commit().await?;
+            account_id
+        }
+    };
+
+    sqlx::query!(
+        "INSERT INTO sso_identities (issuer, subject, account_id, created_at_unix_time, last_login_at_unix_time) VALUES (?,?,?,?,?)",
+        settings.issuer,
+        subject,
+        account_id,
+        now,
+        now
+    )
+    .execute(db)
+    .await?;
+    Ok(SsoOutcome::LoggedIn(account_id))
+}
+
+async fn finish_login(
+    db: &SqlitePool,
+    headers: &HeaderMap,
+    query: CallbackQuery,
+) -> anyhow::Result<SsoOutcome> {
+    let settings = SsoSettings::require()?;
+    if let Some(error) = query.error {
+        return Err(anyhow::anyhow!(
+            "The provider refused: {error} {}",
+            query.error_description.unwrap_or_default()
+        ));
+    }
+
+    let cookie = read_cookie(headers, LOGIN_COOKIE).ok_or_else(|| {
+        anyhow::anyhow!("The login was started in another browser, or took too long")
+    })?;
+    let login = signer(LOGIN_SALT)
+        .unsign(cookie)
+        .map_err(|why| anyhow::anyhow!("Failed to decode the login cookie: {why}"))?
+        .value_if_not_expired(LOGIN_LIFETIME)
+        .map_err(|_| anyhow::anyhow!("The login took too long"))?
+        .to_string();
+    let (state, nonce, verifier) = match login.split(':').collect::<Vec<_>>()[..] {
+        [state, nonce, verifier] => (state, nonce, verifier),
+        _ => return Err(anyhow::anyhow!("The login cookie is malformed")),
+    };
+    if query.state.as_deref() != Some(state) {
+        return Err(anyhow::anyhow!("The login was started in another tab"));
+    }
+    let code = query
+        .code
+        .ok_or_else(|| anyhow::anyhow!("The provider didn't send a code"))?;
+
+    let metadata = discover(&settings).await?;
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", &code),
+        ("redirect_uri", &settings.redirect_url),
+        ("client_id", &settings.client_id),
+        ("code_verifier", verifier),
+    ];
+    if let Some(secret) = &settings.client_secret {
+        form.push(("client_secret", secret));
+    }
+    let tokens: TokenResponse = reqwest::Client::default()
+        .post(&metadata.token_endpoint)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let claims = validate_id_token(&settings, &metadata, &tokens.id_token, nonce).await?;
+    let outcome = find_or_provision_account(db, &settings, &claims).await?;
+    if let (SsoOutcome::LoggedIn(account_id), true) = (&outcome, settings.mark_verified) {
+        mark_verified(db, *account_id, VerificationMethod::SingleSignOn).await?;
+    }
+    Ok(outcome)
+}
+
+/// Where the provider sends the browser back to.
+/// Either way, the browser ends up on the frontend, which shows the profile, what went wrong,
+/// or that the user has to log in with their password to link the identity to their account.
+pub async fn callback(
+    State(AppState { db, .. }): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, AppError> {
+    let settings = SsoSettings::require()?;
+    let done_url = format!("{}/sso-done", settings.frontend_url);
+    let clear_login_cookie = (header::SET_COOKIE, sso_cookie(LOGIN_COOKIE, "", 0));
+
+    let account_id = match finish_login(&db, &headers, query).await {
+        Ok(SsoOutcome::LoggedIn(account_id)) => account_id,
+        Ok(SsoOutcome::NeedsPassword {
+            account_id,
+            handle,
+            subject,
+        }) => {
+            let link =
+                signer(LINK_SALT).sign(format!("{account_id}:{}", urlencoding::encode(&subject)));
+            let url = format!("{done_url}?link_handle={}", urlencoding::encode(&handle));
+            return Ok((
+                [
+                    clear_login_cookie,
+                    (
+                        header::SET_COOKIE,
+                        sso_cookie(LINK_COOKIE, &link, LINK_LIFETIME.as_secs()),
+                    ),
+                ],
+                Redirect::to(&url),
+            )
+                .into_response());
+        }
+        Err(why) => {
+            tracing::warn!("Single sign-on failed: {why:#}");
+            let url = format!("{done_url}?error={}", urlencoding::encode(&why.to_string()));
+            return Ok(([clear_login_cookie], Redirect::to(&url)).into_response());
+        }
+    };
+
+    let token = create_session(&db, account_id, user_agent(&headers)).await?;
+    Ok((
+        [
+            clear_login_cookie,
+            (header::SET_COOKIE, session_cookie(&token)),
+        ],
+        Redirect::to(&done_url),
+    )
+        .into_response())
+}
+
+/// Called after a successful password login: if the browser came from single sign-on
+/// with an identity to link to this very account, link it now.
+/// Returns the `Set-Cookie` value that forgets the identity, if it was linked.
+pub async fn link_after_login(
+    db: &SqlitePool,
+    headers: &HeaderMap,
+    account_id: i64,
+) -> anyhow::Result<Option<HeaderValue>> {
+    let (Some(settings), Some(cookie)) =
+        (SsoSettings::from_env(), read_cookie(headers, LINK_COOKIE))
+    else {
+        return Ok(None);
+    };
+    if !settings.link_existing {
+        return Ok(None);
+    }
+    let Some(link) = signer(LINK_SALT)
+        .unsign(cookie)
+        .ok()
+        .and_then(|link| link.value_if_not_expired(LINK_LIFETIME).ok())
+        .map(str::to_string)
+    else {
+        return Ok(None);
+    };
+    let Some((link_account_id, subject)) = link.split_once(':') else {
+        return Ok(None);
+    };
+    let subject = urlencoding::decode(subject)?;
+    if link_account_id.parse::<i64>().ok() != Some(account_id)
+        || !is_linkable(db, account_id).await?
+    {
+        return Ok(None);
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let mut tx = db.begin().await?;
+    // If the identity got linked to some account in the meantime, that link stays.
+    let linked = sqlx::query!(
+        "INSERT INTO sso_identities (issuer, subject, account_id, created_at_unix_time, last_login_at_unix_time) VALUES (?,?,?,?,?)
+        ON CONFLICT (issuer, subject) DO NOTHING",
+        settings.issuer,
+        subject,
+        account_id,
+        now,
+        now
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if linked {
+        record_admin_action(
+            &mut *tx,
+            None,
+            "sso_link_account",
+            json!({"account_id": account_id, "issuer": settings.issuer, "subject": subject}),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(Some(sso_cookie(LINK_COOKIE, "", 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use api::LoginResponse;
+    use axum::{
+        extract::ConnectInfo,
+        http::StatusCode,
+        routing::{get, post},
+        Form, Router,
+    };
+    use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::*;
+    use crate::{
+        auth::SESSION_COOKIE,
+        testing::{serve, set_env, TestEnv, TestServer},
+    };
+
+    const CLIENT_ID: &str = "test-client";
+    const KEY_ID: &str = "test-key";
+
+    /// Who the stub provider says has logged in, for the next ID token it hands out.
+    struct NextLogin {
+        subject: String,
+        handle: String,
+        nonce: String,
+        code_challenge: String,
+    }
+
+    #[derive(Default)]
+    struct StubIssuer {
+        url: String,
+        pkcs8: Vec<u8>,
+        next_login: Option<NextLogin>,
+    }
+
+    type Stub = Arc<Mutex<StubIssuer>>;
+
+    /// A stand-in for the identity provider: discovery, a key set with one Ed25519 key,
+    /// and a token endpoint that checks PKCE and signs an ID token for the next login.
+    async fn start_issuer() -> Stub {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let stub = Arc::new(Mutex::new(StubIssuer {
+            pkcs8: pkcs8.as_ref().to_vec(),
+            ..Default::default()
+        }));
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(stub_discovery))
+            .route("/jwks", get(stub_jwks))
+            .route("/token", post(stub_token))
+            .with_state(stub.clone());
+        stub.lock().unwrap().url = serve(router).await;
+        stub
+    }
+
+    async fn stub_discovery(State(stub): State<Stub>) -> Json<Value> {
+        let url = stub.lock().unwrap().url.clone();
+        Json(json!({
+            "issuer": url,
+            "authorization_endpoint": format!("{url}/authorize"),
+            "token_endpoint": format!("{url}/token"),
+            "jwks_uri": format!("{url}/jwks"),
+        }))
+    }
+
+    async fn stub_jwks(State(stub): State<Stub>) -> Json<Value> {
+        let key = Ed25519KeyPair::from_pkcs8(&stub.lock().unwrap().pkcs8).unwrap();
+        Json(json!({"keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "use": "sig",
+            "alg": "EdDSA",
+            "kid": KEY_ID,
+            "x": BASE64_URL_SAFE_NO_PAD.encode(key.public_key().as_ref()),
+        }]}))
+    }
+
+    async fn stub_token(
+        State(stub): State<Stub>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Response {
+        let stub = stub.lock().unwrap();
+        let Some(next) = &stub.next_login else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        if form.get("code").map(String::as_str) != Some("test-code")
+            || BASE64_URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(verifier)) != next.code_challenge
+        {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = json!({
+            "iss": stub.url,
+            "aud": CLIENT_ID,
+            "sub": next.subject,
+            "preferred_username": next.handle,
+            "nonce": next.nonce,
+            "iat": now,
+            "exp": now + 300,
+        });
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(KEY_ID.to_string());
+        let id_token =
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ed_der(&stub.pkcs8)).unwrap();
+        Json(json!({"access_token": "unused", "token_type": "Bearer", "id_token": id_token}))
+            .into_response()
+    }
+
+    async fn sso_env(stub: &Stub, link_existing: bool) -> TestEnv {
+        let issuer = stub.lock().unwrap().url.clone();
+        set_env(&[
+            ("OIDC_ISSUER", &issuer),
+            ("OIDC_CLIENT_ID", CLIENT_ID),
+            ("OIDC_REDIRECT_URL", "http://server.test/api/sso/callback"),
+            (
+                "OIDC_LINK_EXISTING",
+                if link_existing { "true" } else { "false" },
+            ),
+        ])
+        .await
+    }
+
+    /// The `name=value` of the cookie the response sets, unless it only clears it.
+    fn set_cookie(resp: &Response, name: &str) -> Option<String> {
+        resp.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().unwrap().split(';').next())
+            .find(|pair| pair.strip_prefix(name).is_some_and(|rest| rest.len() > 1))
+            .map(str::to_string)
+    }
+
+    fn location(resp: &Response) -> &str {
+        resp.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    /// Go all the way through single sign-on, as whoever has this subject and handle at the provider.
+    async fn log_in_as(server: &TestServer, stub: &Stub, subject: &str, handle: &str) -> Response {
+        let started = login().await.unwrap();
+        let url = reqwest::Url::parse(location(&started)).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .unwrap()
+                .1
+                .into_owned()
+        };
+        stub.lock().unwrap().next_login = Some(NextLogin {
+            subject: subject.to_string(),
+            handle: handle.to_string(),
+            nonce: param("nonce"),
+            code_challenge: param("code_challenge"),
+        });
+
+        let mut headers = HeaderMap::new();
+        let cookie = set_cookie(&started, LOGIN_COOKIE).unwrap();
+        headers.insert(header::COOKIE, cookie.parse().unwrap());
+        callback(
+            State(server.state.clone()),
+            headers,
+            Query(CallbackQuery {
+                code: Some("test-code".to_string()),
+                state: Some(param("state")),
+                error: None,
+                error_description: None,
+            }),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn password_login(
+        server: &TestServer,
+        cookie: &str,
+        handle: &str,
+        password: &str,
+    ) -> (Response, LoginResponse) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, cookie.parse().unwrap());
+        let resp = crate::profile::login(
+            State(server.state.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))),
+            headers,
+            Json(api::LoginRequest {
+                handle: handle.to_string(),
+                password: password.to_string(),
+                proof_of_work: None,
+                two_factor_code: None,
+            }),
+        )
+        .await
+        .unwrap();
+        let (parts, body) = resp.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let login = serde_json::from_slice(&body).unwrap();
+        (
+            Response::from_parts(parts, axum::body::Body::empty()),
+            login,
+        )
+    }
+
+    async fn linked_account(server: &TestServer, subject: &str) -> Option<i64> {
+        sqlx::query_scalar("SELECT account_id FROM sso_identities WHERE subject=?")
+            .bind(subject)
+            .fetch_optional(server.db())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn first_login_makes_an_account() {
+        let stub = start_issuer().await;
+        let _env = sso_env(&stub, false).await;
+        let server = TestServer::new().await;
+
+        let resp = log_in_as(&server, &stub, "subject-1", "newcomer").await;
+        assert_eq!(location(&resp), "http://frontend.test/sso-done");
+        assert!(set_cookie(&resp, SESSION_COOKIE).is_some());
+        let account_id: i64 = sqlx::query_scalar("SELECT account_id FROM logins WHERE handle=?")
+            .bind("newcomer")
+            .fetch_one(server.db())
+            .await
+            .unwrap();
+        assert_eq!(linked_account(&server, "subject-1").await, Some(account_id));
+
+        // The subject is what identifies the user, so a new handle at the provider changes nothing.
+        let resp = log_in_as(&server, &stub, "subject-1", "renamed").await;
+        assert!(set_cookie(&resp, SESSION_COOKIE).is_some());
+        let accounts: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM accounts")
+            .fetch_one(server.db())
+            .await
+            .unwrap();
+        assert_eq!(accounts, 1);
+    }
+
+    #[tokio::test]
+    async fn taken_handle_is_not_linked_by_default() {
+        let stub = start_issuer().await;
+        let _env = sso_env(&stub, false).await;
+        let server = TestServer::new().await;
+        server
+            .make_account("alice", Role::Student, Some("alice's password"))
+            .await;
+
+        let resp = log_in_as(&server, &stub, "subject-2", "alice").await;
+        assert!(location(&resp).starts_with("http://frontend.test/sso-done?error="));
+        assert!(set_cookie(&resp, SESSION_COOKIE).is_none());
+        assert!(set_cookie(&resp, LINK_COOKIE).is_none());
+        assert_eq!(linked_account(&server, "subject-2").await, None);
+    }
+
+    #[tokio::test]
+    async fn taken_handle_is_linked_only_after_password_login() {
+        let stub = start_issuer().await;
+        let _env = sso_env(&stub, true).await;
+        let server = TestServer::new().await;
+        let alice = server
+            .make_account("alice", Role::Student, Some("alice's password"))
+            .await;
+
+        let resp = log_in_as(&server, &stub, "subject-3", "alice").await;
+        assert_eq!(
+            location(&resp),
+            "http://frontend.test/sso-done?link_handle=alice"
+        );
+        assert!(set_cookie(&resp, SESSION_COOKIE).is_none());
+        let link_cookie = set_cookie(&resp, LINK_COOKIE).unwrap();
+        assert_eq!(linked_account(&server, "subject-3").await, None);
+
+        let (_, login) = password_login(&server, &link_cookie, "alice", "wrong password").await;
+        assert_eq!(login, LoginResponse::InvalidCredentials);
+        assert_eq!(linked_account(&server, "subject-3").await, None);
+
+        let (resp, login) =
+            password_login(&server, &link_cookie, "alice", "alice's password").await;
+        assert!(matches!(login, LoginResponse::Ok { .. }));
+        assert!(set_cookie(&resp, LINK_COOKIE).is_none());
+        assert_eq!(linked_account(&server, "subject-3").await, Some(alice));
+
+        let resp = log_in_as(&server, &stub, "subject-3", "alice").await;
+        assert_eq!(location(&resp), "http://frontend.test/sso-done");
+        assert!(set_cookie(&resp, SESSION_COOKIE).is_some());
+    }
+
+    #[tokio::test]
+    async fn link_is_only_for_the_account_it_was_made_for() {
+        let stub = start_issuer().await;
+        let _env = sso_env(&stub, true).await;
+        let server = TestServer::new().await;
+        server
+            .make_account("alice", Role::Student, Some("alice's password"))
+            .await;
+        server
+            .make_account("mallory", Role::Student, Some("mallory's password"))
+            .await;
+
+        let resp = log_in_as(&server, &stub, "subject-4", "alice").await;
+        let link_cookie = set_cookie(&resp, LINK_COOKIE).unwrap();
+        let (_, login) =
+            password_login(&server, &link_cookie, "mallory", "mallory's password").await;
+        assert!(matches!(login, LoginResponse::Ok { .. }));
+        assert_eq!(linked_account(&server, "subject-4").await, None);
+    }
+
+    #[tokio::test]
+    async fn privileged_and_two_factor_accounts_are_never_linked() {
+        let stub = start_issuer().await;
+        let _env = sso_env(&stub, true).await;
+        let server = TestServer::new().await;
+        server
+            .make_account("admin", Role::Admin, Some("admin's password"))
+            .await;
+        server
+            .make_account("teacher", Role::Instructor, Some("teacher's password"))
+            .await;
+        let guarded = server
+            .make_account("guarded", Role::Student, Some("guarded password"))
+            .await;
+        sqlx::query(
+            "INSERT INTO two_factor (account_id, secret, confirmed_at_unix_time) VALUES (?, 'JBSWY3DPEHPK3PXP', 1)",
+        )
+        .bind(guarded)
+        .execute(server.db())
+        .await
+        .unwrap();
+
+        for (subject, handle) in [
+            ("subject-5", "admin"),
+            ("subject-6", "teacher"),
+            ("subject-7", "guarded"),
+        ] {
+            let resp = log_in_as(&server, &stub, subject, handle).await;
+            assert!(location(&resp).starts_with("http://frontend.test/sso-done?error="));
+            assert!(set_cookie(&resp, SESSION_COOKIE).is_none());
+            assert!(set_cookie(&resp, LINK_COOKIE).is_none());
+            assert_eq!(linked_account(&server, subject).await, None);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Shared setup for the tests: a fresh database, the environment the server is configured with,
+//! and local stand-ins for the outside services it talks to.
+
+use api::Role;
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+use tokio::sync::{mpsc, MutexGuard};
+
+use crate::{admin::insert_account, passwords::hash_password, AppState};
+
+/// The server is configured with environment variables, which the whole test process shares,
+/// so tests that set them take turns.
+static ENV_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Set for every test, so that passwords hash quickly and cookies work over plain HTTP.
+const COMMON_ENV: &[(&str, &str)] = &[
+    ("SECRET_KEY", "test-secret-key"),
+    ("FRONTEND_URL", "http://frontend.test"),
+    ("PASSWORD_ARGON2_MEMORY_KIB", "64"),
+    ("PASSWORD_ARGON2_ITERATIONS", "1"),
+];
+
+/// Holds the environment for one test; the variables it set are removed when it is dropped.
+pub struct TestEnv {
+    names: Vec<String>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for name in &self.names {
+            std::env::remove_var(name);
+        }
+    }
+}
+
+pub async fn set_env(vars: &[(&str, &str)]) -> TestEnv {
+    let guard = ENV_LOCK.lock().await;
+    let mut names = vec![];
+    for (name, value) in COMMON_ENV.iter().chain(vars) {
+        std::env::set_var(name, value);
+        names.push(name.to_string());
+    }
+    TestEnv {
+        names,
+        _guard: guard,
+    }
+}
+
+/// The server's state, with a migrated database in a temporary directory.
+pub struct TestServer {
+    pub state: AppState,
+    _dir: tempfile::TempDir,
+}
+
+impl TestServer {
+    pub async fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .filename(dir.path().join("test.db"))
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        sqlx::migrate!().run(&db).await.unwrap();
+        // Nothing in the tests runs orders, so the manager isn't started.
+        let (manager_connection, _) = mpsc::channel(1);
+        Self {
+            state: AppState {
+                db,
+                manager_connection,
+            },
+            _dir: dir,
+        }
+    }
+
+    pub fn db(&self) -> &SqlitePool {
+        &self.state.db
+    }
+
+    pub async fn make_account(&self, handle: &str, role: Role, password: Option<&str>) -> i64 {
+        let hash = password.map(|password| hash_password(password).unwrap());
+        let mut tx = self.db().begin().await.unwrap();
+        let account_id = insert_account(&mut tx, handle, handle, hash.as_deref(), role)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        account_id
+    }
+}
+
+/// Serve a stand-in for an outside service on a free local port, and return its base URL.
+pub async fn serve(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{address}")
+}
@@ -0,0 +1,366 @@
+//! Two-factor authentication with an authenticator app (TOTP), and recovery codes for when it's lost.
+//!
+//! Enrolling takes two steps: first the secret is made and shown as a QR code,
+//! then a code from the app confirms that it was added. Only then does logging in ask for codes.
+//! If both the app and the recovery codes are lost, staff can turn it off with `admin::reset_password`.
+//!
+//! The issuer shown in the app is `TOTP_ISSUER` (default `pandoc-web-compiler`).
+
+use api::{
+    BeginTwoFactorResponse, ConfirmTwoFactorResponse, DisableTwoFactorResponse, SecurityEventKind,
+    TwoFactorCodeRequest, TwoFactorStatus,
+};
+use axum::{extract::State, Json};
+use rand::Rng;
+use sqlx::{Sqlite, SqliteExecutor, SqlitePool, Transaction};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    auth::CurrentAccount,
+    login_throttle::{record_security_event, record_two_factor_failure, two_factor_locked_until},
+    result::AppError,
+    sessions::hash_token,
+    AppState,
+};
+
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+
+/// How many steps before or after the current one are still accepted, for clocks that are a bit off.
+const SKEW_STEPS: i64 = 1;
+
+/// 160 bits, as RFC 4226 recommends.
+const SECRET_BYTES: usize = 20;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const RECOVERY_CODE_HALF_LEN: usize = 5;
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Build the generator for the secret.
+/// Its own skew is zero, because the steps are checked one by one to find which one matched.
+fn make_totp(secret: &str, account_name: &str) -> anyhow::Result<TOTP> {
+    let issuer = std::env::var("TOTP_ISSUER").unwrap_or_else(|_| "pandoc-web-compiler".to_string());
+    // The provisioning URI uses colons to separate the issuer from the account.
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECONDS,
+        Secret::Encoded(secret.to_string()).to_bytes()?,
+        Some(issuer.replace(':', "_")),
+        account_name.replace(':', "_"),
+    )?)
+}
+
+/// Find the time step that the code is for, if it is one of the accepted ones and comes after `last_used_step`.
+fn matching_step(totp: &TOTP, code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    let current_step = now() / STEP_SECONDS as i64;
+    (current_step - SKEW_STEPS..=current_step + SKEW_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| totp.check(code, *step as u64 * STEP_SECONDS))
+}
+
+/// The codes are stored hashed together with the account, like email codes are.
+/// Dashes and case don't matter when they are typed in.
+fn hash_recovery_code(account_id: i64, code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&format!("{account_id}:{normalized}"))
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut half = || {
+        (0..RECOVERY_CODE_HALF_LEN)
+            .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+            .collect::<String>()
+    };
+    format!("{}-{}", half(), half())
+}
+
+/// Replace the account's recovery codes with new ones, and return them.
+async fn replace_recovery_codes(
+    tx: &mut Transaction<'_, Sqlite>,
+    account_id: i64,
+) -> anyhow::Result<Vec<String>> {
+    sqlx::query!(
+        "DELETE FROM two_factor_recovery_codes WHERE account_id=?",
+        account_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    for code in &codes {
+        let code_hash = hash_recovery_code(account_id, code);
+        sqlx::query!(
+            "INSERT INTO two_factor_recovery_codes (account_id, code_hash) VALUES (?,?)",
+            account_id,
+            code_hash
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(codes)
+}
+
+/// The name that the account is shown under in the authenticator app.
+async fn account_name(db: &SqlitePool, account_id: i64) -> anyhow::Result<String> {
+    let handle = sqlx::query!("SELECT handle FROM logins WHERE account_id=?", account_id)
+        .fetch_optional(db)
+        .await?;
+    Ok(match handle {
+        Some(row) => row.handle,
+        None => {
+            sqlx::query!("SELECT user_name FROM accounts WHERE id=?", account_id)
+                .fetch_one(db)
+                .await?
+                .user_name
+        }
+    })
+}
+
+/// Whether logging in to the account needs a two-factor code.
+pub async fn is_enabled(db: &SqlitePool, account_id: i64) -> anyhow::Result<bool> {
+    Ok(sqlx::query!(
+        "SELECT account_id FROM two_factor WHERE account_id=? AND confirmed_at_unix_time IS NOT NULL",
+        account_id
+    )
+    .fetch_optional(db)
+    .await?
+    .is_some())
+}
+
+/// Check a code from the authenticator app, or else a recovery code, and use it up.
+pub async fn check_code(
+    db: &SqlitePool,
+    account_id: i64,
+    code: &str,
+    ip: Option<&str>,
+) -> anyhow::Result<bool> {
+    let row = match sqlx::query!(
+        "SELECT * FROM two_factor WHERE account_id=? AND confirmed_at_unix_time IS NOT NULL",
+        account_id
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(false),
+    };
+
+    let code = code.trim();
+    let totp = make_totp(&row.secret, &account_name(db, account_id).await?)?;
+    if let Some(step) = matching_step(&totp, code, row.last_used_step) {
+        // Only one of two concurrent logins with the same code gets to use it.
+        let used = sqlx::query!(
+            "UPDATE two_factor SET last_used_step=? WHERE account_id=? AND (last_used_step IS NULL OR last_used_step<?)",
+            step,
+            account_id,
+            step
+        )
+        .execute(db)
+        .await?
+        .rows_affected();
+        return Ok(used == 1);
+    }
+
+    let code_hash = hash_recovery_code(account_id, code);
+    let now = now();
+    let used = sqlx::query!(
+        "UPDATE two_factor_recovery_codes SET used_at_unix_time=? WHERE account_id=? AND code_hash=? AND used_at_unix_time IS NULL",
+        now,
+        account_id,
+        code_hash
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    if used == 0 {
+        return Ok(false);
+    }
+    record_security_event(db, account_id, SecurityEventKind::RecoveryCodeUsed, ip).await?;
+    Ok(true)
+}
+
+/// Turn off two-factor authentication for the account, forgetting its secret and recovery codes.
+/// Returns whether it was on.
+pub async fn remove_two_factor(
+    tx: &mut Transaction<'_, Sqlite>,
+    account_id: i64,
+) -> anyhow::Result<bool> {
+    let removed = sqlx::query!(
+        "DELETE FROM two_factor WHERE account_id=? RETURNING confirmed_at_unix_time",
+        account_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .is_some_and(|row| row.confirmed_at_unix_time.is_some());
+    sqlx::query!(
+        "DELETE FROM two_factor_recovery_codes WHERE account_id=?",
+        account_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(removed)
+}
+
+async fn recovery_codes_left(db: impl SqliteExecutor<'_>, account_id: i64) -> anyhow::Result<u64> {
+    Ok(sqlx::query!(
+        "SELECT COUNT(*) AS count FROM two_factor_recovery_codes WHERE account_id=? AND used_at_unix_time IS NULL",
+        account_id
+    )
+    .fetch_one(db)
+    .await?
+    .count as u64)
+}
+
+pub async fn get_status(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+) -> Result<Json<TwoFactorStatus>, AppError> {
+    let enabled = is_enabled(&db, account.account_id).await?;
+    Ok(Json(TwoFactorStatus {
+        enabled,
+        recovery_codes_left: if enabled {
+            recovery_codes_left(&db, account.account_id).await?
+        } else {
+            0
+        },
+    }))
+}
+
+pub async fn begin(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+) -> Result<Json<BeginTwoFactorResponse>, AppError> {
+    if is_enabled(&db, account.account_id).await? {
+        return Ok(Json(BeginTwoFactorResponse::AlreadyEnabled));
+    }
+
+    let secret_bytes: [u8; SECRET_BYTES] = rand::thread_rng().gen();
+    let secret = Secret::Raw(secret_bytes.to_vec()).to_encoded().to_string();
+    let totp = make_totp(&secret, &account_name(&db, account.account_id).await?)?;
+    let provisioning_uri = totp.get_url();
+    let qr_svg = qrcode::QrCode::new(provisioning_uri.as_bytes())?
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    // Starting over replaces a secret that was never confirmed, but never a confirmed one.
+    sqlx::query!(
+        "INSERT INTO two_factor (account_id, secret) VALUES (?,?)
+        ON CONFLICT (account_id) DO UPDATE SET secret=excluded.secret, last_used_step=NULL
+        WHERE confirmed_at_unix_time IS NULL",
+        account.account_id,
+        secret
+    )
+    .execute(&db)
+    .await?;
+
+    Ok(Json(BeginTwoFactorResponse::Ok {
+        secret,
+        provisioning_uri,
+        qr_svg,
+    }))
+}
+
+pub async fn confirm(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Json(TwoFactorCodeRequest { code }): Json<TwoFactorCodeRequest>,
+) -> Result<Json<ConfirmTwoFactorResponse>, AppError> {
+    let row = match sqlx::query!(
+        "SELECT * FROM two_factor WHERE account_id=?",
+        account.account_id
+    )
+    .fetch_optional(&db)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(Json(ConfirmTwoFactorResponse::NotStarted)),
+    };
+    if row.confirmed_at_unix_time.is_some() {
+        return Ok(Json(ConfirmTwoFactorResponse::AlreadyEnabled));
+    }
+
+    let totp = make_totp(&row.secret, &account_name(&db, account.account_id).await?)?;
+    let step = match matching_step(&totp, code.trim(), None) {
+        Some(step) => step,
+        None => return Ok(Json(ConfirmTwoFactorResponse::InvalidCode)),
+    };
+
+    let now = now();
+    let mut tx = db.begin().await?;
+    let confirmed = sqlx::query!(
+        "UPDATE two_factor SET confirmed_at_unix_time=?, last_used_step=? WHERE account_id=? AND secret=? AND confirmed_at_unix_time IS NULL",
+        now,
+        step,
+        account.account_id,
+        row.secret
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    // Another tab may have started over or confirmed in the meantime.
+    if confirmed == 0 {
+        return Ok(Json(ConfirmTwoFactorResponse::NotStarted));
+    }
+    let recovery_codes = replace_recovery_codes(&mut tx, account.account_id).await?;
+    record_security_event(
+        &mut *tx,
+        account.account_id,
+        SecurityEventKind::TwoFactorEnabled,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(ConfirmTwoFactorResponse::Ok { recovery_codes }))
+}
+
+pub async fn disable(
+    State(AppState { db, .. }): State<AppState>,
+    account: CurrentAccount,
+    Json(TwoFactorCodeRequest { code }): Json<TwoFactorCodeRequest>,
+) -> Result<Json<DisableTwoFactorResponse>, AppError> {
+    if !is_enabled(&db, account.account_id).await? {
+        return Ok(Json(DisableTwoFactorResponse::NotEnabled));
+    }
+    // A stolen session alone shouldn't be enough to turn it off,
+    // and wrong codes are counted so that they can't be guessed here either.
+    if let Some(until) = two_factor_locked_until(&db, account.account_id).await? {
+        return Ok(Json(DisableTwoFactorResponse::LockedOut {
+            until_unix_time: until as u64,
+        }));
+    }
+    if !check_code(&db, account.account_id, &code, None).await? {
+        record_two_factor_failure(&db, account.account_id).await?;
+        return Ok(Json(DisableTwoFactorResponse::InvalidCode));
+    }
+
+    let mut tx = db.begin().await?;
+    remove_two_factor(&mut tx, account.account_id).await?;
+    record_security_event(
+        &mut *tx,
+        account.account_id,
+        SecurityEventKind::TwoFactorDisabled,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(DisableTwoFactorResponse::Ok))
+}
@@ -1,7 +1,11 @@
 use std::{collections::HashSet, fmt::Debug, os::unix::fs::MetadataExt};
 
 use anyhow::anyhow;
-use api::{LiveStatus, OrderFile, OrderFileList, OrderInfoFull, OrderInfoResult};
+use api::{
+    CostEstimate, CostEstimateRequest, JobTerminationStatus, LiveStatus, OrderExecutionMetrics,
+    OrderFile, OrderFileList, OrderInfo, OrderInfoFull, OrderInfoResult, PricingInfo,
+    TerminationCause,
+};
 use axum::{
     extract::{ws::WebSocket, Multipart, Path, Query, State, WebSocketUpgrade},
     http::{header, HeaderMap, StatusCode},
@@ -18,36 +22,69 @@ use tokio_util::io::ReaderStream;
 use tracing::Instrument;
 
 use crate::{
-    manager::ManagerRequest, pricing::get_current_pricing, result::AppError,
-    worker::RunningJobHandle, AppState,
+    api_keys::{has_spending_left, reserve_spending, KeyBudget},
+    auth::{ReadOrdersAccess, UploadOrdersAccess},
+    groups::get_available_funds,
+    manager::ManagerRequest,
+    pricing::{get_surge_multiplier, get_surged_pricing},
+    refund::get_order_refund,
+    result::AppError,
+    worker::RunningJobHandle,
+    AppState,
 };
 
+#[derive(Deserialize)]
+pub struct UploadOrderOptions {
+    /// The most that the user is willing to spend on this order.
+    pub budget: Option<f64>,
+}
+
 pub async fn upload_order(
     State(AppState {
         db,
         manager_connection,
     }): State<AppState>,
-    Path(token): Path<String>,
+    UploadOrdersAccess(account): UploadOrdersAccess,
+    Query(UploadOrderOptions { budget }): Query<UploadOrderOptions>,
     mut files: Multipart,
 ) -> Result<String, AppError> {
-    let data = match sqlx::query!("SELECT * FROM accounts WHERE token=?", token)
-        .fetch_optional(&db)
-        .await?
-    {
-        Some(v) => v,
-        None => Err(anyhow::anyhow!("No such token found"))?,
-    };
+    let data = sqlx::query!("SELECT * FROM accounts WHERE id=?", account.account_id)
+        .fetch_one(&db)
+        .await?;
 
     if data.verification_method.is_none() {
         return Err(anyhow::anyhow!("The account is not verified"))?;
     }
 
+    if let Some(budget) = budget {
+        if budget.is_nan() || budget <= 0.0 {
+            return Err(anyhow::anyhow!("The order budget must be positive"))?;
+        }
+    }
+
+    // A key that has nothing left can't make orders, so don't bother taking the files.
+    if let Some(key) = &account.api_key {
+        if !has_spending_left(&db, key).await? {
+            return Err(anyhow::anyhow!(
+                "This API key has used up its spending limit"
+            ))?;
+        }
+    }
+    let api_key_id = account.api_key.as_ref().map(|key| key.id);
+
     let span = tracing::debug_span!("order_upload");
     async move {
         tracing::debug!("Received order from {data:?}");
 
         let order_id = ManagerRequest::allocate_order(&manager_connection, data.id).await;
         tracing::debug!("The order was allocated ID {order_id}");
+        sqlx::query!(
+            "UPDATE orders SET api_key_id=? WHERE id=?",
+            api_key_id,
+            order_id
+        )
+        .execute(&db)
+        .await?;
 
         tracing::debug!("Starting to copy files into work directory...");
         // Now we need to copy the files into the work directory.
@@ -77,6 +114,7 @@ pub async fn upload_order(
 
             let mut file = tokio::fs::OpenOptions::new()
                 .create(true)
+                .truncate(false)
                 .write(true)
                 .open(path)
                 .await?;
@@ -84,14 +122,35 @@ pub async fn upload_order(
             file.write_all(&data).await?;
         }
         tracing::debug!("Files copied to work directory!");
+        let size_mb = size as f64 / 1024.0 / 1024.0;
+
+        // An order made with an API key can't cost more than what is left of the key's spending limit,
+        // and its budget is set aside from that until the order finishes.
+        // This is only done once the files are stored, so that a failed upload doesn't hold any money.
+        let budget = match &account.api_key {
+            Some(key) if key.spending_limit.is_some() => {
+                let amount = match budget {
+                    Some(budget) => budget,
+                    None => {
+                        let pricing = get_surged_pricing(&manager_connection).await;
+                        reservation_estimate(&db, data.id, &file_list, size_mb, &pricing).await?
+                    }
+                };
+                match reserve_spending(&db, key, order_id, amount).await? {
+                    KeyBudget::UsedUp => {
+                        return Err(anyhow::anyhow!(
+                            "This API key has used up its spending limit"
+                        ))?;
+                    }
+                    KeyBudget::Reserved(amount) => Some(amount),
+                    KeyBudget::Unlimited => budget,
+                }
+            }
+            _ => budget,
+        };
 
-        ManagerRequest::uploaded_files(
-            &manager_connection,
-            order_id,
-            file_list,
-            size as f64 / 1024.0 / 1024.0,
-        )
-        .await;
+        ManagerRequest::uploaded_files(&manager_connection, order_id, file_list, size_mb, budget)
+            .await;
 
         Ok(format!("{order_id}"))
     }
@@ -99,14 +158,170 @@ pub async fn upload_order(
     .await
 }
 
+/// How many of the most similar past orders are used to estimate the execution cost.
+const ESTIMATE_SIMILAR_ORDERS: usize = 5;
+
+/// How many of the most recent past orders are searched for similar ones.
+const ESTIMATE_HISTORY_DEPTH: i64 = 100;
+
+pub async fn estimate_order_cost(
+    State(AppState {
+        db,
+        manager_connection,
+    }): State<AppState>,
+    UploadOrdersAccess(current): UploadOrdersAccess,
+    Json(CostEstimateRequest { files }): Json<CostEstimateRequest>,
+) -> Result<Json<CostEstimate>, AppError> {
+    let account = sqlx::query!("SELECT * FROM accounts WHERE id=?", current.account_id)
+        .fetch_one(&db)
+        .await?;
+
+    let pricing = get_surged_pricing(&manager_connection).await;
+
+    // The upload part is known exactly.
+    let total_bytes: u64 = files.iter().map(|f| f.size_bytes).sum();
+    let upload_metrics = OrderExecutionMetrics {
+        uploaded_mb: total_bytes as f64 / 1024.0 / 1024.0,
+        uploaded_files: files.len(),
+        ..Default::default()
+    };
+    let upload_cost = upload_metrics.calculate_costs(&pricing).grand_total();
+
+    // The execution part is guessed from past orders that had similar input files.
+    let planned: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+    let similar = find_similar_orders(&db, account.id, &planned).await?;
+
+    let execution_cost_range = similar
+        .iter()
+        .map(|metrics| execution_cost(metrics, &pricing))
+        .fold(None, |range, cost| match range {
+            None => Some((cost, cost)),
+            Some((min, max)) => Some((f64::min(min, cost), f64::max(max, cost))),
+        });
+
+    Ok(Json(CostEstimate {
+        pricing,
+        upload_cost,
+        execution_cost_range,
+        similar_orders: similar.len(),
+    }))
+}
+
+/// The metrics of the account's completed past orders whose input files were most like the planned ones,
+/// the most similar first.
+async fn find_similar_orders(
+    db: &SqlitePool,
+    account_id: i64,
+    planned: &[&str],
+) -> anyhow::Result<Vec<OrderExecutionMetrics>> {
+    let past_orders = sqlx::query!(
+        "SELECT status_json, src_file_list FROM orders WHERE user_id=? AND is_running=0 AND status_json IS NOT NULL ORDER BY created_at_unix_time DESC LIMIT ?",
+        account_id,
+        ESTIMATE_HISTORY_DEPTH
+    )
+    .fetch_all(db)
+    .await?;
+
+    let planned: HashSet<&str> = planned.iter().copied().collect();
+    let mut similar = vec![];
+    for row in past_orders {
+        let info = match row
+            .status_json
+            .and_then(|v| serde_json::from_str::<OrderInfo>(&v).ok())
+        {
+            Some(info) => info,
+            None => continue,
+        };
+
+        // Only orders that ran to completion say anything about how expensive the makefile is.
+        let metrics = match info.termination {
+            JobTerminationStatus::ProcessExit {
+                cause: TerminationCause::NaturalTermination,
+                metrics,
+                ..
+            } => metrics,
+            _ => continue,
+        };
+
+        // Similarity is the Jaccard index of the file name sets.
+        let past_files: HashSet<String> =
+            serde_json::from_str(&row.src_file_list).unwrap_or_default();
+        let common = past_files
+            .iter()
+            .filter(|p| planned.contains(p.as_str()))
+            .count();
+        if common == 0 {
+            continue;
+        }
+        let union = planned.len() + past_files.len() - common;
+        similar.push((common as f64 / union as f64, metrics));
+    }
+    similar.sort_by(|a, b| b.0.total_cmp(&a.0));
+    similar.truncate(ESTIMATE_SIMILAR_ORDERS);
+    Ok(similar.into_iter().map(|(_, metrics)| metrics).collect())
+}
+
+/// The part of an order's cost that comes from running the makefile.
+fn execution_cost(metrics: &OrderExecutionMetrics, pricing: &PricingInfo) -> f64 {
+    let costs = metrics.calculate_costs(pricing);
+    costs.cpu_time + costs.wall_time + costs.processes
+}
+
+/// How many times the most expensive similar past order is set aside for an order made with an API key
+/// that didn't give a budget, since the new order may well cost more.
+const RESERVATION_MARGIN: f64 = 2.0;
+
+/// What is set aside from an API key's limit for an order that didn't give a budget:
+/// the exact upload cost, plus a margin over the most expensive similar past order.
+/// If there are none, the execution part is `API_KEY_DEFAULT_RESERVATION` (default 10000).
+/// The order is stopped if it goes over this, like it would be with a budget.
+async fn reservation_estimate(
+    db: &SqlitePool,
+    account_id: i64,
+    file_list: &[String],
+    size_mb: f64,
+    pricing: &PricingInfo,
+) -> anyhow::Result<f64> {
+    let upload_metrics = OrderExecutionMetrics {
+        uploaded_mb: size_mb,
+        uploaded_files: file_list.len(),
+        ..Default::default()
+    };
+    let upload_cost = upload_metrics.calculate_costs(pricing).grand_total();
+
+    let planned: Vec<&str> = file_list.iter().map(String::as_str).collect();
+    let execution_cost = find_similar_orders(db, account_id, &planned)
+        .await?
+        .iter()
+        .map(|metrics| execution_cost(metrics, pricing))
+        .reduce(f64::max)
+        .map(|cost| cost * RESERVATION_MARGIN)
+        .unwrap_or_else(|| {
+            std::env::var("API_KEY_DEFAULT_RESERVATION")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|v: &f64| v.is_finite() && *v > 0.0)
+                .unwrap_or(10000.0)
+        });
+
+    Ok(upload_cost + execution_cost)
+}
+
+/// The order in the path. Any other path parameters, like the token or the file name, are ignored.
+#[derive(Deserialize)]
+pub struct OrderPath {
+    id: i64,
+}
+
 pub async fn get_order_status(
     State(AppState {
         db,
         manager_connection,
     }): State<AppState>,
-    Path((token, order_id)): Path<(String, i64)>,
+    ReadOrdersAccess(account): ReadOrdersAccess,
+    Path(OrderPath { id: order_id }): Path<OrderPath>,
 ) -> Result<Json<OrderInfoResult>, AppError> {
-    let data = match sqlx::query!("SELECT orders.*, accounts.balance FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE token=? AND orders.id=?", token, order_id)
+    let data = match sqlx::query!("SELECT orders.*, accounts.balance FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE accounts.id=? AND orders.id=?", account.account_id, order_id)
         .fetch_optional(&db)
         .await?
     {
@@ -116,7 +331,7 @@ pub async fn get_order_status(
 
     match ManagerRequest::query_live_status(&manager_connection, order_id).await {
         Some(_handle) => Ok(Json(OrderInfoResult::Running {
-            balance_at_start: data.balance,
+            balance_at_start: get_available_funds(&db, data.user_id, data.balance).await?,
         })),
         None => {
             // It's not running: only use data from database.
@@ -127,6 +342,7 @@ pub async fn get_order_status(
                 ))?)?,
                 is_on_disk: data.is_on_disk,
                 created_at_unix_time: data.created_at_unix_time as u64,
+                refund: get_order_refund(&db, order_id).await?,
             })))
         }
     }
@@ -137,15 +353,22 @@ pub async fn get_live_order_status(
         db,
         manager_connection,
     }): State<AppState>,
-    Path((token, order_id)): Path<(String, i64)>,
+    ReadOrdersAccess(account): ReadOrdersAccess,
+    Path(OrderPath { id: order_id }): Path<OrderPath>,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, AppError> {
-    let _data = match sqlx::query!("SELECT orders.* FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE token=? AND orders.id=?", token, order_id)
-        .fetch_optional(&db)
-        .await?
+    let _data = match sqlx::query!(
+        "SELECT * FROM orders WHERE user_id=? AND id=?",
+        account.account_id,
+        order_id
+    )
+    .fetch_optional(&db)
+    .await?
     {
         Some(v) => v,
-        None => Err(anyhow::anyhow!("the order does not exist or is inaccessible"))?,
+        None => Err(anyhow::anyhow!(
+            "the order does not exist or is inaccessible"
+        ))?,
     };
 
     let status = ManagerRequest::query_live_status(&manager_connection, order_id).await;
@@ -153,7 +376,7 @@ pub async fn get_live_order_status(
         Some(handle) => Ok(ws.on_upgrade(move |ws| {
             handle_live_order_status(handle, order_id, db, manager_connection, ws)
         })),
-        None => Ok(ws.on_upgrade(move |ws| close_immediately(ws))),
+        None => Ok(ws.on_upgrade(close_immediately)),
     }
 }
 
@@ -167,13 +390,12 @@ async fn close_immediately(mut ws: WebSocket) {
     .await
     .unwrap();
     ws.close().await.unwrap();
-    return;
 }
 
 async fn handle_live_order_status(
     mut handle: RunningJobHandle,
-    order_id: i64,
-    db: SqlitePool,
+    _order_id: i64,
+    _db: SqlitePool,
     manager_connection: mpsc::Sender<ManagerRequest>,
     mut ws: WebSocket,
 ) {
@@ -184,10 +406,12 @@ async fn handle_live_order_status(
         tokio::select! {
             _ =  handle.status.changed() => {
                 if last_update_at.elapsed().unwrap() > update_interval {
+                    let status = (*handle.status.borrow_and_update()).clone();
                     let data = serde_json::to_string(
                         &LiveStatus{
-                            status: (&*handle.status.borrow_and_update()).clone(),
-                            pricing: get_current_pricing(),
+                            status,
+                            pricing: handle.pricing.clone(),
+                            current_surge_multiplier: get_surge_multiplier(&manager_connection).await,
                         }
                     ).unwrap();
                     ws.send(axum::extract::ws::Message::Text(data))
@@ -210,14 +434,9 @@ async fn handle_live_order_status(
             }
 
             Some(msg) = ws.recv() => {
-                if let Ok(msg) = msg {
-                    match msg {
-                        axum::extract::ws::Message::Text(_data) => {
-                            // The user has requested a stop: that's the only reason for receiving messages.
-                            handle.stop.cancel();
-                        },
-                        _ => {}
-                    }
+                if let Ok(axum::extract::ws::Message::Text(_data)) = msg {
+                    // The user has requested a stop: that's the only reason for receiving messages.
+                    handle.stop.cancel();
                 }
             }
         }
@@ -227,20 +446,24 @@ async fn handle_live_order_status(
 pub async fn get_order_file_list(
     State(AppState {
         db,
-        manager_connection,
+        manager_connection: _,
     }): State<AppState>,
-    Path((token, order_id)): Path<(String, i64)>,
+    ReadOrdersAccess(account): ReadOrdersAccess,
+    Path(OrderPath { id: order_id }): Path<OrderPath>,
 ) -> Result<Json<OrderFileList>, AppError> {
-    let data = match sqlx::query!("SELECT orders.* FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE token=? AND orders.id=?", token, order_id)
-        .fetch_optional(&db)
-        .await?
+    let data = match sqlx::query!(
+        "SELECT * FROM orders WHERE user_id=? AND id=?",
+        account.account_id,
+        order_id
+    )
+    .fetch_optional(&db)
+    .await?
     {
         Some(v) => v,
         None => return Err(anyhow!("Order does not exist or is inaccessible"))?,
     };
 
-    let src_list =
-        HashSet::from_iter(serde_json::from_str::<Vec<String>>(&data.src_file_list)?.into_iter());
+    let src_list = HashSet::from_iter(serde_json::from_str::<Vec<String>>(&data.src_file_list)?);
 
     // Collect the file list
     let mut files = vec![];
@@ -259,7 +482,7 @@ pub async fn get_order_file_list(
             let meta = v.metadata().await?;
             if meta.file_type().is_dir() {
                 let mut new_location = location.clone();
-                new_location.push(&name.to_string());
+                new_location.push(name.to_string());
                 let mut new_prefix = prefix.clone();
                 new_prefix.push_str(&name);
                 new_prefix.push('/');
@@ -298,12 +521,17 @@ pub struct FetchFileUrl {
 
 pub async fn fetch_file(
     State(AppState { db, .. }): State<AppState>,
-    Path((token, order_id, _fake_filename)): Path<(String, i64, String)>,
+    ReadOrdersAccess(account): ReadOrdersAccess,
+    Path(OrderPath { id: order_id }): Path<OrderPath>,
     Query(FetchFileUrl { path, download }): Query<FetchFileUrl>,
 ) -> Result<impl IntoResponse, AppError> {
-    let _data = match sqlx::query!("SELECT orders.* FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE token=? AND orders.id=?", token, order_id)
-        .fetch_optional(&db)
-        .await?
+    let _data = match sqlx::query!(
+        "SELECT * FROM orders WHERE user_id=? AND id=?",
+        account.account_id,
+        order_id
+    )
+    .fetch_optional(&db)
+    .await?
     {
         Some(v) => v,
         None => return Err(anyhow!("Order does not exist or is inaccessible"))?,
@@ -326,10 +554,9 @@ pub async fn fetch_file(
             return Ok((StatusCode::NOT_FOUND, format!("File not found: {}", err)).into_response())
         }
     };
-    let content_type = match mime_guess::from_path(&file_path).first_raw() {
-        Some(mime) => mime,
-        None => "application/octet-stream",
-    };
+    let content_type = mime_guess::from_path(&file_path)
+        .first_raw()
+        .unwrap_or("application/octet-stream");
 
     let stream = ReaderStream::new(file);
     let body = axum::body::Body::from_stream(stream);
@@ -337,7 +564,7 @@ pub async fn fetch_file(
     let mut headers = HeaderMap::new();
     headers.insert(header::CONTENT_TYPE, content_type.try_into().unwrap());
 
-    if let Some(_) = download {
+    if download.is_some() {
         headers.insert(
             header::CONTENT_DISPOSITION,
             format!(
@@ -359,20 +586,36 @@ pub enum StreamKind {
     Stderr,
 }
 
+#[derive(Deserialize)]
+pub struct OrderStreamPath {
+    id: i64,
+    stream: StreamKind,
+}
+
 pub async fn get_live_order_stream(
     State(AppState {
         db,
         manager_connection,
     }): State<AppState>,
-    Path((token, order_id, stream)): Path<(String, i64, StreamKind)>,
+    ReadOrdersAccess(account): ReadOrdersAccess,
+    Path(OrderStreamPath {
+        id: order_id,
+        stream,
+    }): Path<OrderStreamPath>,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, AppError> {
-    let _data = match sqlx::query!("SELECT orders.* FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE token=? AND orders.id=?", token, order_id)
-        .fetch_optional(&db)
-        .await?
+    let _data = match sqlx::query!(
+        "SELECT * FROM orders WHERE user_id=? AND id=?",
+        account.account_id,
+        order_id
+    )
+    .fetch_optional(&db)
+    .await?
     {
         Some(v) => v,
-        None => Err(anyhow::anyhow!("the order does not exist or is inaccessible"))?,
+        None => Err(anyhow::anyhow!(
+            "the order does not exist or is inaccessible"
+        ))?,
     };
 
     let status = ManagerRequest::query_live_status(&manager_connection, order_id).await;
@@ -387,11 +630,11 @@ pub async fn get_live_order_stream(
 }
 
 async fn handle_live_order_stream(
-    mut handle: Option<RunningJobHandle>,
+    handle: Option<RunningJobHandle>,
     order_id: i64,
     stream: StreamKind,
-    db: SqlitePool,
-    manager_connection: mpsc::Sender<ManagerRequest>,
+    _db: SqlitePool,
+    _manager_connection: mpsc::Sender<ManagerRequest>,
     mut ws: WebSocket,
 ) {
     let path = match stream {
@@ -448,7 +691,7 @@ async fn handle_live_order_stream(
                                     ws.send(axum::extract::ws::Message::Close(Some(
                                     axum::extract::ws::CloseFrame {
                                         code: 1000,
-                                        reason: format!("The job was terminated and will no longer produce any output").into(),
+                                        reason: "The job was terminated and will no longer produce any output".to_string().into(),
                                     },
                                 )))
                                 .await
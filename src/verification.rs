@@ -1 +1,81 @@
+//! Making sure that an account belongs to a real person before it can make orders.
+//!
+//! Which methods are offered is set with the `VERIFICATION_METHODS` environment variable,
+//! a comma-separated list of `proof-of-work`, `email-code`, `admin-approval` and `promocode`.
+//! By default, all of them are offered, except that `email-code` needs a mail sender (see [`crate::mail`]).
+
+pub mod admin_approval;
+pub mod email_code;
 pub mod proof_of_work;
+
+use api::VerificationMethod;
+use sqlx::SqliteExecutor;
+
+const ALL_METHODS: [VerificationMethod; 4] = [
+    VerificationMethod::ProofOfWork,
+    VerificationMethod::EmailCode,
+    VerificationMethod::AdminApproval,
+    VerificationMethod::Promocode,
+];
+
+fn method_name(method: VerificationMethod) -> &'static str {
+    match method {
+        VerificationMethod::None => "none",
+        VerificationMethod::ProofOfWork => "proof-of-work",
+        VerificationMethod::EmailCode => "email-code",
+        VerificationMethod::AdminApproval => "admin-approval",
+        VerificationMethod::Promocode => "promocode",
+        VerificationMethod::SingleSignOn => "single-sign-on",
+    }
+}
+
+/// The verification methods that this server offers.
+pub fn enabled_methods() -> Vec<VerificationMethod> {
+    match std::env::var("VERIFICATION_METHODS") {
+        Ok(list) => {
+            let names: Vec<&str> = list.split(',').map(str::trim).collect();
+            ALL_METHODS
+                .into_iter()
+                .filter(|method| names.contains(&method_name(*method)))
+                .collect()
+        }
+        Err(_) => ALL_METHODS
+            .into_iter()
+            .filter(|method| {
+                *method != VerificationMethod::EmailCode || crate::mail::is_configured()
+            })
+            .collect(),
+    }
+}
+
+pub fn is_enabled(method: VerificationMethod) -> bool {
+    enabled_methods().contains(&method)
+}
+
+pub fn require_enabled(method: VerificationMethod) -> anyhow::Result<()> {
+    if !is_enabled(method) {
+        return Err(anyhow::anyhow!(
+            "Verification by {} is not offered on this server",
+            method_name(method)
+        ));
+    }
+    Ok(())
+}
+
+/// Mark the account as verified by the method, unless it was verified already.
+/// Returns whether it was changed.
+pub async fn mark_verified<'c>(
+    db: impl SqliteExecutor<'c>,
+    account_id: i64,
+    method: VerificationMethod,
+) -> anyhow::Result<bool> {
+    let method = method as i64;
+    let result = sqlx::query!(
+        "UPDATE accounts SET verification_method=? WHERE id=? AND verification_method IS NULL",
+        method,
+        account_id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
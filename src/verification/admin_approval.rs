@@ -0,0 +1,144 @@
+//! Verifying an account by asking the staff to approve it by hand.
+
+use api::{
+    verification::{ApprovalRequest, ApprovalRequestResponse},
+    VerificationMethod,
+};
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::CurrentAccount,
+    result::AppError,
+    verification::{mark_verified, require_enabled},
+    AppState,
+};
+
+/// The longest note that can be left with a request.
+const MAX_NOTE_LEN: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+pub struct PendingVerificationRequest {
+    pub id: i64,
+    pub account_id: i64,
+    pub name: String,
+    pub handle: Option<String>,
+    pub note: String,
+    pub created_at_unix_time: i64,
+}
+
+pub async fn request_approval(
+    State(AppState { db, .. }): State<AppState>,
+    current: CurrentAccount,
+    Json(ApprovalRequest { note }): Json<ApprovalRequest>,
+) -> Result<Json<ApprovalRequestResponse>, AppError> {
+    require_enabled(VerificationMethod::AdminApproval)?;
+
+    let account = sqlx::query!("SELECT * FROM accounts WHERE id=?", current.account_id)
+        .fetch_one(&db)
+        .await?;
+    if account.verification_method.is_some() {
+        return Ok(Json(ApprovalRequestResponse::AlreadyVerified));
+    }
+
+    let note = note.trim();
+    if note.is_empty() {
+        return Ok(Json(ApprovalRequestResponse::EmptyNote));
+    }
+    let note: String = note.chars().take(MAX_NOTE_LEN).collect();
+
+    if has_pending_request(&db, account.id).await? {
+        return Ok(Json(ApprovalRequestResponse::AlreadyRequested));
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    sqlx::query!(
+        "INSERT INTO verification_requests (account_id, note, created_at_unix_time) VALUES (?,?,?)",
+        account.id,
+        note,
+        now
+    )
+    .execute(&db)
+    .await?;
+
+    Ok(Json(ApprovalRequestResponse::Requested))
+}
+
+pub async fn has_pending_request(db: &SqlitePool, account_id: i64) -> anyhow::Result<bool> {
+    Ok(sqlx::query!(
+        "SELECT id FROM verification_requests WHERE account_id=? AND decided_at_unix_time IS NULL",
+        account_id
+    )
+    .fetch_optional(db)
+    .await?
+    .is_some())
+}
+
+/// The requests that are still waiting for a decision, oldest first.
+pub async fn pending_requests(db: &SqlitePool) -> anyhow::Result<Vec<PendingVerificationRequest>> {
+    let rows = sqlx::query!(
+        r#"SELECT verification_requests.*, accounts.user_name, logins.handle AS "handle?"
+        FROM verification_requests
+        INNER JOIN accounts ON accounts.id=verification_requests.account_id
+        LEFT JOIN logins ON logins.account_id=verification_requests.account_id
+        WHERE decided_at_unix_time IS NULL
+        ORDER BY created_at_unix_time"#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| PendingVerificationRequest {
+            id: row.id,
+            account_id: row.account_id,
+            name: row.user_name,
+            handle: row.handle,
+            note: row.note,
+            created_at_unix_time: row.created_at_unix_time,
+        })
+        .collect())
+}
+
+/// Approve or reject a pending request; approving it verifies the account.
+/// Returns the account that made the request.
+pub async fn decide_request(
+    db: &SqlitePool,
+    request_id: i64,
+    approve: bool,
+    decided_by: i64,
+) -> anyhow::Result<i64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let mut tx = db.begin().await?;
+    let account_id = sqlx::query!(
+        "UPDATE verification_requests SET approved=?, decided_by=?, decided_at_unix_time=? WHERE id=? AND decided_at_unix_time IS NULL RETURNING account_id",
+        approve,
+        decided_by,
+        now,
+        request_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("No such pending verification request"))?
+    .account_id;
+    if account_id == decided_by {
+        return Err(anyhow::anyhow!(
+            "Staff can't decide on their own verification requests"
+        ));
+    }
+
+    if approve {
+        mark_verified(&mut *tx, account_id, VerificationMethod::AdminApproval).await?;
+    }
+    tx.commit().await?;
+
+    Ok(account_id)
+}
@@ -0,0 +1,469 @@
+//! Verifying an account by sending a code to an email address, and having it entered back.
+//!
+//! Each address can verify only one account.
+//! To only accept addresses from, say, a university, set `VERIFICATION_EMAIL_DOMAINS`
+//! to a comma-separated list of domains.
+
+use api::{
+    verification::{
+        ConfirmEmailCodeRequest, ConfirmEmailCodeResponse, SendEmailCodeRequest,
+        SendEmailCodeResponse,
+    },
+    VerificationMethod,
+};
+use axum::{extract::State, Json};
+use rand::Rng;
+
+use crate::{
+    auth::CurrentAccount,
+    mail::configured_sender,
+    result::AppError,
+    sessions::hash_token,
+    verification::{mark_verified, require_enabled},
+    AppState,
+};
+
+/// How long a code can be entered for.
+const CODE_LIFETIME_SECONDS: i64 = 15 * 60;
+
+/// How long to wait before sending another code to the same account.
+const RESEND_INTERVAL_SECONDS: i64 = 60;
+
+/// How many wrong codes can be entered before a new one must be sent.
+const CODE_ATTEMPTS: i64 = 5;
+
+fn allowed_domains() -> Vec<String> {
+    std::env::var("VERIFICATION_EMAIL_DOMAINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|d| d.trim().to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+/// The code is stored hashed together with the account, like session tokens are.
+fn hash_code(account_id: i64, code: &str) -> String {
+    hash_token(&format!("{account_id}:{code}"))
+}
+
+pub async fn send_code(
+    State(AppState { db, .. }): State<AppState>,
+    current: CurrentAccount,
+    Json(SendEmailCodeRequest { email }): Json<SendEmailCodeRequest>,
+) -> Result<Json<SendEmailCodeResponse>, AppError> {
+    require_enabled(VerificationMethod::EmailCode)?;
+
+    let account = sqlx::query!("SELECT * FROM accounts WHERE id=?", current.account_id)
+        .fetch_one(&db)
+        .await?;
+    if account.verification_method.is_some() {
+        return Ok(Json(SendEmailCodeResponse::AlreadyVerified));
+    }
+
+    let email = email.trim().to_lowercase();
+    let domain = match email.parse::<lettre::Address>() {
+        Ok(address) => address.domain().to_string(),
+        Err(_) => return Ok(Json(SendEmailCodeResponse::InvalidEmail)),
+    };
+    let allowed_domains = allowed_domains();
+    if !allowed_domains.is_empty() && !allowed_domains.contains(&domain) {
+        return Ok(Json(SendEmailCodeResponse::DomainNotAllowed {
+            allowed_domains,
+        }));
+    }
+
+    let taken = sqlx::query!("SELECT id FROM accounts WHERE verified_email=?", email)
+        .fetch_optional(&db)
+        .await?
+        .is_some();
+    if taken {
+        return Ok(Json(SendEmailCodeResponse::EmailTaken));
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    if let Some(previous) = sqlx::query!(
+        "SELECT sent_at_unix_time FROM email_verification_codes WHERE account_id=?",
+        account.id
+    )
+    .fetch_optional(&db)
+    .await?
+    {
+        let retry_at = previous.sent_at_unix_time + RESEND_INTERVAL_SECONDS;
+        if retry_at > now {
+            return Ok(Json(SendEmailCodeResponse::TooSoon {
+                retry_after_seconds: (retry_at - now) as u64,
+            }));
+        }
+    }
+
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let code_hash = hash_code(account.id, &code);
+    let expires_at = now + CODE_LIFETIME_SECONDS;
+
+    // The mail is sent first, so that a failure to send doesn't count against the resend interval.
+    configured_sender()?
+        .send(
+            &email,
+            "Код подтверждения",
+            &format!(
+                "Ваш код для подтверждения аккаунта {}: {code}\n\nКод действует {} минут. Если вы его не запрашивали, просто проигнорируйте это письмо.",
+                account.user_name,
+                CODE_LIFETIME_SECONDS / 60
+            ),
+        )
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO email_verification_codes (account_id, email, code_hash, attempts_left, sent_at_unix_time, expires_at_unix_time) VALUES (?,?,?,?,?,?)
+        ON CONFLICT (account_id) DO UPDATE SET
+            email=excluded.email,
+            code_hash=excluded.code_hash,
+            attempts_left=excluded.attempts_left,
+            sent_at_unix_time=excluded.sent_at_unix_time,
+            expires_at_unix_time=excluded.expires_at_unix_time",
+        account.id,
+        email,
+        code_hash,
+        CODE_ATTEMPTS,
+        now,
+        expires_at
+    )
+    .execute(&db)
+    .await?;
+
+    Ok(Json(SendEmailCodeResponse::Sent))
+}
+
+pub async fn confirm_code(
+    State(AppState { db, .. }): State<AppState>,
+    current: CurrentAccount,
+    Json(ConfirmEmailCodeRequest { code }): Json<ConfirmEmailCodeRequest>,
+) -> Result<Json<ConfirmEmailCodeResponse>, AppError> {
+    require_enabled(VerificationMethod::EmailCode)?;
+
+    let account = sqlx::query!("SELECT * FROM accounts WHERE id=?", current.account_id)
+        .fetch_one(&db)
+        .await?;
+    if account.verification_method.is_some() {
+        return Ok(Json(ConfirmEmailCodeResponse::AlreadyVerified));
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let mut tx = db.begin().await?;
+    let sent = match sqlx::query!(
+        "SELECT * FROM email_verification_codes WHERE account_id=? AND expires_at_unix_time>? AND attempts_left>0",
+        account.id,
+        now
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(Json(ConfirmEmailCodeResponse::NoCodeSent)),
+    };
+
+    if hash_code(account.id, code.trim()) != sent.code_hash {
+        let attempts_left = sqlx::query!(
+            "UPDATE email_verification_codes SET attempts_left=attempts_left-1 WHERE account_id=? RETURNING attempts_left",
+            account.id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .attempts_left;
+        tx.commit().await?;
+        return Ok(Json(ConfirmEmailCodeResponse::WrongCode {
+            attempts_left: attempts_left.max(0) as u64,
+        }));
+    }
+
+    // Another account may have claimed the address while this code was in the mail.
+    let claimed = sqlx::query!(
+        "UPDATE accounts SET verified_email=? WHERE id=? AND NOT EXISTS (SELECT 1 FROM accounts WHERE verified_email=?)",
+        sent.email,
+        account.id,
+        sent.email
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if claimed == 0 {
+        return Ok(Json(ConfirmEmailCodeResponse::EmailTaken));
+    }
+    mark_verified(&mut *tx, account.id, VerificationMethod::EmailCode).await?;
+    sqlx::query!(
+        "DELETE FROM email_verification_codes WHERE account_id=?",
+        account.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(ConfirmEmailCodeResponse::Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use api::Role;
+    use base64::Engine;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::testing::{set_env, TestEnv, TestServer};
+
+    /// A local SMTP server that accepts any mail, and passes every message on to the test.
+    async fn start_smtp_sink() -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_smtp(socket, sender.clone()));
+            }
+        });
+        (port, receiver)
+    }
+
+    async fn serve_smtp(
+        socket: TcpStream,
+        messages: mpsc::UnboundedSender<String>,
+    ) -> std::io::Result<()> {
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 sink ESMTP\r\n").await?;
+        while let Some(line) = lines.next_line().await? {
+            let command = line.to_ascii_uppercase();
+            if command.starts_with("EHLO") {
+                write.write_all(b"250-sink\r\n250 8BITMIME\r\n").await?;
+            } else if command.starts_with("DATA") {
+                write.write_all(b"354 go ahead\r\n").await?;
+                let mut message = String::new();
+                while let Some(line) = lines.next_line().await? {
+                    if line == "." {
+                        break;
+                    }
+                    message.push_str(line.strip_prefix('.').unwrap_or(&line));
+                    message.push('\n');
+                }
+                let _ = messages.send(message);
+                write.write_all(b"250 queued\r\n").await?;
+            } else if command.starts_with("QUIT") {
+                write.write_all(b"221 bye\r\n").await?;
+                break;
+            } else {
+                write.write_all(b"250 ok\r\n").await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn mail_env(smtp_port: u16) -> TestEnv {
+        set_env(&[
+            ("MAIL_SENDER", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_TLS", "none"),
+            ("SMTP_PORT", &smtp_port.to_string()),
+            ("MAIL_FROM", "Pandoc <noreply@example.test>"),
+        ])
+        .await
+    }
+
+    /// Take the next mail from the sink, and find the code in it, for the account with this name.
+    async fn received_code(
+        messages: &mut mpsc::UnboundedReceiver<String>,
+        to: &str,
+        name: &str,
+    ) -> String {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(10), messages.recv())
+            .await
+            .expect("No mail was sent")
+            .unwrap();
+        let (headers, body) = message.split_once("\n\n").unwrap();
+        assert!(headers.contains(&format!("To: {to}")));
+        let encoding = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Transfer-Encoding: "))
+            .unwrap_or("7bit");
+        let body = match encoding {
+            "base64" => String::from_utf8(
+                base64::prelude::BASE64_STANDARD
+                    .decode(body.lines().collect::<String>())
+                    .unwrap(),
+            )
+            .unwrap(),
+            "quoted-printable" => body.replace("=\n", ""),
+            _ => body.to_string(),
+        };
+        let marker = format!("{name}: ");
+        let start = body.find(&marker).expect("The mail has no code") + marker.len();
+        body[start..start + 6].to_string()
+    }
+
+    fn account(account_id: i64) -> CurrentAccount {
+        CurrentAccount {
+            account_id,
+            session_id: None,
+            api_key: None,
+        }
+    }
+
+    async fn send(server: &TestServer, account_id: i64, email: &str) -> SendEmailCodeResponse {
+        send_code(
+            State(server.state.clone()),
+            account(account_id),
+            Json(SendEmailCodeRequest {
+                email: email.to_string(),
+            }),
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    async fn confirm(server: &TestServer, account_id: i64, code: &str) -> ConfirmEmailCodeResponse {
+        confirm_code(
+            State(server.state.clone()),
+            account(account_id),
+            Json(ConfirmEmailCodeRequest {
+                code: code.to_string(),
+            }),
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    fn wrong_code(code: &str) -> String {
+        format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000)
+    }
+
+    #[tokio::test]
+    async fn code_from_the_mail_verifies_the_account() {
+        let (port, mut messages) = start_smtp_sink().await;
+        let _env = mail_env(port).await;
+        let server = TestServer::new().await;
+        let student = server.make_account("student", Role::Student, None).await;
+
+        assert_eq!(
+            send(&server, student, " Student@Example.test ").await,
+            SendEmailCodeResponse::Sent
+        );
+        let code = received_code(&mut messages, "student@example.test", "student").await;
+        assert!(matches!(
+            send(&server, student, "student@example.test").await,
+            SendEmailCodeResponse::TooSoon { .. }
+        ));
+
+        assert_eq!(
+            confirm(&server, student, &wrong_code(&code)).await,
+            ConfirmEmailCodeResponse::WrongCode {
+                attempts_left: CODE_ATTEMPTS as u64 - 1
+            }
+        );
+        assert_eq!(
+            confirm(&server, student, &code).await,
+            ConfirmEmailCodeResponse::Ok
+        );
+        let (verified_email, verification_method): (Option<String>, Option<i64>) =
+            sqlx::query_as("SELECT verified_email, verification_method FROM accounts WHERE id=?")
+                .bind(student)
+                .fetch_one(server.db())
+                .await
+                .unwrap();
+        assert_eq!(verified_email.as_deref(), Some("student@example.test"));
+        assert!(verification_method.is_some());
+        assert_eq!(
+            confirm(&server, student, &code).await,
+            ConfirmEmailCodeResponse::AlreadyVerified
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_use_up_the_code() {
+        let (port, mut messages) = start_smtp_sink().await;
+        let _env = mail_env(port).await;
+        let server = TestServer::new().await;
+        let student = server.make_account("student", Role::Student, None).await;
+
+        send(&server, student, "student@example.test").await;
+        let code = received_code(&mut messages, "student@example.test", "student").await;
+        for _ in 0..CODE_ATTEMPTS {
+            confirm(&server, student, &wrong_code(&code)).await;
+        }
+        assert_eq!(
+            confirm(&server, student, &code).await,
+            ConfirmEmailCodeResponse::NoCodeSent
+        );
+    }
+
+    #[tokio::test]
+    async fn address_verifies_only_one_account() {
+        let (port, mut messages) = start_smtp_sink().await;
+        let _env = mail_env(port).await;
+        let server = TestServer::new().await;
+        let first = server.make_account("first", Role::Student, None).await;
+        let second = server.make_account("second", Role::Student, None).await;
+
+        // Both get a code before either confirms it: only the first to confirm gets the address.
+        send(&server, first, "shared@example.test").await;
+        let first_code = received_code(&mut messages, "shared@example.test", "first").await;
+        send(&server, second, "shared@example.test").await;
+        let second_code = received_code(&mut messages, "shared@example.test", "second").await;
+
+        assert_eq!(
+            confirm(&server, first, &first_code).await,
+            ConfirmEmailCodeResponse::Ok
+        );
+        assert_eq!(
+            confirm(&server, second, &second_code).await,
+            ConfirmEmailCodeResponse::EmailTaken
+        );
+        assert_eq!(
+            send(&server, second, "shared@example.test").await,
+            SendEmailCodeResponse::EmailTaken
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_mail_does_not_hold_up_the_next_code() {
+        // Nothing listens on a port that was just freed.
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let _env = mail_env(closed_port).await;
+        let server = TestServer::new().await;
+        let student = server.make_account("student", Role::Student, None).await;
+
+        assert!(send_code(
+            State(server.state.clone()),
+            account(student),
+            Json(SendEmailCodeRequest {
+                email: "student@example.test".to_string(),
+            }),
+        )
+        .await
+        .is_err());
+
+        let (port, mut messages) = start_smtp_sink().await;
+        std::env::set_var("SMTP_PORT", port.to_string());
+        assert_eq!(
+            send(&server, student, "student@example.test").await,
+            SendEmailCodeResponse::Sent
+        );
+        received_code(&mut messages, "student@example.test", "student").await;
+    }
+}
@@ -1,91 +1,280 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
 use api::{
-    verification::{check_hash_difficulty, ProofOfWorkAttempt, ProofOfWorkChallenge},
+    verification::{
+        check_hash_difficulty, expected_hashes, ProofOfWorkAlgorithm, ProofOfWorkAttempt,
+        ProofOfWorkChallenge, ProofOfWorkChallengeResponse,
+    },
     VerificationMethod,
 };
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
     Json,
 };
-use itsdangerous::Signer;
+use itsdangerous::{IntoTimestampSigner, TimestampSigner};
 use rand::Rng;
+use sqlx::SqlitePool;
 
-use crate::{result::AppError, AppState};
+use crate::{
+    auth::CurrentAccount,
+    login_throttle::client_ip,
+    result::AppError,
+    verification::{mark_verified, require_enabled},
+    AppState,
+};
 
 // Salt is OK to be hardcoded: https://itsdangerous.palletsprojects.com/en/2.2.x/concepts/#the-salt
 const POW_SALT: &str = "pandoc-proof-of-work";
 
-pub async fn get_challenge() -> String {
+/// How long after it was given out a challenge can still be solved.
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// Which algorithm challenges use, and how hard they are.
+///
+/// Configured with environment variables:
+/// - `POW_ALGORITHM`: `sha256` (the default) or `argon2id`;
+/// - `POW_DIFFICULTY`: how many leading zero bits the hash needs at least (default 10 for SHA-256, 4 for Argon2id);
+/// - `POW_MAX_DIFFICULTY`: how hard challenges can get, for verification when there are many of them
+///   and for logging in after many failures (default 8 more);
+/// - `POW_HOURLY_BASELINE`: how many verifications an hour are normal (default 20);
+///   each time there are twice as many, challenges get twice as hard;
+/// - `POW_ARGON2_MEMORY_KIB` and `POW_ARGON2_ITERATIONS`: the Argon2id cost (default 8192 KiB and 1 iteration).
+#[derive(Debug, Clone, Copy)]
+pub struct PowSettings {
+    pub algorithm: ProofOfWorkAlgorithm,
+    pub difficulty: u64,
+    pub max_difficulty: u64,
+    pub hourly_baseline: i64,
+}
+
+impl PowSettings {
+    pub fn from_env() -> Self {
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        let (algorithm, default_difficulty) = match std::env::var("POW_ALGORITHM")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "argon2id" => {
+                let memory_kib = env_or("POW_ARGON2_MEMORY_KIB", 8192);
+                let iterations = env_or("POW_ARGON2_ITERATIONS", 1);
+                if let Err(why) = argon2::Params::new(memory_kib, iterations, 1, Some(32)) {
+                    panic!("Invalid Argon2 proof-of-work parameters: {why}");
+                }
+                (
+                    ProofOfWorkAlgorithm::Argon2id {
+                        memory_kib,
+                        iterations,
+                    },
+                    4,
+                )
+            }
+            "" | "sha256" => (ProofOfWorkAlgorithm::Sha256, 10),
+            other => panic!("Unknown POW_ALGORITHM {other:?}, expected sha256 or argon2id"),
+        };
+
+        let difficulty = env_or("POW_DIFFICULTY", default_difficulty);
+        Self {
+            algorithm,
+            difficulty,
+            max_difficulty: env_or("POW_MAX_DIFFICULTY", difficulty + 8).max(difficulty),
+            hourly_baseline: env_or("POW_HOURLY_BASELINE", 20).max(1),
+        }
+    }
+}
+
+/// Choose how hard a verification challenge for this client should be.
+///
+/// Each doubling of the verifications in the last hour over the baseline adds a bit,
+/// and so does each account that was already verified from the same IP in the last day.
+async fn choose_difficulty(
+    db: &SqlitePool,
+    settings: &PowSettings,
+    ip: &str,
+) -> anyhow::Result<u64> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let hour_ago = now - 60 * 60;
+    let day_ago = now - 24 * 60 * 60;
+
+    let recent = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM pow_verifications WHERE verified_at_unix_time>=?"#,
+        hour_ago
+    )
+    .fetch_one(db)
+    .await?
+    .count;
+    let from_ip = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM pow_verifications WHERE ip=? AND verified_at_unix_time>=?"#,
+        ip,
+        day_ago
+    )
+    .fetch_one(db)
+    .await?
+    .count;
+
+    let volume_extra = if recent > settings.hourly_baseline {
+        (recent / settings.hourly_baseline).ilog2() as u64 + 1
+    } else {
+        0
+    };
+    let difficulty = settings.difficulty + volume_extra + from_ip as u64;
+    Ok(difficulty.min(settings.max_difficulty))
+}
+
+/// The subject of a challenge for verifying this account.
+fn account_subject(account_id: i64) -> String {
+    format!("account:{account_id}")
+}
+
+/// Make a new challenge for the subject, signed with the given salt.
+pub fn sign_challenge(
+    salt: &'static str,
+    subject: &str,
+    algorithm: ProofOfWorkAlgorithm,
+    difficulty: u64,
+) -> String {
     let secret_key = std::env::var("SECRET_KEY").unwrap();
     let mut rng = rand::thread_rng();
     let nonce: [u8; 32] = rng.gen();
     let challenge = ProofOfWorkChallenge {
         nonce: nonce.into(),
-        difficulty: 10,
-        algorithm: api::verification::ProofOfWorkAlgorithm::Sha256,
+        difficulty,
+        algorithm,
+        subject: subject.to_string(),
     };
 
     let json_text = serde_json::to_string(&challenge).unwrap();
     let itsdangerous_signer = itsdangerous::default_builder(secret_key)
-        .with_salt(POW_SALT)
-        .build();
+        .with_salt(salt)
+        .build()
+        .into_timestamp_signer();
+
+    itsdangerous_signer.sign(json_text)
+}
 
-    let output = itsdangerous_signer.sign(json_text);
+/// Check that the challenge was signed by us with the given salt for the given subject,
+/// that it hasn't expired or been used before, and that the attempt solves it.
+/// The challenge is used up by any attempt, right or wrong; if it was solved, its difficulty is returned.
+pub async fn check_attempt(
+    db: &SqlitePool,
+    salt: &'static str,
+    subject: &str,
+    attempt: &ProofOfWorkAttempt,
+) -> anyhow::Result<u64> {
+    let secret_key = std::env::var("SECRET_KEY").unwrap();
+    let itsdangerous_signer = itsdangerous::default_builder(secret_key)
+        .with_salt(salt)
+        .build()
+        .into_timestamp_signer();
+    let unsigned = itsdangerous_signer
+        .unsign(&attempt.challenge_string)
+        .map_err(|why| anyhow::anyhow!("Failed to decode challenge string: {why}"))?;
+    let expires_at = unsigned.timestamp() + CHALLENGE_LIFETIME;
+    let challenge = unsigned
+        .value_if_not_expired(CHALLENGE_LIFETIME)
+        .map_err(|why| anyhow::anyhow!("Challenge is no longer valid: {why}"))?;
+    let challenge: ProofOfWorkChallenge = serde_json::from_str(challenge)?;
 
-    output
+    if challenge.subject != subject {
+        return Err(anyhow::anyhow!(
+            "Challenge was given to {:?}, but used by {subject:?}",
+            challenge.subject
+        ));
+    }
+
+    // Use up the challenge before hashing, so that one challenge can't be sent over and over
+    // to make the server compute hashes for free.
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let expires_at = expires_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    sqlx::query!(
+        "DELETE FROM used_pow_nonces WHERE expires_at_unix_time<?",
+        now
+    )
+    .execute(db)
+    .await?;
+    let inserted = sqlx::query!(
+        "INSERT INTO used_pow_nonces (nonce, expires_at_unix_time) VALUES (?,?) ON CONFLICT DO NOTHING",
+        challenge.nonce,
+        expires_at
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+        return Err(anyhow::anyhow!("Challenge has already been used"));
+    }
+
+    // Argon2id hashes take a while on purpose, so they are kept off the async threads.
+    let algorithm = challenge.algorithm;
+    let nonce = challenge.nonce.clone();
+    let postfix = attempt.nonce_postfix.clone();
+    let hash = tokio::task::spawn_blocking(move || algorithm.hash(&nonce, &postfix)).await?;
+    let solved_difficulty = check_hash_difficulty(&hash);
+    if challenge.difficulty > solved_difficulty {
+        return Err(anyhow::anyhow!(
+            "Proof of work not satisfied: required difficulty: {}, solved difficulty: {solved_difficulty}",
+            challenge.difficulty
+        ));
+    }
+
+    Ok(challenge.difficulty)
+}
+
+pub async fn get_challenge(
+    State(AppState { db, .. }): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    current: CurrentAccount,
+) -> Result<Json<ProofOfWorkChallengeResponse>, AppError> {
+    require_enabled(VerificationMethod::ProofOfWork)?;
+    let settings = PowSettings::from_env();
+    let ip = client_ip(&headers, peer);
+    let difficulty = choose_difficulty(&db, &settings, &ip).await?;
+
+    Ok(Json(ProofOfWorkChallengeResponse {
+        challenge: sign_challenge(
+            POW_SALT,
+            &account_subject(current.account_id),
+            settings.algorithm,
+            difficulty,
+        ),
+        expected_hashes: expected_hashes(difficulty),
+    }))
 }
 
 pub async fn verify_challenge(
     State(AppState { db, .. }): State<AppState>,
-    Path(token): Path<String>,
-    Json(ProofOfWorkAttempt {
-        challenge_string,
-        nonce_postfix,
-    }): Json<ProofOfWorkAttempt>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    current: CurrentAccount,
+    Json(attempt): Json<ProofOfWorkAttempt>,
 ) -> Result<String, AppError> {
     // We're only expecting valid attempts, so any errors can be returned as AppErrors.
     // The official client will retry in that case.
 
-    // Extract and verify the challenge
-    let secret_key = std::env::var("SECRET_KEY").unwrap();
-    let itsdangerous_signer = itsdangerous::default_builder(secret_key).build();
-    let challenge = itsdangerous_signer.unsign(&challenge_string);
-    let challenge = match challenge {
-        Ok(c) => c,
-        Err(why) => Err(anyhow::anyhow!(
-            "Failed to decode challenge string: {}",
-            why
-        ))?,
-    };
-
-    let challenge_struct: ProofOfWorkChallenge = serde_json::from_str(&challenge)
-        .map_err(|why| anyhow::anyhow!("failed to parse challenge: {why}; this is a bug!"))?;
-
-    // Verify the challenge
-    match challenge_struct.algorithm {
-        api::verification::ProofOfWorkAlgorithm::Sha256 => {
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(&challenge_struct.nonce);
-            hasher.update(&nonce_postfix);
-            let result = hasher.finalize();
-            let result = &result[..];
-
-            let target_difficulty = challenge_struct.difficulty;
-            let solved_difficulty = check_hash_difficulty(result);
-            if target_difficulty > solved_difficulty {
-                return Err(anyhow::anyhow!("Proof of work not satisfied: required difficulty: {target_difficulty}, solved difficulty: {solved_difficulty}"))?;
-            }
-        }
-    }
-
-    // Check if the account exists
-    let account = sqlx::query!("SELECT * FROM accounts WHERE token=?", token)
-        .fetch_optional(&db)
+    require_enabled(VerificationMethod::ProofOfWork)?;
+    let account = sqlx::query!("SELECT * FROM accounts WHERE id=?", current.account_id)
+        .fetch_one(&db)
         .await?;
-    let account = match account {
-        Some(a) => a,
-        None => return Err(anyhow::anyhow!("Invalid token"))?,
-    };
 
     // If the account is already verified, no need to verify it again
     if account.verification_method.is_some() {
@@ -95,13 +284,25 @@ pub async fn verify_challenge(
         ))?;
     }
 
+    let difficulty =
+        check_attempt(&db, POW_SALT, &account_subject(account.id), &attempt).await? as i64;
+
+    let ip = client_ip(&headers, peer);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
     sqlx::query!(
-        "UPDATE accounts SET verification_method=? WHERE token=?",
-        VerificationMethod::ProofOfWork as i64,
-        token
+        "INSERT INTO pow_verifications (account_id, ip, difficulty, verified_at_unix_time) VALUES (?,?,?,?)",
+        account.id,
+        ip,
+        difficulty,
+        now
     )
     .execute(&db)
     .await?;
 
+    mark_verified(&db, account.id, VerificationMethod::ProofOfWork).await?;
+
     Ok("success".to_string())
 }
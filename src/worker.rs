@@ -6,7 +6,10 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use api::{JobStatus, JobTerminationStatus, OrderExecutionMetrics, OrderInfo, TerminationCause};
+use api::{
+    JobStatus, JobTerminationStatus, OrderExecutionMetrics, OrderInfo, PricingInfo,
+    TerminationCause,
+};
 use nix::{
     sys::{
         time::TimeSpec,
@@ -18,7 +21,13 @@ use sqlx::SqlitePool;
 use tokio::sync::{broadcast, mpsc, watch};
 use tokio_util::sync::CancellationToken;
 
-use crate::{manager::ManagerRequest, pricing::get_current_pricing};
+use crate::{
+    api_keys::record_api_key_spending,
+    groups::{charge_order_cost, get_available_funds},
+    manager::ManagerRequest,
+    pricing::get_surged_pricing,
+    refund::apply_auto_refund,
+};
 
 /// This allows communicating with a job that's currently running.
 #[derive(Debug)]
@@ -32,6 +41,9 @@ pub struct RunningJobHandle {
     /// This channel will never be written to.
     /// If this channel is closed, then the job has terminated.
     pub job_termination: broadcast::Receiver<()>,
+
+    /// The pricing that was locked in when the job started.
+    pub pricing: PricingInfo,
 }
 
 impl Clone for RunningJobHandle {
@@ -40,6 +52,7 @@ impl Clone for RunningJobHandle {
             status: self.status.clone(),
             stop: self.stop.clone(),
             job_termination: self.job_termination.resubscribe(),
+            pricing: self.pricing.clone(),
         }
     }
 }
@@ -52,6 +65,7 @@ pub async fn run_order_work(
     db: SqlitePool,
     sender: mpsc::Sender<ManagerRequest>,
     (uploaded_files, uploaded_mb): (usize, f64),
+    budget: Option<f64>,
 ) -> anyhow::Result<()> {
     let (mut status_send, status_recv) = watch::channel(JobStatus::Preparing);
     let mut cancel = CancellationToken::new();
@@ -59,10 +73,23 @@ pub async fn run_order_work(
     // We'll drop this on the way out of the function (including on panics)
     let (_term_send, term_recv) = broadcast::channel(1);
 
+    // Lock in the pricing now, so that later changes in server load do not affect this order.
+    // It is written down too, for when the server restarts while the order is running.
+    let pricing = get_surged_pricing(&sender).await;
+    let pricing_json = serde_json::to_string(&pricing).unwrap();
+    sqlx::query!(
+        "UPDATE orders SET pricing_json=? WHERE id=?",
+        pricing_json,
+        order_id
+    )
+    .execute(&db)
+    .await?;
+
     let handle = RunningJobHandle {
         status: status_recv,
         stop: cancel.clone(),
         job_termination: term_recv,
+        pricing: pricing.clone(),
     };
 
     // The first thing to do is to announce ourselves.
@@ -77,14 +104,13 @@ pub async fn run_order_work(
         Some(v) => v,
         None => {
             let term = JobTerminationStatus::AbnormalTermination(format!("When job was preparing, could not find account associated with order {order_id}"));
-            let order_status = OrderInfo { balance_before: 0.0, order_cost: 0.0, pricing_applied: get_current_pricing(), termination: term.clone() };
-            let status_json = serde_json::to_string(&order_status).unwrap();
-            status_send.send_replace(JobStatus::Terminated(term));
-            sqlx::query!("UPDATE orders SET is_running=0, status_json=? WHERE id=?", status_json, order_id).execute(&db).await?;
+            status_send.send_replace(JobStatus::Terminated(term.clone()));
+            finish_failed_order(&db, order_id, pricing, term).await?;
             return Ok(());
         },
     };
-    let original_balance = user_data.balance;
+    // Orders can spend the group's balance as well as the personal one.
+    let original_balance = get_available_funds(&db, user_data.id, user_data.balance).await?;
 
     let pre_metrics = OrderExecutionMetrics {
         uploaded_mb,
@@ -93,14 +119,23 @@ pub async fn run_order_work(
     };
 
     tracing::warn!("Entering danger section");
-    let termination = fork_and_make(
+    let termination = match fork_and_make(
         order_id,
         pre_metrics,
         &mut status_send,
         &mut cancel,
         original_balance,
+        budget,
+        &pricing,
     )
-    .await?;
+    .await
+    {
+        Ok(termination) => termination,
+        // The order couldn't run because of us, so it is billed and refunded like one.
+        Err(why) => JobTerminationStatus::AbnormalTermination(format!(
+            "The server failed to run the order: {why}"
+        )),
+    };
     tracing::warn!("Exiting danger section");
 
     status_send.send_replace(JobStatus::Terminated(termination.clone()));
@@ -110,13 +145,17 @@ pub async fn run_order_work(
     let total_cost = if let JobTerminationStatus::ProcessExit { ref costs, .. } = &termination {
         costs.grand_total()
     } else {
-        get_current_pricing().error_order_cost // Small baseline cost for errored orders
+        pricing.error_order_cost // Small baseline cost for errored orders
     };
 
+    let group_charge = charge_order_cost(&mut transaction, order_id, user_data.id, total_cost).await?;
+    record_api_key_spending(&mut transaction, order_id, total_cost).await?;
+
     let order_status = OrderInfo {
-        balance_before: user_data.balance,
+        balance_before: original_balance,
         order_cost: total_cost,
-        pricing_applied: get_current_pricing(),
+        group_charge,
+        pricing_applied: pricing,
         termination: termination.clone(),
     };
     let status_json = serde_json::to_string(&order_status).unwrap();
@@ -129,16 +168,10 @@ pub async fn run_order_work(
     .execute(&mut *transaction)
     .await?;
 
-    sqlx::query!(
-        "UPDATE accounts SET balance=balance-? WHERE id=?",
-        total_cost,
-        user_data.id
-    )
-    .execute(&mut *transaction)
-    .await?;
-
     transaction.commit().await?;
 
+    apply_auto_refund(&db, order_id, &order_status).await;
+
     sender
         .send(ManagerRequest::FinishWork { order_id })
         .await
@@ -147,17 +180,76 @@ pub async fn run_order_work(
     Ok(())
 }
 
+/// Write down an order that failed on the server's side.
+/// An [`JobTerminationStatus::AbnormalTermination`] is charged the small baseline cost for errored orders,
+/// and whether that is given back is up to the automatic refund policy;
+/// a [`JobTerminationStatus::VeryAbnormalTermination`], like a restart or a crashed task, is never charged.
+pub async fn finish_failed_order(
+    db: &SqlitePool,
+    order_id: i64,
+    pricing: PricingInfo,
+    termination: JobTerminationStatus,
+) -> anyhow::Result<()> {
+    let mut transaction = db.begin().await?;
+    let account = sqlx::query!("SELECT accounts.id, accounts.balance FROM accounts INNER JOIN orders ON orders.user_id=accounts.id WHERE orders.id=?", order_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+    // If the account is gone, there is nobody to charge.
+    let (balance_before, order_cost, group_charge) = match account {
+        Some(account) => {
+            let balance_before =
+                get_available_funds(&mut *transaction, account.id, account.balance).await?;
+            let cost = match termination {
+                JobTerminationStatus::AbnormalTermination(_) => pricing.error_order_cost,
+                _ => 0.0,
+            };
+            let group_charge = if cost > 0.0 {
+                charge_order_cost(&mut transaction, order_id, account.id, cost).await?
+            } else {
+                0.0
+            };
+            // This also lets go of what was reserved from the API key's limit.
+            record_api_key_spending(&mut transaction, order_id, cost).await?;
+            (balance_before, cost, group_charge)
+        }
+        None => (0.0, 0.0, 0.0),
+    };
+
+    let order_status = OrderInfo {
+        balance_before,
+        order_cost,
+        group_charge,
+        pricing_applied: pricing,
+        termination,
+    };
+    let status_json = serde_json::to_string(&order_status).unwrap();
+    sqlx::query!(
+        "UPDATE orders SET is_running=0, status_json=? WHERE id=?",
+        status_json,
+        order_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    apply_auto_refund(db, order_id, &order_status).await;
+    Ok(())
+}
+
 async fn fork_and_make(
     order_id: i64,
     mut metrics: OrderExecutionMetrics,
     status: &mut watch::Sender<JobStatus>,
     cancel: &mut CancellationToken,
     user_balance_at_start: f64,
+    budget: Option<f64>,
+    pricing: &PricingInfo,
 ) -> anyhow::Result<JobTerminationStatus> {
     Ok(match unsafe { nix::unistd::fork() } {
         Ok(nix::unistd::ForkResult::Child) => {
             // We're the child process: chdir to the order's directory
-            std::env::set_current_dir(&Path::new(&format!("/compile/{order_id}")))
+            std::env::set_current_dir(Path::new(&format!("/compile/{order_id}")))
                 .expect("Child failed to chdir into the order directory");
 
             // Become the process group leader.
@@ -166,11 +258,13 @@ async fn fork_and_make(
             // Set up a stdout and stderr redirection.
             let stdout_file = std::fs::OpenOptions::new()
                 .create(true)
+                .truncate(false)
                 .write(true)
                 .open("make-stdout.txt")
                 .expect("Failed to open stdout file");
             let stderr_file = std::fs::OpenOptions::new()
                 .create(true)
+                .truncate(false)
                 .write(true)
                 .open("make-stderr.txt")
                 .expect("Failed to open stdout file");
@@ -210,7 +304,6 @@ async fn fork_and_make(
             //     child
             // );
             let spawned_at = std::time::SystemTime::now();
-            let pricing = get_current_pricing();
 
             // Loop, periodically waiting for the child.
             // Collect the process times each cycle.
@@ -237,10 +330,17 @@ async fn fork_and_make(
 
                 status.send_replace(JobStatus::Executing(metrics));
 
-                let total_cost = metrics.calculate_costs(&pricing).grand_total();
-                if user_balance_at_start - total_cost < 0.0 {
-                    if overdraft_started_at.is_none() {
-                        overdraft_started_at = Some(SystemTime::now());
+                let total_cost = metrics.calculate_costs(pricing).grand_total();
+                if user_balance_at_start - total_cost < 0.0 && overdraft_started_at.is_none() {
+                    overdraft_started_at = Some(SystemTime::now());
+                }
+
+                if let Some(budget) = budget {
+                    let remaining = budget - total_cost;
+                    metrics.remaining_budget = Some(remaining);
+                    if remaining <= 0.0 {
+                        child_should_die = true;
+                        termination_cause = TerminationCause::BudgetCapReached;
                     }
                 }
 
@@ -289,7 +389,7 @@ async fn fork_and_make(
                 exit_code: child_exit_status.unwrap(),
                 cause: termination_cause,
                 metrics,
-                costs: metrics.calculate_costs(&pricing),
+                costs: metrics.calculate_costs(pricing),
             }
         }
 